@@ -8,23 +8,24 @@ use diploma::{
         PrivateKey, PublicKey,
     },
     hash::Sha256Builder,
+    secret::Secret,
 };
 
 fn register(c: &mut Criterion) {
     let mut group = c.benchmark_group("register");
-    let password = [0xde; 32];
+    let password = Secret::new([0xde; 32]);
     group.bench_function("sha256-10000", |b| {
-        b.iter(|| Sha256Builder::private_from_password(10000, password))
+        b.iter(|| Sha256Builder::private_from_password(10000, password.clone()))
     });
 
-    let password = [0xde; 16];
-    let secret = [0xad; 16];
+    let password = Secret::new([0xde; 16]);
+    let secret = Secret::new([0xad; 16]);
 
     group.bench_function("aes128-software-10000", |b| {
-        b.iter(|| Aes128SafeBuilder::private_from_password(10000, secret, password))
+        b.iter(|| Aes128SafeBuilder::private_from_password(10000, secret.clone(), password.clone()))
     });
     group.bench_function("aes128-hardware-10000", |b| {
-        b.iter(|| Aes128NiBuilder::private_from_password(10000, secret, password))
+        b.iter(|| Aes128NiBuilder::private_from_password(10000, secret.clone(), password.clone()))
     });
 }
 
@@ -33,30 +34,34 @@ fn verify(c: &mut Criterion) {
 
     let private = Sha256Builder::new_private(5);
     let p0 = private.get_password().unwrap();
-    let public = Sha256Builder::new_public(p0);
+    let public = Sha256Builder::new_public(*p0.expose());
     let p1 = private.get_password().unwrap();
 
-    group.bench_function("sha256", |b| b.iter(|| public.verify_dry(&p1)));
+    group.bench_function("sha256", |b| b.iter(|| public.verify_dry(p1.expose())));
 
-    let secret = [0xad; 16];
+    let secret = Secret::new([0xad; 16]);
 
-    let private = Aes128SafeBuilder::new_private(5, secret);
+    let private = Aes128SafeBuilder::new_private(5, secret.clone());
 
     let p0 = private.get_password().unwrap();
-    let public = Aes128SafeBuilder::new_public(secret, p0);
+    let public = Aes128SafeBuilder::new_public(secret.clone(), *p0.expose());
 
     let p1 = private.get_password().unwrap();
 
-    group.bench_function("aes128-software", |b| b.iter(|| public.verify_dry(&p1)));
+    group.bench_function("aes128-software", |b| {
+        b.iter(|| public.verify_dry(p1.expose()))
+    });
 
-    let private = Aes128NiBuilder::new_private(5, secret);
+    let private = Aes128NiBuilder::new_private(5, secret.clone());
 
     let p0 = private.get_password().unwrap();
-    let public = Aes128NiBuilder::new_public(secret, p0);
+    let public = Aes128NiBuilder::new_public(secret, *p0.expose());
 
     let p1 = private.get_password().unwrap();
 
-    group.bench_function("aes128-hardware", |b| b.iter(|| public.verify_dry(&p1)));
+    group.bench_function("aes128-hardware", |b| {
+        b.iter(|| public.verify_dry(p1.expose()))
+    });
 }
 
 fn commitment_register(c: &mut Criterion) {
@@ -0,0 +1,104 @@
+/*
+ * C interface of the diploma one-time password library.
+ *
+ * Keys are opaque handles owned by the caller and released with the matching
+ * free function. Handles aren't thread safe. Every function returns
+ * DIPLOMA_OK or a negative DIPLOMA_ERR_* code and never aborts the caller.
+ *
+ * Byte strings are returned through a buffer and a pointer to its capacity.
+ * The capacity is replaced by the length needed, DIPLOMA_ERR_BUFFER means
+ * the buffer was too small, so a call with a capacity of 0 asks for the size.
+ *
+ * diploma_keypair_new() creates the user's private key and the verifier's
+ * public key. For hash chains (DIPLOMA_SHA256, DIPLOMA_AES128) a login is
+ *
+ *     diploma_private_password(), diploma_private_pop(),
+ *     diploma_public_verify() with next = NULL
+ *
+ * Commitments (DIPLOMA_COMMIT_*) have no fixed number of rounds. The user
+ * reveals the private element, moves on to a new one and sends its public
+ * element along, which the verifier expects the next time:
+ *
+ *     diploma_private_password(), diploma_private_pop(),
+ *     diploma_private_commitment(), diploma_public_verify() with next
+ *
+ * Serialized private keys hold every remaining password, keep them secret.
+ * Serialized public keys are the records of the verifier store.
+ */
+
+
+#ifndef DIPLOMA_H
+#define DIPLOMA_H
+
+/* Generated by cbindgen from capi/src/lib.rs, do not edit */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define DIPLOMA_OK 0
+
+#define DIPLOMA_ERR_NULL -1
+
+#define DIPLOMA_ERR_ARGUMENT -2
+
+#define DIPLOMA_ERR_AUTH -3
+
+#define DIPLOMA_ERR_EMPTY -4
+
+#define DIPLOMA_ERR_BUFFER -5
+
+#define DIPLOMA_ERR_DECODE -6
+
+#define DIPLOMA_ERR_PANIC -7
+
+#define DIPLOMA_SHA256 1
+
+#define DIPLOMA_AES128 2
+
+#define DIPLOMA_COMMIT_SHA256 3
+
+#define DIPLOMA_COMMIT_AES128 4
+
+#define DIPLOMA_COMMIT_ED25519 5
+
+typedef struct DiplomaPrivateKey DiplomaPrivateKey;
+
+typedef struct DiplomaPublicKey DiplomaPublicKey;
+
+int diploma_keypair_new(uint8_t algorithm,
+                        size_t rounds,
+                        struct DiplomaPrivateKey **private_key,
+                        struct DiplomaPublicKey **public_key);
+
+int diploma_private_password(const struct DiplomaPrivateKey *key, uint8_t *buf, size_t *len);
+
+int diploma_private_commitment(const struct DiplomaPrivateKey *key, uint8_t *buf, size_t *len);
+
+int diploma_private_pop(struct DiplomaPrivateKey *key);
+
+int diploma_private_serialize(const struct DiplomaPrivateKey *key, uint8_t *buf, size_t *len);
+
+int diploma_private_deserialize(const uint8_t *data, size_t len, struct DiplomaPrivateKey **key);
+
+void diploma_private_free(struct DiplomaPrivateKey *key);
+
+int diploma_public_new(uint8_t algorithm,
+                       const uint8_t *anchor,
+                       size_t anchor_len,
+                       const uint8_t *secret,
+                       size_t secret_len,
+                       struct DiplomaPublicKey **key);
+
+int diploma_public_verify(struct DiplomaPublicKey *key,
+                          const uint8_t *password,
+                          size_t password_len,
+                          const uint8_t *next,
+                          size_t next_len);
+
+int diploma_public_serialize(const struct DiplomaPublicKey *key, uint8_t *buf, size_t *len);
+
+int diploma_public_deserialize(const uint8_t *data, size_t len, struct DiplomaPublicKey **key);
+
+void diploma_public_free(struct DiplomaPublicKey *key);
+
+#endif /* DIPLOMA_H */
@@ -0,0 +1,498 @@
+// Every function takes raw pointers from C, the contract is in the header
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+    os::raw::c_int,
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+};
+
+use crypto::{aessafe::AesSafe128Encryptor, sha2::Sha256};
+use zeroize::Zeroizing;
+
+use diploma::{
+    base::{self, State},
+    cipher::{Aes128SafeBuilder, BlockOneWay},
+    commitment::{self, aes::Aes128SafeEncryptor, ed25519::Ed25519},
+    hash::Sha256Builder,
+    keystore::{Element, Persist},
+    secret::Secret,
+    store::{Algorithm, Record},
+};
+
+// C interface, see include/diploma.h. Keys are opaque handles owned by the
+// caller. Functions return one of the codes below and never unwind into C.
+// Byte strings are returned through a buffer and its capacity; the capacity
+// is replaced by the length needed, so a call with a capacity of 0 asks for
+// the size.
+
+pub const DIPLOMA_OK: c_int = 0;
+pub const DIPLOMA_ERR_NULL: c_int = -1;
+pub const DIPLOMA_ERR_ARGUMENT: c_int = -2;
+pub const DIPLOMA_ERR_AUTH: c_int = -3;
+pub const DIPLOMA_ERR_EMPTY: c_int = -4;
+pub const DIPLOMA_ERR_BUFFER: c_int = -5;
+pub const DIPLOMA_ERR_DECODE: c_int = -6;
+pub const DIPLOMA_ERR_PANIC: c_int = -7;
+
+// Same ids as `store::Algorithm`
+pub const DIPLOMA_SHA256: u8 = 1;
+pub const DIPLOMA_AES128: u8 = 2;
+pub const DIPLOMA_COMMIT_SHA256: u8 = 3;
+pub const DIPLOMA_COMMIT_AES128: u8 = 4;
+pub const DIPLOMA_COMMIT_ED25519: u8 = 5;
+
+type Aes128Key = base::PrivateKey<BlockOneWay<AesSafe128Encryptor, 16>, 16>;
+
+enum Private {
+    Sha256(base::PrivateKey<Sha256, 32>),
+    // The chain key is only needed by the verifier, but travels with it
+    Aes128(Secret<16>, Aes128Key),
+    CommitSha256(commitment::PrivateKey<commitment::hash::Sha256>),
+    CommitAes128(commitment::PrivateKey<Aes128SafeEncryptor>),
+    CommitEd25519(commitment::PrivateKey<Ed25519>),
+}
+
+pub struct DiplomaPrivateKey(Private);
+
+// Verifier state, the same as a store record
+pub struct DiplomaPublicKey(Record);
+
+impl Private {
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            Private::Sha256(_) => Algorithm::Sha256,
+            Private::Aes128(..) => Algorithm::Aes128,
+            Private::CommitSha256(_) => Algorithm::CommitSha256,
+            Private::CommitAes128(_) => Algorithm::CommitAes128,
+            Private::CommitEd25519(_) => Algorithm::CommitEd25519,
+        }
+    }
+
+    // Chains give their first password to the verifier and drop it
+    fn generate(algorithm: Algorithm, rounds: usize) -> Option<(Self, Record)> {
+        fn register<F: base::OneWay, const N: usize>(key: &mut base::PrivateKey<F, N>) -> Vec<u8> {
+            let anchor = key.get_password().unwrap().to_vec();
+            assert_eq!(key.pop_password(), State::Ok);
+            anchor
+        }
+
+        if algorithm.is_chain() && rounds == 0 {
+            return None;
+        }
+        let (private, anchor, secret) = match algorithm {
+            Algorithm::Sha256 => {
+                let mut key = Sha256Builder::new_private(rounds);
+                let anchor = register(&mut key);
+                (Private::Sha256(key), anchor, vec![])
+            }
+            Algorithm::Aes128 => {
+                let secret = Secret::random();
+                let mut key = Aes128SafeBuilder::new_private(rounds, secret.clone());
+                let anchor = register(&mut key);
+                let secret_bytes = secret.to_vec();
+                (Private::Aes128(secret, key), anchor, secret_bytes)
+            }
+            Algorithm::CommitSha256 => {
+                let key = commitment::PrivateKey::new(commitment::hash::Sha256);
+                let anchor = key.public().to_vec();
+                (Private::CommitSha256(key), anchor, vec![])
+            }
+            Algorithm::CommitAes128 => {
+                let key = commitment::PrivateKey::new(Aes128SafeEncryptor);
+                let anchor = key.public().to_vec();
+                (Private::CommitAes128(key), anchor, vec![])
+            }
+            Algorithm::CommitEd25519 => {
+                let key = commitment::PrivateKey::new(Ed25519);
+                let anchor = key.public().to_bytes().to_vec();
+                (Private::CommitEd25519(key), anchor, vec![])
+            }
+        };
+        Some((private, Record::new(algorithm, anchor, secret)))
+    }
+
+    // The password to send now, or the private element to reveal
+    fn password(&self) -> Option<Zeroizing<Vec<u8>>> {
+        match self {
+            Private::Sha256(key) => key.get_password().map(|p| Zeroizing::new(p.to_vec())),
+            Private::Aes128(_, key) => key.get_password().map(|p| Zeroizing::new(p.to_vec())),
+            Private::CommitSha256(key) => Some(key.private().to_bytes()),
+            Private::CommitAes128(key) => Some(key.private().to_bytes()),
+            Private::CommitEd25519(key) => Some(key.private().to_bytes()),
+        }
+    }
+
+    // Public element of the current private one, commitments only
+    fn commitment(&self) -> Option<Vec<u8>> {
+        match self {
+            Private::Sha256(_) | Private::Aes128(..) => None,
+            Private::CommitSha256(key) => Some(key.public().to_vec()),
+            Private::CommitAes128(key) => Some(key.public().to_vec()),
+            Private::CommitEd25519(key) => Some(key.public().to_bytes().to_vec()),
+        }
+    }
+
+    fn pop(&mut self) -> Result<(), c_int> {
+        if self.password().is_none() {
+            return Err(DIPLOMA_ERR_EMPTY);
+        }
+        match self {
+            // Popping the last password leaves an empty key, not an error
+            Private::Sha256(key) => drop(key.pop_password()),
+            Private::Aes128(_, key) => drop(key.pop_password()),
+            Private::CommitSha256(key) => key.advance(),
+            Private::CommitAes128(key) => key.advance(),
+            Private::CommitEd25519(key) => key.advance(),
+        }
+        Ok(())
+    }
+
+    fn encode(&self) -> Zeroizing<Vec<u8>> {
+        let mut out = Zeroizing::new(vec![self.algorithm().id()]);
+        let body = match self {
+            Private::Sha256(key) => key.encode(),
+            Private::Aes128(secret, key) => {
+                out.extend_from_slice(&secret[..]);
+                key.encode()
+            }
+            Private::CommitSha256(key) => key.encode(),
+            Private::CommitAes128(key) => key.encode(),
+            Private::CommitEd25519(key) => key.encode(),
+        };
+        out.extend_from_slice(&body);
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (id, rest) = bytes.split_first()?;
+        Some(match Algorithm::from_id(*id)? {
+            Algorithm::Sha256 => Private::Sha256(Persist::decode(rest)?),
+            Algorithm::Aes128 => {
+                let secret = Secret::from_slice(rest.get(..16)?)?;
+                Private::Aes128(secret, Persist::decode(&rest[16..])?)
+            }
+            Algorithm::CommitSha256 => Private::CommitSha256(Persist::decode(rest)?),
+            Algorithm::CommitAes128 => Private::CommitAes128(Persist::decode(rest)?),
+            Algorithm::CommitEd25519 => Private::CommitEd25519(Persist::decode(rest)?),
+        })
+    }
+}
+
+fn guard(f: impl FnOnce() -> Result<(), c_int>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => DIPLOMA_OK,
+        Ok(Err(code)) => code,
+        Err(_) => DIPLOMA_ERR_PANIC,
+    }
+}
+
+unsafe fn input<'a>(data: *const u8, len: usize) -> Result<&'a [u8], c_int> {
+    match (data.is_null(), len) {
+        (true, 0) => Ok(&[]),
+        (true, _) => Err(DIPLOMA_ERR_NULL),
+        (false, _) => Ok(slice::from_raw_parts(data, len)),
+    }
+}
+
+unsafe fn output(bytes: &[u8], buf: *mut u8, len: *mut usize) -> Result<(), c_int> {
+    let len = len.as_mut().ok_or(DIPLOMA_ERR_NULL)?;
+    let capacity = *len;
+    *len = bytes.len();
+    if capacity < bytes.len() {
+        return Err(DIPLOMA_ERR_BUFFER);
+    }
+    if !bytes.is_empty() {
+        if buf.is_null() {
+            return Err(DIPLOMA_ERR_NULL);
+        }
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buf, bytes.len());
+    }
+    Ok(())
+}
+
+unsafe fn handle<'a, T>(ptr: *const T) -> Result<&'a T, c_int> {
+    ptr.as_ref().ok_or(DIPLOMA_ERR_NULL)
+}
+
+unsafe fn handle_mut<'a, T>(ptr: *mut T) -> Result<&'a mut T, c_int> {
+    ptr.as_mut().ok_or(DIPLOMA_ERR_NULL)
+}
+
+unsafe fn store<T>(out: *mut *mut T, value: T) -> Result<(), c_int> {
+    let out = out.as_mut().ok_or(DIPLOMA_ERR_NULL)?;
+    *out = Box::into_raw(Box::new(value));
+    Ok(())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn diploma_keypair_new(
+    algorithm: u8,
+    rounds: usize,
+    private_key: *mut *mut DiplomaPrivateKey,
+    public_key: *mut *mut DiplomaPublicKey,
+) -> c_int {
+    guard(|| {
+        if private_key.is_null() || public_key.is_null() {
+            return Err(DIPLOMA_ERR_NULL);
+        }
+        let algorithm = Algorithm::from_id(algorithm).ok_or(DIPLOMA_ERR_ARGUMENT)?;
+        let (key, record) = Private::generate(algorithm, rounds).ok_or(DIPLOMA_ERR_ARGUMENT)?;
+        store(private_key, DiplomaPrivateKey(key))?;
+        store(public_key, DiplomaPublicKey(record))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn diploma_private_password(
+    key: *const DiplomaPrivateKey,
+    buf: *mut u8,
+    len: *mut usize,
+) -> c_int {
+    guard(|| {
+        let password = handle(key)?.0.password().ok_or(DIPLOMA_ERR_EMPTY)?;
+        output(&password, buf, len)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn diploma_private_commitment(
+    key: *const DiplomaPrivateKey,
+    buf: *mut u8,
+    len: *mut usize,
+) -> c_int {
+    guard(|| {
+        let public = handle(key)?.0.commitment().ok_or(DIPLOMA_ERR_ARGUMENT)?;
+        output(&public, buf, len)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn diploma_private_pop(key: *mut DiplomaPrivateKey) -> c_int {
+    guard(|| handle_mut(key)?.0.pop())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn diploma_private_serialize(
+    key: *const DiplomaPrivateKey,
+    buf: *mut u8,
+    len: *mut usize,
+) -> c_int {
+    guard(|| output(&handle(key)?.0.encode(), buf, len))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn diploma_private_deserialize(
+    data: *const u8,
+    len: usize,
+    key: *mut *mut DiplomaPrivateKey,
+) -> c_int {
+    guard(|| {
+        let private = Private::decode(input(data, len)?).ok_or(DIPLOMA_ERR_DECODE)?;
+        store(key, DiplomaPrivateKey(private))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn diploma_private_free(key: *mut DiplomaPrivateKey) {
+    if !key.is_null() {
+        drop(Box::from_raw(key));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn diploma_public_new(
+    algorithm: u8,
+    anchor: *const u8,
+    anchor_len: usize,
+    secret: *const u8,
+    secret_len: usize,
+    key: *mut *mut DiplomaPublicKey,
+) -> c_int {
+    guard(|| {
+        let algorithm = Algorithm::from_id(algorithm).ok_or(DIPLOMA_ERR_ARGUMENT)?;
+        let anchor = input(anchor, anchor_len)?;
+        let secret = input(secret, secret_len)?;
+        let secret_len = if algorithm == Algorithm::Aes128 {
+            16
+        } else {
+            0
+        };
+        if anchor.len() != algorithm.size() || secret.len() != secret_len {
+            return Err(DIPLOMA_ERR_ARGUMENT);
+        }
+        let record = Record::new(algorithm, anchor.to_vec(), secret.to_vec());
+        store(key, DiplomaPublicKey(record))
+    })
+}
+
+// Hash chains ignore `next`, commitments need the public element of the
+// private one that follows the revealed one
+#[no_mangle]
+pub unsafe extern "C" fn diploma_public_verify(
+    key: *mut DiplomaPublicKey,
+    password: *const u8,
+    password_len: usize,
+    next: *const u8,
+    next_len: usize,
+) -> c_int {
+    guard(|| {
+        let record = handle_mut(key)?;
+        let password = input(password, password_len)?;
+        let next = if next.is_null() {
+            None
+        } else {
+            Some(input(next, next_len)?)
+        };
+        record
+            .0
+            .accept(password, next)
+            .map_err(|_| DIPLOMA_ERR_AUTH)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn diploma_public_serialize(
+    key: *const DiplomaPublicKey,
+    buf: *mut u8,
+    len: *mut usize,
+) -> c_int {
+    guard(|| output(&handle(key)?.0.encode(), buf, len))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn diploma_public_deserialize(
+    data: *const u8,
+    len: usize,
+    key: *mut *mut DiplomaPublicKey,
+) -> c_int {
+    guard(|| {
+        let record = Record::decode(input(data, len)?).ok_or(DIPLOMA_ERR_DECODE)?;
+        store(key, DiplomaPublicKey(record))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn diploma_public_free(key: *mut DiplomaPublicKey) {
+    if !key.is_null() {
+        drop(Box::from_raw(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn password(key: *const DiplomaPrivateKey) -> Vec<u8> {
+        let mut len = 0;
+        unsafe {
+            assert_eq!(
+                diploma_private_password(key, ptr::null_mut(), &mut len),
+                DIPLOMA_ERR_BUFFER
+            );
+            let mut buf = vec![0; len];
+            assert_eq!(
+                diploma_private_password(key, buf.as_mut_ptr(), &mut len),
+                DIPLOMA_OK
+            );
+            buf
+        }
+    }
+
+    #[test]
+    fn every_algorithm() {
+        for algorithm in Algorithm::ALL {
+            let mut private = ptr::null_mut();
+            let mut public = ptr::null_mut();
+            unsafe {
+                let ret = diploma_keypair_new(algorithm.id(), 2, &mut private, &mut public);
+                assert_eq!(ret, DIPLOMA_OK);
+                for _ in 0..2 {
+                    let reveal = password(private);
+                    assert_eq!(diploma_private_pop(private), DIPLOMA_OK);
+                    let mut next = [0; 32];
+                    let mut next_len = next.len();
+                    let next_ptr =
+                        match diploma_private_commitment(private, next.as_mut_ptr(), &mut next_len)
+                        {
+                            DIPLOMA_OK => next.as_ptr(),
+                            _ => ptr::null(),
+                        };
+                    let verify = |public| {
+                        diploma_public_verify(
+                            public,
+                            reveal.as_ptr(),
+                            reveal.len(),
+                            next_ptr,
+                            next_len,
+                        )
+                    };
+                    assert_eq!(verify(public), DIPLOMA_OK, "{}", algorithm);
+                    assert_eq!(verify(public), DIPLOMA_ERR_AUTH, "{}", algorithm);
+                }
+
+                let mut len = 0;
+                diploma_private_serialize(private, ptr::null_mut(), &mut len);
+                let mut bytes = vec![0; len];
+                assert_eq!(
+                    diploma_private_serialize(private, bytes.as_mut_ptr(), &mut len),
+                    DIPLOMA_OK
+                );
+                let mut restored = ptr::null_mut();
+                assert_eq!(
+                    diploma_private_deserialize(bytes.as_ptr(), len, &mut restored),
+                    DIPLOMA_OK
+                );
+                assert_eq!((*restored).0.encode(), (*private).0.encode());
+                if algorithm.is_chain() {
+                    assert_eq!(diploma_private_pop(private), DIPLOMA_ERR_EMPTY);
+                }
+
+                diploma_private_free(private);
+                diploma_private_free(restored);
+                diploma_public_free(public);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_arguments() {
+        unsafe {
+            let mut private = ptr::null_mut();
+            let mut public = ptr::null_mut();
+            assert_eq!(
+                diploma_keypair_new(9, 2, &mut private, &mut public),
+                DIPLOMA_ERR_ARGUMENT
+            );
+            assert_eq!(
+                diploma_keypair_new(DIPLOMA_SHA256, 0, &mut private, &mut public),
+                DIPLOMA_ERR_ARGUMENT
+            );
+            assert_eq!(
+                diploma_keypair_new(DIPLOMA_SHA256, 2, ptr::null_mut(), &mut public),
+                DIPLOMA_ERR_NULL
+            );
+            assert_eq!(diploma_private_pop(ptr::null_mut()), DIPLOMA_ERR_NULL);
+            assert_eq!(
+                diploma_public_new(
+                    DIPLOMA_AES128,
+                    [0; 16].as_ptr(),
+                    16,
+                    ptr::null(),
+                    0,
+                    &mut public
+                ),
+                DIPLOMA_ERR_ARGUMENT
+            );
+            assert_eq!(
+                diploma_public_deserialize([1, 2, 3].as_ptr(), 3, &mut public),
+                DIPLOMA_ERR_DECODE
+            );
+            assert_eq!(
+                diploma_private_deserialize([DIPLOMA_AES128, 0].as_ptr(), 2, &mut private),
+                DIPLOMA_ERR_DECODE
+            );
+            diploma_private_free(ptr::null_mut());
+        }
+    }
+}
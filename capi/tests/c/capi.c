@@ -0,0 +1,160 @@
+/* Exercises the C interface the way a C service would use it */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "diploma.h"
+
+#define CHECK(expr)                                                            \
+    do {                                                                       \
+        if (!(expr)) {                                                         \
+            fprintf(stderr, "%s:%d: %s\n", __FILE__, __LINE__, #expr);         \
+            exit(1);                                                           \
+        }                                                                      \
+    } while (0)
+
+/* Serializes through a buffer sized by a first call */
+static uint8_t *
+serialize_public(const DiplomaPublicKey *key, size_t *len)
+{
+    uint8_t *buf;
+
+    *len = 0;
+    CHECK(diploma_public_serialize(key, NULL, len) == DIPLOMA_ERR_BUFFER);
+    buf = malloc(*len);
+    CHECK(buf != NULL);
+    CHECK(diploma_public_serialize(key, buf, len) == DIPLOMA_OK);
+    return buf;
+}
+
+static void
+hash_chain(uint8_t algorithm)
+{
+    DiplomaPrivateKey *user, *restored;
+    DiplomaPublicKey *verifier, *loaded;
+    uint8_t password[32], bytes[1024];
+    size_t len, stored_len;
+    uint8_t *stored;
+    int round;
+
+    CHECK(diploma_keypair_new(algorithm, 3, &user, &verifier) == DIPLOMA_OK);
+
+    for (round = 0; round < 3; round++) {
+        len = sizeof(password);
+        CHECK(diploma_private_password(user, password, &len) == DIPLOMA_OK);
+        CHECK(len == (algorithm == DIPLOMA_SHA256 ? 32 : 16));
+        CHECK(diploma_private_pop(user) == DIPLOMA_OK);
+        CHECK(diploma_public_verify(verifier, password, len, NULL, 0) == DIPLOMA_OK);
+        /* Replays are refused */
+        CHECK(diploma_public_verify(verifier, password, len, NULL, 0) == DIPLOMA_ERR_AUTH);
+
+        /* The verifier survives a round trip through its store format */
+        stored = serialize_public(verifier, &stored_len);
+        CHECK(diploma_public_deserialize(stored, stored_len, &loaded) == DIPLOMA_OK);
+        diploma_public_free(verifier);
+        verifier = loaded;
+        free(stored);
+    }
+
+    len = sizeof(password);
+    CHECK(diploma_private_password(user, password, &len) == DIPLOMA_ERR_EMPTY);
+    CHECK(diploma_private_pop(user) == DIPLOMA_ERR_EMPTY);
+    len = sizeof(password);
+    CHECK(diploma_private_commitment(user, password, &len) == DIPLOMA_ERR_ARGUMENT);
+
+    len = sizeof(bytes);
+    CHECK(diploma_private_serialize(user, bytes, &len) == DIPLOMA_OK);
+    CHECK(diploma_private_deserialize(bytes, len, &restored) == DIPLOMA_OK);
+
+    diploma_private_free(restored);
+    diploma_private_free(user);
+    diploma_public_free(verifier);
+}
+
+static void
+commitment(void)
+{
+    DiplomaPrivateKey *user, *restored;
+    DiplomaPublicKey *verifier;
+    uint8_t reveal[32], next[32], bytes[64];
+    size_t reveal_len, next_len, len;
+    int round;
+
+    CHECK(diploma_keypair_new(DIPLOMA_COMMIT_ED25519, 0, &user, &verifier) == DIPLOMA_OK);
+
+    for (round = 0; round < 3; round++) {
+        reveal_len = sizeof(reveal);
+        CHECK(diploma_private_password(user, reveal, &reveal_len) == DIPLOMA_OK);
+        CHECK(diploma_private_pop(user) == DIPLOMA_OK);
+        next_len = sizeof(next);
+        CHECK(diploma_private_commitment(user, next, &next_len) == DIPLOMA_OK);
+
+        /* A reveal without the next commitment is useless */
+        CHECK(diploma_public_verify(verifier, reveal, reveal_len, NULL, 0) == DIPLOMA_ERR_AUTH);
+        CHECK(diploma_public_verify(verifier, reveal, reveal_len, next, next_len) == DIPLOMA_OK);
+        CHECK(diploma_public_verify(verifier, reveal, reveal_len, next, next_len) == DIPLOMA_ERR_AUTH);
+    }
+
+    /* A restored key continues where the original stopped */
+    len = sizeof(bytes);
+    CHECK(diploma_private_serialize(user, bytes, &len) == DIPLOMA_OK);
+    CHECK(diploma_private_deserialize(bytes, len, &restored) == DIPLOMA_OK);
+    reveal_len = sizeof(reveal);
+    CHECK(diploma_private_password(restored, reveal, &reveal_len) == DIPLOMA_OK);
+    CHECK(diploma_private_pop(restored) == DIPLOMA_OK);
+    next_len = sizeof(next);
+    CHECK(diploma_private_commitment(restored, next, &next_len) == DIPLOMA_OK);
+    CHECK(diploma_public_verify(verifier, reveal, reveal_len, next, next_len) == DIPLOMA_OK);
+
+    diploma_private_free(restored);
+    diploma_private_free(user);
+    diploma_public_free(verifier);
+}
+
+static void
+errors(void)
+{
+    static const uint8_t anchor[32];
+    DiplomaPrivateKey *user = NULL;
+    DiplomaPublicKey *verifier = NULL;
+    uint8_t small[4];
+    size_t len;
+
+    CHECK(diploma_keypair_new(42, 3, &user, &verifier) == DIPLOMA_ERR_ARGUMENT);
+    CHECK(diploma_keypair_new(DIPLOMA_SHA256, 0, &user, &verifier) == DIPLOMA_ERR_ARGUMENT);
+    CHECK(diploma_keypair_new(DIPLOMA_SHA256, 3, NULL, &verifier) == DIPLOMA_ERR_NULL);
+    CHECK(user == NULL && verifier == NULL);
+
+    CHECK(diploma_keypair_new(DIPLOMA_SHA256, 3, &user, &verifier) == DIPLOMA_OK);
+    len = sizeof(small);
+    CHECK(diploma_private_password(user, small, &len) == DIPLOMA_ERR_BUFFER);
+    CHECK(len == 32);
+    CHECK(diploma_private_password(user, small, NULL) == DIPLOMA_ERR_NULL);
+    CHECK(diploma_public_verify(verifier, small, sizeof(small), NULL, 0) == DIPLOMA_ERR_AUTH);
+    CHECK(diploma_public_verify(verifier, NULL, 32, NULL, 0) == DIPLOMA_ERR_NULL);
+    CHECK(diploma_private_pop(NULL) == DIPLOMA_ERR_NULL);
+    diploma_private_free(user);
+    diploma_public_free(verifier);
+
+    CHECK(diploma_public_new(DIPLOMA_SHA256, anchor, 16, NULL, 0, &verifier) == DIPLOMA_ERR_ARGUMENT);
+    CHECK(diploma_public_new(DIPLOMA_AES128, anchor, 16, NULL, 0, &verifier) == DIPLOMA_ERR_ARGUMENT);
+    CHECK(diploma_public_new(DIPLOMA_SHA256, anchor, 32, NULL, 0, &verifier) == DIPLOMA_OK);
+    diploma_public_free(verifier);
+    CHECK(diploma_public_deserialize(anchor, 3, &verifier) == DIPLOMA_ERR_DECODE);
+    CHECK(diploma_private_deserialize(anchor, 0, &user) == DIPLOMA_ERR_DECODE);
+
+    diploma_private_free(NULL);
+    diploma_public_free(NULL);
+}
+
+int
+main(void)
+{
+    hash_chain(DIPLOMA_SHA256);
+    hash_chain(DIPLOMA_AES128);
+    commitment();
+    errors();
+    puts("ok");
+    return 0;
+}
@@ -0,0 +1,81 @@
+use std::{env, fs, path::PathBuf, process::Command};
+
+// The C test program in tests/c, built against the header and the shared
+// library of this build
+
+fn root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+#[test]
+fn header_is_current() {
+    let config = cbindgen::Config::from_file(root().join("cbindgen.toml")).unwrap();
+    let mut generated = vec![];
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(root().join("src/lib.rs"))
+        .generate()
+        .unwrap()
+        .write(&mut generated);
+
+    let path = root().join("include/diploma.h");
+    if env::var_os("DIPLOMA_UPDATE_HEADER").is_some() {
+        fs::write(&path, &generated).unwrap();
+    }
+    let current = fs::read(&path).unwrap();
+    assert!(
+        current == generated,
+        "capi/include/diploma.h is out of date, rerun with DIPLOMA_UPDATE_HEADER=1"
+    );
+}
+
+#[test]
+fn c_program() {
+    // Test builds link the rlib only, so the shared library is built here,
+    // into a target directory of its own: this test's one is locked
+    let target = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("capi");
+    let output = Command::new(env!("CARGO"))
+        .args(["build", "--offline", "-p", "diploma_capi"])
+        .arg("--manifest-path")
+        .arg(root().join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "building libdiploma_capi.so failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let lib_dir = target.join("debug");
+    let dir = tempfile::tempdir().unwrap();
+    let program = dir.path().join("capi");
+
+    let status = match Command::new("cc")
+        .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-I"])
+        .arg(root().join("include"))
+        .arg(root().join("tests/c/capi.c"))
+        .arg("-o")
+        .arg(&program)
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-ldiploma_capi")
+        .status()
+    {
+        Ok(status) => status,
+        Err(_) => {
+            eprintln!("skipping, no C compiler");
+            return;
+        }
+    };
+    assert!(status.success());
+
+    let output = Command::new(&program).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ok\n");
+}
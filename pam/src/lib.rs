@@ -0,0 +1,300 @@
+use std::{
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_void},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+use crypto::{digest::Digest, sha2::Sha256};
+use diploma::{
+    audit::{AuditLog, Audited},
+    encoding::{self, to_hex},
+    policy::{Policy, PolicyConfig, PolicyError, SystemClock},
+    store::{FileStore, Record, Store, StoreError},
+};
+use zeroize::Zeroizing;
+
+// PAM module for hash chain logins, e.g.
+//
+//   auth required pam_diploma.so store=/var/lib/otp/store [audit=/var/log/otp]
+//
+// The user is shown an RFC 2289 style challenge
+//
+//   otp-sha256 <round> <seed>
+//
+// and answers with the password of that round in hex, words or decimal.
+// Rounds count up from 1 as in `base::PublicKey`. Chains aren't derived from
+// a passphrase, so the seed only names the user's chain.
+//
+// The store is opened under its lock twice, to read the challenge and to
+// verify, and never while waiting for the user. A login that races another
+// one for the same round fails verification.
+//
+// Commitment users need to send a fresh public element with every login and
+// can't type one, they get PAM_AUTHINFO_UNAVAIL.
+
+const SEED_LABEL: &[u8] = b"diploma/pam/v1 seed";
+
+pub const PAM_SUCCESS: c_int = 0;
+pub const PAM_SERVICE_ERR: c_int = 3;
+pub const PAM_SYSTEM_ERR: c_int = 4;
+pub const PAM_AUTH_ERR: c_int = 7;
+pub const PAM_AUTHINFO_UNAVAIL: c_int = 9;
+pub const PAM_USER_UNKNOWN: c_int = 10;
+pub const PAM_MAXTRIES: c_int = 11;
+pub const PAM_CONV_ERR: c_int = 19;
+
+pub const PAM_CONV: c_int = 5;
+pub const PAM_PROMPT_ECHO_ON: c_int = 2;
+
+#[repr(C)]
+pub struct PamMessage {
+    pub msg_style: c_int,
+    pub msg: *const c_char,
+}
+
+#[repr(C)]
+pub struct PamResponse {
+    pub resp: *mut c_char,
+    pub resp_retcode: c_int,
+}
+
+pub type ConvFn = extern "C" fn(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata: *mut c_void,
+) -> c_int;
+
+#[repr(C)]
+pub struct PamConv {
+    pub conv: Option<ConvFn>,
+    pub appdata_ptr: *mut c_void,
+}
+
+#[repr(C)]
+pub struct PamHandle {
+    _private: [u8; 0],
+}
+
+// Linked by soname, the unversioned libpam.so only comes with the headers
+#[link(name = "libpam.so.0", kind = "dylib", modifiers = "+verbatim")]
+extern "C" {
+    fn pam_get_user(pamh: *mut PamHandle, user: *mut *const c_char, prompt: *const c_char)
+        -> c_int;
+    fn pam_get_item(pamh: *const PamHandle, item_type: c_int, item: *mut *const c_void) -> c_int;
+}
+
+struct Args {
+    store: String,
+    audit: Option<String>,
+}
+
+impl Args {
+    fn parse<'a>(args: impl IntoIterator<Item = &'a str>) -> Option<Self> {
+        let mut store = None;
+        let mut audit = None;
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("store", path)) => store = Some(path.to_owned()),
+                Some(("audit", path)) => audit = Some(path.to_owned()),
+                _ => return None,
+            }
+        }
+        Some(Self {
+            store: store?,
+            audit,
+        })
+    }
+}
+
+#[must_use]
+pub fn seed(user: &str) -> String {
+    let mut hash = Sha256::new();
+    hash.input(SEED_LABEL);
+    hash.input(user.as_bytes());
+    let mut out = [0; 32];
+    hash.result(&mut out);
+    to_hex(&out[..4])
+}
+
+#[must_use]
+pub fn challenge(user: &str, record: &Record) -> String {
+    format!("otp-{} {} {}", record.algorithm, record.round, seed(user))
+}
+
+fn status(err: StoreError) -> c_int {
+    match err {
+        StoreError::NotFound => PAM_USER_UNKNOWN,
+        StoreError::Auth(_) => PAM_AUTH_ERR,
+        _ => PAM_AUTHINFO_UNAVAIL,
+    }
+}
+
+fn policy_status(err: PolicyError) -> c_int {
+    match err {
+        PolicyError::Locked { .. } => PAM_MAXTRIES,
+        PolicyError::Backoff { .. } => PAM_AUTH_ERR,
+        PolicyError::Store(err) => status(err),
+    }
+}
+
+fn with_store<T>(
+    args: &Args,
+    f: impl FnOnce(&mut dyn Store) -> Result<T, c_int>,
+) -> Result<T, c_int> {
+    let store = FileStore::open_locked(&args.store).map_err(status)?;
+    match &args.audit {
+        Some(path) => {
+            let log =
+                AuditLog::<Sha256, _>::open(path, SystemClock).map_err(|_| PAM_AUTHINFO_UNAVAIL)?;
+            f(&mut Audited::new(store, log))
+        }
+        None => {
+            let mut store = store;
+            f(&mut store)
+        }
+    }
+}
+
+// Asks one question through the application's conversation function
+unsafe fn converse(pamh: *mut PamHandle, prompt: &str) -> Result<Zeroizing<String>, c_int> {
+    let mut conv: *const c_void = ptr::null();
+    if pam_get_item(pamh, PAM_CONV, &mut conv) != PAM_SUCCESS || conv.is_null() {
+        return Err(PAM_CONV_ERR);
+    }
+    let conv = &*conv.cast::<PamConv>();
+    let conv_fn = conv.conv.ok_or(PAM_CONV_ERR)?;
+
+    let prompt = CString::new(prompt).map_err(|_| PAM_SYSTEM_ERR)?;
+    // Echo is fine, the answer is worthless once verified
+    let message = PamMessage {
+        msg_style: PAM_PROMPT_ECHO_ON,
+        msg: prompt.as_ptr(),
+    };
+    let mut messages = [&message as *const PamMessage];
+    let mut response: *mut PamResponse = ptr::null_mut();
+    let ret = conv_fn(1, messages.as_mut_ptr(), &mut response, conv.appdata_ptr);
+    if ret != PAM_SUCCESS || response.is_null() {
+        return Err(PAM_CONV_ERR);
+    }
+
+    // Both the answer and the array are ours to free
+    let resp = (*response).resp;
+    let answer = if resp.is_null() {
+        None
+    } else {
+        let answer = Zeroizing::new(CStr::from_ptr(resp).to_string_lossy().into_owned());
+        ptr::write_bytes(resp, 0, libc::strlen(resp));
+        libc::free(resp.cast());
+        Some(answer)
+    };
+    libc::free(response.cast());
+    answer.ok_or(PAM_CONV_ERR)
+}
+
+unsafe fn authenticate(pamh: *mut PamHandle, args: &Args) -> Result<(), c_int> {
+    let mut user: *const c_char = ptr::null();
+    if pam_get_user(pamh, &mut user, ptr::null()) != PAM_SUCCESS || user.is_null() {
+        return Err(PAM_USER_UNKNOWN);
+    }
+    let user = CStr::from_ptr(user)
+        .to_str()
+        .map_err(|_| PAM_USER_UNKNOWN)?
+        .to_owned();
+
+    let policy = Policy::new(PolicyConfig::default(), SystemClock);
+    let record = with_store(args, |store| {
+        let record = store.get(&user).map_err(status)?.ok_or(PAM_USER_UNKNOWN)?;
+        policy.check(&record.attempts).map_err(policy_status)?;
+        Ok(record)
+    })?;
+    if !record.algorithm.is_chain() {
+        return Err(PAM_AUTHINFO_UNAVAIL);
+    }
+
+    let prompt = format!("{}\nResponse: ", challenge(&user, &record));
+    let answer = converse(pamh, &prompt)?;
+    // Garbage counts as a wrong password
+    let password =
+        Zeroizing::new(encoding::decode_any(&answer, record.algorithm.size()).unwrap_or_default());
+
+    with_store(args, |store| {
+        policy
+            .accept(store, &user, &password, None)
+            .map_err(policy_status)
+    })
+}
+
+unsafe fn parse_args(argc: c_int, argv: *const *const c_char) -> Option<Args> {
+    let argc = usize::try_from(argc).ok()?;
+    let args = (0..argc)
+        .map(|i| CStr::from_ptr(*argv.add(i)).to_str().ok())
+        .collect::<Option<Vec<_>>>()?;
+    Args::parse(args)
+}
+
+// Called by libpam with a valid handle and `argc` module arguments
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn pam_sm_authenticate(
+    pamh: *mut PamHandle,
+    _flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    // A panic must not unwind into the application
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let args = match parse_args(argc, argv) {
+            Some(args) => args,
+            None => return PAM_SERVICE_ERR,
+        };
+        match authenticate(pamh, &args) {
+            Ok(()) => PAM_SUCCESS,
+            Err(code) => code,
+        }
+    }))
+    .unwrap_or(PAM_SYSTEM_ERR)
+}
+
+// There are no credentials to set
+#[no_mangle]
+pub extern "C" fn pam_sm_setcred(
+    _pamh: *mut PamHandle,
+    _flags: c_int,
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> c_int {
+    PAM_SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diploma::encoding::Format;
+
+    #[test]
+    fn arguments() {
+        let args = Args::parse(["store=/tmp/a", "audit=/tmp/b"]).unwrap();
+        assert_eq!(args.store, "/tmp/a");
+        assert_eq!(args.audit.as_deref(), Some("/tmp/b"));
+        assert!(Args::parse(["audit=/tmp/b"]).is_none());
+        assert!(Args::parse(["store=/tmp/a", "debug"]).is_none());
+    }
+
+    #[test]
+    fn responses() {
+        let password = [0xa5; 16];
+        for format in Format::ALL {
+            let response = encoding::encode(&password, format);
+            assert_eq!(encoding::decode_any(&response, 16).unwrap(), password);
+        }
+        assert!(encoding::decode_any("not a password", 16).is_none());
+
+        let record = Record::aes128([0; 16], [0; 16]);
+        let challenge = challenge("alice", &record);
+        assert!(challenge.starts_with("otp-aes128 1 "));
+        assert_eq!(challenge, super::challenge("alice", &record));
+        assert_ne!(seed("alice"), seed("bob"));
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    ffi::{CStr, CString},
+    fs,
+    os::raw::{c_char, c_int, c_void},
+    path::{Path, PathBuf},
+    process::Command,
+    ptr,
+    sync::OnceLock,
+};
+
+use diploma::{
+    base::{PrivateKey, State},
+    encoding::{self, Format},
+    store::{FileStore, Record, Store},
+};
+use pam_diploma::{
+    PamConv, PamMessage, PamResponse, PAM_AUTH_ERR, PAM_MAXTRIES, PAM_PROMPT_ECHO_ON, PAM_SUCCESS,
+    PAM_USER_UNKNOWN,
+};
+
+// Runs the module through the system libpam with a service file in a
+// temporary directory, so the host's PAM configuration is never read.
+
+type PamStart = unsafe extern "C" fn(
+    service: *const c_char,
+    user: *const c_char,
+    conv: *const PamConv,
+    confdir: *const c_char,
+    pamh: *mut *mut c_void,
+) -> c_int;
+type PamAuthenticate = unsafe extern "C" fn(pamh: *mut c_void, flags: c_int) -> c_int;
+type PamEnd = unsafe extern "C" fn(pamh: *mut c_void, status: c_int) -> c_int;
+
+struct Pam {
+    start: PamStart,
+    authenticate: PamAuthenticate,
+    end: PamEnd,
+}
+
+impl Pam {
+    // libpam is a runtime dependency of the module, not of the crate
+    fn load() -> Option<Self> {
+        unsafe {
+            let lib = libc::dlopen(c"libpam.so.0".as_ptr(), libc::RTLD_NOW);
+            if lib.is_null() {
+                return None;
+            }
+            // Needs Linux-PAM 1.4 for a private configuration directory
+            let start = libc::dlsym(lib, c"pam_start_confdir".as_ptr());
+            let authenticate = libc::dlsym(lib, c"pam_authenticate".as_ptr());
+            let end = libc::dlsym(lib, c"pam_end".as_ptr());
+            if start.is_null() || authenticate.is_null() || end.is_null() {
+                return None;
+            }
+            Some(Self {
+                start: std::mem::transmute::<*mut c_void, PamStart>(start),
+                authenticate: std::mem::transmute::<*mut c_void, PamAuthenticate>(authenticate),
+                end: std::mem::transmute::<*mut c_void, PamEnd>(end),
+            })
+        }
+    }
+}
+
+struct Conversation {
+    prompts: Vec<String>,
+    answer: Box<dyn FnMut(&str) -> String>,
+}
+
+extern "C" fn conv(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata: *mut c_void,
+) -> c_int {
+    unsafe {
+        let conversation = &mut *appdata.cast::<Conversation>();
+        let count = usize::try_from(num_msg).unwrap();
+        let responses =
+            libc::calloc(count, std::mem::size_of::<PamResponse>()).cast::<PamResponse>();
+        for i in 0..count {
+            let message = &**msg.add(i);
+            assert_eq!(message.msg_style, PAM_PROMPT_ECHO_ON);
+            let prompt = CStr::from_ptr(message.msg).to_str().unwrap().to_owned();
+            let answer = CString::new((conversation.answer)(&prompt)).unwrap();
+            (*responses.add(i)).resp = libc::strdup(answer.as_ptr());
+            conversation.prompts.push(prompt);
+        }
+        *resp = responses;
+        PAM_SUCCESS
+    }
+}
+
+// Built here rather than assumed: `cargo test` on a clean target doesn't
+// leave the cdylib behind. The test's own target directory is locked while
+// it runs, so the module gets one of its own.
+fn module() -> &'static Path {
+    static MODULE: OnceLock<PathBuf> = OnceLock::new();
+    MODULE.get_or_init(|| {
+        let target = Path::new(env!("CARGO_TARGET_TMPDIR")).join("module");
+        let output = Command::new(env!("CARGO"))
+            .args(["build", "--offline", "-p", "pam_diploma"])
+            .arg("--manifest-path")
+            .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"))
+            .arg("--target-dir")
+            .arg(&target)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "building libpam_diploma.so failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        target.join("debug/libpam_diploma.so")
+    })
+}
+
+fn login(
+    pam: &Pam,
+    dir: &Path,
+    user: &str,
+    answer: impl FnMut(&str) -> String + 'static,
+) -> (c_int, Vec<String>) {
+    let mut conversation = Conversation {
+        prompts: vec![],
+        answer: Box::new(answer),
+    };
+    let pam_conv = PamConv {
+        conv: Some(conv),
+        appdata_ptr: (&mut conversation as *mut Conversation).cast(),
+    };
+    let service = CString::new("otp-test").unwrap();
+    let user = CString::new(user).unwrap();
+    let confdir = CString::new(dir.to_str().unwrap()).unwrap();
+    let mut pamh = ptr::null_mut();
+    unsafe {
+        let ret = (pam.start)(
+            service.as_ptr(),
+            user.as_ptr(),
+            &pam_conv,
+            confdir.as_ptr(),
+            &mut pamh,
+        );
+        assert_eq!(ret, PAM_SUCCESS);
+        let ret = (pam.authenticate)(pamh, 0);
+        (pam.end)(pamh, ret);
+        (ret, conversation.prompts)
+    }
+}
+
+fn enroll(store: &Path, user: &str) -> PrivateKey<crypto::sha2::Sha256, 32> {
+    let (private, record) = Record::generate_sha256(10);
+    FileStore::open_locked(store)
+        .unwrap()
+        .enroll(user, record)
+        .unwrap();
+    private
+}
+
+#[test]
+fn login_through_libpam() {
+    let pam = match Pam::load() {
+        Some(pam) => pam,
+        None => {
+            eprintln!("skipping, no libpam with pam_start_confdir");
+            return;
+        }
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let store = dir.path().join("store");
+    let audit = dir.path().join("audit");
+    fs::write(
+        dir.path().join("otp-test"),
+        format!(
+            "auth required {} store={} audit={}\n",
+            module().display(),
+            store.display(),
+            audit.display()
+        ),
+    )
+    .unwrap();
+    let mut private = enroll(&store, "alice");
+
+    // Every format is accepted, and every password only once
+    for format in Format::ALL {
+        let password = private.get_password().unwrap();
+        let answer = encoding::encode(&password[..], format);
+        let (ret, prompts) = login(&pam, dir.path(), "alice", move |_| answer.clone());
+        assert_eq!(ret, PAM_SUCCESS, "{}", format);
+        assert_eq!(prompts.len(), 1);
+        let round = prompts[0].split_whitespace().nth(1).unwrap();
+        assert!(prompts[0].starts_with("otp-sha256 "));
+        assert!(prompts[0].ends_with("\nResponse: "));
+
+        let answer = encoding::encode(&password[..], format);
+        let (ret, prompts) = login(&pam, dir.path(), "alice", move |_| answer.clone());
+        assert_eq!(ret, PAM_AUTH_ERR);
+        assert_ne!(prompts[0].split_whitespace().nth(1).unwrap(), round);
+        assert_eq!(private.pop_password(), State::Ok);
+
+        // Past the backoff of the failure
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+    }
+
+    let (ret, prompts) = login(&pam, dir.path(), "bob", |_| String::new());
+    assert_eq!(ret, PAM_USER_UNKNOWN);
+    assert!(prompts.is_empty());
+
+    let store_state = FileStore::open(&store)
+        .unwrap()
+        .get("alice")
+        .unwrap()
+        .unwrap();
+    assert_eq!(store_state.round, 4);
+    // A success and a failure per login pair, enrollment bypassed the log
+    assert_eq!(diploma::audit::read(&audit).unwrap().len(), 2 * 3);
+}
+
+#[test]
+fn lockout() {
+    let pam = match Pam::load() {
+        Some(pam) => pam,
+        None => return,
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let store = dir.path().join("store");
+    fs::write(
+        dir.path().join("otp-test"),
+        format!(
+            "auth required {} store={}\n",
+            module().display(),
+            store.display()
+        ),
+    )
+    .unwrap();
+    enroll(&store, "alice");
+
+    // Lock the user directly, the default policy would take minutes
+    let mut file = FileStore::open_locked(&store).unwrap();
+    let mut record = file.get("alice").unwrap().unwrap();
+    record.attempts.locked_until = diploma::policy::LOCKED_FOREVER;
+    file.put("alice", record).unwrap();
+    drop(file);
+
+    let (ret, prompts) = login(&pam, dir.path(), "alice", |_| "garbage".to_owned());
+    assert_eq!(ret, PAM_MAXTRIES);
+    assert!(prompts.is_empty());
+}
@@ -0,0 +1,492 @@
+use crypto::{aessafe::AesSafe128Encryptor, sha2::Sha256};
+use diploma::{
+    base::{self, State as RustState},
+    cipher::{Aes128SafeBuilder as RustAes128SafeBuilder, BlockOneWay},
+    commitment::{self, aes::Aes128SafeEncryptor, ed25519::Ed25519},
+    hash::Sha256Builder as RustSha256Builder,
+    keystore::{Element, Persist},
+    policy::PolicyConfig,
+    secret::Secret,
+};
+use pyo3::{
+    create_exception,
+    exceptions::{PyException, PyValueError},
+    prelude::*,
+    types::PyBytes,
+};
+
+// Python bindings, built with maturin:
+//
+//   maturin develop -m python/Cargo.toml
+//
+// The classes follow the Rust API: builders make private and public keys,
+// `verify` raises AuthError where Rust returns one. Keys serialize with
+// `to_bytes` and come back with `from_bytes`. Private keys hold secrets,
+// Python gives no way to wipe the copies it makes of them.
+
+create_exception!(pydiploma, AuthError, PyException, "Authentication failed");
+
+type Aes128Key = base::PrivateKey<BlockOneWay<AesSafe128Encryptor, 16>, 16>;
+type Aes128Public = base::PublicKey<BlockOneWay<AesSafe128Encryptor, 16>, 16>;
+
+#[pyclass(module = "pydiploma", eq, eq_int)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ok,
+    Empty,
+}
+
+impl From<RustState> for State {
+    fn from(state: RustState) -> Self {
+        match state {
+            RustState::Ok => State::Ok,
+            RustState::Empty => State::Empty,
+        }
+    }
+}
+
+fn auth(result: Result<(), base::AuthError>) -> PyResult<()> {
+    result.map_err(|err| AuthError::new_err(err.to_string()))
+}
+
+// Every skipped round costs a hash, so callers get the window the servers use
+fn skip(skip: usize) -> PyResult<usize> {
+    let window = PolicyConfig::default().skip_window;
+    if skip > window {
+        return Err(PyValueError::new_err(format!(
+            "skip must be at most {}",
+            window
+        )));
+    }
+    Ok(skip)
+}
+
+fn array<const N: usize>(bytes: &[u8], name: &str) -> PyResult<[u8; N]> {
+    bytes
+        .try_into()
+        .map_err(|_| PyValueError::new_err(format!("{} must be {} bytes", name, N)))
+}
+
+fn secret<const N: usize>(bytes: &[u8], name: &str) -> PyResult<Secret<N>> {
+    array(bytes, name).map(Secret::new)
+}
+
+fn invalid(what: &str) -> PyErr {
+    PyValueError::new_err(format!("invalid {}", what))
+}
+
+// Public keys serialize as the next round followed by the last password
+fn split_public<const N: usize>(data: &[u8]) -> PyResult<(usize, [u8; N])> {
+    if data.len() != 8 + N {
+        return Err(invalid("public key"));
+    }
+    let round = u64::from_be_bytes(data[..8].try_into().unwrap());
+    let round = usize::try_from(round).map_err(|_| invalid("public key"))?;
+    Ok((round, array(&data[8..], "password")?))
+}
+
+fn join_public(py: Python<'_>, round: usize, password: &[u8]) -> Py<PyBytes> {
+    let round = u64::try_from(round).expect("sorry, architecture is not supported");
+    let mut out = round.to_be_bytes().to_vec();
+    out.extend_from_slice(password);
+    PyBytes::new(py, &out).unbind()
+}
+
+// Private keys of both chains only differ in their types
+macro_rules! private_key {
+    ($name:ident, $key:ty) => {
+        #[pyclass(module = "pydiploma")]
+        struct $name($key);
+
+        #[pymethods]
+        impl $name {
+            fn get_password(&self, py: Python<'_>) -> Option<Py<PyBytes>> {
+                self.0
+                    .get_password()
+                    .map(|password| PyBytes::new(py, &password[..]).unbind())
+            }
+
+            fn pop_password(&mut self) -> State {
+                self.0.pop_password().into()
+            }
+
+            #[getter]
+            fn round(&self) -> usize {
+                self.0.round()
+            }
+
+            fn to_bytes(&self, py: Python<'_>) -> Py<PyBytes> {
+                PyBytes::new(py, &self.0.encode()).unbind()
+            }
+
+            #[staticmethod]
+            fn from_bytes(data: &[u8]) -> PyResult<Self> {
+                Persist::decode(data)
+                    .map(Self)
+                    .ok_or_else(|| invalid("private key"))
+            }
+        }
+    };
+}
+
+private_key!(Sha256PrivateKey, base::PrivateKey<Sha256, 32>);
+private_key!(Aes128PrivateKey, Aes128Key);
+
+#[pyclass(module = "pydiploma")]
+struct Sha256PublicKey(base::PublicKey<Sha256, 32>);
+
+#[pymethods]
+impl Sha256PublicKey {
+    fn verify(&mut self, password: &[u8]) -> PyResult<()> {
+        auth(self.0.verify(&array(password, "password")?))
+    }
+
+    fn verify_dry(&self, password: &[u8]) -> PyResult<()> {
+        auth(self.0.verify_dry(&array(password, "password")?))
+    }
+
+    fn verify_skip(&mut self, password: &[u8], skip: usize) -> PyResult<()> {
+        auth(
+            self.0
+                .verify_skip(&array(password, "password")?, self::skip(skip)?),
+        )
+    }
+
+    #[getter]
+    fn round(&self) -> usize {
+        self.0.round()
+    }
+
+    #[getter]
+    fn password(&self, py: Python<'_>) -> Py<PyBytes> {
+        PyBytes::new(py, &self.0.password()).unbind()
+    }
+
+    fn to_bytes(&self, py: Python<'_>) -> Py<PyBytes> {
+        join_public(py, self.0.round(), &self.0.password())
+    }
+
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> PyResult<Self> {
+        let (round, password) = split_public(data)?;
+        Ok(Self(base::PublicKey::from_parts(
+            Sha256::new(),
+            round,
+            password,
+        )))
+    }
+}
+
+// Keeps the key, `BlockOneWay` has no way to give it back
+#[pyclass(module = "pydiploma")]
+struct Aes128PublicKey {
+    secret: Secret<16>,
+    key: Aes128Public,
+}
+
+#[pymethods]
+impl Aes128PublicKey {
+    fn verify(&mut self, password: &[u8]) -> PyResult<()> {
+        auth(self.key.verify(&array(password, "password")?))
+    }
+
+    fn verify_dry(&self, password: &[u8]) -> PyResult<()> {
+        auth(self.key.verify_dry(&array(password, "password")?))
+    }
+
+    fn verify_skip(&mut self, password: &[u8], skip: usize) -> PyResult<()> {
+        auth(
+            self.key
+                .verify_skip(&array(password, "password")?, self::skip(skip)?),
+        )
+    }
+
+    #[getter]
+    fn round(&self) -> usize {
+        self.key.round()
+    }
+
+    #[getter]
+    fn password(&self, py: Python<'_>) -> Py<PyBytes> {
+        PyBytes::new(py, &self.key.password()).unbind()
+    }
+
+    // The key comes first, then the same layout as SHA-256 keys
+    fn to_bytes(&self, py: Python<'_>) -> Py<PyBytes> {
+        let public = join_public(py, self.key.round(), &self.key.password());
+        let mut out = self.secret.to_vec();
+        out.extend_from_slice(public.as_bytes(py));
+        PyBytes::new(py, &out).unbind()
+    }
+
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> PyResult<Self> {
+        let key = data.get(..16).ok_or_else(|| invalid("public key"))?;
+        let secret = secret::<16>(key, "secret")?;
+        let (round, password) = split_public(&data[16..])?;
+        let key = base::PublicKey::from_parts(BlockOneWay::new(secret.clone()), round, password);
+        Ok(Self { secret, key })
+    }
+}
+
+#[pyclass(module = "pydiploma")]
+struct Sha256Builder;
+
+#[pymethods]
+impl Sha256Builder {
+    #[staticmethod]
+    fn new_private(rounds: usize) -> Sha256PrivateKey {
+        Sha256PrivateKey(RustSha256Builder::new_private(rounds))
+    }
+
+    #[staticmethod]
+    fn private_from_password(rounds: usize, password: &[u8]) -> PyResult<Sha256PrivateKey> {
+        let password = secret(password, "password")?;
+        Ok(Sha256PrivateKey(RustSha256Builder::private_from_password(
+            rounds, password,
+        )))
+    }
+
+    #[staticmethod]
+    fn new_public(password: &[u8]) -> PyResult<Sha256PublicKey> {
+        Ok(Sha256PublicKey(RustSha256Builder::new_public(array(
+            password, "password",
+        )?)))
+    }
+}
+
+#[pyclass(module = "pydiploma")]
+struct Aes128SafeBuilder;
+
+#[pymethods]
+impl Aes128SafeBuilder {
+    #[staticmethod]
+    fn new_private(rounds: usize, secret: &[u8]) -> PyResult<Aes128PrivateKey> {
+        let secret = self::secret(secret, "secret")?;
+        Ok(Aes128PrivateKey(RustAes128SafeBuilder::new_private(
+            rounds, secret,
+        )))
+    }
+
+    #[staticmethod]
+    fn private_from_password(
+        rounds: usize,
+        secret: &[u8],
+        password: &[u8],
+    ) -> PyResult<Aes128PrivateKey> {
+        let secret = self::secret(secret, "secret")?;
+        let password = self::secret(password, "password")?;
+        Ok(Aes128PrivateKey(
+            RustAes128SafeBuilder::private_from_password(rounds, secret, password),
+        ))
+    }
+
+    #[staticmethod]
+    fn new_public(secret: &[u8], password: &[u8]) -> PyResult<Aes128PublicKey> {
+        let secret = self::secret::<16>(secret, "secret")?;
+        let key = RustAes128SafeBuilder::new_public(secret.clone(), array(password, "password")?);
+        Ok(Aes128PublicKey { secret, key })
+    }
+}
+
+// The commitment schemes by the names Python uses for them
+enum CommitmentKey {
+    Sha256(commitment::PrivateKey<commitment::hash::Sha256>),
+    Aes128(commitment::PrivateKey<Aes128SafeEncryptor>),
+    Ed25519(commitment::PrivateKey<Ed25519>),
+}
+
+#[derive(Clone, Copy)]
+enum Scheme {
+    Sha256,
+    Aes128,
+    Ed25519,
+}
+
+impl Scheme {
+    fn from_name(name: &str) -> PyResult<Self> {
+        match name {
+            "sha256" => Ok(Scheme::Sha256),
+            "aes128" => Ok(Scheme::Aes128),
+            "ed25519" => Ok(Scheme::Ed25519),
+            _ => Err(PyValueError::new_err(format!(
+                "unknown commitment scheme {:?}",
+                name
+            ))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Scheme::Sha256 => "sha256",
+            Scheme::Aes128 => "aes128",
+            Scheme::Ed25519 => "ed25519",
+        }
+    }
+}
+
+#[pyclass(module = "pydiploma")]
+struct CommitmentPrivateKey(CommitmentKey);
+
+#[pymethods]
+impl CommitmentPrivateKey {
+    #[new]
+    fn new(scheme: &str) -> PyResult<Self> {
+        Ok(Self(match Scheme::from_name(scheme)? {
+            Scheme::Sha256 => {
+                CommitmentKey::Sha256(commitment::PrivateKey::new(commitment::hash::Sha256))
+            }
+            Scheme::Aes128 => {
+                CommitmentKey::Aes128(commitment::PrivateKey::new(Aes128SafeEncryptor))
+            }
+            Scheme::Ed25519 => CommitmentKey::Ed25519(commitment::PrivateKey::new(Ed25519)),
+        }))
+    }
+
+    #[getter]
+    fn scheme(&self) -> &'static str {
+        match self.0 {
+            CommitmentKey::Sha256(_) => Scheme::Sha256.name(),
+            CommitmentKey::Aes128(_) => Scheme::Aes128.name(),
+            CommitmentKey::Ed25519(_) => Scheme::Ed25519.name(),
+        }
+    }
+
+    fn public(&self, py: Python<'_>) -> Py<PyBytes> {
+        let public = match &self.0 {
+            CommitmentKey::Sha256(key) => key.public().to_vec(),
+            CommitmentKey::Aes128(key) => key.public().to_vec(),
+            CommitmentKey::Ed25519(key) => key.public().to_bytes().to_vec(),
+        };
+        PyBytes::new(py, &public).unbind()
+    }
+
+    fn private(&self, py: Python<'_>) -> Py<PyBytes> {
+        let private = match &self.0 {
+            CommitmentKey::Sha256(key) => key.private().to_bytes(),
+            CommitmentKey::Aes128(key) => key.private().to_bytes(),
+            CommitmentKey::Ed25519(key) => key.private().to_bytes(),
+        };
+        PyBytes::new(py, &private).unbind()
+    }
+
+    fn advance(&mut self) {
+        match &mut self.0 {
+            CommitmentKey::Sha256(key) => key.advance(),
+            CommitmentKey::Aes128(key) => key.advance(),
+            CommitmentKey::Ed25519(key) => key.advance(),
+        }
+    }
+
+    fn to_bytes(&self, py: Python<'_>) -> Py<PyBytes> {
+        self.private(py)
+    }
+
+    #[staticmethod]
+    fn from_bytes(scheme: &str, data: &[u8]) -> PyResult<Self> {
+        let key = match Scheme::from_name(scheme)? {
+            Scheme::Sha256 => Persist::decode(data).map(CommitmentKey::Sha256),
+            Scheme::Aes128 => Persist::decode(data).map(CommitmentKey::Aes128),
+            Scheme::Ed25519 => Persist::decode(data).map(CommitmentKey::Ed25519),
+        };
+        key.map(Self).ok_or_else(|| invalid("private key"))
+    }
+}
+
+enum CommitmentVerifier {
+    Sha256(commitment::PublicKey<commitment::hash::Sha256>),
+    Aes128(commitment::PublicKey<Aes128SafeEncryptor>),
+    Ed25519(commitment::PublicKey<Ed25519>),
+}
+
+fn ed25519_public(bytes: &[u8]) -> PyResult<ed25519_dalek::PublicKey> {
+    ed25519_dalek::PublicKey::from_bytes(bytes).map_err(|_| invalid("public element"))
+}
+
+#[pyclass(module = "pydiploma")]
+struct CommitmentPublicKey {
+    scheme: Scheme,
+    public: Vec<u8>,
+    key: CommitmentVerifier,
+}
+
+#[pymethods]
+impl CommitmentPublicKey {
+    #[new]
+    fn new(scheme: &str, public: &[u8]) -> PyResult<Self> {
+        let scheme = Scheme::from_name(scheme)?;
+        let key = match scheme {
+            Scheme::Sha256 => CommitmentVerifier::Sha256(commitment::PublicKey::new(
+                commitment::hash::Sha256,
+                array(public, "public element")?,
+            )),
+            Scheme::Aes128 => CommitmentVerifier::Aes128(commitment::PublicKey::new(
+                Aes128SafeEncryptor,
+                array(public, "public element")?,
+            )),
+            Scheme::Ed25519 => CommitmentVerifier::Ed25519(commitment::PublicKey::new(
+                Ed25519,
+                ed25519_public(public)?,
+            )),
+        };
+        Ok(Self {
+            scheme,
+            public: public.to_vec(),
+            key,
+        })
+    }
+
+    #[getter]
+    fn scheme(&self) -> &'static str {
+        self.scheme.name()
+    }
+
+    // A reveal of the wrong size or form doesn't verify either
+    fn verify(&self, private: &[u8]) -> PyResult<()> {
+        let verified = match &self.key {
+            CommitmentVerifier::Sha256(key) => {
+                Secret::from_slice(private).is_some_and(|private| key.verify(&private))
+            }
+            CommitmentVerifier::Aes128(key) => {
+                Secret::from_slice(private).is_some_and(|private| key.verify(&private))
+            }
+            CommitmentVerifier::Ed25519(key) => {
+                Element::from_bytes(private).is_some_and(|private| key.verify(&private))
+            }
+        };
+        auth(if verified {
+            Ok(())
+        } else {
+            Err(base::AuthError)
+        })
+    }
+
+    fn advance(&mut self, new_public: &[u8]) -> PyResult<()> {
+        *self = Self::new(self.scheme.name(), new_public)?;
+        Ok(())
+    }
+
+    fn to_bytes(&self, py: Python<'_>) -> Py<PyBytes> {
+        PyBytes::new(py, &self.public).unbind()
+    }
+
+    #[staticmethod]
+    fn from_bytes(scheme: &str, data: &[u8]) -> PyResult<Self> {
+        Self::new(scheme, data)
+    }
+}
+
+#[pymodule]
+fn pydiploma(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("AuthError", m.py().get_type::<AuthError>())?;
+    m.add_class::<State>()?;
+    m.add_class::<Sha256Builder>()?;
+    m.add_class::<Sha256PrivateKey>()?;
+    m.add_class::<Sha256PublicKey>()?;
+    m.add_class::<Aes128SafeBuilder>()?;
+    m.add_class::<Aes128PrivateKey>()?;
+    m.add_class::<Aes128PublicKey>()?;
+    m.add_class::<CommitmentPrivateKey>()?;
+    m.add_class::<CommitmentPublicKey>()?;
+    Ok(())
+}
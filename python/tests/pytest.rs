@@ -0,0 +1,46 @@
+use std::{fs, path::PathBuf, process::Command};
+
+// Runs the pytest suite against the extension module of this build. Needs
+// python3 with pytest, so it only runs when asked for:
+//
+//   cargo test -p pydiploma -- --ignored
+
+#[test]
+#[ignore = "needs python3 with pytest"]
+fn pytest_suite() {
+    let has_pytest = Command::new("python3")
+        .args(["-c", "import pytest"])
+        .status()
+        .is_ok_and(|status| status.success());
+    assert!(has_pytest, "no python3 with pytest");
+
+    // Test builds don't leave the cdylib behind, so it's built here, into a
+    // target directory of its own: this test's one is locked
+    let target = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("extension");
+    let output = Command::new(env!("CARGO"))
+        .args(["build", "--offline", "-p", "pydiploma"])
+        .arg("--manifest-path")
+        .arg(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "building libpydiploma.so failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let library = target.join("debug/libpydiploma.so");
+    let dir = tempfile::tempdir().unwrap();
+    fs::copy(&library, dir.path().join("pydiploma.so")).unwrap();
+
+    let tests = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let status = Command::new("python3")
+        .args(["-m", "pytest", "-q", "-p", "no:cacheprovider"])
+        .arg(&tests)
+        .env("PYTHONPATH", dir.path())
+        .env("PYTHONDONTWRITEBYTECODE", "1")
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
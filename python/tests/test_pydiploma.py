@@ -0,0 +1,165 @@
+# Mirrors the Rust tests of the key types, run by tests/pytest.rs or with
+# pytest after `maturin develop`
+
+import pytest
+
+from pydiploma import (
+    Aes128PrivateKey,
+    Aes128PublicKey,
+    Aes128SafeBuilder,
+    AuthError,
+    CommitmentPrivateKey,
+    CommitmentPublicKey,
+    Sha256Builder,
+    Sha256PrivateKey,
+    Sha256PublicKey,
+    State,
+)
+
+SECRET = b"YELLOW SUBMARINE"
+
+
+def run_protocol(private, public):
+    for _ in range(4):
+        password = private.get_password()
+        public.verify(password)
+        assert private.pop_password() == State.Ok
+
+    password = private.get_password()
+    public.verify(password)
+    assert private.pop_password() == State.Empty
+    assert private.get_password() is None
+
+
+def test_sha256_normal_protocol():
+    private = Sha256Builder.new_private(5)
+    p0 = private.get_password()
+    assert private.pop_password() == State.Ok
+    run_protocol(private, Sha256Builder.new_public(p0))
+
+
+def test_aes128_normal_protocol():
+    private = Aes128SafeBuilder.new_private(5, SECRET)
+    p0 = private.get_password()
+    assert private.pop_password() == State.Ok
+    run_protocol(private, Aes128SafeBuilder.new_public(SECRET, p0))
+
+
+def test_wrong_and_replayed_passwords():
+    private = Sha256Builder.new_private(3)
+    public = Sha256Builder.new_public(private.get_password())
+    assert private.pop_password() == State.Ok
+
+    with pytest.raises(AuthError):
+        public.verify(bytes(32))
+    password = private.get_password()
+    public.verify_dry(password)
+    assert public.round == 1
+    public.verify(password)
+    with pytest.raises(AuthError):
+        public.verify(password)
+    assert public.round == 2
+
+    with pytest.raises(ValueError):
+        public.verify(b"short")
+
+
+def test_skip_rounds():
+    private = Sha256Builder.new_private(5)
+    public = Sha256Builder.new_public(private.get_password())
+    assert private.pop_password() == State.Ok
+
+    assert private.pop_password() == State.Ok
+    assert private.pop_password() == State.Ok
+    p3 = private.get_password()
+    with pytest.raises(AuthError):
+        public.verify_skip(p3, 1)
+    with pytest.raises(AuthError):
+        public.verify(p3)
+    public.verify_skip(p3, 2)
+    assert public.round == 4
+    assert private.pop_password() == State.Ok
+
+    public.verify_skip(private.get_password(), 0)
+
+    # Skips are bounded like the servers' window
+    with pytest.raises(ValueError):
+        public.verify_skip(private.get_password(), 9)
+
+
+def test_from_password_is_deterministic():
+    seed = bytes(range(32))
+    a = Sha256Builder.private_from_password(4, seed)
+    b = Sha256Builder.private_from_password(4, seed)
+    assert a.get_password() == b.get_password()
+    assert a.round == 4
+
+    a = Aes128SafeBuilder.private_from_password(4, SECRET, seed[:16])
+    b = Aes128SafeBuilder.private_from_password(4, SECRET, seed[:16])
+    assert a.get_password() == b.get_password()
+    c = Aes128SafeBuilder.private_from_password(4, bytes(16), seed[:16])
+    assert a.get_password() != c.get_password()
+
+
+def test_chain_serialization():
+    private = Sha256Builder.new_private(5)
+    public = Sha256Builder.new_public(private.get_password())
+    assert private.pop_password() == State.Ok
+    public.verify(private.get_password())
+    assert private.pop_password() == State.Ok
+
+    private = Sha256PrivateKey.from_bytes(private.to_bytes())
+    public = Sha256PublicKey.from_bytes(public.to_bytes())
+    assert private.round == 3
+    assert public.round == 2
+    public.verify(private.get_password())
+
+    private = Aes128SafeBuilder.new_private(3, SECRET)
+    public = Aes128SafeBuilder.new_public(SECRET, private.get_password())
+    assert private.pop_password() == State.Ok
+    private = Aes128PrivateKey.from_bytes(private.to_bytes())
+    public = Aes128PublicKey.from_bytes(public.to_bytes())
+    public.verify(private.get_password())
+
+    with pytest.raises(ValueError):
+        Sha256PrivateKey.from_bytes(b"\x00" * 31)
+    with pytest.raises(ValueError):
+        Aes128PublicKey.from_bytes(b"\x00" * 16)
+
+
+@pytest.mark.parametrize("scheme", ["sha256", "aes128", "ed25519"])
+def test_commitment_auth(scheme):
+    private = CommitmentPrivateKey(scheme)
+    public = CommitmentPublicKey(scheme, private.public())
+    assert public.scheme == scheme
+
+    for _ in range(10):
+        reveal = private.private()
+        public.verify(reveal)
+
+        private.advance()
+        with pytest.raises(AuthError):
+            public.verify(private.private())
+        public.advance(private.public())
+
+    with pytest.raises(AuthError):
+        public.verify(b"")
+
+
+@pytest.mark.parametrize("scheme", ["sha256", "aes128", "ed25519"])
+def test_commitment_serialization(scheme):
+    private = CommitmentPrivateKey(scheme)
+    public = CommitmentPublicKey(scheme, private.public())
+
+    private = CommitmentPrivateKey.from_bytes(scheme, private.to_bytes())
+    public = CommitmentPublicKey.from_bytes(scheme, public.to_bytes())
+    public.verify(private.private())
+
+
+def test_commitment_errors():
+    with pytest.raises(ValueError):
+        CommitmentPrivateKey("md5")
+    with pytest.raises(ValueError):
+        CommitmentPublicKey("sha256", b"short")
+    with pytest.raises(ValueError):
+        CommitmentPrivateKey.from_bytes("aes128", bytes(32))
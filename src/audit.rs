@@ -0,0 +1,700 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    marker::PhantomData,
+    path::Path,
+};
+
+use crate::{
+    hash::Hash,
+    policy::Clock,
+    store::{Algorithm, Reader, Record, Store, StoreError},
+};
+
+// Tamper-evident log of authentication events. Every entry carries the hash
+// of the previous one, so modifying or dropping an entry breaks the chain.
+// Dropping entries from the end can only be detected against a checkpoint
+// kept out of band.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Enrolled { algorithm: Algorithm },
+    Accepted { round: u64 },
+    Skipped { from: u64, count: u64 },
+    Rejected { round: u64 },
+    Locked { until: u64 },
+    Unlocked,
+    Renewed { algorithm: Algorithm },
+    Removed,
+}
+
+impl Event {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Event::Enrolled { algorithm } => out.extend_from_slice(&[1, algorithm.id()]),
+            Event::Accepted { round } => {
+                out.push(2);
+                out.extend_from_slice(&round.to_be_bytes());
+            }
+            Event::Skipped { from, count } => {
+                out.push(3);
+                out.extend_from_slice(&from.to_be_bytes());
+                out.extend_from_slice(&count.to_be_bytes());
+            }
+            Event::Rejected { round } => {
+                out.push(4);
+                out.extend_from_slice(&round.to_be_bytes());
+            }
+            Event::Locked { until } => {
+                out.push(5);
+                out.extend_from_slice(&until.to_be_bytes());
+            }
+            Event::Unlocked => out.push(6),
+            Event::Renewed { algorithm } => out.extend_from_slice(&[7, algorithm.id()]),
+            Event::Removed => out.push(8),
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Option<Self> {
+        Some(match reader.u8()? {
+            1 => Event::Enrolled {
+                algorithm: Algorithm::from_id(reader.u8()?)?,
+            },
+            2 => Event::Accepted {
+                round: reader.u64()?,
+            },
+            3 => Event::Skipped {
+                from: reader.u64()?,
+                count: reader.u64()?,
+            },
+            4 => Event::Rejected {
+                round: reader.u64()?,
+            },
+            5 => Event::Locked {
+                until: reader.u64()?,
+            },
+            6 => Event::Unlocked,
+            7 => Event::Renewed {
+                algorithm: Algorithm::from_id(reader.u8()?)?,
+            },
+            8 => Event::Removed,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Enrolled { algorithm } => write!(f, "enrolled {}", algorithm),
+            Event::Accepted { round } => write!(f, "accepted round {}", round),
+            Event::Skipped { from, count } => write!(f, "skipped {} rounds from {}", count, from),
+            Event::Rejected { round } => write!(f, "rejected round {}", round),
+            Event::Locked { until } => write!(f, "locked until {}", until),
+            Event::Unlocked => f.write_str("unlocked"),
+            Event::Renewed { algorithm } => write!(f, "renewed {}", algorithm),
+            Event::Removed => f.write_str("removed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub seq: u64,
+    pub time: u64,
+    pub user: String,
+    pub event: Event,
+    pub prev: Vec<u8>,
+}
+
+impl Entry {
+    fn encode(&self) -> Vec<u8> {
+        let user_len = u16::try_from(self.user.len()).expect("user id is too long");
+        let prev_len = u16::try_from(self.prev.len()).expect("hash is too long");
+        let mut out = vec![];
+        out.extend_from_slice(&self.seq.to_be_bytes());
+        out.extend_from_slice(&self.time.to_be_bytes());
+        out.extend_from_slice(&user_len.to_be_bytes());
+        out.extend_from_slice(self.user.as_bytes());
+        self.event.encode(&mut out);
+        out.extend_from_slice(&prev_len.to_be_bytes());
+        out.extend_from_slice(&self.prev);
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let seq = reader.u64()?;
+        let time = reader.u64()?;
+        let user = String::from_utf8(reader.bytes16()?.to_vec()).ok()?;
+        let event = Event::decode(&mut reader)?;
+        let prev = reader.bytes16()?.to_vec();
+        if !reader.0.is_empty() {
+            return None;
+        }
+        Some(Self {
+            seq,
+            time,
+            user,
+            event,
+            prev,
+        })
+    }
+
+    pub fn hash<H: Hash>(&self) -> Vec<u8> {
+        let mut d = H::new();
+        d.input(&self.encode());
+        let mut out = vec![0; d.output_bytes()];
+        d.result(&mut out);
+        out
+    }
+}
+
+// Length and head hash of a log, kept somewhere the attacker can't rewrite
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub len: u64,
+    pub head: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    Modified { seq: u64 },
+    Truncated { expected: u64, found: u64 },
+    Corrupt,
+    Io(io::Error),
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditError::Modified { seq } => write!(f, "entry {} was modified", seq),
+            AuditError::Truncated { expected, found } => write!(
+                f,
+                "log was truncated: expected {} entries, found {}",
+                expected, found
+            ),
+            AuditError::Corrupt => f.write_str("log is corrupted"),
+            AuditError::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+impl From<io::Error> for AuditError {
+    fn from(err: io::Error) -> Self {
+        AuditError::Io(err)
+    }
+}
+
+impl From<AuditError> for StoreError {
+    fn from(err: AuditError) -> Self {
+        match err {
+            AuditError::Io(err) => StoreError::Io(err),
+            _ => StoreError::Corrupt,
+        }
+    }
+}
+
+pub fn verify<H: Hash>(
+    entries: &[Entry],
+    checkpoint: Option<&Checkpoint>,
+) -> Result<(), AuditError> {
+    let mut prev = vec![0; H::new().output_bytes()];
+    for (i, entry) in entries.iter().enumerate() {
+        let seq = u64::try_from(i).expect("sorry, architecture is not supported");
+        if entry.seq != seq || !crypto::util::fixed_time_eq(&entry.prev, &prev) {
+            // Either this entry was rewritten or the previous one was
+            return Err(AuditError::Modified {
+                seq: seq.saturating_sub(1),
+            });
+        }
+        prev = entry.hash::<H>();
+    }
+
+    if let Some(checkpoint) = checkpoint {
+        let found = u64::try_from(entries.len()).expect("sorry, architecture is not supported");
+        if found < checkpoint.len {
+            return Err(AuditError::Truncated {
+                expected: checkpoint.len,
+                found,
+            });
+        }
+        let index = usize::try_from(checkpoint.len).expect("sorry, architecture is not supported");
+        let head = match index {
+            0 => vec![0; prev.len()],
+            _ => entries[index - 1].hash::<H>(),
+        };
+        if !crypto::util::fixed_time_eq(&head, &checkpoint.head) {
+            return Err(AuditError::Modified {
+                seq: checkpoint.len.saturating_sub(1),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Reads a log file written by `AuditLog`, without verifying the chain
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<Entry>, AuditError> {
+    let mut log = vec![];
+    File::open(path)?.read_to_end(&mut log)?;
+    parse(&log).map(|(entries, _)| entries)
+}
+
+// Returns the entries and the length of the complete prefix of the log
+fn parse(log: &[u8]) -> Result<(Vec<Entry>, usize), AuditError> {
+    let mut reader = Reader(log);
+    let mut entries = vec![];
+    let mut valid = 0;
+    while let Some(len) = reader.u32() {
+        let len = usize::try_from(len).expect("sorry, architecture is not supported");
+        match reader.take(len) {
+            Some(bytes) => entries.push(Entry::decode(bytes).ok_or(AuditError::Corrupt)?),
+            // Torn write at the tail
+            None => break,
+        }
+        valid = log.len() - reader.0.len();
+    }
+    Ok((entries, valid))
+}
+
+pub trait AuditSink {
+    fn emit(&mut self, user: &str, event: Event) -> Result<(), AuditError>;
+}
+
+// What the log needs of its file, a trait so tests can make writes fail
+trait LogFile: Write + Send {
+    fn sync_data(&self) -> io::Result<()>;
+    fn set_len(&self, size: u64) -> io::Result<()>;
+}
+
+impl LogFile for File {
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+}
+
+// Only the length and head hash are kept in memory, use `read` and `verify`
+// for the entries
+pub struct AuditLog<H: Hash, C: Clock> {
+    file: Option<Box<dyn LogFile>>,
+    // Bytes of the file, where a failed append is cut off again
+    size: u64,
+    len: u64,
+    head: Vec<u8>,
+    clock: C,
+    _hash: PhantomData<H>,
+}
+
+impl<H: Hash, C: Clock> AuditLog<H, C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            file: None,
+            size: 0,
+            len: 0,
+            head: vec![0; H::new().output_bytes()],
+            clock,
+            _hash: PhantomData {},
+        }
+    }
+
+    // Opens or creates a log file. An existing log has to verify.
+    pub fn open(path: impl AsRef<Path>, clock: C) -> Result<Self, AuditError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut log = vec![];
+        file.read_to_end(&mut log)?;
+
+        let (entries, valid) = parse(&log)?;
+        verify::<H>(&entries, None)?;
+        if valid != log.len() {
+            file.set_len(u64::try_from(valid).expect("sorry, architecture is not supported"))?;
+            file.sync_all()?;
+        }
+
+        let mut this = Self::new(clock);
+        if let Some(last) = entries.last() {
+            this.head = last.hash::<H>();
+        }
+        this.len = u64::try_from(entries.len()).expect("sorry, architecture is not supported");
+        this.size = u64::try_from(valid).expect("sorry, architecture is not supported");
+        this.file = Some(Box::new(file));
+        Ok(this)
+    }
+
+    #[must_use]
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            len: self.len,
+            head: self.head.clone(),
+        }
+    }
+}
+
+impl<H: Hash, C: Clock> AuditSink for AuditLog<H, C> {
+    fn emit(&mut self, user: &str, event: Event) -> Result<(), AuditError> {
+        let entry = Entry {
+            seq: self.len,
+            time: self.clock.now(),
+            user: user.to_owned(),
+            event,
+            prev: self.head.clone(),
+        };
+        if let Some(file) = &mut self.file {
+            let bytes = entry.encode();
+            let len = u32::try_from(bytes.len()).expect("entry is too long");
+            let framed = [&len.to_be_bytes()[..], &bytes].concat();
+            let written = file.write_all(&framed).and_then(|_| file.sync_data());
+            if let Err(err) = written {
+                // Don't leave a partial entry in front of the next append
+                let _ = file.set_len(self.size);
+                return Err(err.into());
+            }
+            self.size += u64::try_from(framed.len()).expect("sorry, architecture is not supported");
+        }
+        self.head = entry.hash::<H>();
+        self.len += 1;
+        Ok(())
+    }
+}
+
+// Events implied by a record update
+fn events(old: Option<&Record>, new: &Record) -> Vec<Event> {
+    let old = match old {
+        Some(old) => old,
+        None => {
+            return vec![Event::Enrolled {
+                algorithm: new.algorithm,
+            }]
+        }
+    };
+
+    let mut events = vec![];
+    if new.algorithm == old.algorithm && new.round > old.round && new.anchor != old.anchor {
+        let skipped = new.round - old.round - 1;
+        if skipped > 0 {
+            events.push(Event::Skipped {
+                from: old.round,
+                count: skipped,
+            });
+        }
+        events.push(Event::Accepted {
+            round: new.round - 1,
+        });
+    } else if new.algorithm != old.algorithm || new.anchor != old.anchor {
+        events.push(Event::Renewed {
+            algorithm: new.algorithm,
+        });
+    }
+
+    if new.attempts.failures > old.attempts.failures {
+        events.push(Event::Rejected { round: old.round });
+    }
+    if new.attempts.locked_until != 0 && new.attempts.locked_until != old.attempts.locked_until {
+        events.push(Event::Locked {
+            until: new.attempts.locked_until,
+        });
+    }
+    if events.is_empty() && old.attempts.locked_until != 0 && new.attempts.locked_until == 0 {
+        events.push(Event::Unlocked);
+    }
+    events
+}
+
+// Store wrapper that emits audit events for every change. Events are written
+// before the store is updated, so a failing log stops the update: the log may
+// show a change that didn't happen, but never misses one that did.
+pub struct Audited<S: Store, A: AuditSink> {
+    store: S,
+    sink: A,
+}
+
+impl<S: Store, A: AuditSink> Audited<S, A> {
+    pub fn new(store: S, sink: A) -> Self {
+        Self { store, sink }
+    }
+
+    pub fn sink(&self) -> &A {
+        &self.sink
+    }
+
+    pub fn into_inner(self) -> (S, A) {
+        (self.store, self.sink)
+    }
+}
+
+impl<S: Store, A: AuditSink> Store for Audited<S, A> {
+    fn get(&self, user: &str) -> Result<Option<Record>, StoreError> {
+        self.store.get(user)
+    }
+
+    fn put(&mut self, user: &str, record: Record) -> Result<(), StoreError> {
+        let old = self.store.get(user)?;
+        for event in events(old.as_ref(), &record) {
+            self.sink.emit(user, event)?;
+        }
+        self.store.put(user, record)
+    }
+
+    fn remove(&mut self, user: &str) -> Result<bool, StoreError> {
+        if self.store.get(user)?.is_none() {
+            return Ok(false);
+        }
+        self.sink.emit(user, Event::Removed)?;
+        self.store.remove(user)
+    }
+
+    fn users(&self) -> Result<Vec<String>, StoreError> {
+        self.store.users()
+    }
+
+    fn accept(
+        &mut self,
+        user: &str,
+        password: &[u8],
+        next: Option<&[u8]>,
+    ) -> Result<(), StoreError> {
+        let mut record = self.store.get(user)?.ok_or(StoreError::NotFound)?;
+        match record.accept(password, next) {
+            Ok(()) => self.put(user, record),
+            Err(err) => {
+                self.sink.emit(
+                    user,
+                    Event::Rejected {
+                        round: record.round,
+                    },
+                )?;
+                Err(err.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crypto::sha2::Sha256;
+
+    use super::*;
+    use crate::{
+        policy::{ManualClock, Policy, PolicyConfig},
+        store::MemoryStore,
+    };
+
+    fn store(path: &Path) -> Audited<MemoryStore, AuditLog<Sha256, ManualClock>> {
+        let log = AuditLog::open(path, ManualClock::new(42)).unwrap();
+        Audited::new(MemoryStore::new(), log)
+    }
+
+    #[test]
+    fn emits_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit");
+        let mut store = store(&path);
+        let (mut private, record) = Record::generate_sha256(10);
+
+        store.enroll("alice", record).unwrap();
+        store
+            .accept("alice", &private.get_password().unwrap()[..], None)
+            .unwrap();
+        assert!(store.accept("alice", &[0; 32], None).is_err());
+
+        let clock = ManualClock::new(100);
+        let config = PolicyConfig {
+            max_failures: 1,
+            require_skip: true,
+            ..Default::default()
+        };
+        let policy = Policy::new(config, &clock);
+        assert!(policy.accept(&mut store, "alice", &[0; 32], None).is_err());
+        clock.advance(config.lockout.unwrap());
+        for _ in 0..3 {
+            let _ = private.pop_password();
+        }
+        policy
+            .accept(
+                &mut store,
+                "alice",
+                &private.get_password().unwrap()[..],
+                None,
+            )
+            .unwrap();
+
+        store.put("alice", Record::sha256([0; 32])).unwrap();
+        store.remove("alice").unwrap();
+
+        let entries = read(&path).unwrap();
+        let events = entries.iter().map(|e| e.event.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                Event::Enrolled {
+                    algorithm: Algorithm::Sha256
+                },
+                Event::Accepted { round: 1 },
+                Event::Rejected { round: 2 },
+                Event::Rejected { round: 2 },
+                Event::Locked { until: 100 + 900 },
+                Event::Skipped { from: 2, count: 2 },
+                Event::Accepted { round: 4 },
+                Event::Renewed {
+                    algorithm: Algorithm::Sha256
+                },
+                Event::Removed,
+            ]
+        );
+        assert!(verify::<Sha256>(&entries, Some(&store.sink().checkpoint())).is_ok());
+    }
+
+    struct Failing;
+
+    impl AuditSink for Failing {
+        fn emit(&mut self, _: &str, _: Event) -> Result<(), AuditError> {
+            Err(io::Error::other("disk full").into())
+        }
+    }
+
+    #[test]
+    fn fails_closed() {
+        let mut inner = MemoryStore::new();
+        inner.put("alice", Record::sha256([0; 32])).unwrap();
+        let mut store = Audited::new(inner, Failing);
+
+        assert!(store.put("alice", Record::sha256([1; 32])).is_err());
+        assert!(store.remove("alice").is_err());
+        assert!(store.put("bob", Record::sha256([1; 32])).is_err());
+
+        let (inner, _) = store.into_inner();
+        assert_eq!(inner.get("alice").unwrap().unwrap().anchor, vec![0; 32]);
+        assert!(inner.get("bob").unwrap().is_none());
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit");
+        let mut log = AuditLog::<Sha256, _>::open(&path, ManualClock::new(0)).unwrap();
+        for round in 0..5 {
+            log.emit("alice", Event::Accepted { round }).unwrap();
+        }
+        let checkpoint = log.checkpoint();
+        let logged = read(&path).unwrap();
+        assert!(verify::<Sha256>(&logged, Some(&checkpoint)).is_ok());
+
+        let mut entries = logged.clone();
+        entries[2].event = Event::Accepted { round: 42 };
+        assert!(matches!(
+            verify::<Sha256>(&entries, Some(&checkpoint)),
+            Err(AuditError::Modified { seq: 2 })
+        ));
+
+        let mut entries = logged.clone();
+        entries.remove(1);
+        assert!(verify::<Sha256>(&entries, None).is_err());
+
+        let mut entries = logged;
+        entries.pop();
+        assert!(verify::<Sha256>(&entries, None).is_ok());
+        assert!(matches!(
+            verify::<Sha256>(&entries, Some(&checkpoint)),
+            Err(AuditError::Truncated {
+                expected: 5,
+                found: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn file_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit");
+        {
+            let mut log = AuditLog::<Sha256, _>::open(&path, ManualClock::new(7)).unwrap();
+            log.emit("alice", Event::Accepted { round: 1 }).unwrap();
+        }
+        let checkpoint = {
+            let mut log = AuditLog::<Sha256, _>::open(&path, ManualClock::new(8)).unwrap();
+            log.emit("bob", Event::Removed).unwrap();
+            log.checkpoint()
+        };
+
+        let entries = read(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].time, 8);
+        assert!(verify::<Sha256>(&entries, Some(&checkpoint)).is_ok());
+
+        // Flip a byte inside the first entry's user id
+        let mut log = std::fs::read(&path).unwrap();
+        log[4 + 18] ^= 1;
+        std::fs::write(&path, log).unwrap();
+        assert!(AuditLog::<Sha256, _>::open(&path, ManualClock::new(9)).is_err());
+    }
+
+    // Takes `room` more bytes, then fails in the middle of a write
+    struct FullDisk {
+        bytes: Arc<Mutex<Vec<u8>>>,
+        room: usize,
+    }
+
+    impl Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.room);
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.room -= n;
+            self.bytes.lock().unwrap().extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogFile for FullDisk {
+        fn sync_data(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_len(&self, size: u64) -> io::Result<()> {
+            let size = usize::try_from(size).expect("sorry, architecture is not supported");
+            self.bytes.lock().unwrap().truncate(size);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_append_is_cut_off() {
+        let bytes = Arc::new(Mutex::new(vec![]));
+        let mut log = AuditLog::<Sha256, _>::new(ManualClock::new(7));
+        log.file = Some(Box::new(FullDisk {
+            bytes: Arc::clone(&bytes),
+            room: 10,
+        }));
+        assert!(log.emit("alice", Event::Removed).is_err());
+        assert!(bytes.lock().unwrap().is_empty());
+
+        log.file = Some(Box::new(FullDisk {
+            bytes: Arc::clone(&bytes),
+            room: usize::MAX,
+        }));
+        log.emit("alice", Event::Removed).unwrap();
+        log.emit("bob", Event::Removed).unwrap();
+
+        let (entries, valid) = parse(&bytes.lock().unwrap()).unwrap();
+        assert_eq!(valid, bytes.lock().unwrap().len());
+        assert_eq!(entries.len(), 2);
+        assert!(verify::<Sha256>(&entries, Some(&log.checkpoint())).is_ok());
+    }
+}
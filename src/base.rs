@@ -1,6 +1,11 @@
-use std::{fmt, marker::PhantomData};
+use core::fmt;
+#[cfg(feature = "std")]
+use core::marker::PhantomData;
 
-use rand::RngCore;
+use rand_core::{CryptoRng, RngCore};
+use subtle::ConstantTimeEq;
+
+use crate::secret::Secret;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
@@ -12,7 +17,7 @@ pub enum State {
 pub struct AuthError;
 
 impl fmt::Display for AuthError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("authentication error")
     }
 }
@@ -20,24 +25,30 @@ impl fmt::Display for AuthError {
 pub trait OneWay {
     fn compute(&self, i: usize, input: &[u8], output: &mut [u8]);
 }
+
+// Keeps the whole chain, see `PebbledPrivateKey` for a fixed-size one
+#[cfg(feature = "std")]
 pub struct PrivateKey<F: OneWay, const SIZE: usize> {
     round: usize,
-    passwords: Vec<[u8; SIZE]>,
+    passwords: Vec<Secret<SIZE>>,
     _oneway: PhantomData<F>,
 }
 
+#[cfg(feature = "std")]
 impl<F: OneWay, const SIZE: usize> PrivateKey<F, SIZE> {
     pub fn new(oneway: F, rounds: usize) -> Self {
-        let mut pass = [0; SIZE];
-        rand::thread_rng().fill_bytes(&mut pass);
-        Self::from_password(oneway, rounds, pass)
+        Self::from_password(oneway, rounds, Secret::random())
     }
 
-    pub fn from_password(oneway: F, rounds: usize, pass: [u8; SIZE]) -> Self {
+    pub fn new_with<R: RngCore + CryptoRng>(oneway: F, rounds: usize, rng: &mut R) -> Self {
+        Self::from_password(oneway, rounds, Secret::random_with(rng))
+    }
+
+    pub fn from_password(oneway: F, rounds: usize, pass: Secret<SIZE>) -> Self {
         let mut counter = rounds;
-        let passwords = std::iter::successors(Some(pass), |pass| {
-            let mut result = [0; SIZE];
-            oneway.compute(counter, pass, &mut result);
+        let passwords = core::iter::successors(Some(pass), |pass| {
+            let mut result = Secret::zeroed();
+            oneway.compute(counter, &pass[..], &mut result[..]);
             counter = counter.checked_sub(1)?;
             Some(result)
         })
@@ -51,8 +62,8 @@ impl<F: OneWay, const SIZE: usize> PrivateKey<F, SIZE> {
         }
     }
 
-    pub fn get_password(&self) -> Option<[u8; SIZE]> {
-        let p = self.passwords.get(self.round).copied();
+    pub fn get_password(&self) -> Option<Secret<SIZE>> {
+        let p = self.passwords.get(self.round).cloned();
         p
     }
 
@@ -71,6 +82,110 @@ impl<F: OneWay, const SIZE: usize> PrivateKey<F, SIZE> {
     pub fn round(&self) -> usize {
         self.round
     }
+
+    // Passwords not used yet, the current one last
+    #[must_use]
+    pub fn passwords(&self) -> &[Secret<SIZE>] {
+        &self.passwords
+    }
+
+    // Restores a key saved with `passwords`
+    pub fn from_passwords(passwords: Vec<Secret<SIZE>>) -> Self {
+        Self {
+            round: passwords.len().saturating_sub(1),
+            passwords,
+            _oneway: PhantomData {},
+        }
+    }
+}
+
+// Serves the same passwords as `PrivateKey` from at most CAP pebbles, i.e.
+// checkpoints on the chain, instead of the whole chain. A pebble is always
+// kept on the current password. Once it's popped, the gap to the pebble
+// below is halved with new pebbles until one reaches the next password.
+// That's O(log n) hashes per password on average and needs one pebble more
+// than the bit length of `rounds`, see `pebbles`.
+pub struct PebbledPrivateKey<F: OneWay, const SIZE: usize, const CAP: usize> {
+    oneway: F,
+    rounds: usize,
+    round: usize,
+    // Chain positions of the pebbles, increasing
+    positions: [usize; CAP],
+    values: [Secret<SIZE>; CAP],
+    len: usize,
+}
+
+// Pebbles needed for a chain of `rounds`
+#[must_use]
+pub const fn pebbles(rounds: usize) -> usize {
+    (usize::BITS - rounds.leading_zeros()) as usize + 1
+}
+
+impl<F: OneWay, const SIZE: usize, const CAP: usize> PebbledPrivateKey<F, SIZE, CAP> {
+    pub fn new<R: RngCore + CryptoRng>(oneway: F, rounds: usize, rng: &mut R) -> Option<Self> {
+        Self::from_password(oneway, rounds, Secret::random_with(rng))
+    }
+
+    // None if the chain doesn't fit into CAP pebbles
+    pub fn from_password(oneway: F, rounds: usize, pass: Secret<SIZE>) -> Option<Self> {
+        if pebbles(rounds) > CAP {
+            return None;
+        }
+        let mut key = Self {
+            oneway,
+            rounds,
+            round: rounds,
+            positions: [0; CAP],
+            values: core::array::from_fn(|_| Secret::zeroed()),
+            len: 1,
+        };
+        key.values[0] = pass;
+        key.fill();
+        Some(key)
+    }
+
+    fn fill(&mut self) {
+        while self.positions[self.len - 1] != self.round {
+            let top = self.len - 1;
+            let start = self.positions[top];
+            let end = start + (self.round - start).div_ceil(2);
+            let mut value = self.values[top].clone();
+            for position in start..end {
+                let mut next = Secret::zeroed();
+                self.oneway
+                    .compute(self.rounds - position, &value[..], &mut next[..]);
+                value = next;
+            }
+            self.positions[self.len] = end;
+            self.values[self.len] = value;
+            self.len += 1;
+        }
+    }
+
+    pub fn get_password(&self) -> Option<Secret<SIZE>> {
+        self.len.checked_sub(1).map(|top| self.values[top].clone())
+    }
+
+    #[must_use]
+    pub fn pop_password(&mut self) -> State {
+        if self.len == 0 {
+            return State::Empty;
+        }
+        self.len -= 1;
+        self.values[self.len] = Secret::zeroed();
+        if let Some(round) = self.round.checked_sub(1) {
+            self.round = round;
+            self.fill();
+            State::Ok
+        } else {
+            State::Empty
+        }
+    }
+
+    #[must_use]
+    pub fn round(&self) -> usize {
+        self.round
+    }
 }
 
 pub struct PublicKey<F: OneWay, const SIZE: usize> {
@@ -89,6 +204,25 @@ impl<F: OneWay, const SIZE: usize> PublicKey<F, SIZE> {
         }
     }
 
+    // Restores a verifier that has already accepted `round - 1` passwords
+    pub fn from_parts(oneway: F, round: usize, password: [u8; SIZE]) -> Self {
+        Self {
+            round,
+            password,
+            oneway,
+        }
+    }
+
+    #[must_use]
+    pub fn round(&self) -> usize {
+        self.round
+    }
+
+    #[must_use]
+    pub fn password(&self) -> [u8; SIZE] {
+        self.password
+    }
+
     pub fn verify_dry(&self, password: &[u8; SIZE]) -> Result<(), AuthError> {
         let hash = {
             let mut out = [0; SIZE];
@@ -96,7 +230,7 @@ impl<F: OneWay, const SIZE: usize> PublicKey<F, SIZE> {
             out
         };
 
-        if crypto::util::fixed_time_eq(&hash, &self.password) {
+        if bool::from(hash.ct_eq(&self.password)) {
             Ok(())
         } else {
             Err(AuthError)
@@ -113,4 +247,23 @@ impl<F: OneWay, const SIZE: usize> PublicKey<F, SIZE> {
             Err(err) => Err(err),
         }
     }
+
+    // Accepts a password `skip` rounds ahead of the expected one, consuming
+    // the skipped rounds as well
+    pub fn verify_skip(&mut self, password: &[u8; SIZE], skip: usize) -> Result<(), AuthError> {
+        let round = self.round.checked_add(skip).ok_or(AuthError)?;
+        let hash = (self.round..=round).rev().fold(*password, |input, i| {
+            let mut out = [0; SIZE];
+            self.oneway.compute(i, &input, &mut out);
+            out
+        });
+
+        if bool::from(hash.ct_eq(&self.password)) {
+            self.password = *password;
+            self.round = round + 1;
+            Ok(())
+        } else {
+            Err(AuthError)
+        }
+    }
 }
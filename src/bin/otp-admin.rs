@@ -0,0 +1,365 @@
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use crypto::sha2::Sha256;
+use diploma::{
+    audit::{self, AuditLog, Audited, Checkpoint},
+    daemon::parse_record,
+    encoding::{from_hex, to_hex},
+    policy::{Policy, PolicyConfig, SystemClock, LOCKED_FOREVER},
+    store::{FileStore, Record, Store},
+};
+
+// Administration of the verifier store used by `otpd`. The daemons lock the
+// store while they run, so stop them first, otp-admin refuses a store in use.
+//
+// Import files hold one user per line, either the fields printed by
+// `otp anchor`
+//
+//   <user> <algorithm> <anchor> [<secret>]
+//
+// for a new enrollment, or the `<user> <record>` lines written by `export`,
+// which carry the full verifier state. Empty lines and `#` comments are
+// skipped.
+
+fn parse_import(text: &str) -> anyhow::Result<Vec<(String, Record)>> {
+    let mut users = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let record = match fields.as_slice() {
+            [_, record] => from_hex(record).and_then(|bytes| Record::decode(&bytes)),
+            [_, rest @ ..] => parse_record(rest),
+            [] => None,
+        };
+        match record {
+            Some(record) => users.push((fields[0].to_owned(), record)),
+            None => bail!("line {}: not a user", number + 1),
+        }
+    }
+    Ok(users)
+}
+
+// Rounds the user has logged in with
+fn rounds_used(record: &Record) -> u64 {
+    if record.algorithm.is_chain() {
+        // Round 0 only comes from a damaged or hand made record
+        record.round.saturating_sub(1)
+    } else {
+        record.round
+    }
+}
+
+fn parse_checkpoint(s: &str) -> Option<Checkpoint> {
+    let (len, head) = s.split_once(':')?;
+    Some(Checkpoint {
+        len: len.parse().ok()?,
+        head: from_hex(head)?,
+    })
+}
+
+fn open(matches: &ArgMatches) -> anyhow::Result<FileStore> {
+    let path = matches.value_of("store").unwrap();
+    if !Path::new(path).exists() {
+        bail!("{} doesn't exist", path);
+    }
+    FileStore::try_open_locked(path).with_context(|| format!("opening {}", path))
+}
+
+fn manage(
+    matches: &ArgMatches,
+    store: &mut impl Store,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    match matches.subcommand() {
+        ("reset", Some(matches)) => {
+            let user = matches.value_of("user").unwrap();
+            // Only the attempts are touched, the configuration doesn't matter
+            Policy::new(PolicyConfig::default(), SystemClock).reset(store, user)?;
+            writeln!(out, "reset {}", user)?;
+        }
+        ("revoke", Some(matches)) => {
+            let user = matches.value_of("user").unwrap();
+            if !store.remove(user)? {
+                bail!("user {} not found", user);
+            }
+            writeln!(out, "revoked {}", user)?;
+        }
+        ("import", Some(matches)) => {
+            let path = matches.value_of("file").unwrap();
+            let text = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+            let users = parse_import(&text).with_context(|| format!("in {}", path))?;
+            // Existing users are refused before anything is written. The
+            // store has no transactions, so a write error part way leaves
+            // the users before it imported.
+            if !matches.is_present("replace") {
+                for (user, _) in &users {
+                    if store.get(user)?.is_some() {
+                        bail!("user {} already exists, use --replace", user);
+                    }
+                }
+            }
+            for (user, record) in &users {
+                store
+                    .put(user, record.clone())
+                    .with_context(|| format!("importing {}", user))?;
+            }
+            writeln!(out, "imported {} users", users.len())?;
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+    Ok(())
+}
+
+fn run(args: &[String], out: &mut impl Write) -> anyhow::Result<()> {
+    let store_arg = Arg::with_name("store")
+        .long("store")
+        .takes_value(true)
+        .required(true)
+        .help("Verifier store file");
+    let audit_arg = Arg::with_name("audit")
+        .long("audit")
+        .takes_value(true)
+        .help("Log changes to the audit log of the daemon");
+    let user_arg = Arg::with_name("user").required(true);
+
+    let matches = App::new("otp-admin")
+        .about("Manages the users of the verifier store")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists users with their algorithm and rounds used")
+                .arg(store_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("reset")
+                .about("Clears failed attempts and lifts a lockout")
+                .args(&[store_arg.clone(), audit_arg.clone(), user_arg.clone()]),
+        )
+        .subcommand(
+            SubCommand::with_name("revoke")
+                .about("Removes a user")
+                .args(&[store_arg.clone(), audit_arg.clone(), user_arg]),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Enrolls the users of a file")
+                .args(&[store_arg.clone(), audit_arg])
+                .arg(Arg::with_name("file").required(true))
+                .arg(
+                    Arg::with_name("replace")
+                        .long("replace")
+                        .help("Overwrite users that already exist"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Writes the full state of every user, for import elsewhere")
+                .arg(store_arg)
+                .arg(Arg::with_name("file").help("Output file, stdout by default")),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-audit")
+                .about("Checks the hash chain of an audit log and prints its checkpoint")
+                .arg(Arg::with_name("log").required(true))
+                .arg(
+                    Arg::with_name("checkpoint")
+                        .long("checkpoint")
+                        .takes_value(true)
+                        .help("<length>:<head> printed by an earlier run"),
+                ),
+        )
+        .get_matches_from_safe(args)?;
+
+    match matches.subcommand() {
+        ("list", Some(matches)) => {
+            let store = open(matches)?;
+            for user in store.users()? {
+                let record = store.get(&user)?.context("store changed while listing")?;
+                let locked = match record.attempts.locked_until {
+                    0 => "-".to_owned(),
+                    LOCKED_FOREVER => "forever".to_owned(),
+                    until => until.to_string(),
+                };
+                writeln!(
+                    out,
+                    "{} {} {} {} {}",
+                    user,
+                    record.algorithm,
+                    rounds_used(&record),
+                    record.attempts.failures,
+                    locked
+                )?;
+            }
+        }
+        ("export", Some(matches)) => {
+            let store = open(matches)?;
+            let mut text = String::new();
+            for user in store.users()? {
+                let record = store.get(&user)?.context("store changed while exporting")?;
+                text.push_str(&format!("{} {}\n", user, to_hex(&record.encode())));
+            }
+            match matches.value_of("file") {
+                Some(path) => fs::write(path, text).with_context(|| format!("writing {}", path))?,
+                None => out.write_all(text.as_bytes())?,
+            }
+        }
+        ("verify-audit", Some(matches)) => {
+            let path = matches.value_of("log").unwrap();
+            let checkpoint = matches
+                .value_of("checkpoint")
+                .map(|s| parse_checkpoint(s).context("invalid checkpoint"))
+                .transpose()?;
+            let entries = audit::read(path).with_context(|| format!("reading {}", path))?;
+            audit::verify::<Sha256>(&entries, checkpoint.as_ref())
+                .with_context(|| format!("{} failed to verify", path))?;
+            let head = entries.last().map_or(vec![0; 32], |e| e.hash::<Sha256>());
+            writeln!(out, "ok {}:{}", entries.len(), to_hex(&head))?;
+        }
+        (name, Some(sub)) => {
+            // Importing may create the store
+            let mut store = match name {
+                "import" => {
+                    let path = sub.value_of("store").unwrap();
+                    FileStore::try_open_locked(path).with_context(|| format!("opening {}", path))?
+                }
+                _ => open(sub)?,
+            };
+            match sub.value_of("audit") {
+                Some(log) => {
+                    let log = AuditLog::<Sha256, _>::open(log, SystemClock)
+                        .with_context(|| format!("opening {}", log))?;
+                    manage(&matches, &mut Audited::new(store, log), out)?;
+                }
+                None => manage(&matches, &mut store, out)?,
+            }
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if let Err(err) = run(&args, &mut io::stdout()) {
+        match err.downcast_ref::<clap::Error>() {
+            Some(err) => err.exit(),
+            None => {
+                eprintln!("otp-admin: {:#}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diploma::policy::{Attempts, ManualClock};
+
+    fn admin(args: &[&str]) -> anyhow::Result<String> {
+        let mut argv = vec!["otp-admin".to_owned()];
+        argv.extend(args.iter().map(|&s| s.to_owned()));
+        let mut out = vec![];
+        run(&argv, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    fn anchor() -> String {
+        to_hex(&Record::generate_sha256(3).1.anchor[..])
+    }
+
+    #[test]
+    fn manage_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        let (store, log, anchors) = (path("store"), path("audit"), path("anchors"));
+        assert!(admin(&["list", "--store", &store]).is_err());
+
+        let text = format!(
+            "# enrolled by hand\nalice sha256 {}\n\nbob aes128 {} {}\n",
+            anchor(),
+            "11".repeat(16),
+            "22".repeat(16)
+        );
+        fs::write(&anchors, text).unwrap();
+        let import = ["import", "--store", &store, "--audit", &log, &anchors];
+        assert_eq!(admin(&import).unwrap(), "imported 2 users\n");
+        assert!(admin(&import).is_err());
+
+        // Lock alice behind the tool's back, which holds off the tool like a
+        // running daemon would
+        {
+            let mut file = FileStore::open_locked(&store).unwrap();
+            let err = admin(&["list", "--store", &store]).unwrap_err();
+            assert!(format!("{:#}", err).contains("in use"));
+            let mut record = file.get("alice").unwrap().unwrap();
+            record.round = 3;
+            record.attempts = Attempts {
+                failures: 5,
+                locked_until: LOCKED_FOREVER,
+                ..Attempts::default()
+            };
+            file.put("alice", record).unwrap();
+        }
+        assert_eq!(
+            admin(&["list", "--store", &store]).unwrap(),
+            "alice sha256 2 5 forever\nbob aes128 0 0 -\n"
+        );
+        admin(&["reset", "--store", &store, "--audit", &log, "alice"]).unwrap();
+        admin(&["revoke", "--store", &store, "--audit", &log, "bob"]).unwrap();
+        assert!(admin(&["revoke", "--store", &store, "bob"]).is_err());
+        assert_eq!(
+            admin(&["list", "--store", &store]).unwrap(),
+            "alice sha256 2 0 -\n"
+        );
+
+        // Export keeps the full state
+        let export = admin(&["export", "--store", &store]).unwrap();
+        fs::write(&anchors, &export).unwrap();
+        let copy = path("copy");
+        admin(&["import", "--store", &copy, &anchors]).unwrap();
+        assert_eq!(admin(&["export", "--store", &copy]).unwrap(), export);
+
+        fs::write(&anchors, "carol sha256 abcd\n").unwrap();
+        let err = admin(&["import", "--store", &copy, &anchors]).unwrap_err();
+        assert!(format!("{:#}", err).contains("line 1"));
+    }
+
+    #[test]
+    fn verify_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit");
+        let log = path.to_str().unwrap();
+        let mut store = Audited::new(
+            diploma::store::MemoryStore::new(),
+            AuditLog::<Sha256, _>::open(&path, ManualClock::new(0)).unwrap(),
+        );
+        store
+            .enroll("alice", parse_record(&["sha256", &anchor()]).unwrap())
+            .unwrap();
+        let first_len = fs::metadata(&path).unwrap().len();
+        let first = admin(&["verify-audit", log]).unwrap();
+        let checkpoint = first.trim().strip_prefix("ok ").unwrap().to_owned();
+        assert!(checkpoint.starts_with("1:"));
+
+        store.remove("alice").unwrap();
+        admin(&["verify-audit", log, "--checkpoint", &checkpoint]).unwrap();
+
+        // Dropping the tail is caught against the checkpoint
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(first_len).unwrap();
+        let second = admin(&["verify-audit", log]).unwrap();
+        assert_eq!(second, "ok 1:".to_owned() + &checkpoint[2..] + "\n");
+        let checkpoint = format!("2:{}", "00".repeat(32));
+        assert!(admin(&["verify-audit", log, "--checkpoint", &checkpoint]).is_err());
+    }
+}
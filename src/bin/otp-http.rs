@@ -0,0 +1,107 @@
+use std::{env, net::TcpListener};
+
+use anyhow::Context;
+use clap::{App, Arg, ArgMatches};
+use crypto::sha2::Sha256;
+use diploma::{
+    audit::{AuditLog, Audited},
+    daemon::Server,
+    http,
+    policy::{Policy, PolicyConfig, SystemClock},
+    store::FileStore,
+};
+
+// HTTP/JSON API in front of the verifier store, see `diploma::http`. Callers
+// aren't authenticated, so the default is to listen on loopback only. The
+// bound address is printed once listening, which tells port 0 apart.
+
+fn number<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> anyhow::Result<T> {
+    // Every numeric option has a default
+    let value = matches.value_of(name).unwrap();
+    value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid --{}: {}", name, value))
+}
+
+fn log_error(err: http::ServeError) {
+    eprintln!("otp-http: {}", err);
+}
+
+fn run(args: &[String]) -> anyhow::Result<()> {
+    let matches = App::new("otp-http")
+        .about("HTTP/JSON API for one-time passwords")
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .takes_value(true)
+                .default_value("127.0.0.1:8080"),
+        )
+        .arg(
+            Arg::with_name("store")
+                .long("store")
+                .takes_value(true)
+                .required(true)
+                .help("Verifier store file"),
+        )
+        .arg(
+            Arg::with_name("audit")
+                .long("audit")
+                .takes_value(true)
+                .help("Append authentication events to this log"),
+        )
+        .arg(
+            Arg::with_name("max-failures")
+                .long("max-failures")
+                .takes_value(true)
+                .default_value("5")
+                .help("Failures before a user is locked, 0 disables the lockout"),
+        )
+        .arg(
+            Arg::with_name("lockout")
+                .long("lockout")
+                .takes_value(true)
+                .default_value("900")
+                .help("Seconds a user stays locked, 0 locks until an admin reset"),
+        )
+        .get_matches_from_safe(args)?;
+
+    let lockout = number::<u64>(&matches, "lockout")?;
+    let policy = Policy::new(
+        PolicyConfig {
+            max_failures: number(&matches, "max-failures")?,
+            lockout: (lockout != 0).then_some(lockout),
+            ..PolicyConfig::default()
+        },
+        SystemClock,
+    );
+    let path = matches.value_of("store").unwrap();
+    // Held until exit, so nothing else writes the store behind our back
+    let store = FileStore::try_open_locked(path).with_context(|| format!("opening {}", path))?;
+    let listen = matches.value_of("listen").unwrap();
+    let listener = TcpListener::bind(listen).with_context(|| format!("binding {}", listen))?;
+    println!("listening on {}", listener.local_addr()?);
+
+    match matches.value_of("audit") {
+        Some(path) => {
+            let log = AuditLog::<Sha256, _>::open(path, SystemClock)
+                .with_context(|| format!("opening {}", path))?;
+            let server = Server::new(Audited::new(store, log), policy);
+            http::serve(&listener, server, log_error);
+        }
+        None => http::serve(&listener, Server::new(store, policy), log_error),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if let Err(err) = run(&args) {
+        match err.downcast_ref::<clap::Error>() {
+            Some(err) => err.exit(),
+            None => {
+                eprintln!("otp-http: {:#}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::{collections::HashMap, env, fs, net::IpAddr, net::UdpSocket};
+
+use anyhow::{bail, Context};
+use clap::{App, Arg};
+use crypto::sha2::Sha256;
+use diploma::{
+    audit::{AuditLog, Audited},
+    policy::{Policy, PolicyConfig, SystemClock},
+    radius::RadiusServer,
+    store::{FileStore, Store},
+};
+
+// RADIUS server in front of the verifier store, see `diploma::radius`. The
+// clients file holds one `<address> <shared secret>` per line.
+
+fn parse_clients(text: &str) -> anyhow::Result<HashMap<IpAddr, Vec<u8>>> {
+    let mut clients = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (address, secret) = match line.split_once(char::is_whitespace) {
+            Some((address, secret)) if !secret.trim().is_empty() => (address, secret.trim()),
+            _ => bail!("line {}: expected <address> <secret>", number + 1),
+        };
+        let address = address
+            .parse()
+            .with_context(|| format!("line {}: invalid address", number + 1))?;
+        clients.insert(address, secret.as_bytes().to_vec());
+    }
+    Ok(clients)
+}
+
+fn serve<S: Store>(
+    socket: &UdpSocket,
+    store: S,
+    clients: HashMap<IpAddr, Vec<u8>>,
+) -> anyhow::Result<()> {
+    let policy = Policy::new(PolicyConfig::default(), SystemClock);
+    RadiusServer::new(store, policy, clients)
+        .serve(socket, |err| eprintln!("otp-radius: {}", err))?;
+    Ok(())
+}
+
+fn run(args: &[String]) -> anyhow::Result<()> {
+    let matches = App::new("otp-radius")
+        .about("RADIUS server for one-time passwords")
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .takes_value(true)
+                .default_value("0.0.0.0:1812"),
+        )
+        .arg(
+            Arg::with_name("store")
+                .long("store")
+                .takes_value(true)
+                .required(true)
+                .help("Verifier store file"),
+        )
+        .arg(
+            Arg::with_name("clients")
+                .long("clients")
+                .takes_value(true)
+                .required(true)
+                .help("File with the shared secret of every client"),
+        )
+        .arg(
+            Arg::with_name("audit")
+                .long("audit")
+                .takes_value(true)
+                .help("Append authentication events to this log"),
+        )
+        .get_matches_from_safe(args)?;
+
+    let path = matches.value_of("clients").unwrap();
+    let text = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+    let clients = parse_clients(&text).with_context(|| format!("in {}", path))?;
+    let path = matches.value_of("store").unwrap();
+    // Held until exit, so nothing else writes the store behind our back
+    let store = FileStore::try_open_locked(path).with_context(|| format!("opening {}", path))?;
+    let listen = matches.value_of("listen").unwrap();
+    let socket = UdpSocket::bind(listen).with_context(|| format!("binding {}", listen))?;
+
+    match matches.value_of("audit") {
+        Some(path) => {
+            let log = AuditLog::<Sha256, _>::open(path, SystemClock)
+                .with_context(|| format!("opening {}", path))?;
+            serve(&socket, Audited::new(store, log), clients)
+        }
+        None => serve(&socket, store, clients),
+    }
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if let Err(err) = run(&args) {
+        match err.downcast_ref::<clap::Error>() {
+            Some(err) => err.exit(),
+            None => {
+                eprintln!("otp-radius: {:#}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_file() {
+        let clients = parse_clients("# vpn\n10.0.0.1 s3cret with spaces\n\n::1 other\n").unwrap();
+        assert_eq!(clients.len(), 2);
+        assert_eq!(
+            clients[&"10.0.0.1".parse::<IpAddr>().unwrap()],
+            b"s3cret with spaces"
+        );
+        assert!(parse_clients("10.0.0.1\n").is_err());
+        assert!(parse_clients("vpn.example s3cret\n").is_err());
+    }
+}
@@ -0,0 +1,628 @@
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use crypto::{aessafe::AesSafe128Encryptor, sha2::Sha256};
+use diploma::{
+    base::{PrivateKey, State},
+    cipher::{Aes128SafeBuilder, BlockOneWay},
+    encoding::{self, Format},
+    hash::Sha256Builder,
+    keystore::{KdfParams, Keystore, Persist},
+    secret::Secret,
+    store::Algorithm,
+    uri::{self, Enrollment, MAX_ROUNDS},
+};
+use rand::RngCore;
+use zeroize::Zeroizing;
+
+// Client for hash chain one-time passwords. The chain lives in a passphrase
+// protected keystore that is rewritten before a password is printed, so a
+// password is never shown twice.
+
+type Sha256Key = PrivateKey<Sha256, 32>;
+type Aes128Key = PrivateKey<BlockOneWay<AesSafe128Encryptor, 16>, 16>;
+
+enum Chain {
+    Sha256 {
+        rounds: u64,
+        anchor: [u8; 32],
+        key: Sha256Key,
+    },
+    Aes128 {
+        rounds: u64,
+        secret: Secret<16>,
+        anchor: [u8; 16],
+        key: Aes128Key,
+    },
+}
+
+impl Chain {
+    fn new(algorithm: Algorithm, rounds: usize, seed: &[u8]) -> anyhow::Result<Self> {
+        // One more password than rounds, the first one is the anchor
+        fn register<const N: usize, F: diploma::base::OneWay>(
+            mut key: PrivateKey<F, N>,
+        ) -> ([u8; N], PrivateKey<F, N>) {
+            let anchor = *key.get_password().unwrap().expose();
+            assert_eq!(key.pop_password(), State::Ok);
+            (anchor, key)
+        }
+
+        let rounds_u64 = u64::try_from(rounds).expect("sorry, architecture is not supported");
+        match algorithm {
+            Algorithm::Sha256 => {
+                let key = Sha256Builder::private_from_password(
+                    rounds,
+                    Secret::from_slice(seed).context("sha256 seed must be 32 bytes")?,
+                );
+                let (anchor, key) = register(key);
+                Ok(Chain::Sha256 {
+                    rounds: rounds_u64,
+                    anchor,
+                    key,
+                })
+            }
+            Algorithm::Aes128 => {
+                let secret = Secret::<16>::random();
+                let key = Aes128SafeBuilder::private_from_password(
+                    rounds,
+                    secret.clone(),
+                    Secret::from_slice(seed).context("aes128 seed must be 16 bytes")?,
+                );
+                let (anchor, key) = register(key);
+                Ok(Chain::Aes128 {
+                    rounds: rounds_u64,
+                    secret,
+                    anchor,
+                    key,
+                })
+            }
+            other => bail!("{} is not a hash chain", other),
+        }
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            Chain::Sha256 { .. } => Algorithm::Sha256,
+            Chain::Aes128 { .. } => Algorithm::Aes128,
+        }
+    }
+
+    fn rounds(&self) -> u64 {
+        match self {
+            Chain::Sha256 { rounds, .. } | Chain::Aes128 { rounds, .. } => *rounds,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        match self {
+            Chain::Sha256 { key, .. } => key.passwords().len(),
+            Chain::Aes128 { key, .. } => key.passwords().len(),
+        }
+    }
+
+    fn password(&self) -> Option<Zeroizing<Vec<u8>>> {
+        match self {
+            Chain::Sha256 { key, .. } => key.get_password().map(|p| Zeroizing::new(p.to_vec())),
+            Chain::Aes128 { key, .. } => key.get_password().map(|p| Zeroizing::new(p.to_vec())),
+        }
+    }
+
+    fn advance(&mut self) -> State {
+        match self {
+            Chain::Sha256 { key, .. } => key.pop_password(),
+            Chain::Aes128 { key, .. } => key.pop_password(),
+        }
+    }
+
+    fn secret(&self) -> &[u8] {
+        match self {
+            Chain::Sha256 { .. } => &[],
+            Chain::Aes128 { secret, .. } => &secret[..],
+        }
+    }
+
+    // What the server needs to enroll the user
+    fn anchor(&self) -> String {
+        match self {
+            Chain::Sha256 { anchor, .. } => {
+                format!("{} {}", Algorithm::Sha256, encoding::to_hex(anchor))
+            }
+            Chain::Aes128 { secret, anchor, .. } => format!(
+                "{} {} {}",
+                Algorithm::Aes128,
+                encoding::to_hex(anchor),
+                encoding::to_hex(&secret[..])
+            ),
+        }
+    }
+}
+
+impl Persist for Chain {
+    fn encode(&self) -> Zeroizing<Vec<u8>> {
+        let mut out = Zeroizing::new(vec![self.algorithm().id()]);
+        out.extend_from_slice(&self.rounds().to_be_bytes());
+        match self {
+            Chain::Sha256 { anchor, key, .. } => {
+                out.extend_from_slice(anchor);
+                out.extend_from_slice(&key.encode());
+            }
+            Chain::Aes128 {
+                secret,
+                anchor,
+                key,
+                ..
+            } => {
+                out.extend_from_slice(&secret[..]);
+                out.extend_from_slice(anchor);
+                out.extend_from_slice(&key.encode());
+            }
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (id, rest) = bytes.split_first()?;
+        let (rounds, rest) = (rest.get(..8)?, rest.get(8..)?);
+        let rounds = u64::from_be_bytes(rounds.try_into().ok()?);
+        match Algorithm::from_id(*id)? {
+            Algorithm::Sha256 => Some(Chain::Sha256 {
+                rounds,
+                anchor: rest.get(..32)?.try_into().ok()?,
+                key: Sha256Key::decode(rest.get(32..)?)?,
+            }),
+            Algorithm::Aes128 => Some(Chain::Aes128 {
+                rounds,
+                secret: Secret::from_slice(rest.get(..16)?)?,
+                anchor: rest.get(16..32)?.try_into().ok()?,
+                key: Aes128Key::decode(rest.get(32..)?)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn default_state() -> PathBuf {
+    if let Some(path) = env::var_os("OTP_STATE") {
+        return path.into();
+    }
+    let home = env::var_os("HOME").unwrap_or_else(|| ".".into());
+    Path::new(&home).join(".otp-chain")
+}
+
+// Reads a line from the terminal with echo turned off
+fn prompt(message: &str) -> anyhow::Result<Zeroizing<String>> {
+    let tty = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("no terminal to ask for the passphrase, use --passphrase-file")?;
+    let fd = tty.as_raw_fd();
+    (&tty).write_all(message.as_bytes())?;
+
+    let mut term = unsafe { std::mem::zeroed::<libc::termios>() };
+    let echo_off = unsafe { libc::tcgetattr(fd, &mut term) } == 0 && {
+        let mut silent = term;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) == 0 }
+    };
+    let mut line = Zeroizing::new(String::new());
+    let read = io::BufReader::new(&tty).read_line(&mut line);
+    if echo_off {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) };
+        (&tty).write_all(b"\n")?;
+    }
+    read?;
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+fn passphrase(matches: &ArgMatches, confirm: bool) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    if let Some(path) = matches.value_of("passphrase-file") {
+        let mut bytes = Zeroizing::new(fs::read(path).context("reading the passphrase file")?);
+        let len = bytes.trim_ascii_end().len();
+        bytes.truncate(len);
+        return Ok(bytes);
+    }
+    if let Some(pass) = env::var_os("OTP_PASSPHRASE") {
+        return Ok(Zeroizing::new(pass.into_encoded_bytes()));
+    }
+    let pass = prompt("Passphrase: ")?;
+    if confirm && *prompt("Repeat passphrase: ")? != *pass {
+        bail!("passphrases don't match");
+    }
+    Ok(Zeroizing::new(pass.as_bytes().to_vec()))
+}
+
+fn format(matches: &ArgMatches) -> Format {
+    // Validated by clap
+    Format::from_name(matches.value_of("format").unwrap_or("hex")).unwrap()
+}
+
+// A QR code of the URI for the terminal with `-`, otherwise for a file of
+// the type its extension names
+fn render_qr(text: &str, path: &str) -> anyhow::Result<Vec<u8>> {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        _ if path == "-" => Ok(uri::qr_terminal(text)?.into_bytes()),
+        Some("png") => {
+            let mut image = vec![];
+            uri::qr_png(text, 8, &mut image)?;
+            Ok(image)
+        }
+        Some("svg") => Ok(uri::qr_svg(text)?.into_bytes()),
+        _ => bail!("{}: QR codes are written as .png or .svg", path),
+    }
+}
+
+fn write_qr(path: &str, image: &[u8], out: &mut impl Write) -> anyhow::Result<()> {
+    if path == "-" {
+        out.write_all(image)?;
+        return Ok(());
+    }
+    // It holds the seed, so only the owner may read it
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(image))
+        .with_context(|| format!("writing {}", path))
+}
+
+fn open(matches: &ArgMatches) -> anyhow::Result<Keystore<Chain>> {
+    let path = matches
+        .value_of("state")
+        .map_or_else(default_state, PathBuf::from);
+    let pass = passphrase(matches, false)?;
+    Keystore::open(&path, &pass).with_context(|| format!("opening {}", path.display()))
+}
+
+fn run(args: &[String], out: &mut impl Write) -> anyhow::Result<()> {
+    let format_arg = Arg::with_name("format")
+        .long("format")
+        .short("f")
+        .takes_value(true)
+        .possible_values(&["hex", "words", "decimal"])
+        .help("How to print the password, hex by default");
+
+    let app = App::new("otp")
+        .about("One-time passwords from a hash chain")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("state")
+                .long("state")
+                .takes_value(true)
+                .global(true)
+                .help("Chain file, $OTP_STATE or ~/.otp-chain by default"),
+        )
+        .arg(
+            Arg::with_name("passphrase-file")
+                .long("passphrase-file")
+                .takes_value(true)
+                .global(true)
+                .help("Read the passphrase from a file instead of $OTP_PASSPHRASE or the terminal"),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Creates a new chain and prints its anchor")
+                .arg(
+                    Arg::with_name("algorithm")
+                        .long("algorithm")
+                        .short("a")
+                        .takes_value(true)
+                        .possible_values(&["sha256", "aes128"])
+                        .default_value("sha256"),
+                )
+                .arg(
+                    Arg::with_name("rounds")
+                        .long("rounds")
+                        .short("n")
+                        .takes_value(true)
+                        .default_value("1000"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .help("Chain seed in hex, random by default"),
+                )
+                .arg(
+                    Arg::with_name("uri")
+                        .long("uri")
+                        .takes_value(true)
+                        .value_name("LABEL")
+                        .help("Also prints an enrollment URI for apps, it contains the seed"),
+                )
+                .arg(
+                    Arg::with_name("issuer")
+                        .long("issuer")
+                        .takes_value(true)
+                        .requires("uri"),
+                )
+                .arg(
+                    Arg::with_name("qr")
+                        .long("qr")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .requires("uri")
+                        .help("Writes the URI as a QR code to a .png or .svg file, - for the terminal"),
+                )
+                .arg(
+                    Arg::with_name("kdf-cost")
+                        .long("kdf-cost")
+                        .takes_value(true)
+                        .default_value("15")
+                        .help("scrypt log2(N) for the passphrase"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("show")
+                .about("Prints the current password without using it up")
+                .arg(format_arg.clone()),
+        )
+        .subcommand(SubCommand::with_name("next").about("Skips the current password"))
+        .subcommand(
+            SubCommand::with_name("take")
+                .about("Uses up the current password and prints it")
+                .arg(format_arg),
+        )
+        .subcommand(SubCommand::with_name("remaining").about("Prints the number of passwords left"))
+        .subcommand(
+            SubCommand::with_name("anchor").about("Prints what the server needs to enroll"),
+        );
+
+    let matches = app.get_matches_from_safe(args)?;
+    match matches.subcommand() {
+        ("init", Some(matches)) => {
+            let algorithm = Algorithm::from_name(matches.value_of("algorithm").unwrap()).unwrap();
+            let rounds = matches
+                .value_of("rounds")
+                .unwrap()
+                .parse::<usize>()
+                .context("invalid number of rounds")?;
+            if rounds == 0 {
+                bail!("a chain needs at least one round");
+            }
+            if rounds > usize::try_from(MAX_ROUNDS).expect("sorry, architecture is not supported") {
+                bail!("a chain holds at most {} rounds", MAX_ROUNDS);
+            }
+            let seed = match matches.value_of("seed") {
+                Some(seed) => Zeroizing::new(encoding::from_hex(seed).context("seed must be hex")?),
+                None => {
+                    let mut seed = Zeroizing::new(vec![0; algorithm.size()]);
+                    rand::thread_rng().fill_bytes(&mut seed);
+                    seed
+                }
+            };
+            let params = KdfParams {
+                log_n: matches
+                    .value_of("kdf-cost")
+                    .unwrap()
+                    .parse()
+                    .context("invalid KDF cost")?,
+                ..KdfParams::default()
+            };
+            if !params.is_sane() {
+                bail!("unsupported KDF cost {}", params.log_n);
+            }
+
+            let chain = Chain::new(algorithm, rounds, &seed)?;
+            let enrollment_uri = matches
+                .value_of("uri")
+                .map(|label| {
+                    let rounds = chain.rounds();
+                    let mut enrollment =
+                        Enrollment::new(label, algorithm, rounds, &seed, chain.secret())
+                            .with_context(|| {
+                                format!("enrollment URIs hold at most {} rounds", MAX_ROUNDS)
+                            })?;
+                    enrollment.issuer = matches.value_of("issuer").map(str::to_owned);
+                    anyhow::Ok(enrollment.to_uri())
+                })
+                .transpose()?;
+            // Rendered before the keystore is written, a bad --qr leaves nothing behind
+            let qr = match (&enrollment_uri, matches.value_of("qr")) {
+                (Some(text), Some(path)) => Some((path, render_qr(text, path)?)),
+                _ => None,
+            };
+            let path = matches
+                .value_of("state")
+                .map_or_else(default_state, PathBuf::from);
+            let pass = passphrase(matches, true)?;
+            let store = Keystore::create(&path, &pass, params, chain)
+                .with_context(|| format!("creating {}", path.display()))?;
+            writeln!(out, "{}", store.get().anchor())?;
+            if let Some(text) = &enrollment_uri {
+                writeln!(out, "{}", text)?;
+            }
+            if let Some((path, image)) = qr {
+                write_qr(path, &image, out)?;
+            }
+        }
+        ("show", Some(matches)) => {
+            let store = open(matches)?;
+            let password = store.get().password().context("the chain is used up")?;
+            writeln!(out, "{}", encoding::encode(&password, format(matches)))?;
+        }
+        ("next", Some(matches)) => {
+            let mut store = open(matches)?;
+            if store.get().password().is_none() {
+                bail!("the chain is used up");
+            }
+            store.update(Chain::advance)?;
+        }
+        ("take", Some(matches)) => {
+            let mut store = open(matches)?;
+            let password = store.get().password().context("the chain is used up")?;
+            // Saved before it is shown
+            store.update(Chain::advance)?;
+            writeln!(out, "{}", encoding::encode(&password, format(matches)))?;
+        }
+        ("remaining", Some(matches)) => {
+            let store = open(matches)?;
+            writeln!(
+                out,
+                "{} of {}",
+                store.get().remaining(),
+                store.get().rounds()
+            )?;
+        }
+        ("anchor", Some(matches)) => {
+            let store = open(matches)?;
+            writeln!(out, "{}", store.get().anchor())?;
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if let Err(err) = run(&args, &mut io::stdout()) {
+        match err.downcast_ref::<clap::Error>() {
+            Some(err) => err.exit(),
+            None => {
+                eprintln!("otp: {:#}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diploma::store::Record;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn otp(dir: &Path, args: &str) -> anyhow::Result<String> {
+        let pass = dir.join("pass");
+        fs::write(&pass, "correct horse\n").unwrap();
+        let state = dir.join("chain");
+        let mut argv = vec!["otp".to_owned()];
+        argv.extend(args.split_whitespace().map(str::to_owned));
+        argv.push(format!("--state={}", state.display()));
+        argv.push(format!("--passphrase-file={}", pass.display()));
+
+        let mut out = vec![];
+        run(&argv, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    fn enroll(anchor: &str) -> Record {
+        let fields = anchor.split_whitespace().collect::<Vec<_>>();
+        let secret = fields
+            .get(2)
+            .map_or(vec![], |s| encoding::from_hex(s).unwrap());
+        Record::new(
+            Algorithm::from_name(fields[0]).unwrap(),
+            encoding::from_hex(fields[1]).unwrap(),
+            secret,
+        )
+    }
+
+    #[test]
+    fn lifecycle() {
+        for algorithm in ["sha256", "aes128"] {
+            let dir = tempfile::tempdir().unwrap();
+            let dir = dir.path();
+            let anchor = otp(dir, &format!("init -a {} -n 3 --kdf-cost 4", algorithm)).unwrap();
+            assert_eq!(otp(dir, "anchor").unwrap(), anchor);
+            assert!(otp(dir, "init -n 3 --kdf-cost 4").is_err());
+            let mut record = enroll(&anchor);
+            let size = record.algorithm.size();
+
+            // Showing doesn't use the password up
+            let shown = otp(dir, "show -f words").unwrap();
+            assert_eq!(otp(dir, "show --format words").unwrap(), shown);
+            let taken = otp(dir, "take").unwrap();
+            let password = encoding::decode(&taken, Format::Hex, size).unwrap();
+            assert_eq!(
+                encoding::decode(&shown, Format::Words, size).unwrap(),
+                password
+            );
+            record.accept(&password, None).unwrap();
+            assert_eq!(otp(dir, "remaining").unwrap(), "2 of 3\n");
+
+            // Skipped passwords are still accepted later with a skip
+            otp(dir, "next").unwrap();
+            let taken = otp(dir, "take -f decimal").unwrap();
+            let password = encoding::decode(&taken, Format::Decimal, size).unwrap();
+            assert!(record.clone().accept(&password, None).is_err());
+            record.accept_skip(&password, 1).unwrap();
+
+            assert_eq!(otp(dir, "remaining").unwrap(), "0 of 3\n");
+            assert!(otp(dir, "take").is_err());
+            assert!(otp(dir, "next").is_err());
+        }
+    }
+
+    #[test]
+    fn seeded_chain_is_reproducible() {
+        let seed = "42".repeat(32);
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let args = format!("init -n 5 --kdf-cost 4 --seed {}", seed);
+        assert_eq!(otp(a.path(), &args).unwrap(), otp(b.path(), &args).unwrap());
+        assert!(otp(a.path(), "init -n 5 --kdf-cost 4 --seed 4242").is_err());
+    }
+
+    #[test]
+    fn enrollment_uri() {
+        for algorithm in ["sha256", "aes128"] {
+            let dir = tempfile::tempdir().unwrap();
+            let qr = dir.path().join("qr.png");
+            let args = format!(
+                "init -a {} -n 4 --kdf-cost 4 --uri alice@example --issuer Example --qr {}",
+                algorithm,
+                qr.display()
+            );
+            let output = otp(dir.path(), &args).unwrap();
+            let lines = output.lines().collect::<Vec<_>>();
+            assert_eq!(lines.len(), 2);
+
+            // The app's chain is the one in the keystore
+            let enrollment = Enrollment::parse(lines[1]).unwrap();
+            assert_eq!(enrollment.label, "alice@example");
+            assert_eq!(enrollment.issuer.as_deref(), Some("Example"));
+            assert_eq!(enrollment.record(), enroll(lines[0]));
+            let mut record = enrollment.record();
+            let taken = otp(dir.path(), "take").unwrap();
+            let size = record.algorithm.size();
+            record
+                .accept(&encoding::decode(&taken, Format::Hex, size).unwrap(), None)
+                .unwrap();
+
+            assert!(fs::read(&qr).unwrap().starts_with(b"\x89PNG"));
+            assert_eq!(
+                fs::metadata(&qr).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = otp(dir.path(), "init -n 2 --kdf-cost 4 --uri bob --qr -").unwrap();
+        assert!(output.lines().count() > 10);
+        let dir = tempfile::tempdir().unwrap();
+        assert!(otp(dir.path(), "init -n 2 --kdf-cost 4 --uri bob --qr a.gif").is_err());
+        assert!(!dir.path().join("chain").exists());
+        assert!(otp(dir.path(), "init -n 2 --kdf-cost 4 --qr -").is_err());
+    }
+
+    #[test]
+    fn init_rejects_out_of_range_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        // Within 1..=24 but too much memory with r = 8
+        assert!(otp(dir, "init -n 3 --kdf-cost 22").is_err());
+        assert!(otp(dir, "init -n 18446744073709551615 --kdf-cost 4").is_err());
+        assert!(otp(dir, &format!("init -n {} --kdf-cost 4", MAX_ROUNDS + 1)).is_err());
+        assert!(!dir.join("chain").exists());
+    }
+}
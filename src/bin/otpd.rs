@@ -0,0 +1,176 @@
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+};
+
+use anyhow::Context;
+use clap::{App, Arg, ArgMatches};
+use crypto::sha2::Sha256;
+use diploma::{
+    audit::{AuditLog, Audited},
+    daemon::{Failure, Response, Server, MAX_LINE},
+    policy::{Policy, PolicyConfig, SystemClock},
+    store::{FileStore, Store},
+};
+
+// Authentication daemon. Holds the verifier store and answers the line
+// protocol of `diploma::daemon` on a Unix socket that only its owner can
+// connect to. Connections are served by their own threads, requests are
+// handled one at a time.
+
+fn connection<S: Store>(
+    stream: UnixStream,
+    server: &Mutex<Server<S, SystemClock>>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let limit = u64::try_from(MAX_LINE).expect("sorry, architecture is not supported");
+        if (&mut reader).take(limit + 1).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && line.len() > MAX_LINE {
+            writeln!(writer, "{}", Response::Error(Failure::Malformed))?;
+            return Ok(());
+        }
+
+        let response = server
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .handle_line(&line)
+            .unwrap_or_else(|err| {
+                eprintln!("otpd: {}", err);
+                Response::Error(Failure::Internal)
+            });
+        writeln!(writer, "{}", response)?;
+    }
+}
+
+fn serve<S: Store + Send + 'static>(
+    listener: UnixListener,
+    server: Server<S, SystemClock>,
+) -> anyhow::Result<()> {
+    let server = Arc::new(Mutex::new(server));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("otpd: accept: {}", err);
+                continue;
+            }
+        };
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(err) = connection(stream, &server) {
+                eprintln!("otpd: connection: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    // A socket left behind by a previous run, anything else is kept
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    // Owner only, the socket is created with the process umask
+    let old = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(old) };
+    listener.with_context(|| format!("binding {}", path.display()))
+}
+
+fn number<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> anyhow::Result<T> {
+    // Every numeric option has a default
+    let value = matches.value_of(name).unwrap();
+    value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid --{}: {}", name, value))
+}
+
+fn run(args: &[String]) -> anyhow::Result<()> {
+    let matches = App::new("otpd")
+        .about("One-time password authentication daemon")
+        .arg(
+            Arg::with_name("socket")
+                .long("socket")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("store")
+                .long("store")
+                .takes_value(true)
+                .required(true)
+                .help("Verifier store file"),
+        )
+        .arg(
+            Arg::with_name("audit")
+                .long("audit")
+                .takes_value(true)
+                .help("Append authentication events to this log"),
+        )
+        .arg(
+            Arg::with_name("max-failures")
+                .long("max-failures")
+                .takes_value(true)
+                .default_value("5")
+                .help("Failures before a user is locked, 0 disables the lockout"),
+        )
+        .arg(
+            Arg::with_name("lockout")
+                .long("lockout")
+                .takes_value(true)
+                .default_value("900")
+                .help("Seconds a user stays locked, 0 locks until an admin reset"),
+        )
+        .get_matches_from_safe(args)?;
+
+    let lockout = number::<u64>(&matches, "lockout")?;
+    let policy = Policy::new(
+        PolicyConfig {
+            max_failures: number(&matches, "max-failures")?,
+            lockout: (lockout != 0).then_some(lockout),
+            ..PolicyConfig::default()
+        },
+        SystemClock,
+    );
+    let path = matches.value_of("store").unwrap();
+    // Held until exit, so nothing else writes the store behind our back
+    let store = FileStore::try_open_locked(path).with_context(|| format!("opening {}", path))?;
+    let listener = bind(Path::new(matches.value_of("socket").unwrap()))?;
+
+    match matches.value_of("audit") {
+        Some(path) => {
+            let log = AuditLog::<Sha256, _>::open(path, SystemClock)
+                .with_context(|| format!("opening {}", path))?;
+            serve(listener, Server::new(Audited::new(store, log), policy))
+        }
+        None => serve(listener, Server::new(store, policy)),
+    }
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if let Err(err) = run(&args) {
+        match err.downcast_ref::<clap::Error>() {
+            Some(err) => err.exit(),
+            None => {
+                eprintln!("otpd: {:#}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
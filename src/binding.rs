@@ -0,0 +1,455 @@
+use std::fmt;
+
+use ed25519_dalek::{Keypair, Signature, Signer, Verifier};
+
+use crate::{
+    base::{AuthError, OneWay, PrivateKey, PublicKey},
+    secret::Secret,
+    session::{self, hmac_sha256, Nonce},
+};
+
+// Channel-bound reveal. The client first sends a MAC, keyed by its next chain
+// element, over the channel binding value (e.g. a TLS exporter) and the
+// server's challenge. The server locks the tag in and acknowledges it with a
+// signature over the tag and its own view of the channel binding, by a key
+// the client has pinned. Only then does the client reveal the element.
+//
+// A relay terminates two different channels. It can't make the real server
+// sign the client's channel binding, so the client never reveals to it, and
+// without the element it can't produce a tag the server would accept.
+//
+// The client burns its element once the ack verifies, even if the reveal
+// never arrives. It then answers the server's stale round with its next one,
+// and the server skips ahead up to `SKIP_WINDOW` rounds. The client never
+// reveals an element twice or one beyond its next.
+
+pub const SKIP_WINDOW: usize = 8;
+
+const TAG_LABEL: &[u8] = b"diploma/binding/v1";
+const ACK_LABEL: &[u8] = b"diploma/binding/v1 ack";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Challenge {
+    pub round: usize,
+    pub nonce: Nonce,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Commit {
+    // The round answered, ahead of the challenge if earlier reveals got lost
+    pub round: usize,
+    pub tag: [u8; 32],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ack {
+    pub signature: Signature,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reveal<const SIZE: usize> {
+    pub password: Secret<SIZE>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingError {
+    // The challenge asks for a round more than `SKIP_WINDOW` behind the one
+    // the client answers next
+    RoundPassed,
+    // The challenge asks for a round deeper in the chain than the next one,
+    // which would leak every element above it (the small-n attack)
+    RoundAhead,
+    Exhausted,
+    NoPendingCommit,
+    // The acknowledgement isn't the pinned server's for this tag and channel
+    BadAck,
+}
+
+impl fmt::Display for BindingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindingError::RoundPassed => f.write_str("challenge round was already passed"),
+            BindingError::RoundAhead => f.write_str("challenge round is ahead of the chain"),
+            BindingError::Exhausted => f.write_str("chain is exhausted"),
+            BindingError::NoPendingCommit => f.write_str("no pending commit"),
+            BindingError::BadAck => f.write_str("commit wasn't acknowledged by the server"),
+        }
+    }
+}
+
+// Channel binding, challenge and the round answered, unambiguously encoded
+fn context(channel_binding: &[u8], challenge: &Challenge, answered: usize) -> Vec<u8> {
+    let round = u64::try_from(challenge.round)
+        .expect("sorry, architecture is not supported")
+        .to_be_bytes();
+    let answered = u64::try_from(answered)
+        .expect("sorry, architecture is not supported")
+        .to_be_bytes();
+    let cb_len = u64::try_from(channel_binding.len())
+        .expect("sorry, architecture is not supported")
+        .to_be_bytes();
+    [
+        &cb_len[..],
+        channel_binding,
+        &round,
+        &answered,
+        &challenge.nonce,
+    ]
+    .concat()
+}
+
+fn tag(
+    password: &[u8],
+    channel_binding: &[u8],
+    challenge: &Challenge,
+    answered: usize,
+) -> [u8; 32] {
+    hmac_sha256(
+        password,
+        &[TAG_LABEL, &context(channel_binding, challenge, answered)],
+    )
+}
+
+fn ack_message(channel_binding: &[u8], challenge: &Challenge, commit: &Commit) -> Vec<u8> {
+    [
+        ACK_LABEL,
+        &context(channel_binding, challenge, commit.round),
+        &commit.tag,
+    ]
+    .concat()
+}
+
+struct Pending<const SIZE: usize> {
+    password: Secret<SIZE>,
+    // What the server has to sign
+    ack: Vec<u8>,
+}
+
+pub struct Client<F: OneWay, const SIZE: usize> {
+    private: PrivateKey<F, SIZE>,
+    server: ed25519_dalek::PublicKey,
+    // Next server round we expect to answer, in `PublicKey::round` numbering
+    next_round: usize,
+    pending: Option<Pending<SIZE>>,
+}
+
+impl<F: OneWay, const SIZE: usize> Client<F, SIZE> {
+    // `private` should already have its registration password popped,
+    // `server` is the pinned key the server acknowledges commits with
+    pub fn new(private: PrivateKey<F, SIZE>, server: ed25519_dalek::PublicKey) -> Self {
+        Self::with_round(private, server, 1)
+    }
+
+    pub fn with_round(
+        private: PrivateKey<F, SIZE>,
+        server: ed25519_dalek::PublicKey,
+        next_round: usize,
+    ) -> Self {
+        Self {
+            private,
+            server,
+            next_round,
+            pending: None,
+        }
+    }
+
+    #[must_use]
+    pub fn next_round(&self) -> usize {
+        self.next_round
+    }
+
+    pub fn commit(
+        &mut self,
+        challenge: &Challenge,
+        channel_binding: &[u8],
+    ) -> Result<Commit, BindingError> {
+        if challenge.round.saturating_add(SKIP_WINDOW) < self.next_round {
+            return Err(BindingError::RoundPassed);
+        }
+        if challenge.round > self.next_round {
+            return Err(BindingError::RoundAhead);
+        }
+        let password = self.private.get_password().ok_or(BindingError::Exhausted)?;
+        let commit = Commit {
+            round: self.next_round,
+            tag: tag(&password[..], channel_binding, challenge, self.next_round),
+        };
+        self.pending = Some(Pending {
+            password,
+            ack: ack_message(channel_binding, challenge, &commit),
+        });
+        Ok(commit)
+    }
+
+    // A bad acknowledgement drops the commit, the element stays secret
+    pub fn reveal(&mut self, ack: &Ack) -> Result<Reveal<SIZE>, BindingError> {
+        let pending = self.pending.take().ok_or(BindingError::NoPendingCommit)?;
+        self.server
+            .verify(&pending.ack, &ack.signature)
+            .map_err(|_| BindingError::BadAck)?;
+        // From now on the element is public, never answer this round again.
+        // If the reveal gets lost the server skips it.
+        let _ = self.private.pop_password();
+        self.next_round += 1;
+        Ok(Reveal {
+            password: pending.password,
+        })
+    }
+}
+
+pub struct Server<F: OneWay, const SIZE: usize> {
+    public: PublicKey<F, SIZE>,
+    keypair: Keypair,
+    challenge: Option<Challenge>,
+    commit: Option<Commit>,
+}
+
+impl<F: OneWay, const SIZE: usize> Server<F, SIZE> {
+    pub fn new(public: PublicKey<F, SIZE>, keypair: Keypair) -> Self {
+        Self {
+            public,
+            keypair,
+            challenge: None,
+            commit: None,
+        }
+    }
+
+    pub fn challenge(&mut self) -> Challenge {
+        let challenge = Challenge {
+            round: self.public.round(),
+            nonce: session::nonce(),
+        };
+        self.challenge = Some(challenge);
+        self.commit = None;
+        challenge
+    }
+
+    pub fn commit(&mut self, commit: &Commit, channel_binding: &[u8]) -> Result<Ack, AuthError> {
+        let challenge = match (&self.challenge, &self.commit) {
+            (Some(challenge), None) => challenge,
+            _ => return Err(AuthError),
+        };
+        if !(challenge.round..=challenge.round.saturating_add(SKIP_WINDOW)).contains(&commit.round)
+        {
+            return Err(AuthError);
+        }
+        let signature = self
+            .keypair
+            .sign(&ack_message(channel_binding, challenge, commit));
+        self.commit = Some(*commit);
+        Ok(Ack { signature })
+    }
+
+    pub fn verify(
+        &mut self,
+        reveal: &Reveal<SIZE>,
+        channel_binding: &[u8],
+    ) -> Result<(), AuthError> {
+        let challenge = self.challenge.take().ok_or(AuthError)?;
+        let commit = self.commit.take().ok_or(AuthError)?;
+
+        // A valid element is burned even if the tag doesn't match: it has
+        // been seen on the wire and must not be accepted on another channel
+        let skip = commit.round - challenge.round;
+        self.public.verify_skip(reveal.password.expose(), skip)?;
+
+        let actual = tag(&reveal.password, channel_binding, &challenge, commit.round);
+        if crypto::util::fixed_time_eq(&actual, &commit.tag) {
+            Ok(())
+        } else {
+            Err(AuthError)
+        }
+    }
+
+    pub fn into_public(self) -> PublicKey<F, SIZE> {
+        self.public
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{base::State, hash::Sha256Builder};
+
+    fn setup() -> (
+        Client<crypto::sha2::Sha256, 32>,
+        Server<crypto::sha2::Sha256, 32>,
+    ) {
+        let mut private = Sha256Builder::new_private(20);
+        let p0 = private.get_password().unwrap();
+        assert_eq!(private.pop_password(), State::Ok);
+        let keypair = Keypair::generate(&mut rand::thread_rng());
+        (
+            Client::new(private, keypair.public),
+            Server::new(Sha256Builder::new_public(*p0.expose()), keypair),
+        )
+    }
+
+    fn login(
+        client: &mut Client<crypto::sha2::Sha256, 32>,
+        server: &mut Server<crypto::sha2::Sha256, 32>,
+        cb: &[u8],
+    ) -> Result<(), AuthError> {
+        let challenge = server.challenge();
+        let commit = client.commit(&challenge, cb).map_err(|_| AuthError)?;
+        let ack = server.commit(&commit, cb)?;
+        let reveal = client.reveal(&ack).map_err(|_| AuthError)?;
+        server.verify(&reveal, cb)
+    }
+
+    #[test]
+    fn normal_protocol() {
+        let (mut client, mut server) = setup();
+        let cb = b"tls-exporter";
+
+        for _ in 0..20 {
+            assert!(login(&mut client, &mut server, cb).is_ok());
+        }
+
+        let challenge = server.challenge();
+        assert_eq!(client.commit(&challenge, cb), Err(BindingError::Exhausted));
+    }
+
+    #[test]
+    fn relay_on_other_channel_fails() {
+        let (mut client, mut server) = setup();
+
+        // The attacker terminates the client's channel and relays to the
+        // server over its own channel
+        let challenge = server.challenge();
+        let commit = client.commit(&challenge, b"client<->attacker").unwrap();
+        let ack = server.commit(&commit, b"attacker<->server").unwrap();
+        assert_eq!(client.reveal(&ack), Err(BindingError::BadAck));
+        assert_eq!(client.next_round(), 1);
+
+        assert!(login(&mut client, &mut server, b"tls-exporter").is_ok());
+    }
+
+    #[test]
+    fn relay_withholding_commit_fails() {
+        let (mut client, mut server) = setup();
+        let keypair = Keypair::generate(&mut rand::thread_rng());
+
+        // The attacker holds the client's commit back and locks in a tag of
+        // its own, hoping to compute the real one after the reveal
+        let challenge = server.challenge();
+        let commit = client.commit(&challenge, b"client<->attacker").unwrap();
+        let substitute = Commit {
+            round: 1,
+            tag: [0; 32],
+        };
+        let ack = server.commit(&substitute, b"attacker<->server").unwrap();
+        assert_eq!(client.reveal(&ack), Err(BindingError::BadAck));
+
+        // Neither can it acknowledge the commit itself
+        client.commit(&challenge, b"client<->attacker").unwrap();
+        let forged = Ack {
+            signature: keypair.sign(&ack_message(b"client<->attacker", &challenge, &commit)),
+        };
+        assert_eq!(client.reveal(&forged), Err(BindingError::BadAck));
+        assert_eq!(client.reveal(&forged), Err(BindingError::NoPendingCommit));
+
+        // The element never left the client
+        assert_eq!(client.next_round(), 1);
+        assert!(login(&mut client, &mut server, b"tls-exporter").is_ok());
+    }
+
+    #[test]
+    fn rejects_spoofed_rounds() {
+        let (mut client, mut server) = setup();
+        let cb = b"tls-exporter";
+
+        for _ in 0..=SKIP_WINDOW {
+            assert!(login(&mut client, &mut server, cb).is_ok());
+        }
+
+        let passed = Challenge {
+            round: 1,
+            nonce: session::nonce(),
+        };
+        assert_eq!(client.commit(&passed, cb), Err(BindingError::RoundPassed));
+
+        let ahead = Challenge {
+            round: SKIP_WINDOW + 3,
+            nonce: session::nonce(),
+        };
+        assert_eq!(client.commit(&ahead, cb), Err(BindingError::RoundAhead));
+
+        let challenge = server.challenge();
+        let commit = Commit {
+            round: challenge.round,
+            tag: [0; 32],
+        };
+        let ack = server.commit(&commit, cb).unwrap();
+        assert_eq!(client.reveal(&ack), Err(BindingError::NoPendingCommit));
+    }
+
+    #[test]
+    fn leaked_element_is_burned() {
+        let (mut client, mut server) = setup();
+        let cb = b"tls-exporter";
+
+        let challenge = server.challenge();
+        let commit = client.commit(&challenge, cb).unwrap();
+        let ack = server.commit(&commit, cb).unwrap();
+        let reveal = client.reveal(&ack).unwrap();
+        assert!(server.verify(&reveal, b"other").is_err());
+
+        let challenge = server.challenge();
+        let commit = Commit {
+            round: challenge.round,
+            tag: tag(&reveal.password, b"other", &challenge, challenge.round),
+        };
+        server.commit(&commit, b"other").unwrap();
+        assert!(server.verify(&reveal, b"other").is_err());
+    }
+
+    #[test]
+    fn login_after_lost_reveals() {
+        let (mut client, mut server) = setup();
+        let cb = b"tls-exporter";
+
+        // The reveals never reach the server, which stays on round 1
+        for _ in 0..SKIP_WINDOW {
+            let challenge = server.challenge();
+            let commit = client.commit(&challenge, cb).unwrap();
+            let ack = server.commit(&commit, cb).unwrap();
+            client.reveal(&ack).unwrap();
+        }
+        assert_eq!(client.next_round(), SKIP_WINDOW + 1);
+
+        assert!(login(&mut client, &mut server, cb).is_ok());
+        assert!(login(&mut client, &mut server, cb).is_ok());
+        assert_eq!(server.into_public().round(), SKIP_WINDOW + 3);
+    }
+
+    #[test]
+    fn server_bounds_the_answered_round() {
+        let (mut client, mut server) = setup();
+        let cb = b"tls-exporter";
+
+        let challenge = server.challenge();
+        let commit = Commit {
+            round: challenge.round + SKIP_WINDOW + 1,
+            tag: [0; 32],
+        };
+        assert!(server.commit(&commit, cb).is_err());
+        assert!(login(&mut client, &mut server, cb).is_ok());
+    }
+
+    #[test]
+    fn server_requires_commit_before_reveal() {
+        let (mut client, mut server) = setup();
+        let cb = b"tls-exporter";
+
+        let challenge = server.challenge();
+        let commit = client.commit(&challenge, cb).unwrap();
+        let ack = server.commit(&commit, cb).unwrap();
+        assert!(server.commit(&commit, cb).is_err());
+        let reveal = client.reveal(&ack).unwrap();
+
+        // A fresh challenge drops the locked-in tag
+        server.challenge();
+        assert!(server.verify(&reveal, cb).is_err());
+    }
+}
@@ -1,14 +1,26 @@
-use std::marker::PhantomData;
+use core::marker::PhantomData;
 
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+#[cfg(feature = "std")]
 use crypto::{
     aes::KeySize::KeySize128, aesni::AesNiEncryptor, aessafe::AesSafe128Encryptor,
     symmetriccipher::BlockEncryptor,
 };
+use rand_core::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+#[cfg(feature = "std")]
+use crate::base::PrivateKey;
+use crate::{
+    base::{OneWay, PebbledPrivateKey, PublicKey},
+    secret::Secret,
+};
 
-use crate::base::{OneWay, PrivateKey, PublicKey};
-
-pub trait BlockCipher: BlockEncryptor + Sized {
+// Same interface as rust-crypto's `BlockEncryptor`, which doesn't build
+// without std. RustCrypto's `aes` does and gives the same chains.
+pub trait BlockCipher: Sized {
     fn new(key: &[u8]) -> Self;
+    fn encrypt_block(&self, block: &[u8], out: &mut [u8]);
 
     fn encrypt(key: &[u8], block: &[u8], out: &mut [u8]) {
         let cipher = Self::new(key);
@@ -16,25 +28,49 @@ pub trait BlockCipher: BlockEncryptor + Sized {
     }
 }
 
+#[cfg(feature = "std")]
 impl BlockCipher for AesSafe128Encryptor {
     fn new(key: &[u8]) -> Self {
         AesSafe128Encryptor::new(key)
     }
+
+    fn encrypt_block(&self, block: &[u8], out: &mut [u8]) {
+        BlockEncryptor::encrypt_block(self, block, out);
+    }
 }
 
+#[cfg(feature = "std")]
 impl BlockCipher for AesNiEncryptor {
     fn new(key: &[u8]) -> Self {
         AesNiEncryptor::new(KeySize128, key)
     }
+
+    fn encrypt_block(&self, block: &[u8], out: &mut [u8]) {
+        BlockEncryptor::encrypt_block(self, block, out);
+    }
+}
+
+impl BlockCipher for aes::Aes128 {
+    fn new(key: &[u8]) -> Self {
+        KeyInit::new(GenericArray::from_slice(key))
+    }
+
+    fn encrypt_block(&self, block: &[u8], out: &mut [u8]) {
+        BlockEncrypt::encrypt_block_b2b(
+            self,
+            GenericArray::from_slice(block),
+            GenericArray::from_mut_slice(out),
+        );
+    }
 }
 
 pub struct BlockOneWay<B: BlockCipher, const N: usize> {
-    secret: [u8; N],
+    secret: Secret<N>,
     _cipher: PhantomData<B>,
 }
 
 impl<B: BlockCipher, const N: usize> BlockOneWay<B, N> {
-    pub fn new(secret: [u8; N]) -> Self {
+    pub fn new(secret: Secret<N>) -> Self {
         Self {
             secret,
             _cipher: PhantomData {},
@@ -49,33 +85,57 @@ impl<B: BlockCipher, const N: usize> OneWay for BlockOneWay<B, N> {
             .to_be_bytes();
         let mut block = [0; N];
         block[N - ctr.len()..].copy_from_slice(&ctr);
-        let mut key = self.secret;
+        // On the stack, this runs for every step of a chain
+        let mut key = *self.secret.expose();
         key.iter_mut().zip(input).for_each(|(a, b)| *a ^= *b);
         B::encrypt(&key, &block, output);
+        key.zeroize();
     }
 }
 
 pub struct BlockBuilder<B: BlockCipher, const N: usize>(PhantomData<B>);
 
 impl<B: BlockCipher, const N: usize> BlockBuilder<B, N> {
-    pub fn new_private(rounds: usize, secret: [u8; N]) -> PrivateKey<BlockOneWay<B, N>, N> {
+    #[cfg(feature = "std")]
+    pub fn new_private(rounds: usize, secret: Secret<N>) -> PrivateKey<BlockOneWay<B, N>, N> {
         PrivateKey::new(BlockOneWay::new(secret), rounds)
     }
 
+    #[cfg(feature = "std")]
     pub fn private_from_password(
         rounds: usize,
-        secret: [u8; N],
-        pass: [u8; N],
+        secret: Secret<N>,
+        pass: Secret<N>,
     ) -> PrivateKey<BlockOneWay<B, N>, N> {
         PrivateKey::from_password(BlockOneWay::new(secret), rounds, pass)
     }
 
-    pub fn new_public(secret: [u8; N], password: [u8; N]) -> PublicKey<BlockOneWay<B, N>, N> {
+    pub fn new_pebbled<R: RngCore + CryptoRng, const CAP: usize>(
+        rounds: usize,
+        secret: Secret<N>,
+        rng: &mut R,
+    ) -> Option<PebbledPrivateKey<BlockOneWay<B, N>, N, CAP>> {
+        PebbledPrivateKey::new(BlockOneWay::new(secret), rounds, rng)
+    }
+
+    pub fn pebbled_from_password<const CAP: usize>(
+        rounds: usize,
+        secret: Secret<N>,
+        pass: Secret<N>,
+    ) -> Option<PebbledPrivateKey<BlockOneWay<B, N>, N, CAP>> {
+        PebbledPrivateKey::from_password(BlockOneWay::new(secret), rounds, pass)
+    }
+
+    pub fn new_public(secret: Secret<N>, password: [u8; N]) -> PublicKey<BlockOneWay<B, N>, N> {
         PublicKey::new(BlockOneWay::new(secret), password)
     }
 }
 
+#[cfg(feature = "std")]
 pub type Aes128SafeBuilder = BlockBuilder<AesSafe128Encryptor, 16>;
+#[cfg(not(feature = "std"))]
+pub type Aes128SafeBuilder = BlockBuilder<aes::Aes128, 16>;
+#[cfg(feature = "std")]
 pub type Aes128NiBuilder = BlockBuilder<AesNiEncryptor, 16>;
 
 #[cfg(test)]
@@ -86,31 +146,58 @@ mod tests {
     #[test]
     fn normal_protocol() {
         let secret = b"YELLOW SUBMARINE";
-        let mut private = Aes128SafeBuilder::new_private(5, *secret);
+        let mut private = Aes128SafeBuilder::new_private(5, Secret::new(*secret));
 
         let p0 = private.get_password().unwrap();
         assert_eq!(private.pop_password(), State::Ok);
 
-        let mut public = Aes128SafeBuilder::new_public(*secret, p0);
+        let mut public = Aes128SafeBuilder::new_public(Secret::new(*secret), *p0.expose());
 
         let p1 = private.get_password().unwrap();
-        assert!(public.verify(&p1).is_ok());
+        assert!(public.verify(p1.expose()).is_ok());
         assert_eq!(private.pop_password(), State::Ok);
 
         let p2 = private.get_password().unwrap();
-        assert!(public.verify(&p2).is_ok());
+        assert!(public.verify(p2.expose()).is_ok());
         assert_eq!(private.pop_password(), State::Ok);
 
         let p3 = private.get_password().unwrap();
-        assert!(public.verify(&p3).is_ok());
+        assert!(public.verify(p3.expose()).is_ok());
         assert_eq!(private.pop_password(), State::Ok);
 
         let p4 = private.get_password().unwrap();
-        assert!(public.verify(&p4).is_ok());
+        assert!(public.verify(p4.expose()).is_ok());
         assert_eq!(private.pop_password(), State::Ok);
 
         let p5 = private.get_password().unwrap();
-        assert!(public.verify(&p5).is_ok());
+        assert!(public.verify(p5.expose()).is_ok());
         assert_eq!(private.pop_password(), State::Empty);
     }
+
+    #[test]
+    fn aes_backend() {
+        type AesBuilder = BlockBuilder<aes::Aes128, 16>;
+
+        let secret = || Secret::new(*b"YELLOW SUBMARINE");
+        let pass = Secret::new([0x42; 16]);
+        let mut private = Aes128SafeBuilder::private_from_password(5, secret(), pass.clone());
+        let mut other = AesBuilder::pebbled_from_password::<4>(5, secret(), pass).unwrap();
+        loop {
+            assert_eq!(other.get_password(), private.get_password());
+            let state = private.pop_password();
+            assert_eq!(other.pop_password(), state);
+            if state == State::Empty {
+                break;
+            }
+        }
+
+        let mut rng = rand::thread_rng();
+        let mut private = AesBuilder::new_pebbled::<_, 4>(5, secret(), &mut rng).unwrap();
+        let p0 = private.get_password().unwrap();
+        assert_eq!(private.pop_password(), State::Ok);
+        let mut public = Aes128SafeBuilder::new_public(secret(), *p0.expose());
+        assert!(public
+            .verify(private.get_password().unwrap().expose())
+            .is_ok());
+    }
 }
@@ -1,23 +1,26 @@
 use crypto::{
     aes::KeySize::KeySize128, aesni::AesNiEncryptor, aessafe, symmetriccipher::BlockEncryptor,
 };
-use rand::Rng;
 
+use crate::secret::Secret;
+
+#[derive(Default)]
 pub struct Aes128SafeEncryptor;
+#[derive(Default)]
 pub struct Aes128NiEncryptor;
 
 impl super::Commitment for Aes128SafeEncryptor {
     type PublicElement = [u8; 16];
-    type PrivateElement = [u8; 16];
+    type PrivateElement = Secret<16>;
 
     fn generate(&self) -> Self::PrivateElement {
-        rand::thread_rng().gen()
+        Secret::random()
     }
 
     fn commit(&self, el: &Self::PrivateElement) -> Self::PublicElement {
         let zeroes = [0; 16];
         let mut out = [0; 16];
-        let cipher = aessafe::AesSafe128Encryptor::new(el);
+        let cipher = aessafe::AesSafe128Encryptor::new(&el[..]);
         cipher.encrypt_block(&zeroes, &mut out);
         out
     }
@@ -30,16 +33,16 @@ impl super::Commitment for Aes128SafeEncryptor {
 
 impl super::Commitment for Aes128NiEncryptor {
     type PublicElement = [u8; 16];
-    type PrivateElement = [u8; 16];
+    type PrivateElement = Secret<16>;
 
     fn generate(&self) -> Self::PrivateElement {
-        rand::thread_rng().gen()
+        Secret::random()
     }
 
     fn commit(&self, el: &Self::PrivateElement) -> Self::PublicElement {
         let zeroes = [0; 16];
         let mut out = [0; 16];
-        let cipher = AesNiEncryptor::new(KeySize128, el);
+        let cipher = AesNiEncryptor::new(KeySize128, &el[..]);
         cipher.encrypt_block(&zeroes, &mut out);
         out
     }
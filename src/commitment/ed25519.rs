@@ -1,13 +1,23 @@
+use std::fmt;
+
 use ed25519_dalek::{PublicKey, SecretKey};
 
+// `SecretKey` wipes itself on drop
 pub struct CloneableSecretKey(pub SecretKey);
 
+impl fmt::Debug for CloneableSecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CloneableSecretKey(REDACTED)")
+    }
+}
+
 impl Clone for CloneableSecretKey {
     fn clone(&self) -> Self {
         Self(SecretKey::from_bytes(self.0.as_bytes()).expect("should be valid"))
     }
 }
 
+#[derive(Default)]
 pub struct Ed25519;
 
 impl super::Commitment for Ed25519 {
@@ -1,19 +1,21 @@
 use crypto::{digest::Digest, sha2};
-use rand::Rng;
 
+use crate::secret::Secret;
+
+#[derive(Default)]
 pub struct Sha256;
 
 impl super::Commitment for Sha256 {
     type PublicElement = [u8; 32];
-    type PrivateElement = [u8; 32];
+    type PrivateElement = Secret<32>;
 
     fn generate(&self) -> Self::PrivateElement {
-        rand::thread_rng().gen()
+        Secret::random()
     }
 
     fn commit(&self, el: &Self::PrivateElement) -> Self::PublicElement {
         let mut digest = sha2::Sha256::new();
-        digest.input(el);
+        digest.input(&el[..]);
         let mut out = [0; 32];
         digest.result(&mut out);
         out
@@ -29,6 +29,10 @@ impl<C: Commitment> PrivateKey<C> {
         Self { commit, private }
     }
 
+    pub fn from_private(commit: C, private: C::PrivateElement) -> Self {
+        Self { commit, private }
+    }
+
     pub fn public(&self) -> C::PublicElement {
         self.commit.commit(&self.private)
     }
@@ -0,0 +1,483 @@
+use std::fmt;
+
+use crate::{
+    encoding::{from_hex, to_hex},
+    policy::{Clock, Policy, PolicyError},
+    store::{Algorithm, Record, Store, StoreError},
+};
+
+// Line protocol of the authentication daemon. Every request and response is
+// a single line of space separated fields, binary values are hex:
+//
+//   ENROLL <user> <algorithm> <anchor> [<secret>]   -> OK
+//   CHALLENGE <user>                                -> OK <algorithm> <round>
+//   VERIFY <user> <password> [<next>]               -> OK
+//   RENEW <user> <password> <algorithm> <anchor> [<secret>] -> OK
+//   STATUS <user>                -> OK <algorithm> <round> <failures> <locked until>
+//
+// Failures are answered with `ERR <reason> [<time>]`. The enrollment fields
+// are what `otp anchor` prints.
+
+// Longer lines are dropped without being parsed
+pub const MAX_LINE: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Enroll {
+        user: String,
+        record: Record,
+    },
+    Challenge {
+        user: String,
+    },
+    // `next` is the next public element for commitment schemes
+    Verify {
+        user: String,
+        password: Vec<u8>,
+        next: Option<Vec<u8>>,
+    },
+    // Replaces the verifier of a user, e.g. when a chain runs out, after
+    // checking a password of the current one
+    Renew {
+        user: String,
+        password: Vec<u8>,
+        record: Record,
+    },
+    Status {
+        user: String,
+    },
+}
+
+// Parses `<algorithm> <anchor> [<secret>]` into a fresh record
+pub fn parse_record(fields: &[&str]) -> Option<Record> {
+    let (algorithm, anchor, secret) = match fields {
+        [algorithm, anchor] => (algorithm, anchor, None),
+        [algorithm, anchor, secret] => (algorithm, anchor, Some(secret)),
+        _ => return None,
+    };
+    let algorithm = Algorithm::from_name(algorithm)?;
+    let anchor = from_hex(anchor)?;
+    let secret = secret.map_or(Some(vec![]), |s| from_hex(s))?;
+    let secret_size = if algorithm == Algorithm::Aes128 {
+        16
+    } else {
+        0
+    };
+    if anchor.len() != algorithm.size() || secret.len() != secret_size {
+        return None;
+    }
+    Some(Record::new(algorithm, anchor, secret))
+}
+
+fn format_record(record: &Record) -> String {
+    let mut out = format!("{} {}", record.algorithm, to_hex(&record.anchor));
+    if !record.secret.is_empty() {
+        out.push(' ');
+        out.push_str(&to_hex(&record.secret));
+    }
+    out
+}
+
+impl Request {
+    pub fn parse(line: &str) -> Option<Self> {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (command, user, rest) = match fields.as_slice() {
+            [command, user, rest @ ..] => (*command, (*user).to_owned(), rest),
+            _ => return None,
+        };
+        // Users are framed with a u16 length in stores and tokens
+        if user.len() > usize::from(u16::MAX) {
+            return None;
+        }
+        match (command, rest) {
+            ("ENROLL", rest) => Some(Request::Enroll {
+                user,
+                record: parse_record(rest)?,
+            }),
+            ("CHALLENGE", []) => Some(Request::Challenge { user }),
+            ("VERIFY", [password]) => Some(Request::Verify {
+                user,
+                password: from_hex(password)?,
+                next: None,
+            }),
+            ("VERIFY", [password, next]) => Some(Request::Verify {
+                user,
+                password: from_hex(password)?,
+                next: Some(from_hex(next)?),
+            }),
+            ("RENEW", [password, rest @ ..]) => Some(Request::Renew {
+                user,
+                password: from_hex(password)?,
+                record: parse_record(rest)?,
+            }),
+            ("STATUS", []) => Some(Request::Status { user }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Request::Enroll { user, record } => {
+                write!(f, "ENROLL {} {}", user, format_record(record))
+            }
+            Request::Challenge { user } => write!(f, "CHALLENGE {}", user),
+            Request::Verify {
+                user,
+                password,
+                next,
+            } => {
+                write!(f, "VERIFY {} {}", user, to_hex(password))?;
+                match next {
+                    Some(next) => write!(f, " {}", to_hex(next)),
+                    None => Ok(()),
+                }
+            }
+            Request::Renew {
+                user,
+                password,
+                record,
+            } => write!(
+                f,
+                "RENEW {} {} {}",
+                user,
+                to_hex(password),
+                format_record(record)
+            ),
+            Request::Status { user } => write!(f, "STATUS {}", user),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Malformed,
+    NotFound,
+    Exists,
+    Auth,
+    Locked { until: u64 },
+    Backoff { retry_at: u64 },
+    // Details stay in the daemon's log
+    Internal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    Challenge {
+        algorithm: Algorithm,
+        round: u64,
+    },
+    Status {
+        algorithm: Algorithm,
+        round: u64,
+        failures: u32,
+        locked_until: u64,
+    },
+    Error(Failure),
+}
+
+impl Response {
+    pub fn parse(line: &str) -> Option<Self> {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let number = |s: &str| s.parse::<u64>().ok();
+        Some(match fields.as_slice() {
+            ["OK"] => Response::Ok,
+            ["OK", algorithm, round] => Response::Challenge {
+                algorithm: Algorithm::from_name(algorithm)?,
+                round: number(round)?,
+            },
+            ["OK", algorithm, round, failures, locked_until] => Response::Status {
+                algorithm: Algorithm::from_name(algorithm)?,
+                round: number(round)?,
+                failures: failures.parse().ok()?,
+                locked_until: number(locked_until)?,
+            },
+            ["ERR", "malformed"] => Response::Error(Failure::Malformed),
+            ["ERR", "not-found"] => Response::Error(Failure::NotFound),
+            ["ERR", "exists"] => Response::Error(Failure::Exists),
+            ["ERR", "auth"] => Response::Error(Failure::Auth),
+            ["ERR", "locked", until] => Response::Error(Failure::Locked {
+                until: number(until)?,
+            }),
+            ["ERR", "backoff", retry_at] => Response::Error(Failure::Backoff {
+                retry_at: number(retry_at)?,
+            }),
+            ["ERR", "internal"] => Response::Error(Failure::Internal),
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Ok => f.write_str("OK"),
+            Response::Challenge { algorithm, round } => write!(f, "OK {} {}", algorithm, round),
+            Response::Status {
+                algorithm,
+                round,
+                failures,
+                locked_until,
+            } => write!(
+                f,
+                "OK {} {} {} {}",
+                algorithm, round, failures, locked_until
+            ),
+            Response::Error(Failure::Malformed) => f.write_str("ERR malformed"),
+            Response::Error(Failure::NotFound) => f.write_str("ERR not-found"),
+            Response::Error(Failure::Exists) => f.write_str("ERR exists"),
+            Response::Error(Failure::Auth) => f.write_str("ERR auth"),
+            Response::Error(Failure::Locked { until }) => write!(f, "ERR locked {}", until),
+            Response::Error(Failure::Backoff { retry_at }) => {
+                write!(f, "ERR backoff {}", retry_at)
+            }
+            Response::Error(Failure::Internal) => f.write_str("ERR internal"),
+        }
+    }
+}
+
+// Splits store errors into answers for the client and faults of the daemon
+fn answer(err: StoreError) -> Result<Response, StoreError> {
+    match err {
+        StoreError::NotFound => Ok(Response::Error(Failure::NotFound)),
+        StoreError::Exists => Ok(Response::Error(Failure::Exists)),
+        StoreError::Auth(_) => Ok(Response::Error(Failure::Auth)),
+        err => Err(err),
+    }
+}
+
+fn answer_policy(err: PolicyError) -> Result<Response, StoreError> {
+    match err {
+        PolicyError::Locked { until } => Ok(Response::Error(Failure::Locked { until })),
+        PolicyError::Backoff { retry_at } => Ok(Response::Error(Failure::Backoff { retry_at })),
+        PolicyError::Store(err) => answer(err),
+    }
+}
+
+pub struct Server<S: Store, C: Clock> {
+    store: S,
+    policy: Policy<C>,
+}
+
+impl<S: Store, C: Clock> Server<S, C> {
+    pub fn new(store: S, policy: Policy<C>) -> Self {
+        Self { store, policy }
+    }
+
+    #[must_use]
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    // Errors are faults of the store, everything the client caused is a
+    // `Response::Error`
+    pub fn handle(&mut self, request: &Request) -> Result<Response, StoreError> {
+        match request {
+            Request::Enroll { user, record } => match self.store.enroll(user, record.clone()) {
+                Ok(()) => Ok(Response::Ok),
+                Err(err) => answer(err),
+            },
+            Request::Challenge { user } => match self.store.get(user)? {
+                Some(record) => Ok(Response::Challenge {
+                    algorithm: record.algorithm,
+                    round: record.round,
+                }),
+                None => Ok(Response::Error(Failure::NotFound)),
+            },
+            Request::Verify {
+                user,
+                password,
+                next,
+            } => match self
+                .policy
+                .accept(&mut self.store, user, password, next.as_deref())
+            {
+                Ok(()) => Ok(Response::Ok),
+                Err(err) => answer_policy(err),
+            },
+            Request::Renew {
+                user,
+                password,
+                record,
+            } => {
+                let current = match self.store.get(user)? {
+                    Some(current) => current,
+                    None => return Ok(Response::Error(Failure::NotFound)),
+                };
+                // The password still has to advance the old verifier, so that
+                // failures are counted. A commitment wants a next element,
+                // the current one will do as the record is replaced anyway.
+                let next = (!current.algorithm.is_chain()).then_some(current.anchor);
+                if let Err(err) =
+                    self.policy
+                        .accept(&mut self.store, user, password, next.as_deref())
+                {
+                    return answer_policy(err);
+                }
+                self.store.put(user, record.clone())?;
+                Ok(Response::Ok)
+            }
+            Request::Status { user } => match self.store.get(user)? {
+                Some(record) => Ok(Response::Status {
+                    algorithm: record.algorithm,
+                    round: record.round,
+                    failures: record.attempts.failures,
+                    locked_until: record.attempts.locked_until,
+                }),
+                None => Ok(Response::Error(Failure::NotFound)),
+            },
+        }
+    }
+
+    // Answers a raw request line
+    pub fn handle_line(&mut self, line: &str) -> Result<Response, StoreError> {
+        match Request::parse(line) {
+            Some(request) => self.handle(&request),
+            None => Ok(Response::Error(Failure::Malformed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        base::State,
+        commitment::{hash::Sha256 as CommitSha256, PrivateKey},
+        policy::{ManualClock, PolicyConfig},
+        store::MemoryStore,
+    };
+
+    fn server(clock: &ManualClock) -> Server<MemoryStore, &ManualClock> {
+        Server::new(
+            MemoryStore::new(),
+            Policy::new(PolicyConfig::default(), clock),
+        )
+    }
+
+    #[test]
+    fn requests_roundtrip() {
+        let requests = [
+            "ENROLL alice sha256 ".to_owned() + &"ab".repeat(32),
+            "ENROLL bob aes128 ".to_owned() + &"01".repeat(16) + " " + &"02".repeat(16),
+            "CHALLENGE alice".to_owned(),
+            "VERIFY alice 00ff".to_owned(),
+            "VERIFY alice 00ff 1234".to_owned(),
+            "RENEW alice 00ff sha256 ".to_owned() + &"cd".repeat(32),
+            "STATUS alice".to_owned(),
+        ];
+        for line in requests {
+            assert_eq!(Request::parse(&line).unwrap().to_string(), line);
+        }
+
+        let malformed = [
+            "",
+            "STATUS",
+            "STATUS alice bob",
+            "ENROLL alice sha256 abcd",
+            "ENROLL alice aes128 0101",
+            "ENROLL alice sha384 00",
+            "VERIFY alice xyz",
+            "PING alice",
+        ];
+        for line in malformed {
+            assert!(Request::parse(line).is_none(), "{}", line);
+        }
+
+        for line in [
+            "OK",
+            "OK sha256 3",
+            "OK aes128 3 2 100",
+            "ERR locked 7",
+            "ERR auth",
+        ] {
+            assert_eq!(Response::parse(line).unwrap().to_string(), line);
+        }
+    }
+
+    #[test]
+    fn lifecycle() {
+        let clock = ManualClock::new(1000);
+        let mut server = server(&clock);
+        let (mut private, record) = Record::generate_sha256(3);
+
+        let enroll = format!("ENROLL alice sha256 {}", to_hex(&record.anchor[..]));
+        assert_eq!(server.handle_line(&enroll).unwrap(), Response::Ok);
+        assert_eq!(
+            server.handle_line(&enroll).unwrap(),
+            Response::Error(Failure::Exists)
+        );
+        assert_eq!(
+            server.handle_line("CHALLENGE alice").unwrap(),
+            Response::Challenge {
+                algorithm: Algorithm::Sha256,
+                round: 1
+            }
+        );
+
+        let p1 = private.get_password().unwrap();
+        let verify = format!("VERIFY alice {}", to_hex(&p1[..]));
+        assert_eq!(server.handle_line(&verify).unwrap(), Response::Ok);
+        assert_eq!(
+            server.handle_line(&verify).unwrap(),
+            Response::Error(Failure::Auth)
+        );
+        assert_eq!(
+            server.handle_line(&verify).unwrap(),
+            Response::Error(Failure::Backoff { retry_at: 1001 })
+        );
+        assert_eq!(
+            server.handle_line("STATUS alice").unwrap(),
+            Response::Status {
+                algorithm: Algorithm::Sha256,
+                round: 2,
+                failures: 1,
+                locked_until: 0
+            }
+        );
+
+        // Switch to a commitment when the chain runs low
+        clock.advance(10);
+        assert_eq!(private.pop_password(), State::Ok);
+        let p2 = private.get_password().unwrap();
+        let mut commitment = PrivateKey::new(CommitSha256);
+        let renew = format!(
+            "RENEW alice {} commit-sha256 {}",
+            to_hex(&p2[..]),
+            to_hex(&commitment.public())
+        );
+        assert_eq!(server.handle_line(&renew).unwrap(), Response::Ok);
+        assert_eq!(
+            server.handle_line(&renew).unwrap(),
+            Response::Error(Failure::Auth)
+        );
+
+        clock.advance(10);
+        let reveal = commitment.private();
+        commitment.advance();
+        let verify = format!(
+            "VERIFY alice {} {}",
+            to_hex(&reveal[..]),
+            to_hex(&commitment.public())
+        );
+        assert_eq!(server.handle_line(&verify).unwrap(), Response::Ok);
+        assert_eq!(
+            server.handle_line("CHALLENGE alice").unwrap(),
+            Response::Challenge {
+                algorithm: Algorithm::CommitSha256,
+                round: 1
+            }
+        );
+
+        assert_eq!(
+            server.handle_line("STATUS bob").unwrap(),
+            Response::Error(Failure::NotFound)
+        );
+        assert_eq!(
+            server.handle_line("STATUS").unwrap(),
+            Response::Error(Failure::Malformed)
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+
+// Printable forms of passwords for people typing them in: hex, one word per
+// byte, or a fixed width decimal number. Parsing ignores case and
+// whitespace, and words can be cut down to their first four letters, which
+// are unique.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Hex,
+    Words,
+    Decimal,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::Hex, Format::Words, Format::Decimal];
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Hex => "hex",
+            Format::Words => "words",
+            Format::Decimal => "decimal",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.name() == name)
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+const PREFIX: usize = 4;
+const GROUP: usize = 5;
+
+pub const WORDS: [&str; 256] = [
+    "acid", "actor", "adult", "agent", "album", "alley", "amber", "angle", "ankle", "apple",
+    "arena", "audio", "award", "badge", "bagel", "baker", "banjo", "barn", "basil", "batch",
+    "beach", "beard", "bench", "berry", "blade", "blank", "blaze", "block", "bloom", "board",
+    "boat", "bonus", "boost", "booth", "bread", "brick", "bride", "brush", "bucket", "buddy",
+    "cabin", "cable", "cactus", "camel", "candy", "canoe", "carpet", "castle", "cedar", "chalk",
+    "chess", "chili", "cider", "cigar", "cliff", "cloud", "clown", "coach", "cobra", "cocoa",
+    "comet", "coral", "couch", "crane", "crate", "crown", "cube", "curry", "daisy", "dance",
+    "delta", "denim", "depot", "desk", "diary", "diner", "disco", "dock", "dolphin", "donut",
+    "dove", "dragon", "drum", "duck", "dune", "eagle", "easel", "echo", "elbow", "elder", "ember",
+    "engine", "epoch", "fable", "fairy", "falcon", "farm", "feast", "fence", "ferry", "fiber",
+    "field", "flame", "flask", "fleet", "flint", "flute", "forest", "fox", "frost", "garden",
+    "gecko", "genie", "giant", "ginger", "glove", "goat", "gold", "grape", "gravy", "guitar",
+    "habit", "harbor", "hazel", "heart", "hedge", "helmet", "hero", "hippo", "honey", "hotel",
+    "house", "igloo", "index", "ink", "iron", "island", "ivory", "jacket", "jaguar", "jelly",
+    "jewel", "judge", "juice", "jungle", "kayak", "kettle", "kite", "koala", "ladder", "lagoon",
+    "lamp", "laser", "lemon", "lilac", "lion", "llama", "lobby", "lotus", "lunar", "magnet",
+    "mango", "maple", "marble", "meadow", "melon", "mercy", "metal", "mint", "mirror", "monkey",
+    "motor", "music", "nectar", "needle", "nest", "noodle", "north", "nutmeg", "oasis", "olive",
+    "onion", "opera", "orbit", "otter", "oyster", "paddle", "panda", "paper", "parrot", "pasta",
+    "peach", "pearl", "pepper", "piano", "pilot", "pixel", "planet", "plum", "polar", "pony",
+    "prism", "pumpkin", "puzzle", "quartz", "quilt", "rabbit", "radio", "reef", "rhino", "ribbon",
+    "river", "ruby", "saddle", "salad", "salmon", "scarf", "shark", "shell", "silver", "sketch",
+    "sloth", "sonic", "spice", "spider", "spoon", "squid", "stamp", "storm", "sugar", "summit",
+    "table", "tango", "teapot", "tiger", "toast", "topaz", "torch", "tower", "trout", "tulip",
+    "tundra", "turtle", "umbrella", "valley", "velvet", "violin", "vortex", "wagon", "walnut",
+    "walrus", "whale", "window", "wizard", "yacht", "zebra",
+];
+
+pub fn encode(bytes: &[u8], format: Format) -> String {
+    match format {
+        Format::Hex => to_hex(bytes),
+        Format::Words => bytes
+            .iter()
+            .map(|b| WORDS[usize::from(*b)])
+            .collect::<Vec<_>>()
+            .join(" "),
+        Format::Decimal => {
+            let digits = to_decimal(bytes);
+            digits
+                .as_bytes()
+                .chunks(GROUP)
+                .map(|group| std::str::from_utf8(group).unwrap())
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+    }
+}
+
+// `len` is the expected number of bytes
+pub fn decode(s: &str, format: Format, len: usize) -> Option<Vec<u8>> {
+    let bytes = match format {
+        Format::Hex => from_hex(&compact(s))?,
+        Format::Words => s
+            .split_whitespace()
+            .map(|word| word_index(&word.to_ascii_lowercase()))
+            .collect::<Option<Vec<_>>>()?,
+        Format::Decimal => from_decimal(&compact(s), len)?,
+    };
+    (bytes.len() == len).then_some(bytes)
+}
+
+// For typed answers in any format, they are told apart by their alphabet and
+// length
+pub fn decode_any(s: &str, len: usize) -> Option<Vec<u8>> {
+    Format::ALL
+        .into_iter()
+        .find_map(|format| decode(s, format, len))
+}
+
+fn compact(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+fn word_index(word: &str) -> Option<u8> {
+    let found = WORDS
+        .iter()
+        .position(|w| *w == word || (word.len() >= PREFIX && w.starts_with(word)))?;
+    u8::try_from(found).ok()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+// Digits needed for any `len` byte number
+fn decimal_width(len: usize) -> usize {
+    (len as f64 * 8.0 * std::f64::consts::LOG10_2).floor() as usize + 1
+}
+
+fn to_decimal(bytes: &[u8]) -> String {
+    let mut number = bytes.to_vec();
+    let mut digits = vec![];
+    for _ in 0..decimal_width(bytes.len()) {
+        // Long division by 10
+        let mut rem = 0u16;
+        for byte in number.iter_mut() {
+            let cur = (rem << 8) | u16::from(*byte);
+            *byte = (cur / 10) as u8;
+            rem = cur % 10;
+        }
+        digits.push(b'0' + rem as u8);
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+fn from_decimal(s: &str, len: usize) -> Option<Vec<u8>> {
+    let mut number = vec![0u8; len];
+    for c in s.chars() {
+        let mut carry = u16::from(c.to_digit(10)? as u8);
+        for byte in number.iter_mut().rev() {
+            let cur = u16::from(*byte) * 10 + carry;
+            *byte = cur as u8;
+            carry = cur >> 8;
+        }
+        if carry != 0 {
+            return None;
+        }
+    }
+    Some(number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_list() {
+        let mut prefixes = WORDS
+            .iter()
+            .map(|w| &w[..PREFIX.min(w.len())])
+            .collect::<Vec<_>>();
+        prefixes.sort_unstable();
+        prefixes.dedup();
+        assert_eq!(prefixes.len(), WORDS.len());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let inputs = [vec![0; 16], vec![0xff; 16], (0..32).collect::<Vec<u8>>()];
+        for bytes in inputs {
+            for format in Format::ALL {
+                let printed = encode(&bytes, format);
+                assert_eq!(decode(&printed, format, bytes.len()), Some(bytes.clone()));
+                let shouted = printed.to_uppercase();
+                assert_eq!(decode(&shouted, format, bytes.len()), Some(bytes.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(encode(&[0xde, 0xad], Format::Hex), "dead");
+        assert_eq!(encode(&[1, 0], Format::Decimal), "00256");
+        assert_eq!(encode(&[0xff; 2], Format::Decimal), "65535");
+        assert_eq!(decode("65536", Format::Decimal, 2), None);
+        assert_eq!(encode(&[0, 255], Format::Words), "acid zebra");
+        assert_eq!(decode("acid zebr", Format::Words, 2), Some(vec![0, 255]));
+        assert_eq!(decode("aci zebra", Format::Words, 2), None);
+        assert_eq!(decode("dead", Format::Hex, 3), None);
+        // 16 bytes give 39 digits in groups of five
+        assert_eq!(encode(&[7; 16], Format::Decimal).len(), 39 + 7);
+    }
+}
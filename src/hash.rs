@@ -1,17 +1,62 @@
-use std::marker::PhantomData;
+use core::marker::PhantomData;
 
+#[cfg(feature = "std")]
 use crypto::{digest::Digest, sha2::Sha256};
-
-use crate::base::{OneWay, PrivateKey, PublicKey};
-
-pub trait Hash: Digest {
+use rand_core::{CryptoRng, RngCore};
+use sha2::Digest as _;
+
+#[cfg(feature = "std")]
+use crate::base::PrivateKey;
+use crate::{
+    base::{OneWay, PebbledPrivateKey, PublicKey},
+    secret::Secret,
+};
+
+// Same interface as rust-crypto's `Digest`, which doesn't build without std.
+// RustCrypto's `sha2` does and gives the same chains.
+pub trait Hash {
     fn new() -> Self;
+    fn input(&mut self, input: &[u8]);
+    fn result(&mut self, out: &mut [u8]);
+    fn output_bytes(&self) -> usize;
 }
 
+#[cfg(feature = "std")]
 impl Hash for Sha256 {
     fn new() -> Self {
         Sha256::new()
     }
+
+    fn input(&mut self, input: &[u8]) {
+        Digest::input(self, input);
+    }
+
+    fn result(&mut self, out: &mut [u8]) {
+        Digest::result(self, out);
+    }
+
+    fn output_bytes(&self) -> usize {
+        Digest::output_bytes(self)
+    }
+}
+
+impl Hash for sha2::Sha256 {
+    fn new() -> Self {
+        sha2::Digest::new()
+    }
+
+    fn input(&mut self, input: &[u8]) {
+        self.update(input);
+    }
+
+    fn result(&mut self, out: &mut [u8]) {
+        let hash = self.finalize_reset();
+        out[..hash.len()].copy_from_slice(&hash);
+    }
+
+    fn output_bytes(&self) -> usize {
+        <Self as sha2::Digest>::output_size()
+    }
 }
 
 impl<T> OneWay for T
@@ -32,24 +77,47 @@ where
 pub struct HashBuilder<H: Hash, const SIZE: usize>(PhantomData<H>);
 
 impl<H: Hash, const SIZE: usize> HashBuilder<H, SIZE> {
+    #[cfg(feature = "std")]
     pub fn new_private(rounds: usize) -> PrivateKey<H, SIZE> {
         PrivateKey::new(H::new(), rounds)
     }
 
-    pub fn private_from_password(rounds: usize, pass: [u8; SIZE]) -> PrivateKey<H, SIZE> {
+    #[cfg(feature = "std")]
+    pub fn private_from_password(rounds: usize, pass: Secret<SIZE>) -> PrivateKey<H, SIZE> {
         PrivateKey::from_password(H::new(), rounds, pass)
     }
 
+    pub fn new_pebbled<R: RngCore + CryptoRng, const CAP: usize>(
+        rounds: usize,
+        rng: &mut R,
+    ) -> Option<PebbledPrivateKey<H, SIZE, CAP>> {
+        PebbledPrivateKey::new(H::new(), rounds, rng)
+    }
+
+    pub fn pebbled_from_password<const CAP: usize>(
+        rounds: usize,
+        pass: Secret<SIZE>,
+    ) -> Option<PebbledPrivateKey<H, SIZE, CAP>> {
+        PebbledPrivateKey::from_password(H::new(), rounds, pass)
+    }
+
     pub fn new_public(password: [u8; SIZE]) -> PublicKey<H, SIZE> {
         PublicKey::new(H::new(), password)
     }
 }
 
+#[cfg(feature = "std")]
 pub type Sha256Builder = HashBuilder<Sha256, 32>;
+#[cfg(not(feature = "std"))]
+pub type Sha256Builder = HashBuilder<sha2::Sha256, 32>;
 
 #[cfg(test)]
 mod tests {
-    use crate::{base::State, hash::Sha256Builder};
+    use crate::{
+        base::{pebbles, State},
+        hash::{HashBuilder, Sha256Builder},
+        secret::Secret,
+    };
 
     #[test]
     fn normal_protocol() {
@@ -58,26 +126,108 @@ mod tests {
         let p0 = private.get_password().unwrap();
         assert_eq!(private.pop_password(), State::Ok);
 
-        let mut public = Sha256Builder::new_public(p0);
+        let mut public = Sha256Builder::new_public(*p0.expose());
 
         let p1 = private.get_password().unwrap();
-        assert!(public.verify(&p1).is_ok());
+        assert!(public.verify(p1.expose()).is_ok());
         assert_eq!(private.pop_password(), State::Ok);
 
         let p2 = private.get_password().unwrap();
-        assert!(public.verify(&p2).is_ok());
+        assert!(public.verify(p2.expose()).is_ok());
         assert_eq!(private.pop_password(), State::Ok);
 
         let p3 = private.get_password().unwrap();
-        assert!(public.verify(&p3).is_ok());
+        assert!(public.verify(p3.expose()).is_ok());
         assert_eq!(private.pop_password(), State::Ok);
 
         let p4 = private.get_password().unwrap();
-        assert!(public.verify(&p4).is_ok());
+        assert!(public.verify(p4.expose()).is_ok());
         assert_eq!(private.pop_password(), State::Ok);
 
         let p5 = private.get_password().unwrap();
-        assert!(public.verify(&p5).is_ok());
+        assert!(public.verify(p5.expose()).is_ok());
         assert_eq!(private.pop_password(), State::Empty);
     }
+
+    #[test]
+    fn skip_rounds() {
+        let mut private = Sha256Builder::new_private(5);
+
+        let p0 = private.get_password().unwrap();
+        assert_eq!(private.pop_password(), State::Ok);
+        let mut public = Sha256Builder::new_public(*p0.expose());
+
+        assert_eq!(private.pop_password(), State::Ok);
+        assert_eq!(private.pop_password(), State::Ok);
+        let p3 = private.get_password().unwrap();
+        assert!(public.verify_skip(p3.expose(), 1).is_err());
+        assert!(public.verify(p3.expose()).is_err());
+        assert!(public.verify_skip(p3.expose(), 2).is_ok());
+        assert_eq!(public.round(), 4);
+        assert_eq!(private.pop_password(), State::Ok);
+
+        let p4 = private.get_password().unwrap();
+        assert!(public.verify_skip(p4.expose(), 0).is_ok());
+    }
+
+    #[test]
+    fn pebbled_matches_full_chain() {
+        for rounds in [0, 1, 2, 3, 7, 8, 100, 257] {
+            let pass = Secret::new([rounds as u8; 32]);
+            let mut private = Sha256Builder::private_from_password(rounds, pass.clone());
+            let mut pebbled = Sha256Builder::pebbled_from_password::<10>(rounds, pass).unwrap();
+            loop {
+                assert_eq!(pebbled.round(), private.round());
+                assert_eq!(pebbled.get_password(), private.get_password());
+                let state = private.pop_password();
+                assert_eq!(pebbled.pop_password(), state);
+                if state == State::Empty {
+                    break;
+                }
+            }
+            assert_eq!(pebbled.get_password(), None);
+            assert_eq!(pebbled.pop_password(), State::Empty);
+        }
+    }
+
+    #[test]
+    fn pebbled_capacity() {
+        assert_eq!(pebbles(0), 1);
+        assert_eq!(pebbles(1), 2);
+        assert_eq!(pebbles(255), 9);
+        assert_eq!(pebbles(256), 10);
+
+        let pass = || Secret::new([0; 32]);
+        assert!(Sha256Builder::pebbled_from_password::<9>(256, pass()).is_none());
+        let mut private = Sha256Builder::pebbled_from_password::<9>(255, pass()).unwrap();
+        while private.pop_password() == State::Ok {}
+        assert_eq!(private.round(), 0);
+
+        let mut rng = rand::thread_rng();
+        let mut private = Sha256Builder::new_pebbled::<_, 5>(10, &mut rng).unwrap();
+        let p0 = private.get_password().unwrap();
+        assert_eq!(private.pop_password(), State::Ok);
+        let mut public = Sha256Builder::new_public(*p0.expose());
+        assert!(public
+            .verify(private.get_password().unwrap().expose())
+            .is_ok());
+    }
+
+    #[test]
+    fn sha2_backend() {
+        type Sha2Builder = HashBuilder<sha2::Sha256, 32>;
+
+        let pass = Secret::new([0x42; 32]);
+        let mut private = Sha256Builder::private_from_password(5, pass.clone());
+        let mut other = Sha2Builder::pebbled_from_password::<4>(5, pass).unwrap();
+        let p0 = other.get_password().unwrap();
+        assert_eq!(p0, private.get_password().unwrap());
+        assert_eq!(other.pop_password(), State::Ok);
+        assert_eq!(private.pop_password(), State::Ok);
+
+        let mut public = Sha2Builder::new_public(*p0.expose());
+        assert!(public
+            .verify(private.get_password().unwrap().expose())
+            .is_ok());
+    }
 }
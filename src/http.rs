@@ -0,0 +1,554 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write as _},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+};
+
+use crate::{
+    daemon::{parse_record, Failure, Request, Response, Server, MAX_LINE},
+    encoding::from_hex,
+    policy::Clock,
+    store::{Store, StoreError},
+};
+
+// HTTP/1.1 front end of `daemon::Server`. Every endpoint takes a JSON object
+// and answers with one, binary values are hex as in the line protocol:
+//
+//   POST /enroll     {"user", "algorithm", "anchor", "secret"?} -> {"ok": true}
+//   POST /challenge  {"user"}                 -> {"algorithm", "round"}
+//   POST /verify     {"user", "password", "next"?}              -> {"ok": true}
+//   POST /status     {"user"}   -> {"algorithm", "round", "failures", "locked_until"}
+//
+// Failures come with a 4xx or 5xx status and {"error": <reason>}, where the
+// reasons are those of the line protocol. Only what these bodies need of
+// JSON and HTTP is implemented: flat objects, Content-Length bodies and
+// keep-alive. There is no authentication of callers, so listen on loopback.
+
+const MAX_HEADERS: usize = 8192;
+
+// Problems that cost a request or a connection but not the server, handed to
+// the caller of `serve` to log
+#[derive(Debug)]
+pub enum ServeError {
+    Accept(io::Error),
+    Connection(io::Error),
+    Store(StoreError),
+}
+
+impl fmt::Display for ServeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServeError::Accept(err) => write!(f, "accept: {}", err),
+            ServeError::Connection(err) => write!(f, "connection: {}", err),
+            ServeError::Store(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ServeError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+}
+
+struct Parser<'a>(&'a [u8]);
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let [b' ' | b'\t' | b'\n' | b'\r', rest @ ..] = self.0 {
+            self.0 = rest;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> Option<()> {
+        self.skip_whitespace();
+        let (first, rest) = self.0.split_first()?;
+        (*first == byte).then(|| self.0 = rest)
+    }
+
+    fn literal(&mut self, word: &[u8]) -> Option<()> {
+        self.0 = self.0.strip_prefix(word)?;
+        Some(())
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = std::str::from_utf8(self.0.get(..4)?).ok()?;
+        self.0 = &self.0[4..];
+        u32::from_str_radix(digits, 16).ok()
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.eat(b'"')?;
+        let mut out = vec![];
+        loop {
+            let (byte, rest) = self.0.split_first()?;
+            self.0 = rest;
+            match byte {
+                b'"' => return String::from_utf8(out).ok(),
+                b'\\' => {
+                    let (escape, rest) = self.0.split_first()?;
+                    self.0 = rest;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let high = self.hex4()?;
+                            let code = if (0xd800..0xdc00).contains(&high) {
+                                self.literal(b"\\u")?;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return None;
+                                }
+                                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                            } else {
+                                high
+                            };
+                            char::from_u32(code)?
+                        }
+                        _ => return None,
+                    };
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                0..=0x1f => return None,
+                _ => out.push(*byte),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_whitespace();
+        match self.0.first()? {
+            b'"' => self.string().map(Json::String),
+            b'n' => self.literal(b"null").map(|_| Json::Null),
+            b't' => self.literal(b"true").map(|_| Json::Bool(true)),
+            b'f' => self.literal(b"false").map(|_| Json::Bool(false)),
+            b'0'..=b'9' => {
+                let len = self.0.iter().take_while(|b| b.is_ascii_digit()).count();
+                let number = std::str::from_utf8(&self.0[..len]).ok()?.parse().ok()?;
+                self.0 = &self.0[len..];
+                Some(Json::Number(number))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Parses an object of strings, unsigned integers, booleans and nulls
+pub fn parse_object(text: &[u8]) -> Option<BTreeMap<String, Json>> {
+    let mut parser = Parser(text);
+    let mut object = BTreeMap::new();
+    parser.eat(b'{')?;
+    if parser.eat(b'}').is_none() {
+        loop {
+            let key = parser.string()?;
+            parser.eat(b':')?;
+            if object.insert(key, parser.value()?).is_some() {
+                return None;
+            }
+            if parser.eat(b'}').is_some() {
+                break;
+            }
+            parser.eat(b',')?;
+        }
+    }
+    parser.skip_whitespace();
+    parser.0.is_empty().then_some(object)
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if u32::from(c) < 0x20 => write!(out, "\\u{:04x}", u32::from(c)).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn object(fields: &[(&str, Json)]) -> String {
+    let fields = fields
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Json::Null => "null".to_owned(),
+                Json::Bool(b) => b.to_string(),
+                Json::Number(n) => n.to_string(),
+                Json::String(s) => quote(s),
+            };
+            format!("{}:{}", quote(key), value)
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(","))
+}
+
+// Turns an endpoint and its body into a request of the line protocol
+pub fn request(path: &str, body: &[u8]) -> Option<Request> {
+    let fields = parse_object(body)?;
+    let string = |key: &str| match fields.get(key) {
+        Some(Json::String(s)) => Some(s.as_str()),
+        _ => None,
+    };
+    let optional = |key: &str| match fields.get(key) {
+        None | Some(Json::Null) => Some(None),
+        Some(Json::String(s)) => Some(Some(s.as_str())),
+        _ => None,
+    };
+    let user = string("user")?.to_owned();
+    // Same users as the line protocol can name
+    if user.is_empty() || user.contains(char::is_whitespace) || user.len() > usize::from(u16::MAX) {
+        return None;
+    }
+    match path {
+        "/enroll" => {
+            let mut record = vec![string("algorithm")?, string("anchor")?];
+            record.extend(optional("secret")?);
+            Some(Request::Enroll {
+                user,
+                record: parse_record(&record)?,
+            })
+        }
+        "/challenge" => Some(Request::Challenge { user }),
+        "/verify" => Some(Request::Verify {
+            user,
+            password: from_hex(string("password")?)?,
+            next: optional("next")?
+                .map(from_hex)
+                .map_or(Some(None), |next| next.map(Some))?,
+        }),
+        "/status" => Some(Request::Status { user }),
+        _ => None,
+    }
+}
+
+// Status line and body of a response
+pub fn response(response: &Response) -> (u16, String) {
+    let error = |status, reason: &str| {
+        (
+            status,
+            object(&[("error", Json::String(reason.to_owned()))]),
+        )
+    };
+    match *response {
+        Response::Ok => (200, object(&[("ok", Json::Bool(true))])),
+        Response::Challenge { algorithm, round } => (
+            200,
+            object(&[
+                ("algorithm", Json::String(algorithm.name().to_owned())),
+                ("round", Json::Number(round)),
+            ]),
+        ),
+        Response::Status {
+            algorithm,
+            round,
+            failures,
+            locked_until,
+        } => (
+            200,
+            object(&[
+                ("algorithm", Json::String(algorithm.name().to_owned())),
+                ("round", Json::Number(round)),
+                ("failures", Json::Number(failures.into())),
+                ("locked_until", Json::Number(locked_until)),
+            ]),
+        ),
+        Response::Error(Failure::Malformed) => error(400, "malformed"),
+        Response::Error(Failure::Auth) => error(401, "auth"),
+        Response::Error(Failure::NotFound) => error(404, "not-found"),
+        Response::Error(Failure::Exists) => error(409, "exists"),
+        Response::Error(Failure::Locked { until }) => (
+            423,
+            object(&[
+                ("error", Json::String("locked".to_owned())),
+                ("until", Json::Number(until)),
+            ]),
+        ),
+        Response::Error(Failure::Backoff { retry_at }) => (
+            429,
+            object(&[
+                ("error", Json::String("backoff".to_owned())),
+                ("retry_at", Json::Number(retry_at)),
+            ]),
+        ),
+        Response::Error(Failure::Internal) => error(500, "internal"),
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        423 => "Locked",
+        429 => "Too Many Requests",
+        _ => "Internal Server Error",
+    }
+}
+
+fn write_response(stream: &mut impl Write, status: u16, body: &str, close: bool) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        if close { "Connection: close\r\n" } else { "" },
+        body
+    )?;
+    stream.flush()
+}
+
+struct Head {
+    method: String,
+    path: String,
+    content_length: usize,
+    close: bool,
+}
+
+// Reads the request line and headers, `None` at the end of the stream
+fn read_head(reader: &mut impl BufRead) -> io::Result<Option<Result<Head, u16>>> {
+    let mut head = String::new();
+    loop {
+        let limit =
+            u64::try_from(MAX_HEADERS - head.len()).expect("sorry, architecture is not supported");
+        let read = reader.take(limit).read_line(&mut head)?;
+        if read == 0 {
+            return Ok(if head.is_empty() {
+                None
+            } else {
+                Some(Err(400))
+            });
+        }
+        if head == "\r\n" || head == "\n" {
+            // Stray line breaks between requests are allowed
+            head.clear();
+            continue;
+        }
+        if head.ends_with("\r\n\r\n") || head.ends_with("\n\n") {
+            break;
+        }
+        if head.len() >= MAX_HEADERS {
+            return Ok(Some(Err(413)));
+        }
+    }
+
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path, version) = match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some(method), Some(path), Some(version)) => (method, path, version),
+        _ => return Ok(Some(Err(400))),
+    };
+    let mut parsed = Head {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        content_length: 0,
+        close: version != "HTTP/1.1",
+    };
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => return Ok(Some(Err(400))),
+        };
+        match name.as_str() {
+            "content-length" => match value.parse() {
+                Ok(len) => parsed.content_length = len,
+                Err(_) => return Ok(Some(Err(400))),
+            },
+            "connection" => match value.to_ascii_lowercase().as_str() {
+                "close" => parsed.close = true,
+                "keep-alive" => parsed.close = false,
+                _ => {}
+            },
+            // Bodies are small, chunked ones aren't worth supporting
+            "transfer-encoding" => return Ok(Some(Err(400))),
+            _ => {}
+        }
+    }
+    Ok(Some(Ok(parsed)))
+}
+
+// Store errors are answered with a 500 and go to `on_error`
+pub fn connection<S: Store, C: Clock>(
+    stream: TcpStream,
+    server: &Mutex<Server<S, C>>,
+    on_error: &dyn Fn(ServeError),
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let head = match read_head(&mut reader)? {
+            None => return Ok(()),
+            Some(Ok(head)) => head,
+            Some(Err(status)) => {
+                return write_response(
+                    &mut writer,
+                    status,
+                    &object(&[("error", Json::String("malformed".to_owned()))]),
+                    true,
+                );
+            }
+        };
+        if head.content_length > MAX_LINE {
+            let body = object(&[("error", Json::String("malformed".to_owned()))]);
+            return write_response(&mut writer, 413, &body, true);
+        }
+        let mut body = vec![0; head.content_length];
+        reader.read_exact(&mut body)?;
+
+        let (status, body) = if head.method != "POST" {
+            (
+                405,
+                object(&[("error", Json::String("malformed".to_owned()))]),
+            )
+        } else {
+            let answer = match request(&head.path, &body) {
+                Some(request) => server
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .handle(&request)
+                    .unwrap_or_else(|err| {
+                        on_error(ServeError::Store(err));
+                        Response::Error(Failure::Internal)
+                    }),
+                None => Response::Error(Failure::Malformed),
+            };
+            response(&answer)
+        };
+        write_response(&mut writer, status, &body, head.close)?;
+        if head.close {
+            return Ok(());
+        }
+    }
+}
+
+// Serves every connection on its own thread, requests are handled one at a
+// time
+pub fn serve<S, C, E>(listener: &TcpListener, server: Server<S, C>, on_error: E)
+where
+    S: Store + Send + 'static,
+    C: Clock + Send + 'static,
+    E: Fn(ServeError) + Send + Sync + 'static,
+{
+    let server = Arc::new(Mutex::new(server));
+    let on_error = Arc::new(on_error);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                on_error(ServeError::Accept(err));
+                continue;
+            }
+        };
+        let server = Arc::clone(&server);
+        let on_error = Arc::clone(&on_error);
+        thread::spawn(move || {
+            if let Err(err) = connection(stream, &server, &*on_error) {
+                on_error(ServeError::Connection(err));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Algorithm;
+
+    #[test]
+    fn json_objects() {
+        let text =
+            br#" { "user" : "al\"ice\u00e9\ud83d\ude00", "round": 42, "next": null, "ok": true } "#;
+        let fields = parse_object(text).unwrap();
+        assert_eq!(
+            fields["user"],
+            Json::String("al\"ice\u{e9}\u{1f600}".to_owned())
+        );
+        assert_eq!(fields["round"], Json::Number(42));
+        assert_eq!(fields["next"], Json::Null);
+        assert_eq!(fields["ok"], Json::Bool(true));
+        assert_eq!(parse_object(b"{}").unwrap().len(), 0);
+
+        for malformed in [
+            &b"{"[..],
+            b"{\"a\":1,}",
+            b"{\"a\":1}x",
+            b"{\"a\":-1}",
+            b"{\"a\":1,\"a\":2}",
+            b"{\"a\":\"\\ud83d\"}",
+            b"[1]",
+        ] {
+            assert!(parse_object(malformed).is_none());
+        }
+
+        let encoded = object(&[
+            ("error", Json::String("a\"b\n".to_owned())),
+            ("n", Json::Number(1)),
+        ]);
+        assert_eq!(encoded, r#"{"error":"a\"b\u000a","n":1}"#);
+        let decoded = parse_object(encoded.as_bytes()).unwrap();
+        assert_eq!(decoded["error"], Json::String("a\"b\n".to_owned()));
+    }
+
+    #[test]
+    fn endpoints() {
+        let anchor = "ab".repeat(32);
+        let body = format!(
+            r#"{{"user":"alice","algorithm":"sha256","anchor":"{}"}}"#,
+            anchor
+        );
+        assert_eq!(
+            request("/enroll", body.as_bytes()).unwrap().to_string(),
+            format!("ENROLL alice sha256 {}", anchor)
+        );
+        let body = br#"{"user":"alice","password":"00ff","next":null}"#;
+        assert_eq!(
+            request("/verify", body).unwrap().to_string(),
+            "VERIFY alice 00ff"
+        );
+        let body = br#"{"user":"alice","password":"00ff","next":"12"}"#;
+        assert_eq!(
+            request("/verify", body).unwrap().to_string(),
+            "VERIFY alice 00ff 12"
+        );
+        assert!(request("/verify", br#"{"user":"alice","password":"0"}"#).is_none());
+        assert!(request("/status", br#"{"user":"al ice"}"#).is_none());
+        assert!(request("/delete", br#"{"user":"alice"}"#).is_none());
+
+        let challenge = Response::Challenge {
+            algorithm: Algorithm::Aes128,
+            round: 3,
+        };
+        assert_eq!(
+            response(&challenge),
+            (200, r#"{"algorithm":"aes128","round":3}"#.to_owned())
+        );
+        assert_eq!(
+            response(&Response::Error(Failure::Backoff { retry_at: 9 })).0,
+            429
+        );
+    }
+}
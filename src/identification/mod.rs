@@ -0,0 +1,190 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    hash::Hash,
+};
+
+use crate::base::AuthError;
+
+pub mod schnorr;
+
+// Interactive identification: commit -> challenge -> response. Unlike the
+// `commitment` schemes, the static key never leaves the prover, so it can be
+// reused indefinitely.
+pub trait Identification {
+    type SecretKey;
+    type PublicKey;
+    type Nonce;
+    type Commitment: Clone;
+    type Challenge: Copy + Eq + Hash;
+    type Response;
+
+    fn keypair(&self) -> (Self::SecretKey, Self::PublicKey);
+
+    fn commit(&self) -> (Self::Nonce, Self::Commitment);
+
+    fn challenge(&self) -> Self::Challenge;
+
+    // Fiat-Shamir challenge bound to the session context
+    fn derive_challenge(
+        &self,
+        public: &Self::PublicKey,
+        commitment: &Self::Commitment,
+        context: &[u8],
+    ) -> Self::Challenge;
+
+    fn respond(
+        &self,
+        secret: &Self::SecretKey,
+        nonce: Self::Nonce,
+        challenge: &Self::Challenge,
+    ) -> Self::Response;
+
+    fn verify(
+        &self,
+        public: &Self::PublicKey,
+        commitment: &Self::Commitment,
+        challenge: &Self::Challenge,
+        response: &Self::Response,
+    ) -> bool;
+}
+
+pub struct Proof<I: Identification> {
+    pub commitment: I::Commitment,
+    pub response: I::Response,
+}
+
+pub struct Prover<I: Identification> {
+    scheme: I,
+    secret: I::SecretKey,
+    public: I::PublicKey,
+}
+
+// The nonce is consumed by `Prover::respond`, so a single commitment can never
+// be answered for two different challenges.
+pub struct ProverSession<I: Identification> {
+    nonce: I::Nonce,
+}
+
+// Challenges remembered by `Verifier::verify_proof` by default
+pub const MAX_SEEN: usize = 1 << 16;
+
+pub struct Verifier<I: Identification> {
+    scheme: I,
+    public: I::PublicKey,
+    // Derived challenges already accepted, oldest first. Past `capacity` the
+    // oldest are forgotten and their proofs accepted again, so contexts
+    // should carry something the caller checks for freshness. Interactive
+    // challenges are fresh random values and aren't tracked.
+    seen: HashSet<I::Challenge>,
+    order: VecDeque<I::Challenge>,
+    capacity: usize,
+}
+
+pub struct VerifierSession<I: Identification> {
+    commitment: I::Commitment,
+    challenge: I::Challenge,
+}
+
+impl<I: Identification> Prover<I> {
+    pub fn new(scheme: I) -> Self {
+        let (secret, public) = scheme.keypair();
+        Self {
+            scheme,
+            secret,
+            public,
+        }
+    }
+
+    pub fn public(&self) -> &I::PublicKey {
+        &self.public
+    }
+
+    pub fn commit(&self) -> (ProverSession<I>, I::Commitment) {
+        let (nonce, commitment) = self.scheme.commit();
+        (ProverSession { nonce }, commitment)
+    }
+
+    pub fn respond(&self, session: ProverSession<I>, challenge: &I::Challenge) -> I::Response {
+        self.scheme.respond(&self.secret, session.nonce, challenge)
+    }
+
+    pub fn prove(&self, context: &[u8]) -> Proof<I> {
+        let (nonce, commitment) = self.scheme.commit();
+        let challenge = self
+            .scheme
+            .derive_challenge(&self.public, &commitment, context);
+        let response = self.scheme.respond(&self.secret, nonce, &challenge);
+        Proof {
+            commitment,
+            response,
+        }
+    }
+}
+
+impl<I: Identification> Verifier<I> {
+    pub fn new(scheme: I, public: I::PublicKey) -> Self {
+        Self::with_capacity(scheme, public, MAX_SEEN)
+    }
+
+    pub fn with_capacity(scheme: I, public: I::PublicKey, capacity: usize) -> Self {
+        Self {
+            scheme,
+            public,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn challenge(&self, commitment: I::Commitment) -> (VerifierSession<I>, I::Challenge) {
+        let challenge = self.scheme.challenge();
+        let session = VerifierSession {
+            commitment,
+            challenge,
+        };
+        (session, challenge)
+    }
+
+    pub fn verify(
+        &self,
+        session: VerifierSession<I>,
+        response: &I::Response,
+    ) -> Result<(), AuthError> {
+        let ok = self.scheme.verify(
+            &self.public,
+            &session.commitment,
+            &session.challenge,
+            response,
+        );
+        if ok {
+            Ok(())
+        } else {
+            Err(AuthError)
+        }
+    }
+
+    pub fn verify_proof(&mut self, context: &[u8], proof: &Proof<I>) -> Result<(), AuthError> {
+        let challenge = self
+            .scheme
+            .derive_challenge(&self.public, &proof.commitment, context);
+        if self.seen.contains(&challenge) {
+            return Err(AuthError);
+        }
+        if !self
+            .scheme
+            .verify(&self.public, &proof.commitment, &challenge, &proof.response)
+        {
+            return Err(AuthError);
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        if self.capacity > 0 {
+            self.seen.insert(challenge);
+            self.order.push_back(challenge);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use crypto::{digest::Digest, sha2::Sha512};
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_POINT,
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+};
+
+const DOMAIN: &[u8] = b"diploma/schnorr/v1";
+
+pub struct Schnorr;
+
+impl super::Identification for Schnorr {
+    type SecretKey = Scalar;
+    type PublicKey = CompressedRistretto;
+    type Nonce = Scalar;
+    type Commitment = CompressedRistretto;
+    type Challenge = [u8; 32];
+    type Response = Scalar;
+
+    fn keypair(&self) -> (Self::SecretKey, Self::PublicKey) {
+        let secret = Scalar::random(&mut rand::thread_rng());
+        let public = (secret * RISTRETTO_BASEPOINT_POINT).compress();
+        (secret, public)
+    }
+
+    fn commit(&self) -> (Self::Nonce, Self::Commitment) {
+        let nonce = Scalar::random(&mut rand::thread_rng());
+        let commitment = (nonce * RISTRETTO_BASEPOINT_POINT).compress();
+        (nonce, commitment)
+    }
+
+    fn challenge(&self) -> Self::Challenge {
+        Scalar::random(&mut rand::thread_rng()).to_bytes()
+    }
+
+    fn derive_challenge(
+        &self,
+        public: &Self::PublicKey,
+        commitment: &Self::Commitment,
+        context: &[u8],
+    ) -> Self::Challenge {
+        let len = u64::try_from(context.len())
+            .expect("sorry, architecture is not supported")
+            .to_be_bytes();
+        let mut d = Sha512::new();
+        d.input(DOMAIN);
+        d.input(public.as_bytes());
+        d.input(commitment.as_bytes());
+        d.input(&len);
+        d.input(context);
+        let mut out = [0; 64];
+        d.result(&mut out);
+        Scalar::from_bytes_mod_order_wide(&out).to_bytes()
+    }
+
+    fn respond(
+        &self,
+        secret: &Self::SecretKey,
+        nonce: Self::Nonce,
+        challenge: &Self::Challenge,
+    ) -> Self::Response {
+        let c = Scalar::from_bytes_mod_order(*challenge);
+        nonce + c * secret
+    }
+
+    fn verify(
+        &self,
+        public: &Self::PublicKey,
+        commitment: &Self::Commitment,
+        challenge: &Self::Challenge,
+        response: &Self::Response,
+    ) -> bool {
+        let (public, commitment, c) = match (
+            public.decompress(),
+            commitment.decompress(),
+            Scalar::from_canonical_bytes(*challenge),
+        ) {
+            (Some(public), Some(commitment), Some(c)) => (public, commitment, c),
+            _ => return false,
+        };
+        let lhs: RistrettoPoint = response * RISTRETTO_BASEPOINT_POINT;
+        lhs == commitment + c * public
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::identification::{Prover, Verifier};
+
+    use super::*;
+
+    #[test]
+    fn interactive_protocol() {
+        let prover = Prover::new(Schnorr);
+        let verifier = Verifier::new(Schnorr, *prover.public());
+
+        for _ in 0..10 {
+            let (session, commitment) = prover.commit();
+            let (check, challenge) = verifier.challenge(commitment);
+            let response = prover.respond(session, &challenge);
+            assert!(verifier.verify(check, &response).is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_replayed_transcript() {
+        let prover = Prover::new(Schnorr);
+        let verifier = Verifier::new(Schnorr, *prover.public());
+
+        let (session, commitment) = prover.commit();
+        let (check, challenge) = verifier.challenge(commitment);
+        let response = prover.respond(session, &challenge);
+        assert!(verifier.verify(check, &response).is_ok());
+
+        // Challenges are fresh, so the old response is useless
+        let (check, replayed) = verifier.challenge(commitment);
+        assert_ne!(replayed, challenge);
+        assert!(verifier.verify(check, &response).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let prover = Prover::new(Schnorr);
+        let other = Prover::new(Schnorr);
+        let verifier = Verifier::new(Schnorr, *other.public());
+
+        let (session, commitment) = prover.commit();
+        let (check, challenge) = verifier.challenge(commitment);
+        let response = prover.respond(session, &challenge);
+        assert!(verifier.verify(check, &response).is_err());
+    }
+
+    #[test]
+    fn non_interactive_protocol() {
+        let prover = Prover::new(Schnorr);
+        let mut verifier = Verifier::new(Schnorr, *prover.public());
+
+        let proof = prover.prove(b"session 1");
+        assert!(verifier.verify_proof(b"session 2", &proof).is_err());
+        assert!(verifier.verify_proof(b"session 1", &proof).is_ok());
+        // Reused challenge
+        assert!(verifier.verify_proof(b"session 1", &proof).is_err());
+
+        let proof = prover.prove(b"session 1");
+        assert!(verifier.verify_proof(b"session 1", &proof).is_ok());
+    }
+
+    #[test]
+    fn seen_challenges_are_bounded() {
+        let prover = Prover::new(Schnorr);
+        let mut verifier = Verifier::with_capacity(Schnorr, *prover.public(), 2);
+
+        let proofs = [b"a", b"b", b"c"].map(|context| (context, prover.prove(context)));
+        for (context, proof) in &proofs {
+            assert!(verifier.verify_proof(*context, proof).is_ok());
+        }
+        let (context, proof) = &proofs[2];
+        assert!(verifier.verify_proof(*context, proof).is_err());
+        // The oldest was forgotten
+        let (context, proof) = &proofs[0];
+        assert!(verifier.verify_proof(*context, proof).is_ok());
+    }
+}
@@ -0,0 +1,460 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use crypto::{
+    aead::{AeadDecryptor, AeadEncryptor},
+    chacha20poly1305::ChaCha20Poly1305,
+    scrypt::{scrypt, ScryptParams},
+};
+use ed25519_dalek::SecretKey;
+use rand::Rng;
+use zeroize::Zeroizing;
+
+use crate::{
+    base::{OneWay, PrivateKey},
+    commitment::{self, ed25519::CloneableSecretKey, Commitment},
+    secret::Secret,
+    session::hmac_sha256,
+    store::{file::write_atomic, Reader},
+};
+
+// Passphrase protected client keys.
+//
+//   MAGIC || log_n || r || p || salt || nonce || ChaCha20Poly1305(key) || tag
+//
+// The scrypt key is derived once per open, every save picks a fresh nonce and
+// encrypts under HMAC(key, nonce), so the 8 byte ChaCha nonce never repeats.
+// The header is authenticated as associated data.
+//
+// Saves replace the file atomically. The keystore holds an exclusive lock on
+// a `.lock` file next to it for as long as it is open, so two processes can't
+// hand out the same round.
+
+const MAGIC: &[u8; 4] = b"DKS1";
+const KEY_LABEL: &[u8] = b"diploma/keystore/v1 key";
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+const HEADER: usize = MAGIC.len() + 1 + 4 + 4 + SALT_SIZE + NONCE_SIZE;
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    // Another process has the keystore open
+    Locked,
+    // Wrong passphrase or a modified file, the two can't be told apart
+    Decrypt,
+    Corrupt,
+    // `create` was given parameters that `open` would reject
+    Params,
+    Io(io::Error),
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeystoreError::Locked => f.write_str("keystore is in use by another process"),
+            KeystoreError::Decrypt => f.write_str("wrong passphrase or damaged keystore"),
+            KeystoreError::Corrupt => f.write_str("keystore is corrupted"),
+            KeystoreError::Params => f.write_str("unsupported KDF parameters"),
+            KeystoreError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<io::Error> for KeystoreError {
+    fn from(err: io::Error) -> Self {
+        KeystoreError::Io(err)
+    }
+}
+
+// Values that can be kept in a keystore
+pub trait Persist: Sized {
+    fn encode(&self) -> Zeroizing<Vec<u8>>;
+
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl<F: OneWay, const SIZE: usize> Persist for PrivateKey<F, SIZE> {
+    fn encode(&self) -> Zeroizing<Vec<u8>> {
+        let mut out = Zeroizing::new(Vec::with_capacity(self.passwords().len() * SIZE));
+        self.passwords()
+            .iter()
+            .for_each(|password| out.extend_from_slice(&password[..]));
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if !bytes.len().is_multiple_of(SIZE) {
+            return None;
+        }
+        let passwords = bytes
+            .chunks_exact(SIZE)
+            .map(|chunk| Secret::from_slice(chunk).unwrap())
+            .collect();
+        Some(Self::from_passwords(passwords))
+    }
+}
+
+// Private elements of commitment schemes
+pub trait Element: Sized {
+    fn to_bytes(&self) -> Zeroizing<Vec<u8>>;
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+}
+
+impl<const N: usize> Element for Secret<N> {
+    fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(self.to_vec())
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Secret::from_slice(bytes)
+    }
+}
+
+impl Element for CloneableSecretKey {
+    fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(self.0.as_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        SecretKey::from_bytes(bytes).ok().map(CloneableSecretKey)
+    }
+}
+
+impl<C> Persist for commitment::PrivateKey<C>
+where
+    C: Commitment + Default,
+    C::PrivateElement: Element,
+{
+    fn encode(&self) -> Zeroizing<Vec<u8>> {
+        self.private().to_bytes()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let private = C::PrivateElement::from_bytes(bytes)?;
+        Some(Self::from_private(C::default(), private))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    // Parameters read from a file are checked before use: ScryptParams panics
+    // on invalid ones, and huge ones would exhaust memory
+    #[must_use]
+    pub fn is_sane(&self) -> bool {
+        (1..=24).contains(&self.log_n)
+            && (1..=32).contains(&self.r)
+            && (1..=16).contains(&self.p)
+            && u32::from(self.log_n) < self.r * 16
+            && (128 * u64::from(self.r)) << self.log_n <= 1 << 30
+    }
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock = path.to_owned().into_os_string();
+    lock.push(".lock");
+    PathBuf::from(lock)
+}
+
+fn lock(path: &Path) -> Result<File, KeystoreError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path(path))?;
+    // Released when the file is closed
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.kind() {
+            ErrorKind::WouldBlock => KeystoreError::Locked,
+            _ => err.into(),
+        });
+    }
+    Ok(file)
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8], params: KdfParams) -> [u8; 32] {
+    let mut key = [0; 32];
+    let params = ScryptParams::new(params.log_n, params.r, params.p);
+    scrypt(passphrase, salt, &params, &mut key);
+    key
+}
+
+fn cipher(key: &[u8; 32], nonce: &[u8], header: &[u8]) -> ChaCha20Poly1305 {
+    let key = hmac_sha256(key, &[KEY_LABEL, nonce]);
+    ChaCha20Poly1305::new(&key, &[0; 8], header)
+}
+
+pub struct Keystore<T: Persist> {
+    path: PathBuf,
+    _lock: File,
+    params: KdfParams,
+    salt: [u8; SALT_SIZE],
+    key: [u8; 32],
+    value: T,
+}
+
+impl<T: Persist> Keystore<T> {
+    // Fails if there already is a keystore at `path`
+    pub fn create(
+        path: impl AsRef<Path>,
+        passphrase: &[u8],
+        params: KdfParams,
+        value: T,
+    ) -> Result<Self, KeystoreError> {
+        if !params.is_sane() {
+            return Err(KeystoreError::Params);
+        }
+        let path = path.as_ref().to_owned();
+        let lock = lock(&path)?;
+        if path.exists() {
+            return Err(io::Error::from(ErrorKind::AlreadyExists).into());
+        }
+
+        let salt: [u8; SALT_SIZE] = rand::thread_rng().gen();
+        let store = Self {
+            key: derive_key(passphrase, &salt, params),
+            path,
+            _lock: lock,
+            params,
+            salt,
+            value,
+        };
+        store.save()?;
+        Ok(store)
+    }
+
+    pub fn open(path: impl AsRef<Path>, passphrase: &[u8]) -> Result<Self, KeystoreError> {
+        let path = path.as_ref().to_owned();
+        let lock = lock(&path)?;
+        let bytes = fs::read(&path)?;
+        if bytes.len() < HEADER + TAG_SIZE {
+            return Err(KeystoreError::Corrupt);
+        }
+
+        let (header, rest) = bytes.split_at(HEADER);
+        let mut reader = Reader(header);
+        if reader.take(MAGIC.len()) != Some(MAGIC) {
+            return Err(KeystoreError::Corrupt);
+        }
+        let params = (|| {
+            Some(KdfParams {
+                log_n: reader.u8()?,
+                r: reader.u32()?,
+                p: reader.u32()?,
+            })
+        })()
+        .ok_or(KeystoreError::Corrupt)?;
+        if !params.is_sane() {
+            return Err(KeystoreError::Corrupt);
+        }
+        let salt: [u8; SALT_SIZE] = reader.take(SALT_SIZE).unwrap().try_into().unwrap();
+        let nonce = reader.take(NONCE_SIZE).unwrap();
+
+        let key = derive_key(passphrase, &salt, params);
+        let (body, tag) = rest.split_at(rest.len() - TAG_SIZE);
+        let mut plain = Zeroizing::new(vec![0; body.len()]);
+        if !cipher(&key, nonce, header).decrypt(body, &mut plain, tag) {
+            return Err(KeystoreError::Decrypt);
+        }
+        let value = T::decode(&plain).ok_or(KeystoreError::Corrupt)?;
+
+        Ok(Self {
+            path,
+            _lock: lock,
+            params,
+            salt,
+            key,
+            value,
+        })
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[must_use]
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    fn save(&self) -> Result<(), KeystoreError> {
+        let nonce: [u8; NONCE_SIZE] = rand::thread_rng().gen();
+        let header = [
+            &MAGIC[..],
+            &[self.params.log_n],
+            &self.params.r.to_be_bytes(),
+            &self.params.p.to_be_bytes(),
+            &self.salt,
+            &nonce,
+        ]
+        .concat();
+
+        let plain = self.value.encode();
+        let mut out = vec![0; HEADER + plain.len() + TAG_SIZE];
+        out[..HEADER].copy_from_slice(&header);
+        let (body, tag) = out[HEADER..].split_at_mut(plain.len());
+        cipher(&self.key, &nonce, &header).encrypt(&plain, body, tag);
+        write_atomic(&self.path, &out)?;
+        Ok(())
+    }
+
+    // Changes the value and saves it before returning. Pop the password
+    // through here before sending it, so a crash can't hand it out twice. If
+    // the save fails the value is left unchanged.
+    pub fn update<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> Result<R, KeystoreError> {
+        let old = self.value.encode();
+        let result = f(&mut self.value);
+        if let Err(err) = self.save() {
+            self.value = T::decode(&old).expect("value must decode its own encoding");
+            return Err(err);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        base::State,
+        commitment::{ed25519::Ed25519, hash::Sha256},
+        hash::Sha256Builder,
+    };
+
+    type Private = PrivateKey<crypto::sha2::Sha256, 32>;
+
+    const FAST: KdfParams = KdfParams {
+        log_n: 4,
+        r: 1,
+        p: 1,
+    };
+
+    #[test]
+    fn chain_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("alice.keys");
+
+        let mut public = {
+            let mut private = Sha256Builder::new_private(10);
+            let p0 = private.get_password().unwrap();
+            assert_eq!(private.pop_password(), State::Ok);
+            Keystore::create(&path, b"hunter2", FAST, private).unwrap();
+            Sha256Builder::new_public(*p0.expose())
+        };
+
+        for _ in 0..3 {
+            let mut store = Keystore::<Private>::open(&path, b"hunter2").unwrap();
+            let password = store.get().get_password().unwrap();
+            assert_eq!(store.update(Private::pop_password).unwrap(), State::Ok);
+            public.verify(password.expose()).unwrap();
+        }
+
+        let store = Keystore::<Private>::open(&path, b"hunter2").unwrap();
+        assert_eq!(store.get().round(), 6);
+        assert!(
+            Keystore::create(&path, b"hunter2", FAST, Private::from_passwords(vec![])).is_err()
+        );
+    }
+
+    #[test]
+    fn commitment_keys() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = dir.path().join("sha");
+        let private = commitment::PrivateKey::new(Sha256);
+        let public = private.public();
+        drop(Keystore::create(&path, b"pass", FAST, private).unwrap());
+        let mut store = Keystore::<commitment::PrivateKey<Sha256>>::open(&path, b"pass").unwrap();
+        assert_eq!(store.get().public(), public);
+        store.update(|key| key.advance()).unwrap();
+        let public = store.get().public();
+        drop(store);
+        let store = Keystore::<commitment::PrivateKey<Sha256>>::open(&path, b"pass").unwrap();
+        assert_eq!(store.get().public(), public);
+
+        let path = dir.path().join("ed");
+        let private = commitment::PrivateKey::new(Ed25519);
+        let public = private.public();
+        drop(Keystore::create(&path, b"pass", FAST, private).unwrap());
+        let store = Keystore::<commitment::PrivateKey<Ed25519>>::open(&path, b"pass").unwrap();
+        assert_eq!(store.get().public(), public);
+    }
+
+    #[test]
+    fn rejects_wrong_passphrase_and_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        let private = Sha256Builder::new_private(3);
+        drop(Keystore::create(&path, b"right", FAST, private).unwrap());
+
+        assert!(matches!(
+            Keystore::<Private>::open(&path, b"wrong"),
+            Err(KeystoreError::Decrypt)
+        ));
+
+        let bytes = fs::read(&path).unwrap();
+        for i in MAGIC.len()..bytes.len() {
+            let mut tampered = bytes.clone();
+            tampered[i] ^= 1;
+            fs::write(&path, &tampered).unwrap();
+            assert!(Keystore::<Private>::open(&path, b"right").is_err());
+        }
+        fs::write(&path, &bytes).unwrap();
+        Keystore::<Private>::open(&path, b"right").unwrap();
+    }
+
+    #[test]
+    fn create_rejects_what_open_would() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        let params = KdfParams { log_n: 22, ..FAST };
+        assert!(matches!(
+            Keystore::create(&path, b"pass", params, Sha256Builder::new_private(3)),
+            Err(KeystoreError::Params)
+        ));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn single_user_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        let store = Keystore::create(&path, b"pass", FAST, Sha256Builder::new_private(3)).unwrap();
+
+        // flock is per open file, so this is the same as another process
+        assert!(matches!(
+            Keystore::<Private>::open(&path, b"pass"),
+            Err(KeystoreError::Locked)
+        ));
+        drop(store);
+        Keystore::<Private>::open(&path, b"pass").unwrap();
+    }
+}
@@ -1,4 +1,42 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+pub mod audit;
 pub mod base;
+#[cfg(feature = "std")]
+pub mod binding;
 pub mod cipher;
+#[cfg(feature = "std")]
 pub mod commitment;
+#[cfg(feature = "std")]
+pub mod daemon;
+#[cfg(feature = "std")]
+pub mod encoding;
 pub mod hash;
+#[cfg(feature = "std")]
+pub mod http;
+#[cfg(feature = "std")]
+pub mod identification;
+#[cfg(feature = "std")]
+pub mod keystore;
+#[cfg(feature = "std")]
+pub mod merkle;
+#[cfg(feature = "std")]
+pub mod mutual;
+#[cfg(feature = "std")]
+pub mod policy;
+#[cfg(feature = "std")]
+pub mod radius;
+#[cfg(feature = "std")]
+pub mod replication;
+pub mod secret;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "std")]
+pub mod shamir;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(feature = "std")]
+pub mod token;
+#[cfg(feature = "std")]
+pub mod uri;
@@ -0,0 +1,257 @@
+use std::marker::PhantomData;
+
+use rand::RngCore;
+
+use crate::{
+    base::{AuthError, OneWay},
+    hash::Hash,
+};
+
+const LEAF_TAG: u8 = 0x00;
+const NODE_TAG: u8 = 0x01;
+
+fn hash_leaf<H: Hash, const SIZE: usize>(password: &[u8; SIZE]) -> [u8; SIZE] {
+    let mut d = H::new();
+    d.input(&[LEAF_TAG]);
+    d.input(password);
+    let mut out = [0; SIZE];
+    d.result(&mut out);
+    out
+}
+
+fn hash_node<H: Hash, const SIZE: usize>(left: &[u8; SIZE], right: &[u8; SIZE]) -> [u8; SIZE] {
+    let mut d = H::new();
+    d.input(&[NODE_TAG]);
+    d.input(left);
+    d.input(right);
+    let mut out = [0; SIZE];
+    d.result(&mut out);
+    out
+}
+
+fn depth(leaves: usize) -> usize {
+    leaves.next_power_of_two().trailing_zeros() as usize
+}
+
+pub struct Proof<const SIZE: usize> {
+    pub index: usize,
+    pub password: [u8; SIZE],
+    pub path: Vec<[u8; SIZE]>,
+}
+
+pub struct PrivateKey<H: Hash, const SIZE: usize> {
+    passwords: Vec<[u8; SIZE]>,
+    // levels[0] holds the leaf hashes, the last level holds the root
+    levels: Vec<Vec<[u8; SIZE]>>,
+    _hash: PhantomData<H>,
+}
+
+impl<H: Hash, const SIZE: usize> PrivateKey<H, SIZE> {
+    pub fn new(leaves: usize) -> Self {
+        let mut seed = [0; SIZE];
+        rand::thread_rng().fill_bytes(&mut seed);
+        Self::from_seed(leaves, seed)
+    }
+
+    pub fn from_seed(leaves: usize, seed: [u8; SIZE]) -> Self {
+        assert!(leaves > 0, "tree should have at least one leaf");
+
+        let hash = H::new();
+        let passwords = (0..leaves)
+            .map(|i| {
+                let mut out = [0; SIZE];
+                hash.compute(i, &seed, &mut out);
+                out
+            })
+            .collect::<Vec<_>>();
+
+        // Pad the leaf level with zero nodes up to the power of two
+        let mut level = passwords
+            .iter()
+            .map(hash_leaf::<H, SIZE>)
+            .collect::<Vec<_>>();
+        level.resize(leaves.next_power_of_two(), [0; SIZE]);
+
+        let mut levels = vec![level];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| hash_node::<H, SIZE>(&pair[0], &pair[1]))
+                .collect();
+            levels.push(next);
+        }
+
+        Self {
+            passwords,
+            levels,
+            _hash: PhantomData {},
+        }
+    }
+
+    #[must_use]
+    pub fn root(&self) -> [u8; SIZE] {
+        self.levels.last().unwrap()[0]
+    }
+
+    #[must_use]
+    pub fn leaves(&self) -> usize {
+        self.passwords.len()
+    }
+
+    pub fn proof(&self, index: usize) -> Option<Proof<SIZE>> {
+        let password = *self.passwords.get(index)?;
+        let path = self
+            .levels
+            .iter()
+            .take(self.levels.len() - 1)
+            .enumerate()
+            .map(|(height, level)| level[(index >> height) ^ 1])
+            .collect();
+        Some(Proof {
+            index,
+            password,
+            path,
+        })
+    }
+}
+
+pub struct PublicKey<H: Hash, const SIZE: usize> {
+    root: [u8; SIZE],
+    leaves: usize,
+    used: Vec<u64>,
+    _hash: PhantomData<H>,
+}
+
+impl<H: Hash, const SIZE: usize> PublicKey<H, SIZE> {
+    pub fn new(root: [u8; SIZE], leaves: usize) -> Self {
+        Self {
+            root,
+            leaves,
+            used: vec![0; leaves.div_ceil(64)],
+            _hash: PhantomData {},
+        }
+    }
+
+    #[must_use]
+    pub fn is_used(&self, index: usize) -> bool {
+        self.used
+            .get(index / 64)
+            .is_some_and(|word| word & (1 << (index % 64)) != 0)
+    }
+
+    #[must_use]
+    pub fn remaining(&self) -> usize {
+        let used = self
+            .used
+            .iter()
+            .map(|w| w.count_ones() as usize)
+            .sum::<usize>();
+        self.leaves - used
+    }
+
+    pub fn verify_dry(&self, proof: &Proof<SIZE>) -> Result<(), AuthError> {
+        if proof.index >= self.leaves
+            || proof.path.len() != depth(self.leaves)
+            || self.is_used(proof.index)
+        {
+            return Err(AuthError);
+        }
+
+        let root = proof.path.iter().enumerate().fold(
+            hash_leaf::<H, SIZE>(&proof.password),
+            |node, (height, sibling)| {
+                if (proof.index >> height) & 1 == 0 {
+                    hash_node::<H, SIZE>(&node, sibling)
+                } else {
+                    hash_node::<H, SIZE>(sibling, &node)
+                }
+            },
+        );
+
+        if crypto::util::fixed_time_eq(&root, &self.root) {
+            Ok(())
+        } else {
+            Err(AuthError)
+        }
+    }
+
+    pub fn verify(&mut self, proof: &Proof<SIZE>) -> Result<(), AuthError> {
+        self.verify_dry(proof)?;
+        self.used[proof.index / 64] |= 1 << (proof.index % 64);
+        Ok(())
+    }
+}
+
+pub struct MerkleBuilder<H: Hash, const SIZE: usize>(PhantomData<H>);
+
+impl<H: Hash, const SIZE: usize> MerkleBuilder<H, SIZE> {
+    pub fn new_private(leaves: usize) -> PrivateKey<H, SIZE> {
+        PrivateKey::new(leaves)
+    }
+
+    pub fn private_from_seed(leaves: usize, seed: [u8; SIZE]) -> PrivateKey<H, SIZE> {
+        PrivateKey::from_seed(leaves, seed)
+    }
+
+    pub fn new_public(root: [u8; SIZE], leaves: usize) -> PublicKey<H, SIZE> {
+        PublicKey::new(root, leaves)
+    }
+}
+
+pub type Sha256MerkleBuilder = MerkleBuilder<crypto::sha2::Sha256, 32>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_access_protocol() {
+        let private = Sha256MerkleBuilder::new_private(5);
+        let mut public = Sha256MerkleBuilder::new_public(private.root(), private.leaves());
+
+        for index in [3, 0, 4, 1, 2] {
+            let proof = private.proof(index).unwrap();
+            assert!(public.verify(&proof).is_ok());
+        }
+        assert_eq!(public.remaining(), 0);
+        assert!(private.proof(5).is_none());
+    }
+
+    #[test]
+    fn rejects_replay_and_forgery() {
+        let private = Sha256MerkleBuilder::private_from_seed(8, [0xde; 32]);
+        let mut public = Sha256MerkleBuilder::new_public(private.root(), private.leaves());
+
+        let proof = private.proof(6).unwrap();
+        assert!(public.verify(&proof).is_ok());
+        assert!(public.verify(&proof).is_err());
+
+        let mut forged = private.proof(2).unwrap();
+        forged.index = 3;
+        assert!(public.verify(&forged).is_err());
+
+        let mut forged = private.proof(2).unwrap();
+        forged.password[0] ^= 1;
+        assert!(public.verify(&forged).is_err());
+
+        let mut forged = private.proof(2).unwrap();
+        forged.path.pop();
+        assert!(public.verify(&forged).is_err());
+
+        assert!(public.verify(&private.proof(2).unwrap()).is_ok());
+        assert_eq!(public.remaining(), 6);
+    }
+
+    #[test]
+    fn single_leaf() {
+        let private = Sha256MerkleBuilder::new_private(1);
+        let mut public = Sha256MerkleBuilder::new_public(private.root(), 1);
+
+        let proof = private.proof(0).unwrap();
+        assert!(proof.path.is_empty());
+        assert!(public.verify(&proof).is_ok());
+        assert!(public.verify(&proof).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+use crate::{
+    base::{AuthError, OneWay, PrivateKey, PublicKey},
+    secret::Secret,
+};
+
+// Mutual authentication: the server reveals the next element of its own chain
+// before the client releases its password, so a fake server can't harvest
+// the client's next OTP.
+//
+// Hellos aren't authenticated, so the server reveals a new element only once
+// the previous one was answered and repeats it otherwise. Rounds the server
+// did burn anyway, e.g. after a restart, are skipped by the client up to
+// `SKIP_WINDOW`. If the client's proof got lost, the client recognises the
+// repeated element and repeats its proof, which isn't secret anymore.
+
+pub const SKIP_WINDOW: usize = 8;
+
+const CLIENT_HELLO: u8 = 1;
+const SERVER_PROOF: u8 = 2;
+const CLIENT_PROOF: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message<const SIZE: usize> {
+    ClientHello,
+    ServerProof(Secret<SIZE>),
+    ClientProof(Secret<SIZE>),
+}
+
+impl<const SIZE: usize> Message<SIZE> {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Message::ClientHello => vec![CLIENT_HELLO],
+            Message::ServerProof(password) => [&[SERVER_PROOF][..], password].concat(),
+            Message::ClientProof(password) => [&[CLIENT_PROOF][..], password].concat(),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, payload) = bytes.split_first()?;
+        match *tag {
+            CLIENT_HELLO if payload.is_empty() => Some(Message::ClientHello),
+            SERVER_PROOF => Some(Message::ServerProof(Secret::from_slice(payload)?)),
+            CLIENT_PROOF => Some(Message::ClientProof(Secret::from_slice(payload)?)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Idle,
+    AwaitingProof,
+    Done,
+}
+
+pub struct Client<F: OneWay, G: OneWay, const SIZE: usize> {
+    private: PrivateKey<F, SIZE>,
+    server: PublicKey<G, SIZE>,
+    step: Step,
+    // The last exchange, repeated if the server didn't get our proof
+    last: Option<(Secret<SIZE>, Secret<SIZE>)>,
+}
+
+impl<F: OneWay, G: OneWay, const SIZE: usize> Client<F, G, SIZE> {
+    pub fn new(private: PrivateKey<F, SIZE>, server: PublicKey<G, SIZE>) -> Self {
+        Self {
+            private,
+            server,
+            step: Step::Idle,
+            last: None,
+        }
+    }
+
+    pub fn hello(&mut self) -> Message<SIZE> {
+        self.step = Step::AwaitingProof;
+        Message::ClientHello
+    }
+
+    pub fn handle(&mut self, msg: &Message<SIZE>) -> Result<Message<SIZE>, AuthError> {
+        match (self.step, msg) {
+            (Step::AwaitingProof, Message::ServerProof(password)) => {
+                self.step = Step::Idle;
+                if let Some((server, client)) = &self.last {
+                    if server == password {
+                        self.step = Step::Done;
+                        return Ok(Message::ClientProof(client.clone()));
+                    }
+                }
+                if !(0..=SKIP_WINDOW)
+                    .any(|skip| self.server.verify_skip(password.expose(), skip).is_ok())
+                {
+                    return Err(AuthError);
+                }
+                let proof = self.private.get_password().ok_or(AuthError)?;
+                let _ = self.private.pop_password();
+                self.step = Step::Done;
+                self.last = Some((password.clone(), proof.clone()));
+                Ok(Message::ClientProof(proof))
+            }
+            _ => {
+                self.step = Step::Idle;
+                Err(AuthError)
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn is_done(&self) -> bool {
+        self.step == Step::Done
+    }
+
+    pub fn into_keys(self) -> (PrivateKey<F, SIZE>, PublicKey<G, SIZE>) {
+        (self.private, self.server)
+    }
+}
+
+pub struct Server<F: OneWay, G: OneWay, const SIZE: usize> {
+    private: PrivateKey<F, SIZE>,
+    client: PublicKey<G, SIZE>,
+    // Revealed, but not answered by the client yet
+    pending: Option<Secret<SIZE>>,
+    done: bool,
+}
+
+impl<F: OneWay, G: OneWay, const SIZE: usize> Server<F, G, SIZE> {
+    pub fn new(private: PrivateKey<F, SIZE>, client: PublicKey<G, SIZE>) -> Self {
+        Self {
+            private,
+            client,
+            pending: None,
+            done: false,
+        }
+    }
+
+    pub fn handle(&mut self, msg: &Message<SIZE>) -> Result<Option<Message<SIZE>>, AuthError> {
+        self.done = false;
+        match msg {
+            Message::ClientHello => {
+                // A revealed element is public from now on, so it's burned
+                let password = match &self.pending {
+                    Some(password) => password.clone(),
+                    None => {
+                        let password = self.private.get_password().ok_or(AuthError)?;
+                        let _ = self.private.pop_password();
+                        self.pending = Some(password.clone());
+                        password
+                    }
+                };
+                Ok(Some(Message::ServerProof(password)))
+            }
+            Message::ClientProof(password) => {
+                if self.pending.is_none() {
+                    return Err(AuthError);
+                }
+                self.client.verify(password.expose())?;
+                self.pending = None;
+                self.done = true;
+                Ok(None)
+            }
+            Message::ServerProof(_) => Err(AuthError),
+        }
+    }
+
+    #[must_use]
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn into_keys(self) -> (PrivateKey<F, SIZE>, PublicKey<G, SIZE>) {
+        (self.private, self.client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{base::State, hash::Sha256Builder};
+
+    type Pair = (
+        Client<crypto::sha2::Sha256, crypto::sha2::Sha256, 32>,
+        Server<crypto::sha2::Sha256, crypto::sha2::Sha256, 32>,
+    );
+
+    fn setup() -> Pair {
+        setup_burned(0)
+    }
+
+    // The server has burned `burned` rounds the client doesn't know about
+    fn setup_burned(burned: usize) -> Pair {
+        let mut client_key = Sha256Builder::new_private(5);
+        let mut server_key = Sha256Builder::new_private(20);
+
+        let client_anchor = client_key.get_password().unwrap();
+        assert_eq!(client_key.pop_password(), State::Ok);
+        let server_anchor = server_key.get_password().unwrap();
+        assert_eq!(server_key.pop_password(), State::Ok);
+        for _ in 0..burned {
+            assert_eq!(server_key.pop_password(), State::Ok);
+        }
+
+        let client = Client::new(
+            client_key,
+            Sha256Builder::new_public(*server_anchor.expose()),
+        );
+        let server = Server::new(
+            server_key,
+            Sha256Builder::new_public(*client_anchor.expose()),
+        );
+        (client, server)
+    }
+
+    #[test]
+    fn normal_protocol() {
+        let (mut client, mut server) = setup();
+
+        for _ in 0..5 {
+            let hello = Message::decode(&client.hello().encode()).unwrap();
+            let proof = server.handle(&hello).unwrap().unwrap();
+            let proof = Message::decode(&proof.encode()).unwrap();
+            let reply = client.handle(&proof).unwrap();
+            assert!(client.is_done());
+            assert_eq!(server.handle(&reply).unwrap(), None);
+            assert!(server.is_done());
+        }
+    }
+
+    #[test]
+    fn fake_server_gets_nothing() {
+        let (mut client, _) = setup();
+
+        client.hello();
+        assert!(client
+            .handle(&Message::ServerProof(Secret::new([0xde; 32])))
+            .is_err());
+        assert!(!client.is_done());
+
+        // Client password wasn't released or consumed
+        let (private, _) = client.into_keys();
+        assert_eq!(private.round(), 4);
+    }
+
+    #[test]
+    fn rejects_out_of_order_messages() {
+        let (mut client, mut server) = setup();
+
+        assert!(server
+            .handle(&Message::ClientProof(Secret::zeroed()))
+            .is_err());
+
+        let hello = client.hello();
+        let proof = server.handle(&hello).unwrap().unwrap();
+        assert!(client.handle(&proof).is_ok());
+        assert!(client.handle(&proof).is_err());
+    }
+
+    fn login(client: &mut Pair) -> Result<(), AuthError> {
+        let (client, server) = client;
+        let proof = server.handle(&client.hello())?.unwrap();
+        let reply = client.handle(&proof)?;
+        assert_eq!(server.handle(&reply)?, None);
+        assert!(server.is_done());
+        Ok(())
+    }
+
+    #[test]
+    fn spurious_hellos_dont_lock_out() {
+        let mut pair = setup();
+
+        // Nobody answers these
+        let (_, server) = &mut pair;
+        let first = server.handle(&Message::ClientHello).unwrap();
+        for _ in 0..2 * SKIP_WINDOW {
+            assert_eq!(server.handle(&Message::ClientHello).unwrap(), first);
+            assert!(server
+                .handle(&Message::ClientProof(Secret::zeroed()))
+                .is_err());
+        }
+        login(&mut pair).unwrap();
+        login(&mut pair).unwrap();
+    }
+
+    #[test]
+    fn lost_messages() {
+        let (mut client, mut server) = setup();
+
+        // Server proof lost
+        let _ = server.handle(&client.hello()).unwrap();
+        let proof = server.handle(&client.hello()).unwrap().unwrap();
+        let reply = client.handle(&proof).unwrap();
+
+        // Client proof lost, both are repeated
+        let proof = server.handle(&client.hello()).unwrap().unwrap();
+        assert_eq!(client.handle(&proof).unwrap(), reply);
+        assert_eq!(server.handle(&reply).unwrap(), None);
+        assert!(server.is_done());
+
+        let (private, _) = client.into_keys();
+        assert_eq!(private.round(), 3);
+    }
+
+    #[test]
+    fn skipped_server_rounds() {
+        login(&mut setup_burned(SKIP_WINDOW)).unwrap();
+
+        let mut pair = setup_burned(SKIP_WINDOW + 1);
+        assert!(login(&mut pair).is_err());
+        assert!(!pair.0.is_done());
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        assert_eq!(Message::<32>::decode(&[]), None);
+        assert_eq!(Message::<32>::decode(&[CLIENT_HELLO, 0]), None);
+        assert_eq!(Message::<32>::decode(&[SERVER_PROOF, 1, 2]), None);
+        assert_eq!(Message::<32>::decode(&[42]), None);
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    base::AuthError,
+    store::{Reader, Store, StoreError},
+};
+
+pub trait Clock {
+    // Seconds since the unix epoch
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Default)]
+pub struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        Self(AtomicU64::new(now))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, secs: u64) {
+        self.0.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl<C: Clock> Clock for &C {
+    fn now(&self) -> u64 {
+        (*self).now()
+    }
+}
+
+// Locked until reset by an administrator
+pub const LOCKED_FOREVER: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attempts {
+    pub failures: u32,
+    pub last_failure: u64,
+    // 0 if the user isn't locked
+    pub locked_until: u64,
+    // The next success has to skip at least one round
+    pub skip_required: bool,
+}
+
+impl Attempts {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(&self.failures.to_be_bytes());
+        out.extend_from_slice(&self.last_failure.to_be_bytes());
+        out.extend_from_slice(&self.locked_until.to_be_bytes());
+        out.push(u8::from(self.skip_required));
+        out
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            failures: reader.u32()?,
+            last_failure: reader.u64()?,
+            locked_until: reader.u64()?,
+            skip_required: match reader.u8()? {
+                0 => false,
+                1 => true,
+                _ => return None,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PolicyConfig {
+    // Failures before the user is locked, 0 disables the lockout
+    pub max_failures: u32,
+    // None locks until reset
+    pub lockout: Option<u64>,
+    // Delay after the first failure, doubled for every following one
+    pub base_delay: u64,
+    pub max_delay: u64,
+    // After a lockout the next success must come from a round beyond the
+    // current one, which an online guesser targeting that round can't hit.
+    // Hash chains only.
+    pub require_skip: bool,
+    // How far ahead a skipping password may be
+    pub skip_window: usize,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            lockout: Some(15 * 60),
+            base_delay: 1,
+            max_delay: 60,
+            require_skip: false,
+            skip_window: 8,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    Locked { until: u64 },
+    Backoff { retry_at: u64 },
+    Store(StoreError),
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::Locked { until } if *until == LOCKED_FOREVER => {
+                f.write_str("user is locked")
+            }
+            PolicyError::Locked { until } => write!(f, "user is locked until {}", until),
+            PolicyError::Backoff { retry_at } => write!(f, "retry after {}", retry_at),
+            PolicyError::Store(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+impl From<StoreError> for PolicyError {
+    fn from(err: StoreError) -> Self {
+        PolicyError::Store(err)
+    }
+}
+
+pub struct Policy<C: Clock> {
+    pub config: PolicyConfig,
+    clock: C,
+}
+
+impl<C: Clock> Policy<C> {
+    pub fn new(config: PolicyConfig, clock: C) -> Self {
+        Self { config, clock }
+    }
+
+    #[must_use]
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    fn delay(&self, failures: u32) -> u64 {
+        if failures == 0 {
+            return 0;
+        }
+        let factor = 1u64.checked_shl(failures - 1).unwrap_or(u64::MAX);
+        self.config
+            .base_delay
+            .saturating_mul(factor)
+            .min(self.config.max_delay)
+    }
+
+    pub fn check(&self, attempts: &Attempts) -> Result<(), PolicyError> {
+        let now = self.clock.now();
+        if attempts.locked_until != 0 && now < attempts.locked_until {
+            return Err(PolicyError::Locked {
+                until: attempts.locked_until,
+            });
+        }
+        let retry_at = attempts
+            .last_failure
+            .saturating_add(self.delay(attempts.failures));
+        if attempts.locked_until == 0 && now < retry_at {
+            return Err(PolicyError::Backoff { retry_at });
+        }
+        Ok(())
+    }
+
+    fn fail(&self, attempts: &mut Attempts) {
+        let now = self.clock.now();
+        attempts.failures = attempts.failures.saturating_add(1);
+        attempts.last_failure = now;
+        if self.config.max_failures != 0 && attempts.failures >= self.config.max_failures {
+            attempts.locked_until = match self.config.lockout {
+                Some(secs) => now.saturating_add(secs),
+                None => LOCKED_FOREVER,
+            };
+            attempts.skip_required |= self.config.require_skip;
+        }
+    }
+
+    // Verify-and-advance through the policy. Failures are counted and stored
+    // together with the verifier state.
+    pub fn accept<S: Store + ?Sized>(
+        &self,
+        store: &mut S,
+        user: &str,
+        password: &[u8],
+        next: Option<&[u8]>,
+    ) -> Result<(), PolicyError> {
+        let mut record = store.get(user)?.ok_or(StoreError::NotFound)?;
+        self.check(&record.attempts)?;
+
+        let mut updated = record.clone();
+        let verified = if record.attempts.skip_required && record.algorithm.is_chain() {
+            (1..=self.config.skip_window).any(|skip| {
+                updated = record.clone();
+                updated.accept_skip(password, skip).is_ok()
+            })
+        } else {
+            updated.accept(password, next).is_ok()
+        };
+
+        if verified {
+            updated.attempts = Default::default();
+            store.put(user, updated)?;
+            Ok(())
+        } else {
+            self.fail(&mut record.attempts);
+            store.put(user, record)?;
+            Err(PolicyError::Store(StoreError::Auth(AuthError)))
+        }
+    }
+
+    // Lifts a lockout, e.g. from an admin tool
+    pub fn reset<S: Store + ?Sized>(&self, store: &mut S, user: &str) -> Result<(), PolicyError> {
+        let mut record = store.get(user)?.ok_or(StoreError::NotFound)?;
+        record.attempts = Default::default();
+        store.put(user, record)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        base::State,
+        store::{FileStore, MemoryStore, Record},
+    };
+
+    fn enroll(store: &mut impl Store) -> crate::base::PrivateKey<crypto::sha2::Sha256, 32> {
+        let (private, record) = Record::generate_sha256(20);
+        store.enroll("alice", record).unwrap();
+        private
+    }
+
+    #[test]
+    fn backoff_and_lockout() {
+        let clock = ManualClock::new(1000);
+        let config = PolicyConfig {
+            max_failures: 3,
+            lockout: Some(600),
+            base_delay: 2,
+            max_delay: 60,
+            ..Default::default()
+        };
+        let policy = Policy::new(config, &clock);
+        let mut store = MemoryStore::new();
+        let private = enroll(&mut store);
+        let password = private.get_password().unwrap();
+
+        assert!(matches!(
+            policy.accept(&mut store, "alice", &[0; 32], None),
+            Err(PolicyError::Store(StoreError::Auth(_)))
+        ));
+        // Even the right password is refused during the backoff
+        assert!(matches!(
+            policy.accept(&mut store, "alice", &password[..], None),
+            Err(PolicyError::Backoff { retry_at: 1002 })
+        ));
+
+        clock.advance(2);
+        assert!(policy.accept(&mut store, "alice", &[0; 32], None).is_err());
+        clock.advance(3);
+        assert!(matches!(
+            policy.accept(&mut store, "alice", &password[..], None),
+            Err(PolicyError::Backoff { retry_at: 1006 })
+        ));
+        clock.advance(1);
+        assert!(policy.accept(&mut store, "alice", &[0; 32], None).is_err());
+        assert!(matches!(
+            policy.accept(&mut store, "alice", &password[..], None),
+            Err(PolicyError::Locked { until: 1606 })
+        ));
+
+        clock.set(1606);
+        assert!(policy
+            .accept(&mut store, "alice", &password[..], None)
+            .is_ok());
+        assert_eq!(
+            store.get("alice").unwrap().unwrap().attempts,
+            Attempts::default()
+        );
+    }
+
+    #[test]
+    fn permanent_lockout_and_reset() {
+        let clock = ManualClock::new(0);
+        let config = PolicyConfig {
+            max_failures: 1,
+            lockout: None,
+            ..Default::default()
+        };
+        let policy = Policy::new(config, &clock);
+        let mut store = MemoryStore::new();
+        let private = enroll(&mut store);
+
+        assert!(policy.accept(&mut store, "alice", &[0; 32], None).is_err());
+        clock.advance(1 << 40);
+        let password = private.get_password().unwrap();
+        assert!(matches!(
+            policy.accept(&mut store, "alice", &password[..], None),
+            Err(PolicyError::Locked { .. })
+        ));
+
+        policy.reset(&mut store, "alice").unwrap();
+        assert!(policy
+            .accept(&mut store, "alice", &password[..], None)
+            .is_ok());
+    }
+
+    #[test]
+    fn skip_required_after_lockout() {
+        let clock = ManualClock::new(0);
+        let config = PolicyConfig {
+            max_failures: 2,
+            lockout: Some(10),
+            base_delay: 0,
+            require_skip: true,
+            ..Default::default()
+        };
+        let policy = Policy::new(config, &clock);
+        let mut store = MemoryStore::new();
+        let mut private = enroll(&mut store);
+
+        assert!(policy.accept(&mut store, "alice", &[0; 32], None).is_err());
+        assert!(policy.accept(&mut store, "alice", &[1; 32], None).is_err());
+        clock.advance(10);
+
+        // The next round in the window isn't enough any more
+        let current = private.get_password().unwrap();
+        assert!(policy
+            .accept(&mut store, "alice", &current[..], None)
+            .is_err());
+
+        clock.advance(10);
+        assert_eq!(private.pop_password(), State::Ok);
+        assert_eq!(private.pop_password(), State::Ok);
+        let ahead = private.get_password().unwrap();
+        assert!(policy.accept(&mut store, "alice", &ahead[..], None).is_ok());
+        assert_eq!(store.get("alice").unwrap().unwrap().round, 4);
+    }
+
+    #[test]
+    fn attempts_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let clock = ManualClock::new(100);
+        let policy = Policy::new(PolicyConfig::default(), &clock);
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            enroll(&mut store);
+            assert!(policy.accept(&mut store, "alice", &[0; 32], None).is_err());
+        }
+        let store = FileStore::open(&path).unwrap();
+        let attempts = store.get("alice").unwrap().unwrap().attempts;
+        assert_eq!(attempts.failures, 1);
+        assert_eq!(attempts.last_failure, 100);
+    }
+}
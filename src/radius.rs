@@ -0,0 +1,622 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+use crypto::{
+    digest::Digest,
+    hmac::Hmac,
+    mac::{Mac, MacResult},
+    md5::Md5,
+};
+use rand::Rng;
+
+use crate::{
+    encoding,
+    policy::{Clock, Policy, PolicyError},
+    store::{Store, StoreError},
+};
+
+// RADIUS front end (RFC 2865) for hash chain users. An Access-Request with
+// a PAP password that reads as a one-time password is answered with
+// Access-Accept or Access-Reject. Any other password, e.g. an empty one or
+// the static password a VPN client sends first, gets an Access-Challenge
+// whose Reply-Message names the expected round. The password sent back
+// together with the challenge's State is then verified.
+//
+// Every request must carry a valid Message-Authenticator (RFC 3579), or it is
+// dropped, and so is anything from an address without a shared secret.
+// Retransmitted requests get the cached answer instead of a second attempt.
+
+pub const ACCESS_REQUEST: u8 = 1;
+pub const ACCESS_ACCEPT: u8 = 2;
+pub const ACCESS_REJECT: u8 = 3;
+pub const ACCESS_CHALLENGE: u8 = 11;
+
+pub const USER_NAME: u8 = 1;
+pub const USER_PASSWORD: u8 = 2;
+pub const REPLY_MESSAGE: u8 = 18;
+pub const STATE: u8 = 24;
+pub const MESSAGE_AUTHENTICATOR: u8 = 80;
+
+const HEADER_SIZE: usize = 20;
+const MAX_PACKET: usize = 4096;
+const AUTH_SIZE: usize = 16;
+// Seconds a challenge can be answered and a reply is kept for retransmits
+const CHALLENGE_TTL: u64 = 120;
+const REPLY_TTL: u64 = 30;
+const MAX_PENDING: usize = 10_000;
+
+pub type Authenticator = [u8; AUTH_SIZE];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub code: u8,
+    pub id: u8,
+    pub authenticator: Authenticator,
+    pub attributes: Vec<(u8, Vec<u8>)>,
+}
+
+impl Packet {
+    #[must_use]
+    pub fn attribute(&self, kind: u8) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, value)| &value[..])
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![self.code, self.id, 0, 0];
+        out.extend_from_slice(&self.authenticator);
+        for (kind, value) in &self.attributes {
+            let len = u8::try_from(value.len() + 2).expect("attribute is too long");
+            out.extend_from_slice(&[*kind, len]);
+            out.extend_from_slice(value);
+        }
+        let len = u16::try_from(out.len()).expect("packet is too long");
+        out[2..4].copy_from_slice(&len.to_be_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let len = usize::from(u16::from_be_bytes(bytes.get(2..4)?.try_into().ok()?));
+        // Octets past the length are padding
+        if !(HEADER_SIZE..=MAX_PACKET).contains(&len) || bytes.len() < len {
+            return None;
+        }
+        let mut rest = &bytes[HEADER_SIZE..len];
+        let mut attributes = vec![];
+        while !rest.is_empty() {
+            let size = usize::from(*rest.get(1)?);
+            if size < 2 || size > rest.len() {
+                return None;
+            }
+            attributes.push((rest[0], rest[2..size].to_vec()));
+            rest = &rest[size..];
+        }
+        Some(Self {
+            code: bytes[0],
+            id: bytes[1],
+            authenticator: bytes[4..HEADER_SIZE].try_into().unwrap(),
+            attributes,
+        })
+    }
+}
+
+fn md5(parts: &[&[u8]]) -> Authenticator {
+    let mut md5 = Md5::new();
+    parts.iter().for_each(|part| md5.input(part));
+    let mut out = [0; AUTH_SIZE];
+    md5.result(&mut out);
+    out
+}
+
+fn hmac_md5(key: &[u8], data: &[u8]) -> MacResult {
+    let mut mac = Hmac::new(Md5::new(), key);
+    mac.input(data);
+    mac.result()
+}
+
+// HMAC-MD5 of the packet with a zeroed Message-Authenticator, `authenticator`
+// is the one of the request
+fn message_authenticator(
+    secret: &[u8],
+    packet: &Packet,
+    authenticator: &Authenticator,
+) -> MacResult {
+    let mut zeroed = packet.clone();
+    zeroed.authenticator = *authenticator;
+    for (kind, value) in &mut zeroed.attributes {
+        if *kind == MESSAGE_AUTHENTICATOR {
+            value.iter_mut().for_each(|b| *b = 0);
+        }
+    }
+    hmac_md5(secret, &zeroed.encode())
+}
+
+fn verify_message_authenticator(
+    secret: &[u8],
+    packet: &Packet,
+    authenticator: &Authenticator,
+) -> bool {
+    match packet.attribute(MESSAGE_AUTHENTICATOR) {
+        Some(mac) => message_authenticator(secret, packet, authenticator) == MacResult::new(mac),
+        None => false,
+    }
+}
+
+// Adds the Message-Authenticator and computes the Response Authenticator
+fn seal_response(secret: &[u8], mut packet: Packet, request: &Authenticator) -> Vec<u8> {
+    packet.authenticator = *request;
+    packet
+        .attributes
+        .push((MESSAGE_AUTHENTICATOR, vec![0; AUTH_SIZE]));
+    let mac = message_authenticator(secret, &packet, request);
+    packet.attributes.last_mut().unwrap().1 = mac.code().to_vec();
+    let bytes = packet.encode();
+    packet.authenticator = md5(&[&bytes, secret]);
+    packet.encode()
+}
+
+// User-Password hiding, RFC 2865 section 5.2
+pub fn hide_password(secret: &[u8], authenticator: &Authenticator, password: &[u8]) -> Vec<u8> {
+    let mut padded = password.to_vec();
+    padded.resize(password.len().div_ceil(AUTH_SIZE).max(1) * AUTH_SIZE, 0);
+    let mut prev = authenticator.to_vec();
+    for block in padded.chunks_mut(AUTH_SIZE) {
+        let key = md5(&[secret, &prev]);
+        block.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+        prev = block.to_vec();
+    }
+    padded
+}
+
+pub fn reveal_password(
+    secret: &[u8],
+    authenticator: &Authenticator,
+    hidden: &[u8],
+) -> Option<Vec<u8>> {
+    if hidden.is_empty() || hidden.len() > 128 || !hidden.len().is_multiple_of(AUTH_SIZE) {
+        return None;
+    }
+    let mut password = Vec::with_capacity(hidden.len());
+    let mut prev = &authenticator[..];
+    for block in hidden.chunks(AUTH_SIZE) {
+        let key = md5(&[secret, prev]);
+        password.extend(block.iter().zip(key).map(|(b, k)| b ^ k));
+        prev = block;
+    }
+    while password.last() == Some(&0) {
+        password.pop();
+    }
+    Some(password)
+}
+
+struct Pending {
+    user: String,
+    expires: u64,
+}
+
+pub struct RadiusServer<S: Store, C: Clock> {
+    store: S,
+    policy: Policy<C>,
+    secrets: HashMap<IpAddr, Vec<u8>>,
+    pending: HashMap<[u8; 16], Pending>,
+    replies: HashMap<(SocketAddr, u8, Authenticator), (u64, Vec<u8>)>,
+}
+
+impl<S: Store, C: Clock> RadiusServer<S, C> {
+    pub fn new(store: S, policy: Policy<C>, secrets: HashMap<IpAddr, Vec<u8>>) -> Self {
+        Self {
+            store,
+            policy,
+            secrets,
+            pending: HashMap::new(),
+            replies: HashMap::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    // Returns the datagram to send back, if any. Errors are faults of the
+    // store, the client isn't answered then and will retry.
+    pub fn handle(
+        &mut self,
+        from: SocketAddr,
+        datagram: &[u8],
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        let secret = match self.secrets.get(&from.ip()) {
+            Some(secret) => secret.clone(),
+            None => return Ok(None),
+        };
+        let request = match Packet::decode(datagram) {
+            Some(packet) if packet.code == ACCESS_REQUEST => packet,
+            _ => return Ok(None),
+        };
+        if !verify_message_authenticator(&secret, &request, &request.authenticator) {
+            return Ok(None);
+        }
+
+        let now = self.policy.clock().now();
+        self.replies.retain(|_, (time, _)| *time + REPLY_TTL > now);
+        let key = (from, request.id, request.authenticator);
+        if let Some((_, reply)) = self.replies.get(&key) {
+            return Ok(Some(reply.clone()));
+        }
+
+        let reply = self.answer(&secret, &request, now)?;
+        let reply = seal_response(&secret, reply, &request.authenticator);
+        self.replies.insert(key, (now, reply.clone()));
+        Ok(Some(reply))
+    }
+
+    fn answer(&mut self, secret: &[u8], request: &Packet, now: u64) -> Result<Packet, StoreError> {
+        let reply = |code, message: Option<String>| Packet {
+            code,
+            id: request.id,
+            authenticator: [0; AUTH_SIZE],
+            attributes: message
+                .map(|m| (REPLY_MESSAGE, m.into_bytes()))
+                .into_iter()
+                .collect(),
+        };
+        let reject = |message: &str| Ok(reply(ACCESS_REJECT, Some(message.to_owned())));
+
+        let user = match request
+            .attribute(USER_NAME)
+            .and_then(|user| std::str::from_utf8(user).ok())
+        {
+            Some(user) => user.to_owned(),
+            None => return reject("missing user name"),
+        };
+        let record = match self.store.get(&user)? {
+            Some(record) if record.algorithm.is_chain() => record,
+            _ => return reject("authentication failed"),
+        };
+
+        self.pending.retain(|_, pending| pending.expires > now);
+        let password = request
+            .attribute(USER_PASSWORD)
+            .and_then(|hidden| reveal_password(secret, &request.authenticator, hidden))
+            .and_then(|password| String::from_utf8(password).ok())
+            .and_then(|password| encoding::decode_any(&password, record.algorithm.size()));
+        let challenged = match request.attribute(STATE) {
+            Some(state) => match <[u8; 16]>::try_from(state)
+                .ok()
+                .and_then(|state| self.pending.remove(&state))
+            {
+                Some(pending) if pending.user == user => true,
+                _ => return reject("challenge has expired"),
+            },
+            None => false,
+        };
+
+        let password = match password {
+            Some(password) => password,
+            // An answer to a challenge has to be a password
+            None if challenged => vec![],
+            None => {
+                if self.pending.len() >= MAX_PENDING {
+                    return reject("too many pending challenges");
+                }
+                let state: [u8; 16] = rand::thread_rng().gen();
+                self.pending.insert(
+                    state,
+                    Pending {
+                        user,
+                        expires: now + CHALLENGE_TTL,
+                    },
+                );
+                let mut challenge = reply(
+                    ACCESS_CHALLENGE,
+                    Some(format!("otp-{} {}", record.algorithm, record.round)),
+                );
+                challenge.attributes.push((STATE, state.to_vec()));
+                return Ok(challenge);
+            }
+        };
+
+        match self.policy.accept(&mut self.store, &user, &password, None) {
+            Ok(()) => Ok(reply(ACCESS_ACCEPT, None)),
+            Err(PolicyError::Locked { .. }) => reject("user is locked"),
+            Err(PolicyError::Backoff { .. }) => reject("too many attempts, try again later"),
+            Err(PolicyError::Store(StoreError::Auth(_) | StoreError::NotFound)) => {
+                reject("authentication failed")
+            }
+            Err(PolicyError::Store(err)) => Err(err),
+        }
+    }
+
+    // Answers packets until the socket fails. Store errors only cost the
+    // packet they came up in and go to `on_error`.
+    pub fn serve(
+        &mut self,
+        socket: &UdpSocket,
+        mut on_error: impl FnMut(StoreError),
+    ) -> io::Result<()> {
+        let mut buf = [0; MAX_PACKET];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf)?;
+            match self.handle(from, &buf[..len]) {
+                Ok(Some(reply)) => {
+                    socket.send_to(&reply, from)?;
+                }
+                Ok(None) => {}
+                // The client retries, maybe the store has recovered by then
+                Err(err) => on_error(err),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u8,
+    pub message: Option<String>,
+    pub state: Option<Vec<u8>>,
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self.code {
+            ACCESS_ACCEPT => "Access-Accept",
+            ACCESS_REJECT => "Access-Reject",
+            ACCESS_CHALLENGE => "Access-Challenge",
+            _ => "unknown",
+        };
+        match &self.message {
+            Some(message) => write!(f, "{}: {}", code, message),
+            None => f.write_str(code),
+        }
+    }
+}
+
+// Minimal client, for tests and for checking a deployment by hand
+pub struct Client {
+    socket: UdpSocket,
+    secret: Vec<u8>,
+    id: u8,
+}
+
+impl Client {
+    pub fn new(server: impl ToSocketAddrs, secret: &[u8]) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(server)?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Ok(Self {
+            socket,
+            secret: secret.to_vec(),
+            id: rand::thread_rng().gen(),
+        })
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.socket.set_read_timeout(Some(timeout))
+    }
+
+    // Builds a signed Access-Request
+    pub fn request(
+        &mut self,
+        user: &str,
+        password: &str,
+        state: Option<&[u8]>,
+    ) -> (Packet, Vec<u8>) {
+        self.id = self.id.wrapping_add(1);
+        let authenticator: Authenticator = rand::thread_rng().gen();
+        let mut packet = Packet {
+            code: ACCESS_REQUEST,
+            id: self.id,
+            authenticator,
+            attributes: vec![
+                (USER_NAME, user.as_bytes().to_vec()),
+                (
+                    USER_PASSWORD,
+                    hide_password(&self.secret, &authenticator, password.as_bytes()),
+                ),
+            ],
+        };
+        if let Some(state) = state {
+            packet.attributes.push((STATE, state.to_vec()));
+        }
+        packet
+            .attributes
+            .push((MESSAGE_AUTHENTICATOR, vec![0; AUTH_SIZE]));
+        let mac = message_authenticator(&self.secret, &packet, &authenticator);
+        packet.attributes.last_mut().unwrap().1 = mac.code().to_vec();
+        let bytes = packet.encode();
+        (packet, bytes)
+    }
+
+    // Sends a request, retransmitting it a few times, and checks the reply
+    pub fn send(&mut self, request: &Packet, bytes: &[u8]) -> io::Result<Reply> {
+        let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+        let mut buf = [0; MAX_PACKET];
+        for _ in 0..3 {
+            self.socket.send(bytes)?;
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => continue,
+                Err(err) => return Err(err),
+            };
+
+            let reply = Packet::decode(&buf[..len]).ok_or_else(|| invalid("malformed reply"))?;
+            if reply.id != request.id {
+                continue;
+            }
+            let mut unsigned = reply.clone();
+            unsigned.authenticator = request.authenticator;
+            let expected = md5(&[&unsigned.encode(), &self.secret]);
+            if !crypto::util::fixed_time_eq(&expected, &reply.authenticator)
+                || !verify_message_authenticator(&self.secret, &reply, &request.authenticator)
+            {
+                return Err(invalid("reply isn't signed with the shared secret"));
+            }
+            return Ok(Reply {
+                code: reply.code,
+                message: reply
+                    .attribute(REPLY_MESSAGE)
+                    .map(|m| String::from_utf8_lossy(m).into_owned()),
+                state: reply.attribute(STATE).map(<[u8]>::to_vec),
+            });
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no reply from the server",
+        ))
+    }
+
+    pub fn authenticate(
+        &mut self,
+        user: &str,
+        password: &str,
+        state: Option<&[u8]>,
+    ) -> io::Result<Reply> {
+        let (packet, bytes) = self.request(user, password, state);
+        self.send(&packet, &bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        base::{PrivateKey, State},
+        encoding::{encode, to_hex, Format},
+        policy::{PolicyConfig, SystemClock},
+        store::{MemoryStore, Record},
+    };
+    use std::{net::Ipv4Addr, thread};
+
+    const SECRET: &[u8] = b"testing123";
+
+    fn start(secrets: &[(IpAddr, &[u8])]) -> (SocketAddr, PrivateKey<crypto::sha2::Sha256, 32>) {
+        let (private, record) = Record::generate_sha256(10);
+        let mut store = MemoryStore::new();
+        store.enroll("alice", record).unwrap();
+
+        let secrets = secrets.iter().map(|(ip, s)| (*ip, s.to_vec())).collect();
+        let policy = Policy::new(PolicyConfig::default(), SystemClock);
+        let mut server = RadiusServer::new(store, policy, secrets);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || server.serve(&socket, |err| panic!("{}", err)));
+        (addr, private)
+    }
+
+    fn localhost() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    }
+
+    #[test]
+    fn password_hiding() {
+        let authenticator = [7; 16];
+        for len in [0, 1, 15, 16, 17, 64, 128] {
+            let password = vec![b'x'; len];
+            let hidden = hide_password(SECRET, &authenticator, &password);
+            assert_eq!(hidden.len(), len.div_ceil(16).max(1) * 16);
+            if len > 0 {
+                assert_ne!(&hidden[..len], &password[..]);
+            }
+            let revealed = reveal_password(SECRET, &authenticator, &hidden).unwrap();
+            assert_eq!(revealed, password);
+        }
+        assert!(reveal_password(SECRET, &authenticator, &[0; 15]).is_none());
+
+        let packet = Packet::decode(
+            &Client::new("127.0.0.1:1", SECRET)
+                .unwrap()
+                .request("a", "b", None)
+                .1,
+        )
+        .unwrap();
+        assert_eq!(Packet::decode(&packet.encode()).unwrap(), packet);
+        assert!(Packet::decode(&packet.encode()[..25]).is_none());
+    }
+
+    #[test]
+    fn pap_and_challenge() {
+        let (addr, mut private) = start(&[(localhost(), SECRET)]);
+        let mut client = Client::new(addr, SECRET).unwrap();
+
+        // The OTP right away
+        let password = private.get_password().unwrap();
+        let reply = client
+            .authenticate("alice", &to_hex(&password[..]), None)
+            .unwrap();
+        assert_eq!(reply.code, ACCESS_ACCEPT);
+        let reply = client
+            .authenticate("alice", &to_hex(&password[..]), None)
+            .unwrap();
+        assert_eq!(reply.code, ACCESS_REJECT);
+        assert_eq!(private.pop_password(), State::Ok);
+        thread::sleep(Duration::from_millis(1100));
+
+        // A static password is answered with a challenge
+        let reply = client.authenticate("alice", "hunter2", None).unwrap();
+        assert_eq!(reply.code, ACCESS_CHALLENGE);
+        assert_eq!(reply.message.as_deref(), Some("otp-sha256 2"));
+        let state = reply.state.unwrap();
+        // 32 bytes in words don't fit the 128 bytes of a PAP password
+        let password = encode(&private.get_password().unwrap()[..], Format::Decimal);
+        let reply = client
+            .authenticate("alice", &password, Some(&state))
+            .unwrap();
+        assert_eq!(reply.code, ACCESS_ACCEPT, "{}", reply);
+        // The state is used up
+        let reply = client
+            .authenticate("alice", &password, Some(&state))
+            .unwrap();
+        assert_eq!(reply.code, ACCESS_REJECT);
+
+        let reply = client.authenticate("bob", "", None).unwrap();
+        assert_eq!(reply.code, ACCESS_REJECT);
+    }
+
+    #[test]
+    fn retransmit_gets_the_same_answer() {
+        let (addr, private) = start(&[(localhost(), SECRET)]);
+        let mut client = Client::new(addr, SECRET).unwrap();
+        let password = to_hex(&private.get_password().unwrap()[..]);
+        let (packet, bytes) = client.request("alice", &password, None);
+        assert_eq!(client.send(&packet, &bytes).unwrap().code, ACCESS_ACCEPT);
+        assert_eq!(client.send(&packet, &bytes).unwrap().code, ACCESS_ACCEPT);
+    }
+
+    #[test]
+    fn unsigned_requests_are_dropped() {
+        let other: IpAddr = "192.0.2.1".parse().unwrap();
+        let (addr, private) = start(&[(localhost(), SECRET), (other, b"other")]);
+        let password = to_hex(&private.get_password().unwrap()[..]);
+
+        // Wrong shared secret
+        let mut client = Client::new(addr, b"wrong").unwrap();
+        client.set_timeout(Duration::from_millis(100)).unwrap();
+        let err = client.authenticate("alice", &password, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Missing Message-Authenticator
+        let mut client = Client::new(addr, SECRET).unwrap();
+        client.set_timeout(Duration::from_millis(100)).unwrap();
+        let (mut packet, _) = client.request("alice", &password, None);
+        packet
+            .attributes
+            .retain(|(kind, _)| *kind != MESSAGE_AUTHENTICATOR);
+        let err = client.send(&packet, &packet.encode()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Tampered attributes
+        let (packet, mut bytes) = client.request("alice", &password, None);
+        bytes[HEADER_SIZE + 2] ^= 1;
+        assert!(client.send(&packet, &bytes).is_err());
+
+        let reply = client.authenticate("alice", &password, None).unwrap();
+        assert_eq!(reply.code, ACCESS_ACCEPT);
+    }
+}
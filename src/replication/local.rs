@@ -0,0 +1,274 @@
+use std::{
+    collections::HashSet,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use super::{Message, Node, NodeId, ReplicaError, Reply, Transport};
+use crate::store::{Record, Store};
+
+// In-process network for deterministic tests. Calls are delivered
+// synchronously, links can be cut and nodes crashed and restarted.
+//
+// A node is locked while it handles a request and while it talks to its
+// peers, so the network is meant to be driven from a single thread.
+pub struct LocalNetwork<S: Store> {
+    nodes: Vec<Mutex<Node<S>>>,
+    cut: Mutex<HashSet<(NodeId, NodeId)>>,
+    down: Mutex<HashSet<NodeId>>,
+}
+
+impl<S: Store> LocalNetwork<S> {
+    pub fn new(stores: Vec<S>) -> Self {
+        let ids = (0..stores.len()).collect::<Vec<_>>();
+        let nodes = stores
+            .into_iter()
+            .enumerate()
+            .map(|(id, store)| {
+                let peers = ids.iter().copied().filter(|peer| *peer != id).collect();
+                Mutex::new(Node::new(id, peers, store))
+            })
+            .collect();
+        Self {
+            nodes,
+            cut: Mutex::new(HashSet::new()),
+            down: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn node(&self, id: NodeId) -> MutexGuard<'_, Node<S>> {
+        self.nodes[id]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Cuts every link between the two groups, in both directions
+    pub fn partition(&self, left: &[NodeId], right: &[NodeId]) {
+        let mut cut = self.cut.lock().unwrap_or_else(PoisonError::into_inner);
+        for a in left {
+            for b in right {
+                cut.insert((*a, *b));
+                cut.insert((*b, *a));
+            }
+        }
+    }
+
+    pub fn heal(&self) {
+        self.cut
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    pub fn crash(&self, id: NodeId) {
+        self.down
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id);
+    }
+
+    pub fn restart(&self, id: NodeId, store: S) {
+        self.node(id).restart(store);
+        self.down
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&id);
+    }
+
+    fn is_down(&self, id: NodeId) -> bool {
+        self.down
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(&id)
+    }
+
+    pub fn campaign(&self, id: NodeId) -> Result<(), ReplicaError> {
+        if self.is_down(id) {
+            return Err(ReplicaError::Unavailable);
+        }
+        self.node(id).campaign(self)
+    }
+
+    pub fn heartbeat(&self, id: NodeId) -> Result<(), ReplicaError> {
+        if self.is_down(id) {
+            return Err(ReplicaError::Unavailable);
+        }
+        self.node(id).replicate(self)
+    }
+
+    pub fn put(&self, id: NodeId, user: &str, record: Record) -> Result<(), ReplicaError> {
+        if self.is_down(id) {
+            return Err(ReplicaError::Unavailable);
+        }
+        self.node(id).put(self, user, record)
+    }
+
+    pub fn accept(
+        &self,
+        id: NodeId,
+        user: &str,
+        password: &[u8],
+        next: Option<&[u8]>,
+    ) -> Result<(), ReplicaError> {
+        if self.is_down(id) {
+            return Err(ReplicaError::Unavailable);
+        }
+        self.node(id).accept(self, user, password, next)
+    }
+}
+
+impl<S: Store> Transport for LocalNetwork<S> {
+    fn call(&self, from: NodeId, to: NodeId, msg: Message) -> Option<Reply> {
+        let cut = self
+            .cut
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(&(from, to));
+        if cut || self.is_down(from) || self.is_down(to) {
+            return None;
+        }
+        Some(self.node(to).handle(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        base::{PrivateKey, State},
+        replication::Role,
+        store::{MemoryStore, StoreError},
+    };
+
+    type Private = PrivateKey<crypto::sha2::Sha256, 32>;
+
+    fn cluster(size: usize) -> (LocalNetwork<MemoryStore>, Private) {
+        let network = LocalNetwork::new((0..size).map(|_| MemoryStore::new()).collect());
+        network.campaign(0).unwrap();
+
+        let (private, record) = Record::generate_sha256(10);
+        network.put(0, "alice", record).unwrap();
+        (network, private)
+    }
+
+    fn round(network: &LocalNetwork<MemoryStore>, id: NodeId) -> Option<u64> {
+        network
+            .node(id)
+            .store()
+            .get("alice")
+            .unwrap()
+            .map(|r| r.round)
+    }
+
+    fn is_auth_error(result: Result<(), ReplicaError>) -> bool {
+        matches!(result, Err(ReplicaError::Store(StoreError::Auth(_))))
+    }
+
+    #[test]
+    fn replicates_to_followers() {
+        let (network, private) = cluster(3);
+        let p1 = private.get_password().unwrap();
+
+        network.accept(0, "alice", &p1[..], None).unwrap();
+        assert!(is_auth_error(network.accept(0, "alice", &p1[..], None)));
+        assert!(matches!(
+            network.accept(1, "alice", &p1[..], None),
+            Err(ReplicaError::NotLeader { leader: Some(0) })
+        ));
+
+        network.heartbeat(0).unwrap();
+        for id in 0..3 {
+            assert_eq!(round(&network, id), Some(2));
+        }
+    }
+
+    #[test]
+    fn new_leader_rejects_replay() {
+        let (network, private) = cluster(3);
+        let p1 = private.get_password().unwrap();
+        network.accept(0, "alice", &p1[..], None).unwrap();
+
+        // The old leader is cut off before it could tell anyone about the
+        // commit index
+        network.partition(&[0], &[1, 2]);
+        network.campaign(1).unwrap();
+        assert!(is_auth_error(network.accept(1, "alice", &p1[..], None)));
+
+        // The stale leader can't get anything through
+        let mut private = private;
+        assert_eq!(private.pop_password(), State::Ok);
+        let p2 = private.get_password().unwrap();
+        assert!(matches!(
+            network.accept(0, "alice", &p2[..], None),
+            Err(ReplicaError::Unavailable)
+        ));
+
+        network.heal();
+        assert!(matches!(
+            network.accept(0, "alice", &p2[..], None),
+            Err(ReplicaError::NotLeader { .. })
+        ));
+        assert_eq!(network.node(0).role(), Role::Follower);
+        network.accept(1, "alice", &p2[..], None).unwrap();
+    }
+
+    #[test]
+    fn minority_leader_never_acknowledges() {
+        let (network, private) = cluster(5);
+        let p1 = private.get_password().unwrap();
+
+        network.partition(&[0, 1], &[2, 3, 4]);
+        assert!(matches!(
+            network.accept(0, "alice", &p1[..], None),
+            Err(ReplicaError::Unavailable)
+        ));
+
+        network.campaign(2).unwrap();
+        network.accept(2, "alice", &p1[..], None).unwrap();
+
+        network.heal();
+        assert!(network.accept(0, "alice", &p1[..], None).is_err());
+        assert!(is_auth_error(network.accept(2, "alice", &p1[..], None)));
+
+        // The uncommitted entry of the old leader is gone everywhere
+        network.heartbeat(2).unwrap();
+        let log = network.node(2).log().to_vec();
+        for id in 0..5 {
+            assert_eq!(network.node(id).log(), log);
+            assert_eq!(round(&network, id), Some(2));
+        }
+    }
+
+    #[test]
+    fn restarted_node_catches_up() {
+        let (network, mut private) = cluster(3);
+
+        network.crash(2);
+        let p1 = private.get_password().unwrap();
+        network.accept(0, "alice", &p1[..], None).unwrap();
+        assert_eq!(private.pop_password(), State::Ok);
+
+        network.restart(2, MemoryStore::new());
+        assert_eq!(round(&network, 2), None);
+        network.heartbeat(0).unwrap();
+        assert_eq!(round(&network, 2), Some(2));
+
+        // Node 2 can take over once the old leader is gone
+        network.crash(0);
+        network.campaign(2).unwrap();
+        assert!(is_auth_error(network.accept(2, "alice", &p1[..], None)));
+        network
+            .accept(2, "alice", &private.get_password().unwrap()[..], None)
+            .unwrap();
+
+        // A lagging node can't win an election
+        network.restart(0, MemoryStore::new());
+        network.crash(2);
+        assert!(matches!(
+            network.campaign(0),
+            Err(ReplicaError::Unavailable)
+        ));
+        network.campaign(1).unwrap();
+        network.heartbeat(1).unwrap();
+        assert_eq!(round(&network, 0), Some(3));
+    }
+}
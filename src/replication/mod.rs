@@ -0,0 +1,460 @@
+use std::fmt;
+
+use crate::store::{Record, Store, StoreError};
+
+pub mod local;
+
+pub use local::LocalNetwork;
+
+// Replicated verifier store. A stripped-down Raft: elections are triggered
+// explicitly instead of by timers, but terms, the vote restriction and the
+// commit rule are the same. An accept is acknowledged only after the new
+// record sits in the logs of a majority, so two replicas can never both
+// accept the same round for a user.
+
+pub type NodeId = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Noop,
+    Put { user: String, record: Record },
+    Remove { user: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub term: u64,
+    pub op: Op,
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        candidate: NodeId,
+        last_index: usize,
+        last_term: u64,
+    },
+    AppendEntries {
+        term: u64,
+        leader: NodeId,
+        prev_index: usize,
+        prev_term: u64,
+        entries: Vec<LogEntry>,
+        commit: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum Reply {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    Appended {
+        term: u64,
+        match_index: Option<usize>,
+    },
+}
+
+pub trait Transport {
+    // `None` if `to` can't be reached
+    fn call(&self, from: NodeId, to: NodeId, msg: Message) -> Option<Reply>;
+}
+
+#[derive(Debug)]
+pub enum ReplicaError {
+    NotLeader { leader: Option<NodeId> },
+    // No majority could be reached, nothing was acknowledged
+    Unavailable,
+    Store(StoreError),
+}
+
+impl fmt::Display for ReplicaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicaError::NotLeader { leader: Some(id) } => {
+                write!(f, "not the leader, try node {}", id)
+            }
+            ReplicaError::NotLeader { leader: None } => f.write_str("not the leader"),
+            ReplicaError::Unavailable => f.write_str("no majority available"),
+            ReplicaError::Store(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ReplicaError {}
+
+impl From<StoreError> for ReplicaError {
+    fn from(err: StoreError) -> Self {
+        ReplicaError::Store(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+pub struct Node<S: Store> {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    // Durable state
+    term: u64,
+    voted_for: Option<NodeId>,
+    log: Vec<LogEntry>,
+    // Volatile state, `store` holds the first `applied` committed entries
+    role: Role,
+    leader: Option<NodeId>,
+    commit: usize,
+    applied: usize,
+    store: S,
+    // Leader only, indexed like `peers`
+    match_index: Vec<usize>,
+}
+
+impl<S: Store> Node<S> {
+    pub fn new(id: NodeId, peers: Vec<NodeId>, store: S) -> Self {
+        let match_index = vec![0; peers.len()];
+        Self {
+            id,
+            peers,
+            term: 0,
+            voted_for: None,
+            log: vec![],
+            role: Role::Follower,
+            leader: None,
+            commit: 0,
+            applied: 0,
+            store,
+            match_index,
+        }
+    }
+
+    #[must_use]
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    #[must_use]
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    #[must_use]
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    #[must_use]
+    pub fn log(&self) -> &[LogEntry] {
+        &self.log
+    }
+
+    #[must_use]
+    pub fn commit_index(&self) -> usize {
+        self.commit
+    }
+
+    // Applied state, possibly behind the leader on followers
+    #[must_use]
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    // Simulates a restart: durable state survives, the store is rebuilt from
+    // the log once the commit index is learned again
+    pub fn restart(&mut self, store: S) {
+        self.role = Role::Follower;
+        self.leader = None;
+        self.commit = 0;
+        self.applied = 0;
+        self.store = store;
+    }
+
+    fn majority(&self) -> usize {
+        let size = self.peers.len() + 1;
+        size / 2 + 1
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(0, |e| e.term)
+    }
+
+    fn term_at(&self, index: usize) -> u64 {
+        match index {
+            0 => 0,
+            _ => self.log[index - 1].term,
+        }
+    }
+
+    fn step_down(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+        }
+        self.role = Role::Follower;
+    }
+
+    fn apply(&mut self) -> Result<(), StoreError> {
+        while self.applied < self.commit {
+            match &self.log[self.applied].op {
+                Op::Noop => {}
+                Op::Put { user, record } => self.store.put(user, record.clone())?,
+                Op::Remove { user } => {
+                    self.store.remove(user)?;
+                }
+            }
+            self.applied += 1;
+        }
+        Ok(())
+    }
+
+    pub fn handle(&mut self, msg: Message) -> Reply {
+        match msg {
+            Message::RequestVote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => {
+                if term > self.term {
+                    self.step_down(term);
+                }
+                let up_to_date = (last_term, last_index) >= (self.last_term(), self.log.len());
+                let granted = term == self.term
+                    && up_to_date
+                    && self.voted_for.is_none_or(|id| id == candidate);
+                if granted {
+                    self.voted_for = Some(candidate);
+                }
+                Reply::Vote {
+                    term: self.term,
+                    granted,
+                }
+            }
+            Message::AppendEntries {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                if term < self.term {
+                    return Reply::Appended {
+                        term: self.term,
+                        match_index: None,
+                    };
+                }
+                self.step_down(term);
+                self.leader = Some(leader);
+
+                if prev_index > self.log.len() || self.term_at(prev_index) != prev_term {
+                    return Reply::Appended {
+                        term: self.term,
+                        match_index: None,
+                    };
+                }
+
+                let match_index = prev_index + entries.len();
+                for (i, entry) in entries.into_iter().enumerate() {
+                    let index = prev_index + i + 1;
+                    if index <= self.log.len() {
+                        if self.log[index - 1].term == entry.term {
+                            continue;
+                        }
+                        // Conflicting entries are never committed
+                        self.log.truncate(index - 1);
+                    }
+                    self.log.push(entry);
+                }
+
+                self.commit = self.commit.max(commit.min(match_index));
+                // A follower that can't apply committed entries is broken
+                // beyond what the protocol can fix
+                self.apply().expect("failed to apply committed entries");
+                Reply::Appended {
+                    term: self.term,
+                    match_index: Some(match_index),
+                }
+            }
+        }
+    }
+
+    pub fn campaign(&mut self, transport: &impl Transport) -> Result<(), ReplicaError> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+
+        let mut votes = 1;
+        for peer in self.peers.clone() {
+            let msg = Message::RequestVote {
+                term: self.term,
+                candidate: self.id,
+                last_index: self.log.len(),
+                last_term: self.last_term(),
+            };
+            match transport.call(self.id, peer, msg) {
+                Some(Reply::Vote { term, .. }) if term > self.term => {
+                    self.step_down(term);
+                    return Err(ReplicaError::NotLeader { leader: None });
+                }
+                Some(Reply::Vote { granted: true, .. }) => votes += 1,
+                _ => {}
+            }
+        }
+
+        if votes < self.majority() {
+            self.role = Role::Follower;
+            return Err(ReplicaError::Unavailable);
+        }
+
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.match_index = vec![0; self.peers.len()];
+        // Committing an entry of the new term also commits everything the
+        // previous leaders left behind
+        self.log.push(LogEntry {
+            term: self.term,
+            op: Op::Noop,
+        });
+        self.replicate(transport)
+    }
+
+    // Brings every reachable follower up to date and advances the commit
+    // index. Fails if the entries can't reach a majority.
+    pub fn replicate(&mut self, transport: &impl Transport) -> Result<(), ReplicaError> {
+        if self.role != Role::Leader {
+            return Err(ReplicaError::NotLeader {
+                leader: self.leader,
+            });
+        }
+
+        for (i, peer) in self.peers.clone().into_iter().enumerate() {
+            // Walk back from the end of the log until the follower agrees
+            let mut prev_index = self.match_index[i].max(self.log.len().saturating_sub(1));
+            loop {
+                let msg = Message::AppendEntries {
+                    term: self.term,
+                    leader: self.id,
+                    prev_index,
+                    prev_term: self.term_at(prev_index),
+                    entries: self.log[prev_index..].to_vec(),
+                    commit: self.commit,
+                };
+                match transport.call(self.id, peer, msg) {
+                    Some(Reply::Appended { term, .. }) if term > self.term => {
+                        self.step_down(term);
+                        return Err(ReplicaError::NotLeader { leader: None });
+                    }
+                    Some(Reply::Appended {
+                        match_index: Some(index),
+                        ..
+                    }) => {
+                        self.match_index[i] = index;
+                        break;
+                    }
+                    Some(Reply::Appended {
+                        match_index: None, ..
+                    }) if prev_index > 0 => prev_index -= 1,
+                    _ => break,
+                }
+            }
+        }
+
+        let mut matched = self.match_index.clone();
+        matched.push(self.log.len());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.majority() - 1];
+        // Only entries of the current term are committed by counting
+        if index > self.commit && self.term_at(index) == self.term {
+            self.commit = index;
+        }
+        self.apply()?;
+
+        if self.commit == self.log.len() {
+            Ok(())
+        } else {
+            Err(ReplicaError::Unavailable)
+        }
+    }
+
+    fn propose(&mut self, transport: &impl Transport, op: Op) -> Result<(), ReplicaError> {
+        self.log.push(LogEntry {
+            term: self.term,
+            op,
+        });
+        self.replicate(transport)
+    }
+
+    fn ready(&mut self, transport: &impl Transport) -> Result<(), ReplicaError> {
+        if self.role != Role::Leader {
+            return Err(ReplicaError::NotLeader {
+                leader: self.leader,
+            });
+        }
+        // Entries left uncommitted by a failed request have to settle first,
+        // otherwise the local state might miss an accepted round
+        if self.commit < self.log.len() {
+            self.replicate(transport)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, user: &str) -> Result<Option<Record>, ReplicaError> {
+        Ok(self.store.get(user)?)
+    }
+
+    pub fn put(
+        &mut self,
+        transport: &impl Transport,
+        user: &str,
+        record: Record,
+    ) -> Result<(), ReplicaError> {
+        self.ready(transport)?;
+        self.propose(
+            transport,
+            Op::Put {
+                user: user.to_owned(),
+                record,
+            },
+        )
+    }
+
+    pub fn remove(&mut self, transport: &impl Transport, user: &str) -> Result<(), ReplicaError> {
+        self.ready(transport)?;
+        self.propose(
+            transport,
+            Op::Remove {
+                user: user.to_owned(),
+            },
+        )
+    }
+
+    // Linearizable "accept round r for user u"
+    pub fn accept(
+        &mut self,
+        transport: &impl Transport,
+        user: &str,
+        password: &[u8],
+        next: Option<&[u8]>,
+    ) -> Result<(), ReplicaError> {
+        self.ready(transport)?;
+        let mut record = self.store.get(user)?.ok_or(StoreError::NotFound)?;
+        record
+            .accept(password, next)
+            .map_err(|err| ReplicaError::Store(err.into()))?;
+        self.propose(
+            transport,
+            Op::Put {
+                user: user.to_owned(),
+                record,
+            },
+        )
+    }
+}
@@ -0,0 +1,238 @@
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+#[cfg(feature = "std")]
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock, PoisonError,
+    },
+};
+
+use rand_core::{CryptoRng, RngCore};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+// Secret bytes. The value lives in its own heap allocation, so moving a
+// `Secret` doesn't leave copies behind, and is zeroed on drop. It isn't
+// `Copy`, clones are explicit, and `Debug` doesn't print it.
+//
+// With `set_mlock(true)` the pages of every secret created afterwards are
+// also locked in memory to keep them out of swap. Several small secrets
+// share a page, so locked pages are reference counted.
+//
+// Without the `std` feature the value is stored inline instead and mlock
+// isn't available, moves may then leave copies behind.
+
+#[cfg(feature = "std")]
+static MLOCK: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "std")]
+pub fn set_mlock(enabled: bool) {
+    MLOCK.store(enabled, Ordering::SeqCst);
+}
+
+#[cfg(feature = "std")]
+#[must_use]
+pub fn mlock_enabled() -> bool {
+    MLOCK.load(Ordering::SeqCst)
+}
+
+#[cfg(feature = "std")]
+fn locked_pages() -> &'static Mutex<HashMap<usize, usize>> {
+    static PAGES: OnceLock<Mutex<HashMap<usize, usize>>> = OnceLock::new();
+    PAGES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+#[cfg(feature = "std")]
+fn pages(ptr: *const u8, len: usize) -> impl Iterator<Item = usize> {
+    let size = usize::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) })
+        .expect("sorry, architecture is not supported");
+    let start = ptr as usize / size * size;
+    let end = ptr as usize + len.max(1);
+    (start..end).step_by(size)
+}
+
+// Best effort: failing to lock, e.g. over RLIMIT_MEMLOCK, isn't an error
+#[cfg(feature = "std")]
+fn lock(ptr: *const u8, len: usize) -> bool {
+    let mut locked = locked_pages()
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if unsafe { libc::mlock(ptr.cast(), len) } != 0 {
+        return false;
+    }
+    pages(ptr, len).for_each(|page| *locked.entry(page).or_insert(0) += 1);
+    true
+}
+
+#[cfg(feature = "std")]
+fn unlock(ptr: *const u8, len: usize) {
+    let mut locked = locked_pages()
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    for page in pages(ptr, len) {
+        let count = locked.get_mut(&page).expect("page must be locked");
+        *count -= 1;
+        if *count == 0 {
+            locked.remove(&page);
+            unsafe { libc::munlock(page as *const libc::c_void, 1) };
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+type Value<const N: usize> = Box<[u8; N]>;
+#[cfg(not(feature = "std"))]
+type Value<const N: usize> = [u8; N];
+
+pub struct Secret<const N: usize> {
+    value: Value<N>,
+    locked: bool,
+}
+
+impl<const N: usize> Secret<N> {
+    // Wipes the argument, copies made by the caller are its own business
+    pub fn new(mut value: [u8; N]) -> Self {
+        let mut secret = Self::zeroed();
+        secret.value.copy_from_slice(&value);
+        value.zeroize();
+        secret
+    }
+
+    #[cfg(feature = "std")]
+    pub fn zeroed() -> Self {
+        let value = Box::new([0; N]);
+        let locked = mlock_enabled() && lock(value.as_ptr(), N);
+        Self { value, locked }
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub fn zeroed() -> Self {
+        Self {
+            value: [0; N],
+            locked: false,
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn random() -> Self {
+        Self::random_with(&mut rand::thread_rng())
+    }
+
+    pub fn random_with<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        let mut secret = Self::zeroed();
+        rng.fill_bytes(&mut secret.value[..]);
+        secret
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != N {
+            return None;
+        }
+        let mut secret = Self::zeroed();
+        secret.value.copy_from_slice(bytes);
+        Some(secret)
+    }
+
+    #[must_use]
+    pub fn expose(&self) -> &[u8; N] {
+        &self.value
+    }
+
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+// Derefs to a slice rather than the array, so `*secret` can't copy it out
+impl<const N: usize> Deref for Secret<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.value[..]
+    }
+}
+
+impl<const N: usize> DerefMut for Secret<N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value[..]
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for Secret<N> {
+    fn as_ref(&self) -> &[u8] {
+        &self.value[..]
+    }
+}
+
+impl<const N: usize> Clone for Secret<N> {
+    fn clone(&self) -> Self {
+        let mut secret = Self::zeroed();
+        secret.value.copy_from_slice(&self.value[..]);
+        secret
+    }
+}
+
+impl<const N: usize> PartialEq for Secret<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value[..].ct_eq(&other.value[..]).into()
+    }
+}
+
+impl<const N: usize> Eq for Secret<N> {}
+
+impl<const N: usize> fmt::Debug for Secret<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret<{}>(REDACTED)", N)
+    }
+}
+
+impl<const N: usize> Drop for Secret<N> {
+    fn drop(&mut self) {
+        self.value.zeroize();
+        #[cfg(feature = "std")]
+        if self.locked {
+            unlock(self.value.as_ptr(), N);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted() {
+        let secret = Secret::new([0x42; 32]);
+        assert_eq!(secret.expose(), &[0x42; 32]);
+        assert_eq!(format!("{:?}", secret), "Secret<32>(REDACTED)");
+
+        assert_eq!(secret.clone(), secret);
+        assert_ne!(Secret::<32>::random(), secret);
+        assert!(Secret::<16>::from_slice(&[0; 15]).is_none());
+    }
+
+    #[test]
+    fn locked_pages_are_counted() {
+        let a = Secret::<32>::zeroed();
+        let b = Secret::<32>::zeroed();
+        assert!(!a.is_locked());
+
+        let count = |secret: &Secret<32>| {
+            let page = pages(secret.as_ptr(), 32).next().unwrap();
+            locked_pages().lock().unwrap().get(&page).copied()
+        };
+        // Locking can fail under a tight RLIMIT_MEMLOCK
+        if lock(a.as_ptr(), 32) {
+            assert!(count(&a).is_some());
+            if lock(b.as_ptr(), 32) {
+                unlock(b.as_ptr(), 32);
+            }
+            unlock(a.as_ptr(), 32);
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use crypto::{
+    digest::Digest,
+    hkdf::{hkdf_expand, hkdf_extract},
+    hmac::Hmac,
+    mac::Mac,
+    sha2::Sha256,
+};
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_POINT, ristretto::CompressedRistretto, scalar::Scalar,
+    traits::IsIdentity,
+};
+use rand::Rng;
+use zeroize::Zeroize;
+
+use crate::{base::AuthError, secret::Secret};
+
+// Session keys derived from a successful exchange. The revealed element goes
+// over the wire in the clear, so it can't be the key on its own: both sides
+// also run an ephemeral Diffie-Hellman, which keeps the keys from a passive
+// observer. The shares aren't authenticated though. An attacker relaying the
+// exchange sees the revealed element as well and ends up sharing keys with
+// each side, the confirmation MACs don't catch that. Against active
+// attackers run this over a server-authenticated channel.
+
+const KEYS_LABEL: &[u8] = b"diploma/session/v1 keys";
+const CLIENT_LABEL: &[u8] = b"diploma/session/v1 client finished";
+const SERVER_LABEL: &[u8] = b"diploma/session/v1 server finished";
+
+pub type Nonce = [u8; 32];
+pub type Share = [u8; 32];
+
+pub fn nonce() -> Nonce {
+    rand::thread_rng().gen()
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    parts.iter().for_each(|part| mac.input(part));
+    let mut out = [0; 32];
+    mac.raw_result(&mut out);
+    out
+}
+
+fn input_len(d: &mut Sha256, bytes: &[u8]) {
+    let len = u64::try_from(bytes.len())
+        .expect("sorry, architecture is not supported")
+        .to_be_bytes();
+    d.input(&len);
+    d.input(bytes);
+}
+
+// Ephemeral Diffie-Hellman over Ristretto, one per side and session
+pub struct Ephemeral {
+    secret: Scalar,
+}
+
+impl Ephemeral {
+    pub fn new() -> (Self, Share) {
+        let secret = Scalar::random(&mut rand::thread_rng());
+        let share = (secret * RISTRETTO_BASEPOINT_POINT).compress().to_bytes();
+        (Self { secret }, share)
+    }
+
+    pub fn agree(self, peer: &Share) -> Result<Secret<32>, AuthError> {
+        let point = CompressedRistretto(*peer).decompress().ok_or(AuthError)?;
+        if point.is_identity() {
+            return Err(AuthError);
+        }
+        Ok(Secret::new((self.secret * point).compress().to_bytes()))
+    }
+}
+
+impl Drop for Ephemeral {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+pub struct Transcript<'a> {
+    pub user_id: &'a [u8],
+    pub round: usize,
+    pub client_nonce: &'a Nonce,
+    pub server_nonce: &'a Nonce,
+    pub client_share: &'a Share,
+    pub server_share: &'a Share,
+}
+
+impl Transcript<'_> {
+    // Unambiguous hash of the public part of the transcript
+    fn digest(&self) -> [u8; 32] {
+        let round = u64::try_from(self.round)
+            .expect("sorry, architecture is not supported")
+            .to_be_bytes();
+        let mut d = Sha256::new();
+        input_len(&mut d, self.user_id);
+        d.input(&round);
+        d.input(self.client_nonce);
+        d.input(self.server_nonce);
+        d.input(self.client_share);
+        d.input(self.server_share);
+        let mut out = [0; 32];
+        d.result(&mut out);
+        out
+    }
+}
+
+pub struct SessionKeys {
+    pub client_to_server: Secret<32>,
+    pub server_to_client: Secret<32>,
+    confirm: Secret<32>,
+    transcript: [u8; 32],
+}
+
+impl SessionKeys {
+    // `shared` comes from `Ephemeral::agree`, `revealed` is the element
+    // accepted by the verifier: the chain password for `base`, or the
+    // revealed private element for `commitment`
+    pub fn derive(transcript: &Transcript, shared: &Secret<32>, revealed: &[u8]) -> Self {
+        let digest = transcript.digest();
+        let salt = [&transcript.client_nonce[..], &transcript.server_nonce[..]].concat();
+
+        let mut ikm = [shared.as_ref(), revealed].concat();
+        let mut prk = [0; 32];
+        hkdf_extract(Sha256::new(), &salt, &ikm, &mut prk);
+        ikm.zeroize();
+
+        let info = [KEYS_LABEL, &digest[..]].concat();
+        let mut okm = [0; 96];
+        hkdf_expand(Sha256::new(), &prk, &info, &mut okm);
+        prk.zeroize();
+
+        let mut keys = Self {
+            client_to_server: Secret::zeroed(),
+            server_to_client: Secret::zeroed(),
+            confirm: Secret::zeroed(),
+            transcript: digest,
+        };
+        keys.client_to_server.copy_from_slice(&okm[..32]);
+        keys.server_to_client.copy_from_slice(&okm[32..64]);
+        keys.confirm.copy_from_slice(&okm[64..]);
+        okm.zeroize();
+        keys
+    }
+
+    #[must_use]
+    pub fn client_confirmation(&self) -> [u8; 32] {
+        hmac_sha256(&self.confirm, &[CLIENT_LABEL, &self.transcript])
+    }
+
+    #[must_use]
+    pub fn server_confirmation(&self) -> [u8; 32] {
+        hmac_sha256(&self.confirm, &[SERVER_LABEL, &self.transcript])
+    }
+
+    pub fn verify_client_confirmation(&self, mac: &[u8; 32]) -> Result<(), AuthError> {
+        if crypto::util::fixed_time_eq(&self.client_confirmation(), mac) {
+            Ok(())
+        } else {
+            Err(AuthError)
+        }
+    }
+
+    pub fn verify_server_confirmation(&self, mac: &[u8; 32]) -> Result<(), AuthError> {
+        if crypto::util::fixed_time_eq(&self.server_confirmation(), mac) {
+            Ok(())
+        } else {
+            Err(AuthError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        base::State,
+        commitment::{self, ed25519::Ed25519},
+        hash::Sha256Builder,
+    };
+
+    // Both sides of the Diffie-Hellman
+    fn agree() -> (Share, Share, Secret<32>, Secret<32>) {
+        let (client, client_share) = Ephemeral::new();
+        let (server, server_share) = Ephemeral::new();
+        let client_shared = client.agree(&server_share).unwrap();
+        let server_shared = server.agree(&client_share).unwrap();
+        (client_share, server_share, client_shared, server_shared)
+    }
+
+    #[test]
+    fn base_exchange() {
+        let mut private = Sha256Builder::new_private(5);
+        let p0 = private.get_password().unwrap();
+        assert_eq!(private.pop_password(), State::Ok);
+        let mut public = Sha256Builder::new_public(*p0.expose());
+
+        let (client_nonce, server_nonce) = (nonce(), nonce());
+        let round = public.round();
+
+        let p1 = private.get_password().unwrap();
+        assert!(public.verify(p1.expose()).is_ok());
+
+        let (client_share, server_share, client_shared, server_shared) = agree();
+        let transcript = Transcript {
+            user_id: b"alice",
+            round,
+            client_nonce: &client_nonce,
+            server_nonce: &server_nonce,
+            client_share: &client_share,
+            server_share: &server_share,
+        };
+        let client = SessionKeys::derive(&transcript, &client_shared, &p1[..]);
+        let server = SessionKeys::derive(&transcript, &server_shared, &p1[..]);
+
+        assert_eq!(client.client_to_server, server.client_to_server);
+        assert_eq!(client.server_to_client, server.server_to_client);
+        assert_ne!(client.client_to_server, client.server_to_client);
+        assert!(server
+            .verify_client_confirmation(&client.client_confirmation())
+            .is_ok());
+        assert!(client
+            .verify_server_confirmation(&server.server_confirmation())
+            .is_ok());
+        assert!(client
+            .verify_server_confirmation(&client.client_confirmation())
+            .is_err());
+    }
+
+    #[test]
+    fn commitment_exchange() {
+        let private = commitment::PrivateKey::new(Ed25519);
+        let public = commitment::PublicKey::new(Ed25519, private.public());
+
+        let reveal = private.private();
+        assert!(public.verify(&reveal));
+
+        let (client_nonce, server_nonce) = (nonce(), nonce());
+        let (client_share, server_share, client_shared, server_shared) = agree();
+        let transcript = Transcript {
+            user_id: b"bob",
+            round: 0,
+            client_nonce: &client_nonce,
+            server_nonce: &server_nonce,
+            client_share: &client_share,
+            server_share: &server_share,
+        };
+        let client = SessionKeys::derive(&transcript, &client_shared, reveal.0.as_bytes());
+        let server = SessionKeys::derive(&transcript, &server_shared, reveal.0.as_bytes());
+        assert!(server
+            .verify_client_confirmation(&client.client_confirmation())
+            .is_ok());
+    }
+
+    #[test]
+    fn transcript_mismatch() {
+        let secret = [0xde; 32];
+        let (client_nonce, server_nonce) = (nonce(), nonce());
+        let (client_share, server_share, shared, _) = agree();
+        let transcript = Transcript {
+            user_id: b"alice",
+            round: 1,
+            client_nonce: &client_nonce,
+            server_nonce: &server_nonce,
+            client_share: &client_share,
+            server_share: &server_share,
+        };
+        let client = SessionKeys::derive(&transcript, &shared, &secret);
+
+        let other = Transcript {
+            round: 2,
+            ..transcript
+        };
+        let server = SessionKeys::derive(&other, &shared, &secret);
+        assert_ne!(client.client_to_server, server.client_to_server);
+        assert!(server
+            .verify_client_confirmation(&client.client_confirmation())
+            .is_err());
+    }
+
+    #[test]
+    fn passive_observer() {
+        let secret = [0xde; 32];
+        let (client_nonce, server_nonce) = (nonce(), nonce());
+        let (client_share, server_share, shared, _) = agree();
+        let transcript = Transcript {
+            user_id: b"alice",
+            round: 1,
+            client_nonce: &client_nonce,
+            server_nonce: &server_nonce,
+            client_share: &client_share,
+            server_share: &server_share,
+        };
+        let client = SessionKeys::derive(&transcript, &shared, &secret);
+
+        // Everything but the Diffie-Hellman secrets is on the wire
+        let (observer, _) = Ephemeral::new();
+        let guess = observer.agree(&server_share).unwrap();
+        let observed = SessionKeys::derive(&transcript, &guess, &secret);
+        assert_ne!(client.client_to_server, observed.client_to_server);
+
+        let (ephemeral, _) = Ephemeral::new();
+        assert!(ephemeral.agree(&[0; 32]).is_err());
+    }
+}
@@ -0,0 +1,451 @@
+use std::{fmt, str::FromStr};
+
+use crypto::{digest::Digest, sha2::Sha256};
+use rand::RngCore;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    base::{OneWay, PrivateKey},
+    commitment::{self, Commitment},
+    encoding::{from_hex, to_hex},
+    keystore::Element,
+    secret::Secret,
+    uri::MAX_ROUNDS,
+};
+
+// k-of-n Shamir secret sharing over GF(256) for backing up client seeds.
+// Every byte of the secret is the constant term of its own random polynomial
+// of degree k - 1, share x holds the values at x.
+//
+// Each share also carries a random id drawn once per split, so shares of
+// different splits can't be mixed unnoticed. It says nothing about the secret.
+// Printed shares look like
+//
+//   ds1-KKXX-IIIIIIII-DATA-CCCCCCCC
+//
+// with the threshold K, index X, id I, the data and a checksum C in hex.
+
+const PREFIX: &str = "ds1";
+const SUM_LABEL: &[u8] = b"diploma/shamir/v1 sum";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShamirError {
+    // Not a printed share or a typo in it
+    Malformed,
+    TooFewShares,
+    // Shares of different secrets, thresholds or lengths
+    Mismatch,
+    Duplicate,
+}
+
+impl fmt::Display for ShamirError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShamirError::Malformed => f.write_str("malformed share"),
+            ShamirError::TooFewShares => f.write_str("not enough shares"),
+            ShamirError::Mismatch => f.write_str("shares don't belong together"),
+            ShamirError::Duplicate => f.write_str("duplicate share"),
+        }
+    }
+}
+
+impl std::error::Error for ShamirError {}
+
+// Multiplication modulo x^8 + x^4 + x^3 + x + 1 without secret dependent
+// branches or table lookups
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut out = 0;
+    for _ in 0..8 {
+        out ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+    out
+}
+
+// a^254 = a^-1
+fn inv(a: u8) -> u8 {
+    let a2 = mul(a, a);
+    let a4 = mul(a2, a2);
+    let a8 = mul(a4, a4);
+    let a16 = mul(a8, a8);
+    let a32 = mul(a16, a16);
+    let a64 = mul(a32, a32);
+    let a128 = mul(a64, a64);
+    [a2, a4, a8, a16, a32, a64, a128].into_iter().fold(1, mul)
+}
+
+fn digest(parts: &[&[u8]]) -> [u8; 4] {
+    let mut d = Sha256::new();
+    parts.iter().for_each(|part| d.input(part));
+    let mut out = [0; 32];
+    d.result(&mut out);
+    out[..4].try_into().unwrap()
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Share {
+    threshold: u8,
+    index: u8,
+    id: [u8; 4],
+    data: Vec<u8>,
+}
+
+impl Share {
+    #[must_use]
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    #[must_use]
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    fn checksum(&self) -> [u8; 4] {
+        digest(&[
+            SUM_LABEL,
+            &[self.threshold, self.index],
+            &self.id,
+            &self.data,
+        ])
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+impl fmt::Debug for Share {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Share")
+            .field("threshold", &self.threshold)
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{:02x}{:02x}-{}-{}-{}",
+            PREFIX,
+            self.threshold,
+            self.index,
+            to_hex(&self.id),
+            to_hex(&self.data),
+            to_hex(&self.checksum())
+        )
+    }
+}
+
+impl FromStr for Share {
+    type Err = ShamirError;
+
+    // Case and whitespace don't matter, shares are typed in by hand
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        let parts = s.split('-').collect::<Vec<_>>();
+        let [prefix, head, id, data, sum] = parts[..] else {
+            return Err(ShamirError::Malformed);
+        };
+        if prefix != PREFIX {
+            return Err(ShamirError::Malformed);
+        }
+
+        let head = from_hex(head).ok_or(ShamirError::Malformed)?;
+        let [threshold, index] = head[..] else {
+            return Err(ShamirError::Malformed);
+        };
+        let share = Share {
+            threshold,
+            index,
+            id: from_hex(id)
+                .and_then(|c| c.try_into().ok())
+                .ok_or(ShamirError::Malformed)?,
+            data: from_hex(data).ok_or(ShamirError::Malformed)?,
+        };
+        let sum = from_hex(sum).ok_or(ShamirError::Malformed)?;
+        if sum != share.checksum() || threshold == 0 || index == 0 {
+            return Err(ShamirError::Malformed);
+        }
+        Ok(share)
+    }
+}
+
+// Splits `secret` into `shares` shares, any `threshold` of which recover it
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Vec<Share> {
+    assert!(threshold > 0, "threshold can't be zero");
+    assert!(
+        threshold <= shares,
+        "threshold can't exceed the number of shares"
+    );
+
+    let mut id = [0; 4];
+    rand::thread_rng().fill_bytes(&mut id);
+    let mut coefficients = Zeroizing::new(vec![0; secret.len() * usize::from(threshold - 1)]);
+    rand::thread_rng().fill_bytes(&mut coefficients);
+
+    (1..=shares)
+        .map(|x| {
+            let data = secret
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| {
+                    let degree = usize::from(threshold - 1);
+                    let poly = &coefficients[i * degree..(i + 1) * degree];
+                    // Horner's rule from the highest coefficient down
+                    let high = poly.iter().rev().fold(0, |acc, c| mul(acc, x) ^ c);
+                    mul(high, x) ^ byte
+                })
+                .collect();
+            Share {
+                threshold,
+                index: x,
+                id,
+                data,
+            }
+        })
+        .collect()
+}
+
+// Recovers the secret from at least `threshold` shares
+pub fn combine(shares: &[Share]) -> Result<Zeroizing<Vec<u8>>, ShamirError> {
+    let first = shares.first().ok_or(ShamirError::TooFewShares)?;
+    if shares.iter().any(|s| {
+        s.threshold != first.threshold || s.id != first.id || s.data.len() != first.data.len()
+    }) {
+        return Err(ShamirError::Mismatch);
+    }
+    for (i, a) in shares.iter().enumerate() {
+        if shares[i + 1..].iter().any(|b| b.index == a.index) {
+            return Err(ShamirError::Duplicate);
+        }
+    }
+    let shares = &shares[..shares.len().min(usize::from(first.threshold))];
+    if shares.len() < usize::from(first.threshold) {
+        return Err(ShamirError::TooFewShares);
+    }
+
+    // Lagrange interpolation at zero, in GF(2^8) subtraction is xor
+    let weights = shares
+        .iter()
+        .map(|a| {
+            let (num, den) = shares
+                .iter()
+                .filter(|b| b.index != a.index)
+                .fold((1, 1), |(num, den), b| {
+                    (mul(num, b.index), mul(den, a.index ^ b.index))
+                });
+            mul(num, inv(den))
+        })
+        .collect::<Vec<_>>();
+    let mut secret = Zeroizing::new(vec![0; first.data.len()]);
+    for (share, weight) in shares.iter().zip(weights) {
+        for (out, byte) in secret.iter_mut().zip(&share.data) {
+            *out ^= mul(*byte, weight);
+        }
+    }
+    Ok(secret)
+}
+
+// Shares the seed of a hash chain together with its length, which is needed
+// to rebuild it
+pub fn split_chain<const SIZE: usize>(
+    seed: &Secret<SIZE>,
+    rounds: usize,
+    threshold: u8,
+    shares: u8,
+) -> Vec<Share> {
+    let rounds = u64::try_from(rounds)
+        .expect("sorry, architecture is not supported")
+        .to_be_bytes();
+    let mut secret = Zeroizing::new(rounds.to_vec());
+    secret.extend_from_slice(&seed[..]);
+    split(&secret, threshold, shares)
+}
+
+// Rebuilds the chain so that its next password is the one the server expects
+// in its `round`, in `PublicKey::round` numbering. `None` if the chain is
+// used up.
+pub fn recover_chain<F: OneWay, const SIZE: usize>(
+    oneway: F,
+    shares: &[Share],
+    round: usize,
+) -> Result<Option<PrivateKey<F, SIZE>>, ShamirError> {
+    let secret = combine(shares)?;
+    if secret.len() != 8 + SIZE {
+        return Err(ShamirError::Mismatch);
+    }
+    let rounds = u64::from_be_bytes(secret[..8].try_into().unwrap());
+    // Shares are untrusted input, don't let them pick the work
+    if !(1..=MAX_ROUNDS).contains(&rounds) {
+        return Err(ShamirError::Mismatch);
+    }
+    let rounds = usize::try_from(rounds).expect("sorry, architecture is not supported");
+    let seed = Secret::from_slice(&secret[8..]).unwrap();
+
+    let Some(index) = rounds.checked_sub(round) else {
+        return Ok(None);
+    };
+    let chain = PrivateKey::<F, SIZE>::from_password(oneway, rounds, seed);
+    let passwords = chain.passwords()[..=index].to_vec();
+    Ok(Some(PrivateKey::from_passwords(passwords)))
+}
+
+// Commitment keys have no master seed, every `advance` draws a fresh element.
+// A backup of the current element is good until the next login, so it has to
+// be shared again after each one.
+pub fn split_commitment<C: Commitment>(
+    private: &commitment::PrivateKey<C>,
+    threshold: u8,
+    shares: u8,
+) -> Vec<Share>
+where
+    C::PrivateElement: Element,
+{
+    split(&private.private().to_bytes(), threshold, shares)
+}
+
+pub fn recover_commitment<C: Commitment + Default>(
+    shares: &[Share],
+) -> Result<commitment::PrivateKey<C>, ShamirError>
+where
+    C::PrivateElement: Element,
+{
+    let secret = combine(shares)?;
+    let private = C::PrivateElement::from_bytes(&secret).ok_or(ShamirError::Mismatch)?;
+    Ok(commitment::PrivateKey::from_private(C::default(), private))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        base::State,
+        commitment::{hash::Sha256 as CommitSha256, PublicKey},
+        hash::Sha256Builder,
+    };
+
+    #[test]
+    fn field() {
+        // FIPS 197, 4.2
+        assert_eq!(mul(0x57, 0x83), 0xc1);
+        assert_eq!(mul(0x57, 0x13), 0xfe);
+        for a in 1..=255 {
+            assert_eq!(mul(a, inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn any_threshold_subset() {
+        let secret = b"correct horse battery staple";
+        let shares = split(secret, 3, 5);
+        for a in 0..5 {
+            for b in a + 1..5 {
+                for c in b + 1..5 {
+                    let subset = [shares[c].clone(), shares[a].clone(), shares[b].clone()];
+                    assert_eq!(&combine(&subset).unwrap()[..], secret);
+                }
+            }
+        }
+        assert_eq!(&combine(&shares).unwrap()[..], secret);
+        assert_eq!(
+            combine(&shares[..2]).unwrap_err(),
+            ShamirError::TooFewShares
+        );
+        assert_eq!(
+            combine(&[shares[0].clone(), shares[0].clone(), shares[1].clone()]).unwrap_err(),
+            ShamirError::Duplicate
+        );
+
+        let other = split(b"incorrect horse battery staple", 3, 5);
+        assert_eq!(
+            combine(&[shares[0].clone(), shares[1].clone(), other[2].clone()]).unwrap_err(),
+            ShamirError::Mismatch
+        );
+
+        // A single share is the secret itself
+        assert_eq!(&split(secret, 1, 3)[2].data[..], secret);
+
+        // The id is fresh for every split, not derived from the secret
+        assert_ne!(split(secret, 3, 5)[0].id, shares[0].id);
+    }
+
+    #[test]
+    fn printable() {
+        let shares = split(&[0xab; 32], 2, 3);
+        let printed = shares[1].to_string();
+        assert!(printed.starts_with("ds1-0202-"));
+        assert_eq!(printed.parse::<Share>().unwrap(), shares[1]);
+
+        let retyped = format!("  {} ", printed.to_uppercase().replace('-', " - "));
+        assert_eq!(retyped.parse::<Share>().unwrap(), shares[1]);
+
+        // Any typo in the data is caught by the checksum
+        for i in 4..printed.len() {
+            let mut typo = printed.clone().into_bytes();
+            typo[i] = if typo[i] == b'0' { b'1' } else { b'0' };
+            let typo = String::from_utf8(typo).unwrap();
+            if typo != printed {
+                assert!(typo.parse::<Share>().is_err());
+            }
+        }
+        assert!(!format!("{:?}", shares[0]).contains("ab"));
+    }
+
+    #[test]
+    fn recovers_chain_at_server_round() {
+        let seed = Secret::random();
+        let mut private = Sha256Builder::private_from_password(10, seed.clone());
+        let p0 = private.get_password().unwrap();
+        assert_eq!(private.pop_password(), State::Ok);
+        let mut public = Sha256Builder::new_public(*p0.expose());
+        for _ in 0..3 {
+            public
+                .verify(private.get_password().unwrap().expose())
+                .unwrap();
+            assert_eq!(private.pop_password(), State::Ok);
+        }
+
+        let shares = split_chain(&seed, 10, 2, 3);
+        let shares = [shares[2].clone(), shares[0].clone()];
+        let recovered = recover_chain(crypto::sha2::Sha256::new(), &shares, public.round())
+            .unwrap()
+            .unwrap();
+        assert_eq!(recovered.round(), private.round());
+        public
+            .verify(recovered.get_password().unwrap().expose())
+            .unwrap();
+
+        assert!(
+            recover_chain::<_, 32>(crypto::sha2::Sha256::new(), &shares, 11)
+                .unwrap()
+                .is_none()
+        );
+
+        // The number of rounds comes from the shares and is bounded like in
+        // enrollment URIs
+        let mut secret = (MAX_ROUNDS + 1).to_be_bytes().to_vec();
+        secret.extend_from_slice(&seed[..]);
+        let shares = split(&secret, 1, 1);
+        assert!(matches!(
+            recover_chain::<_, 32>(crypto::sha2::Sha256::new(), &shares, 0),
+            Err(ShamirError::Mismatch)
+        ));
+
+        let private = commitment::PrivateKey::new(CommitSha256);
+        let public = PublicKey::new(CommitSha256, private.public());
+        let shares = split_commitment(&private, 2, 2);
+        let recovered = recover_commitment::<CommitSha256>(&shares).unwrap();
+        assert!(public.verify(&recovered.private()));
+    }
+}
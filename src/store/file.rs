@@ -0,0 +1,417 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use crypto::{digest::Digest, sha2::Sha256};
+
+use super::{Reader, Record, Store, StoreError};
+
+// Append-only log of `put`/`remove` operations. Every entry is framed as
+//
+//   [u32 length][8 byte checksum][payload]
+//
+// and is fsynced before the operation returns. On open the log is replayed and
+// a last entry shorter than its length is truncated, so a crash in the middle
+// of a write leaves the user at the previous, fully committed state. Any other
+// damage, a complete last entry included, is `StoreError::Corrupt`: dropping
+// committed entries would make used passwords valid again.
+
+const OP_PUT: u8 = 1;
+const OP_REMOVE: u8 = 2;
+const HEADER: usize = 12;
+// Compact once the log holds this many stale entries
+const COMPACT_SLACK: usize = 1024;
+
+pub(super) fn checksum(payload: &[u8]) -> [u8; 8] {
+    let mut d = Sha256::new();
+    d.input(payload);
+    let mut out = [0; 32];
+    d.result(&mut out);
+    out[..8].try_into().unwrap()
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let len = u32::try_from(payload.len()).expect("entry is too long");
+    [&len.to_be_bytes()[..], &checksum(payload), payload].concat()
+}
+
+fn put_entry(user: &str, record: &Record) -> Vec<u8> {
+    let user_len = u16::try_from(user.len()).expect("user id is too long");
+    let payload = [
+        &[OP_PUT][..],
+        &user_len.to_be_bytes(),
+        user.as_bytes(),
+        &record.encode(),
+    ]
+    .concat();
+    frame(&payload)
+}
+
+fn remove_entry(user: &str) -> Vec<u8> {
+    let user_len = u16::try_from(user.len()).expect("user id is too long");
+    let payload = [&[OP_REMOVE][..], &user_len.to_be_bytes(), user.as_bytes()].concat();
+    frame(&payload)
+}
+
+// Applies the entry at the start of `log` and returns its full length
+fn apply(records: &mut BTreeMap<String, Record>, log: &[u8]) -> Option<usize> {
+    let mut reader = Reader(log);
+    let len = usize::try_from(reader.u32()?).ok()?;
+    let sum = reader.take(8)?;
+    let payload = reader.take(len)?;
+    if sum != checksum(payload) {
+        return None;
+    }
+
+    let mut reader = Reader(payload);
+    let op = reader.u8()?;
+    let user = String::from_utf8(reader.bytes16()?.to_vec()).ok()?;
+    match op {
+        OP_PUT => {
+            let record = Record::decode(reader.0)?;
+            records.insert(user, record);
+        }
+        OP_REMOVE if reader.0.is_empty() => {
+            records.remove(&user);
+        }
+        _ => return None,
+    }
+    Some(HEADER + len)
+}
+
+// Whether a bad entry at the start of `rest` can be the one a crash
+// interrupted, i.e. a prefix of it. A complete entry was synced before its
+// round was acknowledged, dropping it would make a used password valid again.
+fn torn(rest: &[u8]) -> bool {
+    match Reader(rest).u32().map(usize::try_from) {
+        Some(Ok(len)) => rest.len() < HEADER + len,
+        Some(Err(_)) => false,
+        None => true,
+    }
+}
+
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+// Replaces `path` with `bytes` so that a crash leaves either the old or the
+// new content
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut tmp = path.to_owned().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    sync_dir(path)
+}
+
+pub struct FileStore {
+    path: PathBuf,
+    file: File,
+    // Length of the committed prefix of the log
+    len: u64,
+    entries: usize,
+    records: BTreeMap<String, Record>,
+    // Held by `open_locked` until the store is dropped
+    _lock: Option<File>,
+}
+
+impl FileStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let path = path.as_ref().to_owned();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let mut log = vec![];
+        file.read_to_end(&mut log)?;
+
+        let mut records = BTreeMap::new();
+        let mut offset = 0;
+        let mut entries = 0;
+        while offset < log.len() {
+            match apply(&mut records, &log[offset..]) {
+                Some(len) => {
+                    offset += len;
+                    entries += 1;
+                }
+                None if torn(&log[offset..]) => break,
+                None => return Err(StoreError::Corrupt),
+            }
+        }
+
+        let len = u64::try_from(offset).expect("sorry, architecture is not supported");
+        if offset != log.len() {
+            // Drop the torn tail left by a crash
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+
+        Ok(Self {
+            path,
+            file,
+            len,
+            entries,
+            records,
+            _lock: None,
+        })
+    }
+
+    // Like `open`, but first waits for an exclusive lock on `<path>.lock`.
+    // Processes that open the same store, e.g. concurrent logins, have to
+    // use this, or one of them may cut off an entry the other is appending.
+    pub fn open_locked(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Self::lock(path.as_ref(), libc::LOCK_EX)
+    }
+
+    // Like `open_locked`, but fails with `WouldBlock` instead of waiting for
+    // a process that holds the store, e.g. a running daemon
+    pub fn try_open_locked(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Self::lock(path.as_ref(), libc::LOCK_EX | libc::LOCK_NB)
+    }
+
+    fn lock(path: &Path, operation: libc::c_int) -> Result<Self, StoreError> {
+        let mut lock_path = path.to_owned().into_os_string();
+        lock_path.push(".lock");
+        let lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path)?;
+        if unsafe { libc::flock(lock.as_raw_fd(), operation) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Err(io::Error::new(err.kind(), "in use by another process").into());
+            }
+            return Err(err.into());
+        }
+
+        let mut store = Self::open(path)?;
+        store._lock = Some(lock);
+        Ok(store)
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn append(&mut self, entry: &[u8]) -> Result<(), StoreError> {
+        let written = self
+            .file
+            .write_all(entry)
+            .and_then(|_| self.file.sync_data());
+        if let Err(err) = written {
+            // Don't leave a partial entry in front of the next append
+            let _ = self.file.set_len(self.len);
+            return Err(err.into());
+        }
+        self.len += u64::try_from(entry.len()).expect("sorry, architecture is not supported");
+        self.entries += 1;
+        Ok(())
+    }
+
+    fn maybe_compact(&mut self) -> Result<(), StoreError> {
+        if self.entries > self.records.len() + COMPACT_SLACK {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the log as a snapshot of the current records
+    pub fn compact(&mut self) -> Result<(), StoreError> {
+        let snapshot = self
+            .records
+            .iter()
+            .flat_map(|(user, record)| put_entry(user, record))
+            .collect::<Vec<_>>();
+        write_atomic(&self.path, &snapshot)?;
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.len = u64::try_from(snapshot.len()).expect("sorry, architecture is not supported");
+        self.entries = self.records.len();
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn get(&self, user: &str) -> Result<Option<Record>, StoreError> {
+        Ok(self.records.get(user).cloned())
+    }
+
+    fn put(&mut self, user: &str, record: Record) -> Result<(), StoreError> {
+        self.append(&put_entry(user, &record))?;
+        self.records.insert(user.to_owned(), record);
+        self.maybe_compact()
+    }
+
+    fn remove(&mut self, user: &str) -> Result<bool, StoreError> {
+        if !self.records.contains_key(user) {
+            return Ok(false);
+        }
+        self.append(&remove_entry(user))?;
+        self.records.remove(user);
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    fn users(&self) -> Result<Vec<String>, StoreError> {
+        Ok(self.records.keys().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::State;
+
+    #[test]
+    fn file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        super::super::tests::exercise(&mut FileStore::open(dir.path().join("db")).unwrap());
+    }
+
+    #[test]
+    fn survives_reopen_and_torn_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+
+        let (mut private, record) = Record::generate_sha256(5);
+        let p1 = private.get_password().unwrap();
+
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.enroll("alice", record).unwrap();
+            store.accept("alice", &p1[..], None).unwrap();
+        }
+
+        // Crash in the middle of the next write
+        let valid = fs::metadata(&path).unwrap().len();
+        let entry = put_entry("alice", &Record::sha256([0; 32]));
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&entry[..entry.len() - 3])
+            .unwrap();
+
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid);
+        assert_eq!(store.get("alice").unwrap().unwrap().round, 2);
+
+        // The accepted password can't be replayed after a restart
+        assert!(store.accept("alice", &p1[..], None).is_err());
+        assert_eq!(private.pop_password(), State::Ok);
+        store
+            .accept("alice", &private.get_password().unwrap()[..], None)
+            .unwrap();
+    }
+
+    #[test]
+    fn corrupted_last_entry_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.put("alice", Record::sha256([1; 32])).unwrap();
+            store.put("alice", Record::sha256([2; 32])).unwrap();
+        }
+
+        // Complete, so it may be an acknowledged round
+        let mut log = fs::read(&path).unwrap();
+        let last = log.len() - 1;
+        log[last] ^= 1;
+        fs::write(&path, &log).unwrap();
+
+        assert!(matches!(FileStore::open(&path), Err(StoreError::Corrupt)));
+        assert_eq!(fs::read(&path).unwrap(), log);
+    }
+
+    #[test]
+    fn corrupted_middle_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.put("alice", Record::sha256([1; 32])).unwrap();
+            store.put("alice", Record::sha256([2; 32])).unwrap();
+            store.put("bob", Record::sha256([3; 32])).unwrap();
+        }
+
+        // In the payload of the first entry
+        let mut log = fs::read(&path).unwrap();
+        log[HEADER + 4] ^= 1;
+        fs::write(&path, &log).unwrap();
+
+        assert!(matches!(FileStore::open(&path), Err(StoreError::Corrupt)));
+        // Nothing was truncated
+        assert_eq!(fs::read(&path).unwrap(), log);
+    }
+
+    #[test]
+    fn compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            for i in 0..COMPACT_SLACK + 10 {
+                let anchor = [(i % 256) as u8; 32];
+                store.put("alice", Record::sha256(anchor)).unwrap();
+            }
+            store.put("bob", Record::sha256([0xbb; 32])).unwrap();
+            store.remove("alice").unwrap();
+            store.compact().unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.users().unwrap(), vec!["bob"]);
+        assert!(fs::metadata(&path).unwrap().len() < 200);
+    }
+
+    #[test]
+    fn locked_open_waits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let mut store = FileStore::open_locked(&path).unwrap();
+
+        let other = std::thread::spawn({
+            let path = path.clone();
+            move || FileStore::open_locked(path).unwrap().get("alice").unwrap()
+        });
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        store.put("alice", Record::sha256([1; 32])).unwrap();
+        drop(store);
+        assert!(other.join().unwrap().is_some());
+    }
+
+    #[test]
+    fn try_locked_open_refuses() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let store = FileStore::open_locked(&path).unwrap();
+        assert!(matches!(
+            FileStore::try_open_locked(&path),
+            Err(StoreError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock
+        ));
+        drop(store);
+        FileStore::try_open_locked(&path).unwrap();
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::BTreeMap;
+
+use super::{Record, Store, StoreError};
+
+#[derive(Default)]
+pub struct MemoryStore {
+    records: BTreeMap<String, Record>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(&self, user: &str) -> Result<Option<Record>, StoreError> {
+        Ok(self.records.get(user).cloned())
+    }
+
+    fn put(&mut self, user: &str, record: Record) -> Result<(), StoreError> {
+        self.records.insert(user.to_owned(), record);
+        Ok(())
+    }
+
+    fn remove(&mut self, user: &str) -> Result<bool, StoreError> {
+        Ok(self.records.remove(user).is_some())
+    }
+
+    fn users(&self) -> Result<Vec<String>, StoreError> {
+        Ok(self.records.keys().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store() {
+        super::super::tests::exercise(&mut MemoryStore::new());
+    }
+}
@@ -0,0 +1,496 @@
+use std::{fmt, io};
+
+use crypto::{aessafe::AesSafe128Encryptor, sha2::Sha256};
+
+use crate::{
+    base::{self, AuthError, PublicKey},
+    cipher::BlockOneWay,
+    commitment::{self, ed25519::CloneableSecretKey},
+    hash::Sha256Builder,
+    policy::Attempts,
+    secret::Secret,
+};
+
+pub mod file;
+pub mod memory;
+pub mod rollback;
+pub mod sharded;
+
+pub use file::FileStore;
+pub use memory::MemoryStore;
+pub use rollback::{FileCounters, Guarded};
+pub use sharded::ShardedStore;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    Sha256,
+    Aes128,
+    CommitSha256,
+    CommitAes128,
+    CommitEd25519,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 5] = [
+        Algorithm::Sha256,
+        Algorithm::Aes128,
+        Algorithm::CommitSha256,
+        Algorithm::CommitAes128,
+        Algorithm::CommitEd25519,
+    ];
+
+    #[must_use]
+    pub fn id(self) -> u8 {
+        match self {
+            Algorithm::Sha256 => 1,
+            Algorithm::Aes128 => 2,
+            Algorithm::CommitSha256 => 3,
+            Algorithm::CommitAes128 => 4,
+            Algorithm::CommitEd25519 => 5,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|alg| alg.id() == id)
+    }
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Aes128 => "aes128",
+            Algorithm::CommitSha256 => "commit-sha256",
+            Algorithm::CommitAes128 => "commit-aes128",
+            Algorithm::CommitEd25519 => "commit-ed25519",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|alg| alg.name() == name)
+    }
+
+    // Size of the anchor and of every revealed element
+    #[must_use]
+    pub fn size(self) -> usize {
+        match self {
+            Algorithm::Sha256 | Algorithm::CommitSha256 | Algorithm::CommitEd25519 => 32,
+            Algorithm::Aes128 | Algorithm::CommitAes128 => 16,
+        }
+    }
+
+    #[must_use]
+    pub fn is_chain(self) -> bool {
+        matches!(self, Algorithm::Sha256 | Algorithm::Aes128)
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Serializable verifier state of a single user.
+//
+// For hash chains `anchor` is the last accepted password and `round` is the
+// next `base::PublicKey` round. For commitments `anchor` is the current public
+// element and `round` counts accepted reveals.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub algorithm: Algorithm,
+    pub round: u64,
+    pub anchor: Vec<u8>,
+    // `BlockOneWay` secret for the AES chain, empty otherwise
+    pub secret: Vec<u8>,
+    // Failed attempts, kept next to the verifier so both change atomically
+    pub attempts: Attempts,
+    // Bumped on every commit when rollback protection is on, 0 otherwise
+    pub version: u64,
+}
+
+fn to_array<const N: usize>(bytes: &[u8]) -> Result<[u8; N], AuthError> {
+    bytes.try_into().map_err(|_| AuthError)
+}
+
+fn to_usize(round: u64) -> usize {
+    usize::try_from(round).expect("sorry, architecture is not supported")
+}
+
+fn to_u64(round: usize) -> u64 {
+    u64::try_from(round).expect("sorry, architecture is not supported")
+}
+
+impl Record {
+    pub fn new(algorithm: Algorithm, anchor: Vec<u8>, secret: Vec<u8>) -> Self {
+        Self {
+            algorithm,
+            round: if algorithm.is_chain() { 1 } else { 0 },
+            anchor,
+            secret,
+            attempts: Attempts::default(),
+            version: 0,
+        }
+    }
+
+    pub fn sha256(anchor: [u8; 32]) -> Self {
+        Self::new(Algorithm::Sha256, anchor.to_vec(), vec![])
+    }
+
+    // A fresh SHA-256 chain and the record that enrolls it, the key is left
+    // at the first login password
+    pub fn generate_sha256(rounds: usize) -> (base::PrivateKey<Sha256, 32>, Self) {
+        let mut private = Sha256Builder::new_private(rounds);
+        let anchor = private.get_password().expect("the anchor is always there");
+        // Zero rounds leave an empty key, not an error
+        let _ = private.pop_password();
+        (private, Self::sha256(*anchor.expose()))
+    }
+
+    pub fn aes128(secret: [u8; 16], anchor: [u8; 16]) -> Self {
+        Self::new(Algorithm::Aes128, anchor.to_vec(), secret.to_vec())
+    }
+
+    pub fn verify(&self, password: &[u8]) -> Result<(), AuthError> {
+        self.clone().accept(password, None)
+    }
+
+    // Verifies `password` and advances the state. Commitment schemes need the
+    // next public element, hash chains ignore it.
+    pub fn accept(&mut self, password: &[u8], next: Option<&[u8]>) -> Result<(), AuthError> {
+        let round = to_usize(self.round);
+        match self.algorithm {
+            Algorithm::Sha256 => {
+                let mut public =
+                    PublicKey::from_parts(Sha256::new(), round, to_array::<32>(&self.anchor)?);
+                public.verify(&to_array(password)?)?;
+                self.anchor = public.password().to_vec();
+                self.round = to_u64(public.round());
+            }
+            Algorithm::Aes128 => {
+                let secret = Secret::new(to_array(&self.secret)?);
+                let oneway = BlockOneWay::<AesSafe128Encryptor, 16>::new(secret);
+                let mut public =
+                    PublicKey::from_parts(oneway, round, to_array::<16>(&self.anchor)?);
+                public.verify(&to_array(password)?)?;
+                self.anchor = public.password().to_vec();
+                self.round = to_u64(public.round());
+            }
+            Algorithm::CommitSha256 => {
+                let reveal = Secret::from_slice(password).ok_or(AuthError)?;
+                let public =
+                    commitment::PublicKey::new(commitment::hash::Sha256, to_array(&self.anchor)?);
+                let ok = public.verify(&reveal);
+                self.advance_commitment(ok, next)?;
+            }
+            Algorithm::CommitAes128 => {
+                let reveal = Secret::from_slice(password).ok_or(AuthError)?;
+                let public = commitment::PublicKey::new(
+                    commitment::aes::Aes128SafeEncryptor,
+                    to_array(&self.anchor)?,
+                );
+                let ok = public.verify(&reveal);
+                self.advance_commitment(ok, next)?;
+            }
+            Algorithm::CommitEd25519 => {
+                let public =
+                    ed25519_dalek::PublicKey::from_bytes(&self.anchor).map_err(|_| AuthError)?;
+                let reveal =
+                    ed25519_dalek::SecretKey::from_bytes(password).map_err(|_| AuthError)?;
+                let public = commitment::PublicKey::new(commitment::ed25519::Ed25519, public);
+                let ok = public.verify(&CloneableSecretKey(reveal));
+                if let Some(next) = next {
+                    ed25519_dalek::PublicKey::from_bytes(next).map_err(|_| AuthError)?;
+                }
+                self.advance_commitment(ok, next)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Like `accept`, but the password is `skip` rounds ahead of the expected
+    // one. Only hash chains can skip rounds.
+    pub fn accept_skip(&mut self, password: &[u8], skip: usize) -> Result<(), AuthError> {
+        let round = to_usize(self.round);
+        match self.algorithm {
+            Algorithm::Sha256 => {
+                let mut public =
+                    PublicKey::from_parts(Sha256::new(), round, to_array::<32>(&self.anchor)?);
+                public.verify_skip(&to_array(password)?, skip)?;
+                self.anchor = public.password().to_vec();
+                self.round = to_u64(public.round());
+            }
+            Algorithm::Aes128 => {
+                let secret = Secret::new(to_array(&self.secret)?);
+                let oneway = BlockOneWay::<AesSafe128Encryptor, 16>::new(secret);
+                let mut public =
+                    PublicKey::from_parts(oneway, round, to_array::<16>(&self.anchor)?);
+                public.verify_skip(&to_array(password)?, skip)?;
+                self.anchor = public.password().to_vec();
+                self.round = to_u64(public.round());
+            }
+            _ => return Err(AuthError),
+        }
+        Ok(())
+    }
+
+    fn advance_commitment(&mut self, ok: bool, next: Option<&[u8]>) -> Result<(), AuthError> {
+        let next = next.ok_or(AuthError)?;
+        if !ok || next.len() != self.algorithm.size() {
+            return Err(AuthError);
+        }
+        self.anchor = next.to_vec();
+        self.round += 1;
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![self.algorithm.id()];
+        out.extend_from_slice(&self.round.to_be_bytes());
+        for field in [&self.anchor, &self.secret] {
+            let len = u16::try_from(field.len()).expect("field is too long");
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(field);
+        }
+        out.extend_from_slice(&self.attempts.encode());
+        out.extend_from_slice(&self.version.to_be_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let algorithm = Algorithm::from_id(reader.u8()?)?;
+        let round = reader.u64()?;
+        let anchor = reader.bytes16()?.to_vec();
+        let secret = reader.bytes16()?.to_vec();
+        // Records written before the policy layer carry no attempts
+        let attempts = if reader.0.is_empty() {
+            Attempts::default()
+        } else {
+            Attempts::decode(&mut reader)?
+        };
+        let version = if reader.0.is_empty() {
+            0
+        } else {
+            reader.u64()?
+        };
+        if !reader.0.is_empty() || anchor.len() != algorithm.size() {
+            return None;
+        }
+        Some(Self {
+            algorithm,
+            round,
+            anchor,
+            secret,
+            attempts,
+            version,
+        })
+    }
+}
+
+pub(crate) struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    pub fn bytes16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(len.into())
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound,
+    Exists,
+    Auth(AuthError),
+    Corrupt,
+    // The stored state is older than the last committed one
+    Rollback {
+        user: String,
+        version: u64,
+        committed: u64,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::NotFound => f.write_str("user not found"),
+            StoreError::Exists => f.write_str("user already exists"),
+            StoreError::Auth(err) => err.fmt(f),
+            StoreError::Corrupt => f.write_str("store is corrupted"),
+            StoreError::Rollback {
+                user,
+                version,
+                committed,
+            } => write!(
+                f,
+                "rollback detected for {}: version {} is older than committed {}",
+                user, version, committed
+            ),
+            StoreError::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<AuthError> for StoreError {
+    fn from(err: AuthError) -> Self {
+        StoreError::Auth(err)
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> Self {
+        StoreError::Io(err)
+    }
+}
+
+pub trait Store {
+    fn get(&self, user: &str) -> Result<Option<Record>, StoreError>;
+
+    fn put(&mut self, user: &str, record: Record) -> Result<(), StoreError>;
+
+    fn remove(&mut self, user: &str) -> Result<bool, StoreError>;
+
+    fn users(&self) -> Result<Vec<String>, StoreError>;
+
+    fn enroll(&mut self, user: &str, record: Record) -> Result<(), StoreError> {
+        if self.get(user)?.is_some() {
+            return Err(StoreError::Exists);
+        }
+        self.put(user, record)
+    }
+
+    // Verify-and-advance. The new state is durable before this returns `Ok`,
+    // and a failed verification doesn't touch the store.
+    fn accept(
+        &mut self,
+        user: &str,
+        password: &[u8],
+        next: Option<&[u8]>,
+    ) -> Result<(), StoreError> {
+        let mut record = self.get(user)?.ok_or(StoreError::NotFound)?;
+        record.accept(password, next)?;
+        self.put(user, record)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{base::State, cipher::Aes128SafeBuilder, commitment::ed25519::Ed25519};
+
+    // Shared by the store implementations
+    pub fn exercise(store: &mut impl Store) {
+        let (mut private, record) = Record::generate_sha256(3);
+
+        store.enroll("alice", record.clone()).unwrap();
+        assert!(matches!(
+            store.enroll("alice", record.clone()),
+            Err(StoreError::Exists)
+        ));
+        assert!(matches!(
+            store.accept("bob", &record.anchor[..], None),
+            Err(StoreError::NotFound)
+        ));
+
+        let p1 = private.get_password().unwrap();
+        store.accept("alice", &p1[..], None).unwrap();
+        assert!(matches!(
+            store.accept("alice", &p1[..], None),
+            Err(StoreError::Auth(_))
+        ));
+        assert_eq!(private.pop_password(), State::Ok);
+        store
+            .accept("alice", &private.get_password().unwrap()[..], None)
+            .unwrap();
+        assert_eq!(store.get("alice").unwrap().unwrap().round, 3);
+
+        let commit = commitment::PrivateKey::new(Ed25519);
+        store
+            .enroll(
+                "bob",
+                Record::new(
+                    Algorithm::CommitEd25519,
+                    commit.public().to_bytes().to_vec(),
+                    vec![],
+                ),
+            )
+            .unwrap();
+        assert_eq!(store.users().unwrap(), vec!["alice", "bob"]);
+
+        assert!(store.remove("alice").unwrap());
+        assert!(!store.remove("alice").unwrap());
+        assert_eq!(store.users().unwrap(), vec!["bob"]);
+    }
+
+    #[test]
+    fn record_chain() {
+        let secret = *b"YELLOW SUBMARINE";
+        let mut private = Aes128SafeBuilder::new_private(5, Secret::new(secret));
+        let p0 = private.get_password().unwrap();
+        assert_eq!(private.pop_password(), State::Ok);
+
+        let mut record = Record::aes128(secret, *p0.expose());
+        for _ in 0..5 {
+            let p = private.get_password().unwrap();
+            assert!(record.verify(&p[..]).is_ok());
+            assert!(record.accept(&p[..], None).is_ok());
+            assert!(record.accept(&p[..], None).is_err());
+            let _ = private.pop_password();
+        }
+        assert_eq!(record.round, 6);
+        assert_eq!(Record::decode(&record.encode()), Some(record));
+    }
+
+    #[test]
+    fn record_commitment() {
+        let mut private = commitment::PrivateKey::new(commitment::hash::Sha256);
+        let mut record = Record::new(Algorithm::CommitSha256, private.public().to_vec(), vec![]);
+
+        for _ in 0..5 {
+            let reveal = private.private();
+            private.advance();
+            let next = private.public();
+            assert!(record.accept(&reveal[..], None).is_err());
+            assert!(record.accept(&reveal[..], Some(&next)).is_ok());
+            assert!(record.accept(&reveal[..], Some(&next)).is_err());
+        }
+        assert_eq!(record.round, 5);
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        let record = Record::sha256([0xde; 32]);
+        let bytes = record.encode();
+        assert_eq!(Record::decode(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(Record::decode(&[bytes.clone(), vec![0]].concat()), None);
+        assert_eq!(Record::decode(&[&[42], &bytes[1..]].concat()), None);
+    }
+}
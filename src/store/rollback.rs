@@ -0,0 +1,357 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    file::{checksum, write_atomic},
+    Reader, Record, Store, StoreError,
+};
+
+// Rollback protection. Every commit bumps the record version and then
+// advances a per-user monotonic counter kept outside the store. Restoring an
+// old copy of the store leaves versions behind their counters, which is
+// caught on open. Crashing between the two writes leaves the store ahead of
+// the counter, which is harmless.
+//
+// A quarantined user can't be read or written at all, so no caller, e.g.
+// `Policy::accept`, can get at its stale record. Only `Guarded::reenroll`
+// lifts the quarantine.
+
+pub trait Counters {
+    fn get(&self, user: &str) -> u64;
+
+    // Counters never go back, committing a lower value is an error
+    fn commit(&mut self, user: &str, version: u64) -> Result<(), StoreError>;
+}
+
+// File stand-in for TPM NV counters, rewritten atomically on every commit.
+// Counters of removed users are kept, so restoring a backup that still holds
+// a revoked user is caught as well.
+pub struct FileCounters {
+    path: PathBuf,
+    values: BTreeMap<String, u64>,
+}
+
+impl FileCounters {
+    // A missing file is an error, deleting it must not reset the counters
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let path = path.as_ref().to_owned();
+        let bytes = fs::read(&path)?;
+        let values = Self::decode(&bytes).ok_or(StoreError::Corrupt)?;
+        Ok(Self { path, values })
+    }
+
+    // Fails if the file already exists
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let counters = Self {
+            path: path.as_ref().to_owned(),
+            values: BTreeMap::new(),
+        };
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&counters.path)?;
+        file.write_all(&counters.encode())?;
+        file.sync_all()?;
+        Ok(counters)
+    }
+
+    fn decode(bytes: &[u8]) -> Option<BTreeMap<String, u64>> {
+        let (payload, sum) = bytes.split_at(bytes.len().checked_sub(8)?);
+        if sum != checksum(payload) {
+            return None;
+        }
+        let mut reader = Reader(payload);
+        let mut values = BTreeMap::new();
+        while !reader.0.is_empty() {
+            let user = String::from_utf8(reader.bytes16()?.to_vec()).ok()?;
+            values.insert(user, reader.u64()?);
+        }
+        Some(values)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        for (user, version) in &self.values {
+            let len = u16::try_from(user.len()).expect("user id is too long");
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(user.as_bytes());
+            out.extend_from_slice(&version.to_be_bytes());
+        }
+        let sum = checksum(&out);
+        out.extend_from_slice(&sum);
+        out
+    }
+}
+
+impl Counters for FileCounters {
+    fn get(&self, user: &str) -> u64 {
+        self.values.get(user).copied().unwrap_or(0)
+    }
+
+    fn commit(&mut self, user: &str, version: u64) -> Result<(), StoreError> {
+        let committed = self.get(user);
+        if version < committed {
+            return Err(StoreError::Rollback {
+                user: user.to_owned(),
+                version,
+                committed,
+            });
+        }
+        self.values.insert(user.to_owned(), version);
+        write_atomic(&self.path, &self.encode())?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnRollback {
+    // Fail to open the store
+    Refuse,
+    // Open the store, but refuse every access to a rolled back user until it
+    // is enrolled again with `Guarded::reenroll`
+    Quarantine,
+}
+
+pub struct Guarded<S: Store, C: Counters> {
+    store: S,
+    counters: C,
+    // user -> (stored version, committed version)
+    quarantined: BTreeMap<String, (u64, u64)>,
+}
+
+impl<S: Store, C: Counters> Guarded<S, C> {
+    pub fn open(store: S, counters: C, on_rollback: OnRollback) -> Result<Self, StoreError> {
+        let mut quarantined = BTreeMap::new();
+        for user in store.users()? {
+            let record = store.get(&user)?.ok_or(StoreError::Corrupt)?;
+            let committed = counters.get(&user);
+            if record.version < committed {
+                if on_rollback == OnRollback::Refuse {
+                    return Err(StoreError::Rollback {
+                        user,
+                        version: record.version,
+                        committed,
+                    });
+                }
+                quarantined.insert(user, (record.version, committed));
+            }
+        }
+        Ok(Self {
+            store,
+            counters,
+            quarantined,
+        })
+    }
+
+    pub fn quarantined(&self) -> impl Iterator<Item = &str> {
+        self.quarantined.keys().map(String::as_str)
+    }
+
+    pub fn into_inner(self) -> (S, C) {
+        (self.store, self.counters)
+    }
+
+    // Replaces the record of a user, quarantined or not, and lifts the
+    // quarantine. The record has to come from a new chain: the old chain's
+    // used passwords may be valid against the restored state.
+    pub fn reenroll(&mut self, user: &str, record: Record) -> Result<(), StoreError> {
+        self.commit(user, record)?;
+        self.quarantined.remove(user);
+        Ok(())
+    }
+
+    fn commit(&mut self, user: &str, mut record: Record) -> Result<(), StoreError> {
+        let stored = self.store.get(user)?.map_or(0, |r| r.version);
+        record.version = stored.max(self.counters.get(user)) + 1;
+        let version = record.version;
+        self.store.put(user, record)?;
+        self.counters.commit(user, version)
+    }
+
+    fn check(&self, user: &str) -> Result<(), StoreError> {
+        match self.quarantined.get(user) {
+            Some((version, committed)) => Err(StoreError::Rollback {
+                user: user.to_owned(),
+                version: *version,
+                committed: *committed,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S: Store, C: Counters> Store for Guarded<S, C> {
+    fn get(&self, user: &str) -> Result<Option<Record>, StoreError> {
+        self.check(user)?;
+        self.store.get(user)
+    }
+
+    fn put(&mut self, user: &str, record: Record) -> Result<(), StoreError> {
+        self.check(user)?;
+        self.commit(user, record)
+    }
+
+    // Removing a quarantined user keeps it quarantined
+    fn remove(&mut self, user: &str) -> Result<bool, StoreError> {
+        self.store.remove(user)
+    }
+
+    fn users(&self) -> Result<Vec<String>, StoreError> {
+        self.store.users()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        policy::{ManualClock, Policy, PolicyConfig, PolicyError},
+        store::FileStore,
+    };
+
+    #[test]
+    fn guarded_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::open(dir.path().join("db")).unwrap();
+        let counters = FileCounters::create(dir.path().join("nv")).unwrap();
+        let mut store = Guarded::open(store, counters, OnRollback::Refuse).unwrap();
+        crate::store::tests::exercise(&mut store);
+    }
+
+    #[test]
+    fn detects_restored_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db");
+        let nv = dir.path().join("nv");
+
+        let (private, record) = Record::generate_sha256(5);
+        let p1 = private.get_password().unwrap();
+
+        {
+            let store = FileStore::open(&db).unwrap();
+            let counters = FileCounters::create(&nv).unwrap();
+            let mut store = Guarded::open(store, counters, OnRollback::Refuse).unwrap();
+            store.enroll("alice", record.clone()).unwrap();
+            store.enroll("bob", record.clone()).unwrap();
+        }
+        let backup = fs::read(&db).unwrap();
+        {
+            let store = FileStore::open(&db).unwrap();
+            let counters = FileCounters::open(&nv).unwrap();
+            let mut store = Guarded::open(store, counters, OnRollback::Refuse).unwrap();
+            store.accept("alice", &p1[..], None).unwrap();
+        }
+
+        fs::write(&db, backup).unwrap();
+
+        let store = FileStore::open(&db).unwrap();
+        let counters = FileCounters::open(&nv).unwrap();
+        assert!(matches!(
+            Guarded::open(store, counters, OnRollback::Refuse),
+            Err(StoreError::Rollback { user, version: 1, committed: 2 }) if user == "alice"
+        ));
+
+        let store = FileStore::open(&db).unwrap();
+        let counters = FileCounters::open(&nv).unwrap();
+        let mut store = Guarded::open(store, counters, OnRollback::Quarantine).unwrap();
+        assert_eq!(store.quarantined().collect::<Vec<_>>(), vec!["alice"]);
+
+        // The already used password is refused, other users are unaffected
+        assert!(matches!(
+            store.accept("alice", &p1[..], None),
+            Err(StoreError::Rollback { .. })
+        ));
+        store.accept("bob", &p1[..], None).unwrap();
+
+        // Nor can the stale record be reached or overwritten
+        assert!(store.get("alice").is_err());
+        assert!(store.put("alice", record.clone()).is_err());
+        assert_eq!(store.quarantined().count(), 1);
+
+        // Re-enrolling lifts the quarantine
+        let (private, record) = Record::generate_sha256(5);
+        store.reenroll("alice", record).unwrap();
+        assert_eq!(store.quarantined().count(), 0);
+        store
+            .accept("alice", &private.get_password().unwrap()[..], None)
+            .unwrap();
+    }
+
+    #[test]
+    fn policy_respects_quarantine() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db");
+        let nv = dir.path().join("nv");
+
+        let (private, record) = Record::generate_sha256(5);
+        let p1 = private.get_password().unwrap();
+        {
+            let store = FileStore::open(&db).unwrap();
+            let counters = FileCounters::create(&nv).unwrap();
+            let mut store = Guarded::open(store, counters, OnRollback::Refuse).unwrap();
+            store.enroll("alice", record).unwrap();
+        }
+        let backup = fs::read(&db).unwrap();
+        {
+            let store = FileStore::open(&db).unwrap();
+            let counters = FileCounters::open(&nv).unwrap();
+            let mut store = Guarded::open(store, counters, OnRollback::Refuse).unwrap();
+            store.accept("alice", &p1[..], None).unwrap();
+        }
+        fs::write(&db, backup).unwrap();
+
+        let store = FileStore::open(&db).unwrap();
+        let counters = FileCounters::open(&nv).unwrap();
+        let mut store = Guarded::open(store, counters, OnRollback::Quarantine).unwrap();
+        let clock = ManualClock::new(0);
+        let policy = Policy::new(PolicyConfig::default(), &clock);
+
+        // Neither the replay nor a failure, which records the attempt, gets
+        // through or lifts the quarantine
+        assert!(matches!(
+            policy.accept(&mut store, "alice", &p1[..], None),
+            Err(PolicyError::Store(StoreError::Rollback { .. }))
+        ));
+        assert!(matches!(
+            policy.accept(&mut store, "alice", &[0; 32], None),
+            Err(PolicyError::Store(StoreError::Rollback { .. }))
+        ));
+        assert_eq!(store.quarantined().collect::<Vec<_>>(), vec!["alice"]);
+    }
+
+    #[test]
+    fn missing_counters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nv");
+        assert!(matches!(FileCounters::open(&path), Err(StoreError::Io(_))));
+        FileCounters::create(&path).unwrap();
+        assert!(FileCounters::create(&path).is_err());
+        assert_eq!(FileCounters::open(&path).unwrap().get("alice"), 0);
+    }
+
+    #[test]
+    fn counters_never_go_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nv");
+        {
+            let mut counters = FileCounters::create(&path).unwrap();
+            counters.commit("alice", 5).unwrap();
+            assert!(counters.commit("alice", 4).is_err());
+        }
+        let counters = FileCounters::open(&path).unwrap();
+        assert_eq!(counters.get("alice"), 5);
+        assert_eq!(counters.get("bob"), 0);
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[0] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        assert!(matches!(
+            FileCounters::open(&path),
+            Err(StoreError::Corrupt)
+        ));
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use super::{Record, Store, StoreError};
+
+// Thread-safe front for a set of stores. Users are spread over shards by a
+// stable hash of their id, and verify-and-advance runs entirely under the
+// shard lock, so a password or reveal is accepted at most once no matter how
+// many threads race it.
+
+// FNV-1a: unlike `DefaultHasher` it is stable across Rust releases, which
+// matters when every shard is its own file
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub struct ShardedStore<S: Store> {
+    shards: Vec<Mutex<S>>,
+}
+
+impl<S: Store> ShardedStore<S> {
+    pub fn new(shards: Vec<S>) -> Self {
+        assert!(!shards.is_empty(), "there should be at least one shard");
+        Self {
+            shards: shards.into_iter().map(Mutex::new).collect(),
+        }
+    }
+
+    fn shard(&self, user: &str) -> MutexGuard<'_, S> {
+        let len = u64::try_from(self.shards.len()).expect("sorry, architecture is not supported");
+        let index = usize::try_from(fnv1a(user.as_bytes()) % len)
+            .expect("sorry, architecture is not supported");
+        // Every store operation commits a whole record, so a panic in another
+        // thread can't leave the shard half-updated
+        self.shards[index]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, user: &str) -> Result<Option<Record>, StoreError> {
+        self.shard(user).get(user)
+    }
+
+    pub fn put(&self, user: &str, record: Record) -> Result<(), StoreError> {
+        self.shard(user).put(user, record)
+    }
+
+    pub fn enroll(&self, user: &str, record: Record) -> Result<(), StoreError> {
+        self.shard(user).enroll(user, record)
+    }
+
+    pub fn remove(&self, user: &str) -> Result<bool, StoreError> {
+        self.shard(user).remove(user)
+    }
+
+    pub fn accept(
+        &self,
+        user: &str,
+        password: &[u8],
+        next: Option<&[u8]>,
+    ) -> Result<(), StoreError> {
+        self.shard(user).accept(user, password, next)
+    }
+
+    pub fn users(&self) -> Result<Vec<String>, StoreError> {
+        let mut users = vec![];
+        for shard in &self.shards {
+            users.extend(
+                shard
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .users()?,
+            );
+        }
+        users.sort();
+        Ok(users)
+    }
+
+    pub fn into_inner(self) -> Vec<S> {
+        self.shards
+            .into_iter()
+            .map(|shard| shard.into_inner().unwrap_or_else(PoisonError::into_inner))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Barrier},
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        commitment,
+        store::{Algorithm, MemoryStore},
+    };
+
+    const THREADS: usize = 32;
+
+    // Every thread submits the same credentials at once, returns the number
+    // of accepted attempts
+    fn race(
+        store: &Arc<ShardedStore<MemoryStore>>,
+        user: &'static str,
+        password: Vec<u8>,
+        next: Option<Vec<u8>>,
+    ) -> usize {
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles = (0..THREADS)
+            .map(|_| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                let password = password.clone();
+                let next = next.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    store.accept(user, &password, next.as_deref()).is_ok()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|ok| *ok)
+            .count()
+    }
+
+    #[test]
+    fn chain_accepted_once_under_contention() {
+        let shards = (0..4).map(|_| MemoryStore::new()).collect();
+        let store = Arc::new(ShardedStore::new(shards));
+
+        let (mut private, record) = Record::generate_sha256(20);
+        store.enroll("alice", record).unwrap();
+
+        for _ in 0..20 {
+            let password = private.get_password().unwrap();
+            assert_eq!(race(&store, "alice", password.to_vec(), None), 1);
+            let _ = private.pop_password();
+        }
+        assert_eq!(store.get("alice").unwrap().unwrap().round, 21);
+    }
+
+    #[test]
+    fn commitment_accepted_once_under_contention() {
+        let store = Arc::new(ShardedStore::new(vec![MemoryStore::new()]));
+
+        let mut private = commitment::PrivateKey::new(commitment::hash::Sha256);
+        store
+            .enroll(
+                "bob",
+                Record::new(Algorithm::CommitSha256, private.public().to_vec(), vec![]),
+            )
+            .unwrap();
+
+        for _ in 0..20 {
+            let reveal = private.private();
+            private.advance();
+            let next = private.public();
+            assert_eq!(race(&store, "bob", reveal.to_vec(), Some(next.to_vec())), 1);
+        }
+    }
+
+    #[test]
+    fn users_across_shards() {
+        let shards = (0..8).map(|_| MemoryStore::new()).collect();
+        let store = ShardedStore::new(shards);
+        for user in ["carol", "alice", "bob"] {
+            store.put(user, Record::sha256([0; 32])).unwrap();
+        }
+        assert_eq!(store.users().unwrap(), vec!["alice", "bob", "carol"]);
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+}
@@ -0,0 +1,533 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crypto::{
+    aead::{AeadDecryptor, AeadEncryptor},
+    chacha20poly1305::ChaCha20Poly1305,
+};
+use rand::Rng;
+
+use crate::{
+    base::AuthError,
+    policy::Clock,
+    session::hmac_sha256,
+    store::{file::sync_dir, Reader, Record},
+};
+
+// Stateless verifier. The server seals the verifier state of a user into a
+// token that the client keeps and presents with every password; a successful
+// login hands out a token for the next round.
+//
+//   token = VERSION || id || ChaCha20Poly1305(epoch || expiry || user || record)
+//
+// Every token is encrypted under its own key derived from the master key and
+// the random token id, so the fixed nonce is never reused. An old token still
+// verifies the password it was issued for, so the ids of redeemed tokens are
+// remembered in a bloom filter per epoch. Tokens live at most one epoch,
+// therefore only the filters of the current and the previous epoch are kept.
+//
+// The filters are the only state. A server made with `open` journals the ids
+// it accepts to a directory, one file per epoch, and rebuilds the filters
+// from it on startup. One made with `new` keeps them in memory only, so after
+// a restart every redeemed token that hasn't expired can be replayed with the
+// password it was redeemed with. Servers sharing a key must share the
+// filters as well, or a token redeemed at one server can be replayed at
+// another.
+
+const VERSION: u8 = 1;
+const KEY_LABEL: &[u8] = b"diploma/token/v1 key";
+const ID_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+
+pub type TokenId = [u8; ID_SIZE];
+
+#[derive(Debug)]
+pub enum TokenError {
+    // Not a token sealed by this server
+    Invalid,
+    Expired,
+    Replayed,
+    WrongUser,
+    Auth(AuthError),
+    Io(io::Error),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Invalid => f.write_str("invalid token"),
+            TokenError::Expired => f.write_str("token has expired"),
+            TokenError::Replayed => f.write_str("token has already been used"),
+            TokenError::WrongUser => f.write_str("token belongs to another user"),
+            TokenError::Auth(err) => err.fmt(f),
+            TokenError::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+impl From<AuthError> for TokenError {
+    fn from(err: AuthError) -> Self {
+        TokenError::Auth(err)
+    }
+}
+
+impl From<io::Error> for TokenError {
+    fn from(err: io::Error) -> Self {
+        TokenError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TokenConfig {
+    // Seconds a token is valid for, at most `epoch`
+    pub ttl: u64,
+    // Seconds per bloom filter
+    pub epoch: u64,
+    // Expected number of logins per epoch
+    pub capacity: usize,
+    // A false positive rejects a fresh token, and its user has to enroll
+    // again, so keep this small
+    pub false_positive: f64,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            ttl: 24 * 3600,
+            epoch: 24 * 3600,
+            capacity: 100_000,
+            false_positive: 1e-9,
+        }
+    }
+}
+
+pub struct Bloom {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl Bloom {
+    pub fn new(capacity: usize, false_positive: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let n = capacity.max(1) as f64;
+        let m = (-n * false_positive.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let hashes = (m / n * ln2).round().clamp(1.0, 64.0) as u32;
+        Self {
+            bits: vec![0; (m as usize).div_ceil(64)],
+            hashes,
+        }
+    }
+
+    // Token ids are random, so they are used as the hash directly
+    fn positions(&self, id: &TokenId) -> impl Iterator<Item = usize> {
+        let h1 = u64::from_be_bytes(id[..8].try_into().unwrap());
+        let h2 = u64::from_be_bytes(id[8..].try_into().unwrap()) | 1;
+        let m = (self.bits.len() * 64) as u64;
+        (0..u64::from(self.hashes)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+    }
+
+    #[must_use]
+    pub fn contains(&self, id: &TokenId) -> bool {
+        self.positions(id)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn insert(&mut self, id: &TokenId) {
+        let positions = self.positions(id).collect::<Vec<_>>();
+        for bit in positions {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+}
+
+struct Sealed {
+    epoch: u64,
+    expiry: u64,
+    user: String,
+    record: Record,
+}
+
+impl Sealed {
+    fn encode(&self) -> Vec<u8> {
+        let user_len = u16::try_from(self.user.len()).expect("user id is too long");
+        [
+            &self.epoch.to_be_bytes()[..],
+            &self.expiry.to_be_bytes(),
+            &user_len.to_be_bytes(),
+            self.user.as_bytes(),
+            &self.record.encode(),
+        ]
+        .concat()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let epoch = reader.u64()?;
+        let expiry = reader.u64()?;
+        let user = String::from_utf8(reader.bytes16()?.to_vec()).ok()?;
+        let record = Record::decode(reader.0)?;
+        Some(Self {
+            epoch,
+            expiry,
+            user,
+            record,
+        })
+    }
+}
+
+// Ids of redeemed tokens, appended to `used-<epoch>` in a directory
+struct Journal {
+    dir: PathBuf,
+}
+
+impl Journal {
+    fn path(&self, epoch: u64) -> PathBuf {
+        self.dir.join(format!("used-{}", epoch))
+    }
+
+    fn load(&self, epoch: u64, bloom: &mut Bloom) -> io::Result<()> {
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.path(epoch))
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut ids = vec![];
+        file.read_to_end(&mut ids)?;
+        let chunks = ids.chunks_exact(ID_SIZE);
+        // A crash in the middle of an append, that token was never handed out
+        if !chunks.remainder().is_empty() {
+            let valid = ids.len() - chunks.remainder().len();
+            file.set_len(u64::try_from(valid).expect("sorry, architecture is not supported"))?;
+            file.sync_all()?;
+        }
+        chunks.for_each(|id| bloom.insert(id.try_into().unwrap()));
+        Ok(())
+    }
+
+    fn append(&self, epoch: u64, id: &TokenId) -> io::Result<()> {
+        let path = self.path(epoch);
+        let created = !path.exists();
+        let mut file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let written = file.write_all(id).and_then(|_| file.sync_data());
+        if let Err(err) = written {
+            // Don't leave a partial id in front of the next append
+            let _ = file.set_len(len);
+            return Err(err);
+        }
+        if created {
+            sync_dir(&path)?;
+        }
+        Ok(())
+    }
+
+    // Drops the files of epochs before `oldest`
+    fn forget(&self, oldest: u64) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let epoch = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("used-"))
+                .and_then(|epoch| epoch.parse::<u64>().ok());
+            if epoch.is_some_and(|epoch| epoch < oldest) {
+                fs::remove_file(self.dir.join(name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct TokenServer<C: Clock> {
+    key: [u8; 32],
+    config: TokenConfig,
+    clock: C,
+    used: BTreeMap<u64, Bloom>,
+    journal: Option<Journal>,
+}
+
+impl<C: Clock> TokenServer<C> {
+    pub fn new(key: [u8; 32], config: TokenConfig, clock: C) -> Self {
+        assert!(config.epoch > 0, "epoch can't be empty");
+        assert!(config.ttl <= config.epoch, "tokens can't outlive an epoch");
+        Self {
+            key,
+            config,
+            clock,
+            used: BTreeMap::new(),
+            journal: None,
+        }
+    }
+
+    // Like `new`, but redeemed tokens are remembered in `dir` across restarts
+    pub fn open(
+        dir: impl AsRef<Path>,
+        key: [u8; 32],
+        config: TokenConfig,
+        clock: C,
+    ) -> io::Result<Self> {
+        let mut server = Self::new(key, config, clock);
+        let journal = Journal {
+            dir: dir.as_ref().to_owned(),
+        };
+        let epoch = server.clock.now() / config.epoch;
+        journal.forget(epoch.saturating_sub(1))?;
+        for epoch in epoch.saturating_sub(1)..=epoch {
+            let mut bloom = Bloom::new(config.capacity, config.false_positive);
+            journal.load(epoch, &mut bloom)?;
+            server.used.insert(epoch, bloom);
+        }
+        server.journal = Some(journal);
+        Ok(server)
+    }
+
+    fn cipher(&self, id: &TokenId) -> ChaCha20Poly1305 {
+        let key = hmac_sha256(&self.key, &[KEY_LABEL, id]);
+        ChaCha20Poly1305::new(&key, &[0; 8], &[&[VERSION][..], id].concat())
+    }
+
+    pub fn issue(&self, user: &str, record: Record) -> Vec<u8> {
+        let now = self.clock.now();
+        let sealed = Sealed {
+            epoch: now / self.config.epoch,
+            expiry: now.saturating_add(self.config.ttl),
+            user: user.to_owned(),
+            record,
+        }
+        .encode();
+
+        let id: TokenId = rand::thread_rng().gen();
+        let mut out = vec![0; 1 + ID_SIZE + sealed.len() + TAG_SIZE];
+        out[0] = VERSION;
+        out[1..=ID_SIZE].copy_from_slice(&id);
+        let (body, tag) = out[1 + ID_SIZE..].split_at_mut(sealed.len());
+        self.cipher(&id).encrypt(&sealed, body, tag);
+        out
+    }
+
+    fn unseal(&self, token: &[u8]) -> Result<(TokenId, Sealed), TokenError> {
+        if token.len() < 1 + ID_SIZE + TAG_SIZE || token[0] != VERSION {
+            return Err(TokenError::Invalid);
+        }
+        let id: TokenId = token[1..=ID_SIZE].try_into().unwrap();
+        let (body, tag) = token[1 + ID_SIZE..].split_at(token.len() - 1 - ID_SIZE - TAG_SIZE);
+        let mut sealed = vec![0; body.len()];
+        if !self.cipher(&id).decrypt(body, &mut sealed, tag) {
+            return Err(TokenError::Invalid);
+        }
+        let sealed = Sealed::decode(&sealed).ok_or(TokenError::Invalid)?;
+        Ok((id, sealed))
+    }
+
+    // Verifies `password` against the state sealed in `token` and returns the
+    // token for the next login
+    pub fn redeem(
+        &mut self,
+        token: &[u8],
+        user: &str,
+        password: &[u8],
+        next: Option<&[u8]>,
+    ) -> Result<Vec<u8>, TokenError> {
+        let (id, mut sealed) = self.unseal(token)?;
+        if sealed.user != user {
+            return Err(TokenError::WrongUser);
+        }
+
+        let now = self.clock.now();
+        let epoch = now / self.config.epoch;
+        if self.used.keys().any(|e| e + 1 < epoch) {
+            self.used.retain(|e, _| e + 1 >= epoch);
+            if let Some(journal) = &self.journal {
+                journal.forget(epoch.saturating_sub(1))?;
+            }
+        }
+        // Filters of older epochs are gone, and so are their tokens
+        if now >= sealed.expiry || sealed.epoch + 1 < epoch {
+            return Err(TokenError::Expired);
+        }
+        if self
+            .used
+            .get(&sealed.epoch)
+            .is_some_and(|bloom| bloom.contains(&id))
+        {
+            return Err(TokenError::Replayed);
+        }
+
+        sealed.record.accept(password, next)?;
+
+        // Remembered before the next token is handed out
+        if let Some(journal) = &self.journal {
+            journal.append(sealed.epoch, &id)?;
+        }
+        let config = self.config;
+        self.used
+            .entry(sealed.epoch)
+            .or_insert_with(|| Bloom::new(config.capacity, config.false_positive))
+            .insert(&id);
+        Ok(self.issue(user, sealed.record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        base::{PrivateKey, State},
+        policy::ManualClock,
+    };
+
+    type Private = PrivateKey<crypto::sha2::Sha256, 32>;
+
+    const DAY: u64 = 24 * 3600;
+
+    fn enroll(server: &TokenServer<&ManualClock>) -> (Vec<u8>, Private) {
+        let (private, record) = Record::generate_sha256(10);
+        (server.issue("alice", record), private)
+    }
+
+    #[test]
+    fn login_rotates_token() {
+        let clock = ManualClock::new(1_000_000);
+        let mut server = TokenServer::new([7; 32], TokenConfig::default(), &clock);
+        let (mut token, mut private) = enroll(&server);
+
+        for _ in 0..5 {
+            let password = private.get_password().unwrap();
+            let next = server.redeem(&token, "alice", &password[..], None).unwrap();
+            assert!(matches!(
+                server.redeem(&next, "alice", &password[..], None),
+                Err(TokenError::Auth(_))
+            ));
+            token = next;
+            assert_eq!(private.pop_password(), State::Ok);
+            clock.advance(3600);
+        }
+
+        let password = private.get_password().unwrap();
+        assert!(matches!(
+            server.redeem(&token, "bob", &password[..], None),
+            Err(TokenError::WrongUser)
+        ));
+
+        // Another server key can't open the token
+        let mut other = TokenServer::new([8; 32], TokenConfig::default(), &clock);
+        assert!(matches!(
+            other.redeem(&token, "alice", &password[..], None),
+            Err(TokenError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn old_token_is_replayed() {
+        let clock = ManualClock::new(DAY * 100 + DAY - 60);
+        let mut server = TokenServer::new([7; 32], TokenConfig::default(), &clock);
+        let (token, private) = enroll(&server);
+        let password = private.get_password().unwrap();
+
+        server.redeem(&token, "alice", &password[..], None).unwrap();
+        assert!(matches!(
+            server.redeem(&token, "alice", &password[..], None),
+            Err(TokenError::Replayed)
+        ));
+
+        // Still caught after the epoch has changed
+        clock.advance(120);
+        assert!(matches!(
+            server.redeem(&token, "alice", &password[..], None),
+            Err(TokenError::Replayed)
+        ));
+
+        clock.advance(DAY);
+        assert!(matches!(
+            server.redeem(&token, "alice", &password[..], None),
+            Err(TokenError::Expired)
+        ));
+        assert!(server.used.is_empty());
+    }
+
+    #[test]
+    fn redeemed_tokens_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = ManualClock::new(DAY * 100 + DAY - 60);
+        let open = || TokenServer::open(dir.path(), [7; 32], TokenConfig::default(), &clock);
+        let mut server = open().unwrap();
+        let (token, mut private) = enroll(&server);
+        let password = private.get_password().unwrap();
+        let next = server.redeem(&token, "alice", &password[..], None).unwrap();
+
+        // Across the epoch change, too
+        drop(server);
+        clock.advance(120);
+        let mut server = open().unwrap();
+        assert!(matches!(
+            server.redeem(&token, "alice", &password[..], None),
+            Err(TokenError::Replayed)
+        ));
+        assert_eq!(private.pop_password(), State::Ok);
+        let password = private.get_password().unwrap();
+        server.redeem(&next, "alice", &password[..], None).unwrap();
+
+        // Files of expired epochs are dropped
+        clock.advance(2 * DAY);
+        let (token, private) = enroll(&server);
+        let password = private.get_password().unwrap();
+        server.redeem(&token, "alice", &password[..], None).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn memory_only_server_forgets_on_restart() {
+        let clock = ManualClock::new(1_000_000);
+        let mut server = TokenServer::new([7; 32], TokenConfig::default(), &clock);
+        let (token, private) = enroll(&server);
+        let password = private.get_password().unwrap();
+        server.redeem(&token, "alice", &password[..], None).unwrap();
+
+        let mut server = TokenServer::new([7; 32], TokenConfig::default(), &clock);
+        assert!(server.redeem(&token, "alice", &password[..], None).is_ok());
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let clock = ManualClock::new(1_000_000);
+        let mut server = TokenServer::new([7; 32], TokenConfig::default(), &clock);
+        let (token, private) = enroll(&server);
+        let password = private.get_password().unwrap();
+
+        for i in 0..token.len() {
+            let mut tampered = token.clone();
+            tampered[i] ^= 1;
+            assert!(matches!(
+                server.redeem(&tampered, "alice", &password[..], None),
+                Err(TokenError::Invalid)
+            ));
+        }
+        assert!(matches!(
+            server.redeem(&token[..20], "alice", &password[..], None),
+            Err(TokenError::Invalid)
+        ));
+        server.redeem(&token, "alice", &password[..], None).unwrap();
+    }
+
+    #[test]
+    fn bloom_filter() {
+        let mut bloom = Bloom::new(1000, 1e-6);
+        let mut rng = rand::thread_rng();
+        let ids = (0..1000).map(|_| rng.gen()).collect::<Vec<TokenId>>();
+        ids.iter().for_each(|id| bloom.insert(id));
+        assert!(ids.iter().all(|id| bloom.contains(id)));
+
+        let false_positives = (0..10_000).filter(|_| bloom.contains(&rng.gen())).count();
+        assert!(false_positives < 5);
+    }
+}
@@ -0,0 +1,420 @@
+use std::{fmt, io};
+
+use qrcode::{
+    render::{svg, unicode},
+    Color, EcLevel, QrCode,
+};
+use zeroize::Zeroizing;
+
+use crate::{
+    cipher::Aes128SafeBuilder,
+    encoding::{from_hex, to_hex},
+    hash::Sha256Builder,
+    secret::Secret,
+    store::{Algorithm, Record},
+};
+
+// Enrollment URIs in the style of Google Authenticator's otpauth ones, for
+// handing a hash chain to an app:
+//
+//   otpauth://chain/<label>?algorithm=sha256&size=32&rounds=1000
+//       &seed=<hex>&anchor=<hex>[&secret=<hex>][&issuer=<name>]
+//
+// The app rebuilds the chain from the seed, the anchor lets it check the
+// result and is all the server needs. AES chains also carry their key as
+// `secret`. Binary values are hex like everywhere else in the project, the
+// label and issuer are percent-encoded.
+//
+// The URI holds the seed, so whoever sees it or its QR code can compute
+// every password of the chain.
+
+const PREFIX: &str = "otpauth://chain/";
+
+// Checking an anchor keeps the whole chain in memory, 32 MiB at this size
+pub const MAX_ROUNDS: u64 = 1 << 20;
+
+// Modules of light border around a QR code, as the standard asks for
+const QUIET_ZONE: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UriError {
+    Scheme,
+    Missing(&'static str),
+    Invalid(&'static str),
+    // The anchor isn't the end of the chain the seed starts
+    Anchor,
+}
+
+impl fmt::Display for UriError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UriError::Scheme => write!(f, "not an {} URI", PREFIX),
+            UriError::Missing(name) => write!(f, "missing {}", name),
+            UriError::Invalid(name) => write!(f, "invalid {}", name),
+            UriError::Anchor => f.write_str("anchor doesn't match the seed"),
+        }
+    }
+}
+
+impl std::error::Error for UriError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Enrollment {
+    pub label: String,
+    pub issuer: Option<String>,
+    pub algorithm: Algorithm,
+    pub rounds: u64,
+    pub seed: Zeroizing<Vec<u8>>,
+    pub anchor: Vec<u8>,
+    pub secret: Zeroizing<Vec<u8>>,
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            out.push(char::from(byte));
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut out = vec![];
+    let mut bytes = s.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' => out.push(b' '),
+            byte => out.push(byte),
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+// Seed and key sizes of a chain
+fn sizes(algorithm: Algorithm) -> Option<(usize, usize)> {
+    match algorithm {
+        Algorithm::Sha256 => Some((32, 0)),
+        Algorithm::Aes128 => Some((16, 16)),
+        _ => None,
+    }
+}
+
+impl Enrollment {
+    // Derives the anchor of a new chain, `seed` and `secret` must have the
+    // sizes the algorithm needs
+    pub fn new(
+        label: &str,
+        algorithm: Algorithm,
+        rounds: u64,
+        seed: &[u8],
+        secret: &[u8],
+    ) -> Option<Self> {
+        let mut enrollment = Self {
+            label: label.to_owned(),
+            issuer: None,
+            algorithm,
+            rounds,
+            seed: Zeroizing::new(seed.to_vec()),
+            anchor: vec![],
+            secret: Zeroizing::new(secret.to_vec()),
+        };
+        enrollment.anchor = enrollment.derive_anchor()?;
+        Some(enrollment)
+    }
+
+    fn derive_anchor(&self) -> Option<Vec<u8>> {
+        let (seed_size, secret_size) = sizes(self.algorithm)?;
+        if self.seed.len() != seed_size
+            || self.secret.len() != secret_size
+            || !(1..=MAX_ROUNDS).contains(&self.rounds)
+        {
+            return None;
+        }
+        let rounds = usize::try_from(self.rounds).ok()?;
+        let anchor = match self.algorithm {
+            Algorithm::Sha256 => {
+                let key =
+                    Sha256Builder::private_from_password(rounds, Secret::from_slice(&self.seed)?);
+                key.get_password()?.to_vec()
+            }
+            _ => {
+                let key = Aes128SafeBuilder::private_from_password(
+                    rounds,
+                    Secret::from_slice(&self.secret)?,
+                    Secret::from_slice(&self.seed)?,
+                );
+                key.get_password()?.to_vec()
+            }
+        };
+        Some(anchor)
+    }
+
+    // What the server enrolls
+    #[must_use]
+    pub fn record(&self) -> Record {
+        Record::new(self.algorithm, self.anchor.clone(), self.secret.to_vec())
+    }
+
+    #[must_use]
+    pub fn to_uri(&self) -> String {
+        let mut uri = format!(
+            "{}{}?algorithm={}&size={}&rounds={}&seed={}&anchor={}",
+            PREFIX,
+            percent_encode(&self.label),
+            self.algorithm,
+            self.algorithm.size(),
+            self.rounds,
+            to_hex(&self.seed),
+            to_hex(&self.anchor)
+        );
+        if !self.secret.is_empty() {
+            uri.push_str("&secret=");
+            uri.push_str(&to_hex(&self.secret));
+        }
+        if let Some(issuer) = &self.issuer {
+            uri.push_str("&issuer=");
+            uri.push_str(&percent_encode(issuer));
+        }
+        uri
+    }
+
+    // Also checks that the anchor belongs to the seed, which takes as many
+    // one-way computations as the chain has rounds
+    pub fn parse(uri: &str) -> Result<Self, UriError> {
+        let rest = uri.strip_prefix(PREFIX).ok_or(UriError::Scheme)?;
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let label = percent_decode(label).ok_or(UriError::Invalid("label"))?;
+
+        let mut params = std::collections::BTreeMap::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or(UriError::Invalid("query"))?;
+            if params.insert(key, value).is_some() {
+                return Err(UriError::Invalid("query"));
+            }
+        }
+        let param = |name: &'static str| params.get(name).copied().ok_or(UriError::Missing(name));
+        let hex = |name: &'static str| from_hex(param(name)?).ok_or(UriError::Invalid(name));
+
+        let algorithm = Algorithm::from_name(param("algorithm")?)
+            .filter(|algorithm| algorithm.is_chain())
+            .ok_or(UriError::Invalid("algorithm"))?;
+        // Apps that don't know an algorithm can still tell the password size
+        if param("size")?.parse::<usize>().ok() != Some(algorithm.size()) {
+            return Err(UriError::Invalid("size"));
+        }
+        let (seed_size, secret_size) = sizes(algorithm).ok_or(UriError::Invalid("algorithm"))?;
+        let rounds = param("rounds")?
+            .parse()
+            .ok()
+            .filter(|rounds| (1..=MAX_ROUNDS).contains(rounds))
+            .ok_or(UriError::Invalid("rounds"))?;
+        let seed = Zeroizing::new(hex("seed")?);
+        if seed.len() != seed_size {
+            return Err(UriError::Invalid("seed"));
+        }
+        let anchor = hex("anchor")?;
+        if anchor.len() != algorithm.size() {
+            return Err(UriError::Invalid("anchor"));
+        }
+        let secret = Zeroizing::new(match params.get("secret") {
+            Some(_) => hex("secret")?,
+            None => vec![],
+        });
+        if secret.len() != secret_size {
+            return Err(match secret_size {
+                0 => UriError::Invalid("secret"),
+                _ if secret.is_empty() => UriError::Missing("secret"),
+                _ => UriError::Invalid("secret"),
+            });
+        }
+        let issuer = params
+            .get("issuer")
+            .map(|issuer| percent_decode(issuer).ok_or(UriError::Invalid("issuer")))
+            .transpose()?;
+
+        let enrollment = Self {
+            label,
+            issuer,
+            algorithm,
+            rounds,
+            seed,
+            anchor,
+            secret,
+        };
+        if enrollment.derive_anchor().as_deref() != Some(&enrollment.anchor[..]) {
+            return Err(UriError::Anchor);
+        }
+        Ok(enrollment)
+    }
+}
+
+fn qr(data: &str) -> io::Result<QrCode> {
+    // The lowest level keeps the modules large enough for phone cameras
+    QrCode::with_error_correction_level(data, EcLevel::L)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))
+}
+
+// Two modules per character cell, dark modules drawn in the foreground colour
+pub fn qr_terminal(data: &str) -> io::Result<String> {
+    Ok(qr(data)?
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .quiet_zone(true)
+        .build())
+}
+
+pub fn qr_svg(data: &str) -> io::Result<String> {
+    Ok(qr(data)?
+        .render::<svg::Color<'_>>()
+        .min_dimensions(256, 256)
+        .quiet_zone(true)
+        .build())
+}
+
+// Greyscale PNG with `scale` pixels per module
+pub fn qr_png(data: &str, scale: u32, out: impl io::Write) -> io::Result<()> {
+    let code = qr(data)?;
+    let width = code.width();
+    let colors = code.to_colors();
+    let scale = usize::try_from(scale.max(1)).expect("sorry, architecture is not supported");
+    let side = (width + 2 * QUIET_ZONE) * scale;
+
+    let mut pixels = vec![0xff; side * side];
+    for (i, color) in colors.iter().enumerate() {
+        if *color == Color::Light {
+            continue;
+        }
+        let (x, y) = (
+            (i % width + QUIET_ZONE) * scale,
+            (i / width + QUIET_ZONE) * scale,
+        );
+        for row in y..y + scale {
+            pixels[row * side + x..row * side + x + scale].fill(0);
+        }
+    }
+
+    let side = u32::try_from(side).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let mut encoder = png::Encoder::new(out, side, side);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(&pixels).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::State;
+
+    fn sha256() -> Enrollment {
+        let mut enrollment =
+            Enrollment::new("alice@example.com", Algorithm::Sha256, 20, &[7; 32], &[]).unwrap();
+        enrollment.issuer = Some("Example Corp".to_owned());
+        enrollment
+    }
+
+    #[test]
+    fn roundtrip() {
+        let enrollment = sha256();
+        let uri = enrollment.to_uri();
+        assert!(uri.starts_with(
+            "otpauth://chain/alice%40example.com?algorithm=sha256&size=32&rounds=20&seed=0707"
+        ));
+        assert!(uri.ends_with("&issuer=Example%20Corp"));
+        assert_eq!(Enrollment::parse(&uri).unwrap(), enrollment);
+
+        let aes = Enrollment::new("bob", Algorithm::Aes128, 5, &[1; 16], &[2; 16]).unwrap();
+        let parsed = Enrollment::parse(&aes.to_uri()).unwrap();
+        assert_eq!(parsed, aes);
+        assert_eq!(
+            parsed.record(),
+            Record::new(Algorithm::Aes128, aes.anchor.clone(), vec![2; 16])
+        );
+
+        // The anchor is the password the server starts from
+        let mut record = sha256().record();
+        let mut key = Sha256Builder::private_from_password(20, Secret::new([7; 32]));
+        assert_eq!(key.pop_password(), State::Ok);
+        record
+            .accept(&key.get_password().unwrap()[..], None)
+            .unwrap();
+
+        assert!(Enrollment::new("carol", Algorithm::Sha256, 5, &[1; 16], &[]).is_none());
+        assert!(Enrollment::new("carol", Algorithm::Aes128, 5, &[1; 16], &[]).is_none());
+        assert!(Enrollment::new("carol", Algorithm::CommitEd25519, 5, &[1; 32], &[]).is_none());
+    }
+
+    #[test]
+    fn rejects() {
+        let uri = sha256().to_uri();
+        let cases = [
+            (
+                uri.replace("otpauth://chain/", "otpauth://totp/"),
+                UriError::Scheme,
+            ),
+            (uri.replace("&size=32", ""), UriError::Missing("size")),
+            (uri.replace("size=32", "size=16"), UriError::Invalid("size")),
+            (
+                uri.replace("rounds=20", "rounds=0"),
+                UriError::Invalid("rounds"),
+            ),
+            (uri.replace("rounds=20", "rounds=21"), UriError::Anchor),
+            (
+                uri.replace("rounds=20", "rounds=99999999999"),
+                UriError::Invalid("rounds"),
+            ),
+            (uri.replace("seed=07", "seed=08"), UriError::Anchor),
+            (uri.replace("seed=07", "seed="), UriError::Invalid("seed")),
+            (format!("{}&secret=00", uri), UriError::Invalid("secret")),
+            (format!("{}&rounds=20", uri), UriError::Invalid("query")),
+            (
+                uri.replace("algorithm=sha256", "algorithm=commit-ed25519"),
+                UriError::Invalid("algorithm"),
+            ),
+        ];
+        for (uri, err) in cases {
+            assert_eq!(Enrollment::parse(&uri).unwrap_err(), err, "{}", uri);
+        }
+
+        let aes = Enrollment::new("bob", Algorithm::Aes128, 5, &[1; 16], &[2; 16])
+            .unwrap()
+            .to_uri();
+        let stripped = aes.replace(&format!("&secret={}", "02".repeat(16)), "");
+        assert_eq!(
+            Enrollment::parse(&stripped).unwrap_err(),
+            UriError::Missing("secret")
+        );
+    }
+
+    #[test]
+    fn qr_codes() {
+        let uri = sha256().to_uri();
+        let terminal = qr_terminal(&uri).unwrap();
+        let lines = terminal.lines().collect::<Vec<_>>();
+        assert!(lines.len() > 10);
+        assert!(lines
+            .iter()
+            .all(|line| line.chars().count() == lines[0].chars().count()));
+
+        assert!(qr_svg(&uri).unwrap().contains("<svg"));
+
+        let mut png = vec![];
+        qr_png(&uri, 3, &mut png).unwrap();
+        let decoder = png::Decoder::new(&png[..]);
+        let reader = decoder.read_info().unwrap();
+        let code = qr(&uri).unwrap();
+        let side = u32::try_from((code.width() + 2 * QUIET_ZONE) * 3).unwrap();
+        assert_eq!((reader.info().width, reader.info().height), (side, side));
+
+        assert!(qr_terminal(&"x".repeat(8000)).is_err());
+    }
+}
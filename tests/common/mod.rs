@@ -0,0 +1,18 @@
+use std::process::{Child, Command};
+
+// A daemon started by a test, killed when the test is done whether it passed
+// or not
+pub struct Process(pub Child);
+
+impl Process {
+    pub fn spawn(command: &mut Command) -> Self {
+        Self(command.spawn().unwrap())
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
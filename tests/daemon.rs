@@ -0,0 +1,201 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::Path,
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+use diploma::{
+    base::State,
+    cipher::Aes128SafeBuilder,
+    commitment::{ed25519::Ed25519, PrivateKey},
+    daemon::{Failure, Request, Response},
+    encoding::to_hex,
+    secret::Secret,
+    store::{Algorithm, Record},
+};
+
+mod common;
+
+use common::Process;
+
+fn start(dir: &Path, extra: &[&str]) -> Process {
+    let socket = dir.join("otpd.sock");
+    let daemon = Process::spawn(
+        Command::new(env!("CARGO_BIN_EXE_otpd"))
+            .arg("--socket")
+            .arg(&socket)
+            .arg("--store")
+            .arg(dir.join("store"))
+            .args(extra),
+    );
+    for _ in 0..500 {
+        if UnixStream::connect(&socket).is_ok() {
+            return daemon;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("otpd didn't come up");
+}
+
+struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    fn connect(dir: &Path) -> Self {
+        let stream = UnixStream::connect(dir.join("otpd.sock")).unwrap();
+        Self {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    fn raw(&mut self, line: &str) -> String {
+        writeln!(self.writer, "{}", line).unwrap();
+        let mut response = String::new();
+        self.reader.read_line(&mut response).unwrap();
+        response.trim_end().to_owned()
+    }
+
+    fn send(&mut self, request: Request) -> Response {
+        Response::parse(&self.raw(&request.to_string())).unwrap()
+    }
+}
+
+#[test]
+fn full_lifecycle() {
+    let dir = tempfile::tempdir().unwrap();
+    let audit = dir.path().join("audit");
+    let audit_arg = audit.to_str().unwrap();
+    let daemon = start(dir.path(), &["--audit", audit_arg, "--max-failures", "2"]);
+    let mode = std::fs::metadata(dir.path().join("otpd.sock"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let mut client = Client::connect(dir.path());
+    let (mut private, record) = Record::generate_sha256(5);
+    let user = || "alice".to_owned();
+
+    let enroll = Request::Enroll {
+        user: user(),
+        record,
+    };
+    assert_eq!(client.send(enroll.clone()), Response::Ok);
+    assert_eq!(client.send(enroll), Response::Error(Failure::Exists));
+
+    for round in 1..=3 {
+        assert_eq!(
+            client.send(Request::Challenge { user: user() }),
+            Response::Challenge {
+                algorithm: Algorithm::Sha256,
+                round
+            }
+        );
+        let password = private.get_password().unwrap();
+        let verify = Request::Verify {
+            user: user(),
+            password: password.to_vec(),
+            next: None,
+        };
+        assert_eq!(client.send(verify), Response::Ok);
+        assert_eq!(private.pop_password(), State::Ok);
+    }
+
+    // Renew onto an AES chain while one password is left
+    let mut aes = Aes128SafeBuilder::new_private(3, Secret::new([9; 16]));
+    let a0 = aes.get_password().unwrap();
+    assert_eq!(aes.pop_password(), State::Ok);
+    let renew = Request::Renew {
+        user: user(),
+        password: private.get_password().unwrap().to_vec(),
+        record: Record::aes128([9; 16], *a0.expose()),
+    };
+    assert_eq!(client.send(renew), Response::Ok);
+
+    // A second connection sees the same state, and the daemon survives
+    // garbage on the first one
+    let mut other = Client::connect(dir.path());
+    assert_eq!(client.raw("HELLO"), "ERR malformed");
+    assert_eq!(
+        other.raw(&format!(
+            "VERIFY alice {}",
+            to_hex(&aes.get_password().unwrap()[..])
+        )),
+        "OK"
+    );
+    assert_eq!(other.raw("STATUS alice"), "OK aes128 2 0 0");
+
+    // Two failures lock the user
+    let wrong = Request::Verify {
+        user: user(),
+        password: vec![0; 16],
+        next: None,
+    };
+    assert_eq!(client.send(wrong.clone()), Response::Error(Failure::Auth));
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.send(wrong), Response::Error(Failure::Auth));
+    assert!(matches!(
+        client.send(Request::Status { user: user() }),
+        Response::Status {
+            failures: 2,
+            locked_until,
+            ..
+        } if locked_until != 0
+    ));
+    assert_eq!(
+        client.send(Request::Challenge {
+            user: "bob".to_owned()
+        }),
+        Response::Error(Failure::NotFound)
+    );
+
+    // Commitments send the next public element along
+    let mut commitment = PrivateKey::new(Ed25519);
+    let enroll = Request::Enroll {
+        user: "bob".to_owned(),
+        record: Record::new(
+            Algorithm::CommitEd25519,
+            commitment.public().to_bytes().to_vec(),
+            vec![],
+        ),
+    };
+    assert_eq!(client.send(enroll), Response::Ok);
+    let reveal = commitment.private();
+    commitment.advance();
+    let verify = Request::Verify {
+        user: "bob".to_owned(),
+        password: reveal.0.to_bytes().to_vec(),
+        next: Some(commitment.public().to_bytes().to_vec()),
+    };
+    assert_eq!(client.send(verify.clone()), Response::Ok);
+    assert_eq!(client.send(verify), Response::Error(Failure::Auth));
+
+    // State survives a restart
+    drop(client);
+    drop(other);
+    drop(daemon);
+    let _daemon = start(dir.path(), &[]);
+    let mut client = Client::connect(dir.path());
+    assert_eq!(client.raw("CHALLENGE bob"), "OK commit-ed25519 1");
+    assert!(client.raw("STATUS alice").starts_with("OK aes128 2 2 "));
+
+    let log = diploma::audit::read(&audit).unwrap();
+    let events = log.iter().map(|e| e.event.to_string()).collect::<Vec<_>>();
+    assert!(events.contains(&"renewed aes128".to_owned()));
+    assert!(events.iter().any(|e| e.starts_with("locked until")));
+}
+
+#[test]
+fn overlong_line_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let _daemon = start(dir.path(), &[]);
+    let mut client = Client::connect(dir.path());
+    let line = format!("STATUS {}", "a".repeat(10_000));
+    assert_eq!(client.raw(&line), "ERR malformed");
+}
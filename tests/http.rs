@@ -0,0 +1,190 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use diploma::{
+    base::State,
+    encoding::to_hex,
+    http::{parse_object, Json},
+    store::Record,
+};
+
+mod common;
+
+use common::Process;
+
+struct Server {
+    _process: Process,
+    addr: SocketAddr,
+}
+
+impl Server {
+    fn start(dir: &Path, extra: &[&str]) -> Self {
+        let mut process = Process::spawn(
+            Command::new(env!("CARGO_BIN_EXE_otp-http"))
+                .args(["--listen", "127.0.0.1:0", "--store"])
+                .arg(dir.join("store"))
+                .args(extra)
+                .stdout(Stdio::piped()),
+        );
+        let mut line = String::new();
+        BufReader::new(process.0.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let addr = line
+            .trim()
+            .strip_prefix("listening on ")
+            .unwrap()
+            .parse()
+            .unwrap();
+        Self {
+            _process: process,
+            addr,
+        }
+    }
+}
+
+struct Client(BufReader<TcpStream>);
+
+impl Client {
+    fn connect(server: &Server) -> Self {
+        Self(BufReader::new(TcpStream::connect(server.addr).unwrap()))
+    }
+
+    fn raw(&mut self, request: &str) -> (u16, String) {
+        self.0.get_mut().write_all(request.as_bytes()).unwrap();
+        let mut status = String::new();
+        self.0.read_line(&mut status).unwrap();
+        let status = status.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            self.0.read_line(&mut header).unwrap();
+            if header == "\r\n" {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        self.0.read_exact(&mut body).unwrap();
+        (status, String::from_utf8(body).unwrap())
+    }
+
+    fn post(&mut self, path: &str, body: &str) -> (u16, String) {
+        self.raw(&format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            body.len(),
+            body
+        ))
+    }
+}
+
+// The backoff is counted in whole seconds, a failure at the start of one
+// leaves the next request inside it
+fn start_of_second() {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    thread::sleep(Duration::from_secs(1) - Duration::from_nanos(now.subsec_nanos().into()));
+}
+
+fn field(body: &str, key: &str) -> Json {
+    parse_object(body.as_bytes()).unwrap()[key].clone()
+}
+
+#[test]
+fn json_api() {
+    let dir = tempfile::tempdir().unwrap();
+    let audit = dir.path().join("audit");
+    let server = Server::start(
+        dir.path(),
+        &["--audit", audit.to_str().unwrap(), "--max-failures", "2"],
+    );
+    let mut client = Client::connect(&server);
+
+    let (mut private, record) = Record::generate_sha256(5);
+    let enroll = format!(
+        r#"{{"user": "alice", "algorithm": "sha256", "anchor": "{}"}}"#,
+        to_hex(&record.anchor[..])
+    );
+    assert_eq!(
+        client.post("/enroll", &enroll),
+        (200, r#"{"ok":true}"#.to_owned())
+    );
+    assert_eq!(client.post("/enroll", &enroll).0, 409);
+
+    // Requests share the connection
+    for round in 1..=2 {
+        let (status, body) = client.post("/challenge", r#"{"user":"alice"}"#);
+        assert_eq!(status, 200);
+        assert_eq!(field(&body, "algorithm"), Json::String("sha256".to_owned()));
+        assert_eq!(field(&body, "round"), Json::Number(round));
+
+        let verify = format!(
+            r#"{{"user":"alice","password":"{}"}}"#,
+            to_hex(&private.get_password().unwrap()[..])
+        );
+        assert_eq!(client.post("/verify", &verify).0, 200);
+        start_of_second();
+        assert_eq!(client.post("/verify", &verify).0, 401);
+        let (status, body) = client.post("/verify", &verify);
+        assert_eq!(status, 429);
+        assert_eq!(field(&body, "error"), Json::String("backoff".to_owned()));
+        assert_eq!(private.pop_password(), State::Ok);
+        thread::sleep(Duration::from_millis(1100));
+    }
+
+    let (status, body) = client.post("/status", r#"{"user":"alice"}"#);
+    assert_eq!(status, 200);
+    assert_eq!(field(&body, "round"), Json::Number(3));
+    assert_eq!(field(&body, "failures"), Json::Number(1));
+
+    // The second failure in a row locks the user
+    let wrong = format!(r#"{{"user":"alice","password":"{}"}}"#, "00".repeat(32));
+    assert_eq!(client.post("/verify", &wrong).0, 401);
+    let (status, body) = client.post("/verify", &wrong);
+    assert_eq!(status, 423);
+    assert_eq!(field(&body, "error"), Json::String("locked".to_owned()));
+
+    assert_eq!(client.post("/challenge", r#"{"user":"bob"}"#).0, 404);
+    let events = diploma::audit::read(&audit).unwrap();
+    assert!(!events.is_empty());
+}
+
+#[test]
+fn malformed_requests() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = Server::start(dir.path(), &[]);
+
+    let mut client = Client::connect(&server);
+    assert_eq!(client.post("/challenge", "{\"user\":").0, 400);
+    assert_eq!(client.post("/challenge", r#"{"user":5}"#).0, 400);
+    assert_eq!(client.post("/delete", r#"{"user":"alice"}"#).0, 400);
+    let enroll = r#"{"user":"alice","algorithm":"sha256","anchor":"00"}"#;
+    assert_eq!(client.post("/enroll", enroll).0, 400);
+    assert_eq!(
+        client
+            .raw("GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .0,
+        405
+    );
+
+    // Oversized bodies close the connection, others are still served
+    let huge = format!(
+        "POST /status HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+        1 << 20
+    );
+    assert_eq!(client.raw(&huge).0, 413);
+    let mut rest = vec![];
+    client.0.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    let mut client = Client::connect(&server);
+    assert_eq!(client.post("/status", r#"{"user":"alice"}"#).0, 404);
+}
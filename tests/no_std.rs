@@ -0,0 +1,70 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+// Builds tests/no_std/lib.rs, a `#![no_std]` crate depending on this one
+// without the `std` feature, the way firmware would use the core
+fn build(target: Option<&str>) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("no_std");
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(
+        dir.join("Cargo.toml"),
+        format!(
+            "[package]\nname = \"firmware\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [dependencies]\ndiploma = {{ path = {:?}, default-features = false }}\n\n\
+             [workspace]\n",
+            root
+        ),
+    )
+    .unwrap();
+    fs::copy(root.join("tests/no_std/lib.rs"), dir.join("src/lib.rs")).unwrap();
+    // Same versions as this build, and nothing to fetch
+    if root.join("Cargo.lock").exists() {
+        fs::copy(root.join("Cargo.lock"), dir.join("Cargo.lock")).unwrap();
+    }
+
+    let mut cargo = Command::new(env!("CARGO"));
+    cargo
+        .args(["build", "--offline"])
+        .arg("--manifest-path")
+        .arg(dir.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(dir.join("target"));
+    if let Some(target) = target {
+        cargo.args(["--target", target]);
+    }
+    let output = cargo.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn installed(target: &str) -> bool {
+    let output = Command::new("rustc")
+        .args(["--print", "sysroot"])
+        .output()
+        .unwrap();
+    let sysroot = PathBuf::from(String::from_utf8(output.stdout).unwrap().trim());
+    sysroot.join("lib/rustlib").join(target).exists()
+}
+
+#[test]
+fn host() {
+    build(None);
+}
+
+// CI adds the target, elsewhere it's skipped rather than failed
+#[test]
+fn thumbv7em() {
+    let target = "thumbv7em-none-eabihf";
+    if !installed(target) {
+        eprintln!("skipping, run rustup target add {}", target);
+        return;
+    }
+    build(Some(target));
+}
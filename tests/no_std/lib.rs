@@ -0,0 +1,19 @@
+#![no_std]
+
+// A crate without std that depends on the `base`, `hash` and `cipher` core,
+// built by tests/no_std.rs
+
+use diploma::{base::AuthError, cipher::Aes128SafeBuilder, hash::Sha256Builder, secret::Secret};
+
+pub fn first_login(seed: [u8; 32]) -> Result<(), AuthError> {
+    let mut private =
+        Sha256Builder::pebbled_from_password::<4>(16, Secret::new(seed)).ok_or(AuthError)?;
+    let anchor = private.get_password().ok_or(AuthError)?;
+    let _ = private.pop_password();
+    let mut public = Sha256Builder::new_public(*anchor.expose());
+    public.verify(private.get_password().ok_or(AuthError)?.expose())
+}
+
+pub fn check(secret: [u8; 16], anchor: [u8; 16], password: [u8; 16]) -> Result<(), AuthError> {
+    Aes128SafeBuilder::new_public(Secret::new(secret), anchor).verify_dry(&password)
+}